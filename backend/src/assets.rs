@@ -0,0 +1,38 @@
+use axum::{
+    http::{header, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use rust_embed::RustEmbed;
+use std::path::Path;
+use tower_http::services::{ServeDir, ServeFile};
+
+/// Entry point of the single-page frontend.
+const INDEX_HTML: &str = "index.html";
+
+/// Serves the frontend bundle compiled into the binary by `A`.
+///
+/// Paths that match no embedded file get `index.html`, so client-side
+/// routes load the app instead of a 404.
+pub fn embedded_frontend<A: RustEmbed + Send + Sync + 'static>() -> Router {
+    Router::new().fallback(get(serve_embedded::<A>))
+}
+
+/// Serves the frontend bundle from a directory on disk, falling back to
+/// its `index.html` for paths that match no file.
+pub fn dir_frontend(dir: &Path) -> Router {
+    let files = ServeDir::new(dir)
+        .append_index_html_on_directories(true)
+        .fallback(ServeFile::new(dir.join(INDEX_HTML)));
+    Router::new().fallback_service(files)
+}
+
+async fn serve_embedded<A: RustEmbed>(uri: Uri) -> Response {
+    let path = uri.path().trim_start_matches('/');
+    let path = if path.is_empty() { INDEX_HTML } else { path };
+    match A::get(path).or_else(|| A::get(INDEX_HTML)) {
+        Some(file) => ([(header::CONTENT_TYPE, file.metadata.mimetype().to_string())], file.data).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
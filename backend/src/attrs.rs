@@ -0,0 +1,130 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Permissions, ownership and extended attributes of a source file, kept
+/// with its record when `preserve_attrs` is enabled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct FileAttrs {
+    /// Permission bits, e.g. `0o640`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Extended attributes by name. Only collected on Linux.
+    #[serde(default)]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+/// Reads the attributes of `path`, whose metadata is `meta`.
+#[cfg(unix)]
+pub fn read(path: &Path, meta: &fs::Metadata) -> Result<FileAttrs> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(FileAttrs {
+        mode: meta.mode() & 0o7777,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        xattrs: read_xattrs(path)?,
+    })
+}
+
+/// Permissions and ownership are not tracked on this platform.
+#[cfg(not(unix))]
+pub fn read(_path: &Path, _meta: &fs::Metadata) -> Result<FileAttrs> {
+    Ok(FileAttrs::default())
+}
+
+/// Applies `attrs` to `path`. Ownership needs privileges the service may
+/// not have, so failing to change it is only logged.
+#[cfg(unix)]
+pub fn apply(path: &Path, attrs: &FileAttrs) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(attrs.mode))?;
+    if let Err(e) = std::os::unix::fs::chown(path, Some(attrs.uid), Some(attrs.gid)) {
+        tracing::warn!(
+            "Cannot restore owner {}:{} of {}: {}",
+            attrs.uid, attrs.gid, path.display(), e
+        );
+    }
+    for (name, value) in &attrs.xattrs {
+        set_xattr(path, name, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply(_path: &Path, _attrs: &FileAttrs) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &Path) -> Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Lists and reads every extended attribute of `path`. Filesystems without
+/// xattr support simply have none.
+#[cfg(target_os = "linux")]
+fn read_xattrs(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let c_path = c_path(path)?;
+    let mut xattrs = BTreeMap::new();
+    // SAFETY: a null buffer of size 0 only asks for the needed length.
+    let len = unsafe { libc::listxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+    if len < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENOTSUP) {
+            return Ok(xattrs);
+        }
+        return Err(err.into());
+    }
+    let mut names = vec![0u8; len as usize];
+    // SAFETY: `names` is a writable buffer of the given length.
+    let len = unsafe { libc::listxattr(c_path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+    if len < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    names.truncate(len as usize);
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let c_name = std::ffi::CString::new(name)?;
+        // SAFETY: as above, first the length, then into a buffer of that length.
+        let len = unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let mut value = vec![0u8; len as usize];
+        let len = unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+        if len < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        value.truncate(len as usize);
+        xattrs.insert(String::from_utf8_lossy(name).into_owned(), value);
+    }
+    Ok(xattrs)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn read_xattrs(_path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    Ok(BTreeMap::new())
+}
+
+/// Sets one extended attribute, replacing any existing value.
+#[cfg(target_os = "linux")]
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let c_path = c_path(path)?;
+    let c_name = std::ffi::CString::new(name)?;
+    // SAFETY: all pointers are valid for the given lengths.
+    let rc = unsafe { libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn set_xattr(_path: &Path, name: &str, _value: &[u8]) -> Result<()> {
+    anyhow::bail!("cannot set extended attribute {}: not supported on this platform", name)
+}
@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+/// Bytes a scan still has to encode and how fast encoding has gone so far,
+/// for estimating when everything will be protected.
+#[derive(Debug, Default, Clone)]
+pub struct Backlog {
+    remaining_bytes: u64,
+    encoded_bytes: u64,
+    encode_time: Duration,
+}
+
+impl Backlog {
+    pub fn new(total_bytes: u64) -> Self {
+        Self { remaining_bytes: total_bytes, ..Default::default() }
+    }
+
+    /// Records that a file of `bytes` was taken off the backlog after
+    /// `elapsed`, whether or not its encode succeeded.
+    pub fn done(&mut self, bytes: u64, elapsed: Duration) {
+        self.remaining_bytes = self.remaining_bytes.saturating_sub(bytes);
+        self.encoded_bytes += bytes;
+        self.encode_time += elapsed;
+    }
+
+    pub fn remaining_bytes(&self) -> u64 {
+        self.remaining_bytes
+    }
+
+    /// Seconds until the remaining bytes are encoded at the average
+    /// throughput so far. `None` until some bytes took measurable time.
+    pub fn eta_secs(&self) -> Option<u64> {
+        let secs = self.encode_time.as_secs_f64();
+        if self.encoded_bytes == 0 || secs == 0.0 {
+            return None;
+        }
+        let bytes_per_sec = self.encoded_bytes as f64 / secs;
+        Some((self.remaining_bytes as f64 / bytes_per_sec).ceil() as u64)
+    }
+}
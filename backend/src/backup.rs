@@ -0,0 +1,50 @@
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::metadata::{self, MetadataDb};
+
+const BACKUP_PREFIX: &str = "rs_guard_meta-";
+const BACKUP_SUFFIX: &str = ".json";
+
+/// Writes a timestamped metadata export into `dir` and prunes all but the
+/// newest `keep` backups. Returns the path of the new backup.
+pub fn write_backup(db: &MetadataDb, dir: &Path, keep: usize) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let name = format!(
+        "{}{}{}",
+        BACKUP_PREFIX,
+        chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        BACKUP_SUFFIX
+    );
+    let path = dir.join(name);
+    // Write under a temporary name so a crash never leaves a truncated backup behind.
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, metadata::export_json(db)?)?;
+    fs::rename(&tmp, &path)?;
+    rotate(dir, keep.max(1))?;
+    Ok(path)
+}
+
+/// Lists existing backups in `dir`, oldest first.
+pub fn list_backups(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(BACKUP_PREFIX) && n.ends_with(BACKUP_SUFFIX))
+        })
+        .collect();
+    // The timestamp format sorts lexicographically in chronological order.
+    backups.sort();
+    Ok(backups)
+}
+
+fn rotate(dir: &Path, keep: usize) -> Result<()> {
+    let backups = list_backups(dir)?;
+    let excess = backups.len().saturating_sub(keep);
+    for old in &backups[..excess] {
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
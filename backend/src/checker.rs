@@ -1,24 +1,559 @@
-use anyhow::Result;
-use std::sync::{Arc, Mutex};
-use crate::metadata::MetadataDb;
-use shared::AppStatus;
-
-/// Runs a full integrity check on all protected files.
-pub async fn run_check(app_status: Arc<Mutex<AppStatus>>, db: Arc<MetadataDb>) -> Result<()> {
-    // TODO:
-    // 1. Lock the app status to 'Checking'.
-    // 2. Iterate through all file records in the metadata DB.
-    // 3. For each file, check if the original file still exists.
-    // 4. For each set of shards, verify that all shard files exist and their checksums match
-    //    what's stored in the metadata. A simple way is to re-calculate a checksum/hash.
-    // 5. If corruption or missing files are detected, log them and add them to a "needs_repair" queue.
-    // 6. Update the AppStatus with the results (files checked, errors found).
-    // 7. Set status back to 'Idle' or 'Error' if issues were found.
-    
-    println!("Starting integrity check...");
-    // Simulate work
-    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-    println!("Integrity check finished.");
-
-    Ok(())
-} 
\ No newline at end of file
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::field;
+use shared::{CheckDiff, CheckRun, CheckTrigger, ServiceStatus};
+use crate::encoder::RSEncoder;
+use crate::metadata::{self, FileRecord};
+use crate::notifications::Notification;
+use crate::sparse::{self, Extent};
+use crate::{ioprio, merkle, protector, AppContext};
+
+/// Something found wrong with a protected file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    /// Shards or source are damaged; the file needs a repair.
+    Corrupted(String),
+    /// The source was edited but its record was not updated, so the shards
+    /// no longer represent it; the file needs to be re-protected.
+    Drifted(String),
+}
+
+impl Finding {
+    pub fn message(&self) -> &str {
+        match self {
+            Finding::Corrupted(message) | Finding::Drifted(message) => message,
+        }
+    }
+}
+
+/// The outcome of checking one file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileCheck {
+    pub path: PathBuf,
+    /// What is wrong with the file, or `None` if it is healthy.
+    pub finding: Option<Finding>,
+}
+
+impl FileCheck {
+    pub fn is_corrupted(&self) -> bool {
+        matches!(self.finding, Some(Finding::Corrupted(_)))
+    }
+
+    pub fn is_drifted(&self) -> bool {
+        matches!(self.finding, Some(Finding::Drifted(_)))
+    }
+}
+
+/// Runs a full integrity check on all protected files and records the result.
+///
+/// A file counts as corrupted when one of its shards is missing or fails its
+/// checksum, when the shard checksums disagree with the stored Merkle root,
+/// or when its source is gone or has different content although
+/// its size and modification time are unchanged. With `detect_drift`, a
+/// source that was edited since its last encode is reported as drifted.
+/// With `full_decode_verify`, each file is also rebuilt from its shards and
+/// compared with its source. Files marked as deleted are not checked.
+pub fn run_check(ctx: &AppContext) -> Result<CheckRun> {
+    run_triggered_check(ctx, CheckTrigger::Manual)
+}
+
+/// Like [`run_check`], recording `trigger` as what started it.
+pub fn run_triggered_check(ctx: &AppContext, trigger: CheckTrigger) -> Result<CheckRun> {
+    run_check_detailed(ctx, trigger).map(|(run, _)| run)
+}
+
+/// Counts a check as running in `AppContext::checks_running` until dropped.
+pub struct RunningCheck(Arc<AtomicUsize>);
+
+impl RunningCheck {
+    fn start(ctx: &AppContext) -> Self {
+        ctx.checks_running.fetch_add(1, Ordering::SeqCst);
+        RunningCheck(ctx.checks_running.clone())
+    }
+
+    /// Counts a check as running only if no other one is.
+    pub fn start_exclusive(ctx: &AppContext) -> Result<Self, CheckInProgress> {
+        ctx.checks_running
+            .compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst)
+            .map(|_| RunningCheck(ctx.checks_running.clone()))
+            .map_err(|_| CheckInProgress)
+    }
+}
+
+/// An operation that must run alone found a check already running.
+#[derive(Debug, thiserror::Error)]
+#[error("another integrity check is already running")]
+pub struct CheckInProgress;
+
+impl Drop for RunningCheck {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Spawns the task that runs [`run_scheduled_check`] every `period`.
+///
+/// With `skip_overlapping_checks`, a tick that finds a check still running
+/// is skipped. Otherwise the next check waits for the running one, and
+/// missed ticks are made up back to back.
+pub fn spawn_periodic_checks(ctx: AppContext, period: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        // The first tick fires right away, as the service starts.
+        let mut trigger = CheckTrigger::Startup;
+        loop {
+            interval.tick().await;
+            let skip_overlapping = ctx.config.skip_overlapping_checks;
+            if skip_overlapping && ctx.checks_running.load(Ordering::SeqCst) > 0 {
+                tracing::info!("Periodic check skipped, previous still running.");
+                continue;
+            }
+            tracing::info!("Kicking off periodic integrity check.");
+            let check_ctx = ctx.clone();
+            // Count the check as running before the next tick can look.
+            let running = RunningCheck::start(&ctx);
+            let check = tokio::task::spawn_blocking(move || {
+                let _running = running;
+                run_periodic_check(&check_ctx, trigger)
+            });
+            trigger = CheckTrigger::Scheduled;
+            let report = async move {
+                match check.await {
+                    Ok(Err(e)) => tracing::error!("Periodic check failed: {}", e),
+                    Err(e) => tracing::error!("Periodic check panicked: {}", e),
+                    Ok(Ok(_)) => {}
+                }
+            };
+            if skip_overlapping {
+                tokio::spawn(report);
+            } else {
+                report.await;
+            }
+        }
+    })
+}
+
+/// Runs a check for the periodic task, unless the current local time is
+/// outside the configured `check_window`. Returns `None` when skipped.
+pub fn run_scheduled_check(ctx: &AppContext) -> Result<Option<CheckRun>> {
+    run_periodic_check(ctx, CheckTrigger::Scheduled)
+}
+
+fn run_periodic_check(ctx: &AppContext, trigger: CheckTrigger) -> Result<Option<CheckRun>> {
+    if let Some(window) = ctx.config.check_window {
+        if !window.contains(chrono::Local::now().time()) {
+            tracing::info!("Skipping periodic check outside the check window.");
+            // Waiting for the window is the loop working as configured.
+            ctx.heartbeat.beat();
+            return Ok(None);
+        }
+    }
+    run_triggered_check(ctx, trigger).map(Some)
+}
+
+/// Compares the corrupted files of two check runs.
+pub fn diff_runs(from: &CheckRun, to: &CheckRun) -> CheckDiff {
+    let before: HashSet<&String> = from.corrupted_paths.iter().collect();
+    let after: HashSet<&String> = to.corrupted_paths.iter().collect();
+    let mut diff = CheckDiff::default();
+    for path in &to.corrupted_paths {
+        if before.contains(path) {
+            diff.still_corrupted.push(path.clone());
+        } else {
+            diff.newly_corrupted.push(path.clone());
+        }
+    }
+    diff.newly_healed = from.corrupted_paths.iter().filter(|path| !after.contains(path)).cloned().collect();
+    diff
+}
+
+/// Like [`run_triggered_check`], but also returns the outcome for every checked file.
+pub fn run_check_detailed(ctx: &AppContext, trigger: CheckTrigger) -> Result<(CheckRun, Vec<FileCheck>)> {
+    let _running = RunningCheck::start(ctx);
+    let _io = ioprio::lower(ctx.config.io_priority);
+    let span = tracing::trace_span!(
+        "check",
+        files = field::Empty,
+        corrupted = field::Empty,
+        drifted = field::Empty,
+        duration_ms = field::Empty,
+    );
+    let _entered = span.enter();
+    let started = chrono::Utc::now();
+    ctx.status.lock().unwrap().status = ServiceStatus::Checking;
+    ctx.publish_status();
+    let result = check_all(ctx);
+    let finished = chrono::Utc::now();
+    span.record("duration_ms", (finished - started).num_milliseconds());
+    if let Err(e) = &result {
+        ctx.status.lock().unwrap().status = ServiceStatus::Error(e.to_string());
+        ctx.publish_status();
+    }
+    let files = result?;
+    let paths = |keep: fn(&FileCheck) -> bool| -> Vec<String> {
+        files.iter().filter(|file| keep(file)).map(|file| metadata::display_path(&file.path)).collect()
+    };
+    let corrupted_paths = paths(FileCheck::is_corrupted);
+    let drifted_paths = paths(FileCheck::is_drifted);
+    span.record("files", files.len());
+    span.record("corrupted", corrupted_paths.len());
+    span.record("drifted", drifted_paths.len());
+
+    // Compare with the previous run so that only transitions are alerted on.
+    let previously_corrupted: HashSet<String> = metadata::last_check_run(&ctx.db)?
+        .map(|previous| previous.corrupted_paths.into_iter().collect())
+        .unwrap_or_default();
+    let newly_corrupted: Vec<String> =
+        corrupted_paths.iter().filter(|path| !previously_corrupted.contains(*path)).cloned().collect();
+    let newly_healed: Vec<String> = files
+        .iter()
+        .filter(|file| !file.is_corrupted())
+        .map(|file| metadata::display_path(&file.path))
+        .filter(|path| previously_corrupted.contains(path))
+        .collect();
+
+    let run = CheckRun {
+        id: metadata::next_check_id(&ctx.db)?,
+        trigger,
+        started: started.to_rfc3339(),
+        finished: finished.to_rfc3339(),
+        checked: files.len() as u64,
+        corrupted: corrupted_paths.len() as u64,
+        corrupted_paths,
+        drifted: drifted_paths.len() as u64,
+        drifted_paths,
+        newly_corrupted,
+        newly_healed,
+    };
+    metadata::store_check_run(&ctx.db, &run)?;
+    ctx.session.check_run(run.newly_corrupted.len());
+    if !run.newly_corrupted.is_empty() || !run.newly_healed.is_empty() {
+        ctx.notifications.send(ctx, Notification::CorruptionChanged {
+            check_id: run.id,
+            newly_corrupted: run.newly_corrupted.clone(),
+            newly_healed: run.newly_healed.clone(),
+            events: 1,
+        });
+    }
+
+    let summary = format!(
+        "{} files checked, {} corrupted, {} drifted",
+        run.checked, run.corrupted, run.drifted
+    );
+    tracing::info!("Integrity check finished: {}", summary);
+    let mut status = ctx.status.lock().unwrap();
+    status.last_check_time = Some(run.finished.clone());
+    status.last_check_result = summary.clone();
+    status.status = ServiceStatus::Idle;
+    status.logs.push(format!("[Checker] {}", summary));
+    drop(status);
+    update_stale_files(ctx)?;
+    ctx.publish_status();
+    ctx.heartbeat.beat();
+    Ok((run, files))
+}
+
+/// Counts protected files whose last successful verification (or encode,
+/// if never verified) is older than `max_verification_age_secs`, and
+/// publishes the count as `stale_files` in the status.
+pub fn update_stale_files(ctx: &AppContext) -> Result<u64> {
+    let max_age = ctx.config.max_verification_age_secs;
+    if max_age == 0 {
+        return Ok(0);
+    }
+    let cutoff = protector::now_secs().saturating_sub(max_age);
+    let stale = metadata::list_files(&ctx.db)?
+        .iter()
+        .filter(|(_, record)| record.deleted_at.is_none())
+        .filter(|(_, record)| record.last_verified.unwrap_or(record.protected_at) < cutoff)
+        .count() as u64;
+    if stale > 0 {
+        tracing::warn!("{} protected files were not verified in the last {} seconds", stale, max_age);
+    }
+    ctx.status.lock().unwrap().stale_files = stale;
+    Ok(stale)
+}
+
+fn check_all(ctx: &AppContext) -> Result<Vec<FileCheck>> {
+    let mut records = metadata::list_files(&ctx.db)?;
+    // High-priority files first, so their damage is found and repaired soonest.
+    records.sort_by_key(|(_, record)| std::cmp::Reverse(record.priority));
+    let mut files = Vec::new();
+    for (path, _) in records {
+        let _lock = ctx.file_locks.lock(&path);
+        // Re-read the record under the lock in case a repair or re-encode just replaced it.
+        let Some(record) = metadata::get_file_metadata(&ctx.db, &path)? else {
+            continue;
+        };
+        if record.deleted_at.is_some() {
+            continue;
+        }
+        let finding = check_record(ctx, &path, record)?;
+        files.push(FileCheck { path, finding });
+    }
+    Ok(files)
+}
+
+/// Checks one protected file as a full check would, without recording a
+/// check run. Fails if the file is not protected.
+pub fn check_file(ctx: &AppContext, path: &Path) -> Result<Option<Finding>> {
+    let _lock = ctx.file_locks.lock(path);
+    let record = metadata::get_file_metadata(&ctx.db, path)?
+        .ok_or_else(|| anyhow!("{} is not protected", metadata::display_path(path)))?;
+    check_record(ctx, path, record)
+}
+
+/// Checks a file whose lock is held, logging a problem or recording that
+/// it was verified.
+fn check_record(ctx: &AppContext, path: &Path, mut record: FileRecord) -> Result<Option<Finding>> {
+    let finding = find_problem(ctx, path, &record)?;
+    match &finding {
+        Some(Finding::Corrupted(problem)) => {
+            tracing::warn!("{} is corrupted: {}", metadata::display_path(path), problem);
+        }
+        Some(Finding::Drifted(problem)) => {
+            tracing::warn!("{} has drifted: {}", metadata::display_path(path), problem);
+        }
+        None => {
+            record.last_verified = Some(protector::now_secs());
+            metadata::store_file_metadata(&ctx.db, path, &record)?;
+        }
+    }
+    Ok(finding)
+}
+
+/// Describes the first problem found with a protected file, if any.
+fn find_problem(ctx: &AppContext, path: &Path, record: &FileRecord) -> Result<Option<Finding>> {
+    if record.merkle_root.as_ref().is_some_and(|root| *root != merkle::merkle_root(&record.shard_checksums)) {
+        return Ok(Some(Finding::Corrupted("shard checksums do not match the Merkle root".to_string())));
+    }
+    let stripes = record.stripes();
+    // Cheap length comparison first; hashing every shard is the expensive part.
+    if ctx.config.check_shard_lengths {
+        if let Some(finding) = shard_length_finding(ctx, &stripes)? {
+            return Ok(Some(finding));
+        }
+    }
+    if ctx.config.full_decode_verify {
+        return full_decode(ctx, path, record);
+    }
+    // The stored hash tells first whether the source is intact. Trusted, a
+    // match leaves only the shards' presence and length to confirm.
+    let source_matches = matches_stored_hash(ctx, path, record)?;
+    if ctx.config.trust_source_hash && source_matches == Some(true) {
+        if !ctx.config.check_shard_lengths {
+            if let Some(finding) = shard_length_finding(ctx, &stripes)? {
+                return Ok(Some(finding));
+            }
+        }
+        return source_finding(ctx, path, record, || Ok(true));
+    }
+    // Shards of appended stripes are numbered on from those of the first.
+    for (number, stripe) in stripes.iter().enumerate() {
+        let first = number * stripe.total_shards();
+        for index in 0..stripe.total_shards() {
+            match ctx.store.read_shard_uncached(&stripe.file_id, index)? {
+                None => return Ok(Some(Finding::Corrupted(format!("shard {} is missing", first + index)))),
+                Some(shard) if protector::checksum(&shard) != stripe.shard_checksums[index] => {
+                    return Ok(Some(Finding::Corrupted(format!("shard {} is damaged", first + index))));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    source_finding(ctx, path, record, || match source_matches {
+        Some(matches) => Ok(matches),
+        None => Ok(protector::hash_file(path)? == record.checksum),
+    })
+}
+
+/// Reports the first shard of `stripes` that is missing or not the length
+/// its stripe was encoded with.
+fn shard_length_finding(ctx: &AppContext, stripes: &[FileRecord]) -> Result<Option<Finding>> {
+    for (number, stripe) in stripes.iter().enumerate() {
+        let first = number * stripe.total_shards();
+        for index in 0..stripe.total_shards() {
+            match ctx.store.shard_len(&stripe.file_id, index)? {
+                None => return Ok(Some(Finding::Corrupted(format!("shard {} is missing", first + index)))),
+                Some(len) if len != stripe.shard_size as u64 => {
+                    return Ok(Some(Finding::Corrupted(format!(
+                        "shard {} is {} bytes, expected {}",
+                        first + index, len, stripe.shard_size
+                    ))));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the source hashes to the digest stored when it was encoded;
+/// `None` if there is no BLAKE3 digest to compare with, it is not that of
+/// `record` (left behind by an import or a replica replacing the record),
+/// or the source cannot be read, leaving the decision to the full check.
+fn matches_stored_hash(ctx: &AppContext, path: &Path, record: &FileRecord) -> Result<Option<bool>> {
+    let Some(stored) = metadata::get_file_hash(&ctx.db, path)? else {
+        return Ok(None);
+    };
+    if stored.algorithm != metadata::BLAKE3 || stored.digest != record.checksum {
+        return Ok(None);
+    }
+    Ok(protector::hash_file(path).ok().map(|live| live == stored.digest))
+}
+
+/// Judges a source once its shards were found intact. `content_matches`
+/// tells whether its content is what was protected, and is only called
+/// when that matters.
+fn source_finding(
+    ctx: &AppContext,
+    path: &Path,
+    record: &FileRecord,
+    content_matches: impl FnOnce() -> Result<bool>,
+) -> Result<Option<Finding>> {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(Some(Finding::Corrupted("source file is missing".to_string()))),
+    };
+    let edited = meta.len() != record.size || protector::modified_secs(&meta) != record.mtime;
+    // Without drift detection, an edit is left for the scanner to pick up.
+    // Mtimes on network filesystems are not trusted, so there it is hashed anyway.
+    if edited && !ctx.config.detect_drift && !ctx.config.network_fs_mode {
+        return Ok(None);
+    }
+    if content_matches()? {
+        return Ok(None);
+    }
+    // An edit changes size or mtime; different content behind unchanged
+    // ones is damage to the source.
+    Ok(Some(if edited {
+        Finding::Drifted("source was modified since it was protected".to_string())
+    } else {
+        Finding::Corrupted("source content no longer matches its checksum".to_string())
+    }))
+}
+
+/// Rebuilds a file from its shards and compares it with the source, as
+/// `full_decode_verify` does. The file is processed in stripes, each the
+/// same range of every shard, narrow enough that the buffers for one stripe
+/// fit in `full_decode_memory_bytes`. Shard checksums and parity are checked
+/// in the same pass.
+fn full_decode(ctx: &AppContext, path: &Path, record: &FileRecord) -> Result<Option<Finding>> {
+    let mut source = fs::File::open(path).ok();
+    let mut source_offset = 0;
+    for (number, stripe) in record.stripes().iter().enumerate() {
+        let first = number * stripe.total_shards();
+        if let Some(finding) = full_decode_stripe(ctx, stripe, first, source_offset, &mut source)? {
+            return Ok(Some(finding));
+        }
+        source_offset += stripe.size;
+    }
+    source_finding(ctx, path, record, || Ok(source.is_some()))
+}
+
+/// Decodes one independently encoded stripe of a file for [`full_decode`],
+/// whose shards are numbered from `first` and whose content starts at
+/// `source_offset` in the source. Returns what is wrong with the shards;
+/// `source` is dropped as soon as it differs from what was decoded.
+fn full_decode_stripe(
+    ctx: &AppContext,
+    record: &FileRecord,
+    first: usize,
+    source_offset: u64,
+    source: &mut Option<fs::File>,
+) -> Result<Option<Finding>> {
+    let total = record.total_shards();
+    let mut shards = Vec::with_capacity(total);
+    for index in 0..total {
+        match ctx.store.open_shard(&record.file_id, index)? {
+            None => return Ok(Some(Finding::Corrupted(format!("shard {} is missing", first + index)))),
+            Some(shard) if shard.payload_len() != record.shard_size as u64 => {
+                return Ok(Some(Finding::Corrupted(format!(
+                    "shard {} is {} bytes, expected {}",
+                    first + index, shard.payload_len(), record.shard_size
+                ))));
+            }
+            Some(shard) => shards.push(shard),
+        }
+    }
+    let encoder = RSEncoder::with_field(record.data_shards, record.parity_shards, record.parity_algorithm, record.field)?;
+    // One buffer per shard, plus one for the matching part of the source.
+    // GF(2^16) parity is only checked on whole 2-byte elements.
+    let symbol = record.field.symbol_len();
+    let width = (ctx.config.full_decode_memory_bytes / (total + 1) / symbol * symbol).clamp(symbol, record.shard_size.max(1));
+    let mut stripe = vec![vec![0u8; width]; total];
+    let mut source_buf = vec![0u8; width];
+    let mut hashers = vec![blake3::Hasher::new(); total];
+    let mut parity_matches = true;
+    let encoded_len = record.encoded_len() as u64;
+    let mut offset = 0;
+    while offset < record.shard_size {
+        let len = width.min(record.shard_size - offset);
+        for (index, shard) in shards.iter_mut().enumerate() {
+            shard.read_at(offset as u64, &mut stripe[index][..len])?;
+            hashers[index].update(&stripe[index][..len]);
+        }
+        let slices: Vec<&[u8]> = stripe.iter().map(|buf| &buf[..len]).collect();
+        parity_matches = parity_matches && encoder.verify(&slices)?;
+        if let Some(file) = source {
+            for (index, data) in slices[..record.data_shards].iter().enumerate() {
+                let start = (index * record.shard_size + offset) as u64;
+                let end = (start + len as u64).min(encoded_len);
+                let expected = &data[..end.saturating_sub(start) as usize];
+                let extents = record.data_extents.as_deref();
+                if !matches_source(file, extents, source_offset, start, expected, &mut source_buf)? {
+                    *source = None;
+                    break;
+                }
+            }
+        }
+        offset += len;
+    }
+    for (index, hasher) in hashers.iter().enumerate() {
+        if hasher.finalize().to_hex().as_str() != record.shard_checksums[index] {
+            return Ok(Some(Finding::Corrupted(format!("shard {} is damaged", first + index))));
+        }
+    }
+    if !parity_matches {
+        return Ok(Some(Finding::Corrupted("parity shards do not match the data shards".to_string())));
+    }
+    Ok(None)
+}
+
+/// Compares `expected`, found at `pos` in the encoded content of a stripe
+/// starting at `stripe_offset`, with the source. Only the data extents of a
+/// sparse source are compared.
+fn matches_source(
+    file: &mut fs::File,
+    extents: Option<&[Extent]>,
+    stripe_offset: u64,
+    pos: u64,
+    expected: &[u8],
+    buf: &mut [u8],
+) -> Result<bool> {
+    let mut at = 0;
+    while at < expected.len() {
+        let (offset, run) = match extents {
+            Some(extents) => sparse::locate(extents, pos + at as u64),
+            None => (stripe_offset + pos + at as u64, u64::MAX),
+        };
+        let len = (expected.len() - at).min(run.min(usize::MAX as u64) as usize);
+        if len == 0 {
+            return Ok(false);
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        match file.read_exact(&mut buf[..len]) {
+            Ok(()) if buf[..len] == expected[at..at + len] => at += len,
+            Ok(()) => return Ok(false),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, bail, Result};
+use std::fs;
+use std::path::Path;
+use shared::CheckTrigger;
+use crate::report::{self, ReportFormat};
+use crate::{checker, config, metadata, par2, salvage, AppContext};
+
+/// Exit code when every file is healthy.
+pub const EXIT_OK: i32 = 0;
+/// Exit code when at least one file is corrupted or could not be recovered.
+pub const EXIT_CORRUPTED: i32 = 1;
+
+const VERIFY_USAGE: &str =
+    "usage: backend verify --format json|junit [--config PATH] [--db PATH] [--output PATH]";
+const RECOVER_USAGE: &str = "usage: backend recover-from-shards SHARD_DIR [--output DIR]";
+const IMPORT_PAR2_USAGE: &str = "usage: backend import-par2 SET.par2 [--config PATH] [--db PATH]";
+
+/// `verify`: runs a full check and writes a report, for CI pipelines that
+/// gate on data integrity. Returns the process exit code.
+///
+/// The metadata DB can only be opened by one process at a time, so this is
+/// meant to run while the service is stopped.
+pub fn verify(args: &[String]) -> Result<i32> {
+    let mut format = None;
+    let mut config_path = "config/folders.toml".to_string();
+    let mut db_path = "rs_guard_meta.db".to_string();
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or_else(|| anyhow!("`{}` needs a value\n{}", arg, VERIFY_USAGE));
+        match arg.as_str() {
+            "--format" => format = Some(value()?.parse::<ReportFormat>()?),
+            "--config" => config_path = value()?,
+            "--db" => db_path = value()?,
+            "--output" => output = Some(value()?),
+            other => bail!("unexpected argument `{}`\n{}", other, VERIFY_USAGE),
+        }
+    }
+    let format = format.ok_or_else(|| anyhow!("`--format` is required\n{}", VERIFY_USAGE))?;
+
+    let ctx = AppContext::new(config::load_config(&config_path)?, metadata::open_db(&db_path)?)?;
+    let (run, files) = checker::run_check_detailed(&ctx, CheckTrigger::Manual)?;
+    let rendered = report::render(format, &run, &files)?;
+    match output {
+        Some(path) => fs::write(path, rendered)?,
+        None => print!("{}", rendered),
+    }
+    Ok(if run.corrupted == 0 { EXIT_OK } else { EXIT_CORRUPTED })
+}
+
+/// `recover-from-shards`: rebuilds files from the footers of the shards in
+/// a directory alone, for when the metadata DB is lost. Needs shards written
+/// with `shard_footers` enabled. Returns the process exit code: non-zero if
+/// any file could not be rebuilt.
+pub fn recover_from_shards(args: &[String]) -> Result<i32> {
+    let mut shard_dir = None;
+    let mut output = "recovered".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => {
+                output = args.next().cloned().ok_or_else(|| anyhow!("`--output` needs a value\n{}", RECOVER_USAGE))?;
+            }
+            other if shard_dir.is_none() && !other.starts_with("--") => shard_dir = Some(other.to_string()),
+            other => bail!("unexpected argument `{}`\n{}", other, RECOVER_USAGE),
+        }
+    }
+    let shard_dir = shard_dir.ok_or_else(|| anyhow!("the shard directory is required\n{}", RECOVER_USAGE))?;
+
+    let summary = salvage::recover_from_shards(Path::new(&shard_dir), Path::new(&output))?;
+    for path in &summary.recovered {
+        println!("recovered {}", path.display());
+    }
+    for (path, reason) in &summary.failed {
+        eprintln!("failed {}: {}", path, reason);
+    }
+    if summary.unusable_shards > 0 {
+        eprintln!("{} shard files had no valid footer or failed their checksum", summary.unusable_shards);
+    }
+    Ok(if summary.failed.is_empty() { EXIT_OK } else { EXIT_CORRUPTED })
+}
+
+/// `import-par2`: protects the files covered by an existing PAR2 set, so
+/// they are checked and repaired like any other. Returns the process exit
+/// code: non-zero if any file of the set is missing or no longer matches it.
+///
+/// Like `verify`, this is meant to run while the service is stopped.
+pub fn import_par2(args: &[String]) -> Result<i32> {
+    let mut set = None;
+    let mut config_path = "config/folders.toml".to_string();
+    let mut db_path = "rs_guard_meta.db".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or_else(|| anyhow!("`{}` needs a value\n{}", arg, IMPORT_PAR2_USAGE));
+        match arg.as_str() {
+            "--config" => config_path = value()?,
+            "--db" => db_path = value()?,
+            other if set.is_none() && !other.starts_with("--") => set = Some(other.to_string()),
+            other => bail!("unexpected argument `{}`\n{}", other, IMPORT_PAR2_USAGE),
+        }
+    }
+    let set = set.ok_or_else(|| anyhow!("the .par2 file is required\n{}", IMPORT_PAR2_USAGE))?;
+
+    let ctx = AppContext::new(config::load_config(&config_path)?, metadata::open_db(&db_path)?)?;
+    let outcome = par2::import(&ctx, Path::new(&set))?;
+    for path in &outcome.imported {
+        println!("imported {}", path.display());
+    }
+    for path in &outcome.mismatched {
+        eprintln!("mismatched {}: content differs from the PAR2 set", path.display());
+    }
+    for path in &outcome.missing {
+        eprintln!("missing {}", path.display());
+    }
+    Ok(if outcome.mismatched.is_empty() && outcome.missing.is_empty() { EXIT_OK } else { EXIT_CORRUPTED })
+}
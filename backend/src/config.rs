@@ -1,17 +1,955 @@
 use serde::Deserialize;
 use std::fs;
-use std::path::PathBuf;
-use anyhow::Result;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use crate::encoder::ParityAlgorithm;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct AppConfig {
+    /// Also holds the paths of the `[[watched]]` entries once parsed.
+    #[serde(default)]
     pub watched_directories: Vec<PathBuf>,
+    /// Watched directories in table form, which can carry a label:
+    /// `[[watched]]` with `path = "..."` and `label = "..."`.
+    #[serde(default)]
+    pub watched: Vec<WatchedDirectory>,
     pub data_shards: usize,
     pub parity_shards: usize,
+    /// Directory where encoded shards are written.
+    #[serde(default = "default_shard_store_dir")]
+    pub shard_store_dir: PathBuf,
+    /// When true, files whose path is not valid UTF-8 are skipped with a warning.
+    /// When false they are protected and their path is stored byte-for-byte.
+    #[serde(default)]
+    pub skip_non_utf8_paths: bool,
+    /// Directory that receives periodic metadata exports. Disabled when unset.
+    #[serde(default)]
+    pub metadata_backup_dir: Option<PathBuf>,
+    #[serde(default = "default_metadata_backup_interval_secs")]
+    pub metadata_backup_interval_secs: u64,
+    /// Number of backups kept in `metadata_backup_dir`; older ones are deleted.
+    #[serde(default = "default_metadata_backup_keep")]
+    pub metadata_backup_keep: usize,
+    /// Refuse bulk encoding when the shard store would run out of inodes.
+    #[serde(default = "default_true")]
+    pub check_free_inodes: bool,
+    /// Inodes that must remain free in the shard store after a bulk encode.
+    #[serde(default = "default_inode_reserve")]
+    pub inode_reserve: u64,
+    /// Bytes that must stay free on the shard store's filesystem. Encodes
+    /// that would eat into this reserve are refused.
+    #[serde(default)]
+    pub shard_store_reserve_bytes: u64,
+    /// Write shards with a self-describing header and the `.rsguard`
+    /// extension so they cannot be mistaken for user data.
+    #[serde(default = "default_true")]
+    pub shard_headers: bool,
+    /// End each shard file with a footer describing the whole file, so files
+    /// can be rebuilt with `recover-from-shards` even if the metadata DB is lost.
+    #[serde(default)]
+    pub shard_footers: bool,
+    /// Memory budget for caching recently read shards. 0 disables the cache.
+    #[serde(default)]
+    pub shard_cache_bytes: usize,
+    /// Maximum number of API requests served at once; excess requests get
+    /// `503 Service Unavailable`. 0 means unlimited.
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Maximum number of `/api/events` streams open at once; further
+    /// subscriptions get `503 Service Unavailable`. 0 means unlimited.
+    #[serde(default = "default_max_stream_clients")]
+    pub max_stream_clients: usize,
+    /// Glob patterns for files that are never protected. Merged with the
+    /// `.rsguardignore` files found in watched directories.
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+    /// Protect only the files listed in `protected_paths`, ignoring every
+    /// other file under the watched directories.
+    #[serde(default)]
+    pub allowlist_only: bool,
+    /// Files protected when `allowlist_only` is set. A directory entry
+    /// admits everything below it.
+    #[serde(default)]
+    pub protected_paths: Vec<PathBuf>,
+    /// File types protected by content rather than by path: names such as
+    /// `sqlite` or `png`, or leading magic bytes as hex (`0x89504e47`). A
+    /// file starting with one of them is admitted even when `allowlist_only`
+    /// would skip it, whatever its name or extension.
+    #[serde(default)]
+    pub protect_signatures: Vec<String>,
+    /// Serve the web UI from this directory instead of the bundle embedded
+    /// in release builds. Debug builds default to `../frontend/dist`.
+    #[serde(default)]
+    pub frontend_dir: Option<PathBuf>,
+    /// Abandon a single file's encode after this many seconds so that one
+    /// stuck file cannot hold up the rest. 0 disables the timeout.
+    #[serde(default)]
+    pub encode_timeout_secs: u64,
+    /// Capacity of the queue between the file watcher and the code that
+    /// handles its events. Events that do not fit count as an overflow.
+    #[serde(default = "default_watcher_event_buffer")]
+    pub watcher_event_buffer: usize,
+    /// What to do when watcher events were lost, either because the OS
+    /// queue overflowed or `watcher_event_buffer` was full.
+    #[serde(default)]
+    pub watcher_overflow: OverflowStrategy,
+    /// Store a Merkle root over each file's shard checksums so that checks
+    /// can detect checksums that were rewritten to match tampered shards.
+    #[serde(default = "default_true")]
+    pub merkle_roots: bool,
+    /// Where `/api/read` takes file content from when the request does not say.
+    #[serde(default)]
+    pub default_read_source: ReadSource,
+    /// Base URLs of peer rs_guard instances (e.g. `http://backup-host:3000`)
+    /// that receive a copy of every file's shards and record. Peers require
+    /// the same `api_token` on replication requests.
+    #[serde(default)]
+    pub replication_peers: Vec<String>,
+    /// URL that check results are POSTed to as JSON when files become
+    /// corrupted or healed since the previous check.
+    #[serde(default)]
+    pub notification_webhook: Option<String>,
+    /// Notifications raised within this many seconds of each other are sent
+    /// as one summarized message, and at most one is sent per window. 0
+    /// sends each notification as soon as it is raised.
+    #[serde(default = "default_notification_coalesce_secs")]
+    pub notification_coalesce_secs: u64,
+    /// Check the erasure codec against a known parity vector at startup and
+    /// refuse to start if it computes anything else.
+    #[serde(default = "default_true")]
+    pub verify_codec_on_startup: bool,
+    /// Seconds to keep the record and shards of a deleted file before they
+    /// are purged. 0 keeps them forever.
+    #[serde(default)]
+    pub deleted_retention_secs: u64,
+    /// Order in which scans, such as the one at startup, encode files.
+    #[serde(default)]
+    pub initial_scan_order: ScanOrder,
+    /// Store each file's mode, owner and extended attributes at encode time
+    /// and restore them when the file is recovered.
+    #[serde(default)]
+    pub preserve_attrs: bool,
+    /// For watched directories on NFS/SMB: poll them instead of relying on
+    /// change events, and compare file contents wherever size and mtime
+    /// would otherwise be trusted to detect edits.
+    #[serde(default)]
+    pub network_fs_mode: bool,
+    /// `/api/heartbeat` reports stale (and answers 503) once no check cycle
+    /// completed for this many seconds. 0 never reports stale.
+    #[serde(default = "default_heartbeat_max_age_secs")]
+    pub heartbeat_max_age_secs: u64,
+    /// Treat paths differing only in case as the same file, so a case-only
+    /// rename moves the record instead of protecting the file twice. Unset,
+    /// it is detected from the first watched directory's filesystem.
+    #[serde(default)]
+    pub case_insensitive_paths: Option<bool>,
+    /// Records read from the database per step of a file listing. Other
+    /// requests get their turn between steps.
+    #[serde(default = "default_listing_batch_size")]
+    pub listing_batch_size: usize,
+    /// I/O priority of the threads running encodes and checks.
+    #[serde(default)]
+    pub io_priority: IoPriority,
+    /// Compact the metadata database this often, reclaiming the space of
+    /// deleted and rewritten records. 0 leaves it to sled's own pace.
+    #[serde(default)]
+    pub db_compact_interval_secs: u64,
+    /// Verify every protected file this often, starting at startup. 0
+    /// disables scheduled checks; checks then only run when requested.
+    #[serde(default = "default_check_interval_secs")]
+    pub check_interval_secs: u64,
+    /// Defer encoding a file while another process has it open for writing
+    /// (Linux), so a half-written state is never captured. It is encoded
+    /// once the writer closes it.
+    #[serde(default)]
+    pub skip_files_open_for_write: bool,
+    #[serde(default)]
+    pub startup_scan_mode: StartupScanMode,
+    /// Earlier versions of each file to keep when it is re-encoded, so they
+    /// can still be recovered from their shards. 0 keeps only the current one.
+    #[serde(default)]
+    pub keep_versions: usize,
+    /// Files compared with each peer by `POST /api/replication/verify`,
+    /// picked at random on every run. 0 compares all of them.
+    #[serde(default)]
+    pub replication_verify_sample: usize,
+    /// Re-encode a file the watcher sees changing at most this often;
+    /// changes in between are picked up by the next encode. 0 encodes on
+    /// every change.
+    #[serde(default)]
+    pub min_reencode_interval_secs: u64,
+    /// Hex-encoded ed25519 secret key that `GET /api/report/signed` signs
+    /// integrity reports with.
+    #[serde(default)]
+    pub report_signing_key: Option<String>,
+    /// Have checks rebuild each file from its shards and compare it with
+    /// the source, rather than only hashing shards and source separately.
+    #[serde(default)]
+    pub full_decode_verify: bool,
+    /// Memory a full-decode check may use for one file. Files are rebuilt
+    /// in stripes small enough to fit, however large they are.
+    #[serde(default = "default_full_decode_memory_bytes")]
+    pub full_decode_memory_bytes: usize,
+    /// Protect the config file and an export of the metadata DB too, in
+    /// a `self` directory of the shard store with its own DB. Refreshed
+    /// every `metadata_backup_interval_secs`.
+    #[serde(default)]
+    pub protect_self: bool,
+    /// When a protected file only grew, encode just the appended bytes as
+    /// a stripe of their own instead of re-encoding the whole file. Meant
+    /// for append-only logs.
+    #[serde(default)]
+    pub append_optimize: bool,
+    /// Gather the lines of `/api/logs/stream` for this many milliseconds
+    /// and send them as one `logs` event holding a JSON array. 0 sends each
+    /// line as a `log` event of its own.
+    #[serde(default)]
+    pub log_stream_batch_ms: u64,
+    /// Most lines in one batched `logs` event; more are sent as several
+    /// events, in order.
+    #[serde(default = "default_log_stream_batch_lines")]
+    pub log_stream_batch_lines: usize,
+    /// Read and write errors after which a top-level directory of the shard
+    /// store is marked degraded and new shards are placed elsewhere. 0
+    /// never marks a location.
+    #[serde(default = "default_shard_location_error_threshold")]
+    pub shard_location_error_threshold: u64,
+    /// Files larger than this are encoded by streaming them in stripes of
+    /// `streaming_stripe_bytes` rather than reading them whole. 0 always
+    /// reads the whole file. Ignored for files with holes under
+    /// `sparse_files`, which need the whole file as one stripe. Larger files
+    /// are not replicated, as a replica is sent in one request.
+    #[serde(default = "default_streaming_threshold_bytes")]
+    pub streaming_threshold_bytes: u64,
+    /// Bytes per stripe of a streamed encode.
+    #[serde(default = "default_streaming_stripe_bytes")]
+    pub streaming_stripe_bytes: usize,
+    /// Directories under the watched ones that are never protected. Not
+    /// read from the config file; filled in at startup with the shard store
+    /// if it lies inside a watched directory, so rs_guard does not protect
+    /// its own shards.
+    #[serde(skip)]
+    pub excluded_dirs: Vec<PathBuf>,
+    /// Compare every shard's length with the recorded shard size before
+    /// hashing, so truncated shards are flagged without reading them.
+    #[serde(default = "default_true")]
+    pub check_shard_lengths: bool,
+    /// Hash sources that changed since their last encode during checks and
+    /// report them as drifted (needing re-protection) rather than passing them.
+    #[serde(default = "default_true")]
+    pub detect_drift: bool,
+    /// During checks, count a file whose source still hashes to the digest
+    /// stored when it was protected as intact once its shards exist with the
+    /// right length, without hashing them. Much faster, but damage to a shard's
+    /// content goes unnoticed until the source changes. Ignored with
+    /// `full_decode_verify`.
+    #[serde(default)]
+    pub trust_source_hash: bool,
+    /// Repairs allowed per file before it is quarantined as flapping and
+    /// left alone until an operator resets it. 0 means unlimited.
+    #[serde(default = "default_max_repair_attempts")]
+    pub max_repair_attempts: u32,
+    /// IP address the HTTP server listens on.
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Bearer token required by privileged API endpoints such as
+    /// `/api/shutdown`, and by peers on the replication endpoints.
+    #[serde(default)]
+    pub api_token: Option<Secret>,
+    /// Allow stopping the server with `POST /api/shutdown`.
+    #[serde(default)]
+    pub allow_remote_shutdown: bool,
+    /// How parity shards are computed for new encodes. `xor` requires
+    /// `parity_shards = 1`.
+    #[serde(default)]
+    pub parity_algorithm: ParityAlgorithm,
+    /// How files with several hardlinks are protected.
+    #[serde(default)]
+    pub hardlinks: HardlinkMode,
+    /// Files not verified by a check for longer than this are counted as
+    /// stale in the status. 0 disables the alarm.
+    #[serde(default)]
+    pub max_verification_age_secs: u64,
+    /// Encode only the data extents of sparse files and restore their holes
+    /// on recovery (Linux).
+    #[serde(default = "default_true")]
+    pub sparse_files: bool,
+    /// Read a source again if it changes while being encoded, so its shards
+    /// never mix two versions of the file.
+    #[serde(default = "default_true")]
+    pub snapshot_reads: bool,
+    /// Local time range such as `"02:00-05:00"` outside of which periodic
+    /// checks are skipped. Manual checks run at any time.
+    #[serde(default)]
+    pub check_window: Option<CheckWindow>,
+    /// Subdirectories per level of the shard store; a power of 16.
+    #[serde(default = "default_shard_dir_fanout")]
+    pub shard_dir_fanout: usize,
+    /// Levels of subdirectories in the shard store. 0 keeps all shards in
+    /// the store's root.
+    #[serde(default = "default_shard_dir_depth")]
+    pub shard_dir_depth: usize,
+    /// Skip a periodic check while the previous one is still running,
+    /// instead of running it as soon as the previous one finishes.
+    #[serde(default = "default_true")]
+    pub skip_overlapping_checks: bool,
+    /// Flag shards in the file detail that are not on the same device as
+    /// the shard store's root.
+    #[serde(default)]
+    pub verify_shard_device: bool,
+    /// Reserve each shard file's full size before writing it.
+    #[serde(default)]
+    pub preallocate_shards: bool,
+}
+
+/// A config value that must not show up in logs.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\"<redacted>\"")
+    }
+}
+
+/// Where file content served by `/api/read` comes from.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadSource {
+    /// The live source file.
+    #[default]
+    Source,
+    /// Reconstructed from the shards, bypassing a possibly corrupted source.
+    Shards,
+}
+
+/// Treatment of source files that have more than one hardlink.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HardlinkMode {
+    /// All links to one inode are a single protected file, encoded once
+    /// under the first path seen. Only effective on Unix.
+    #[default]
+    Shared,
+    /// Every link is protected as a file of its own.
+    Separate,
+}
+
+/// A daily time range in local time. The end may be earlier than the start
+/// for windows that span midnight.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct CheckWindow {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+}
+
+impl CheckWindow {
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl std::str::FromStr for CheckWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let parse = |part: &str| chrono::NaiveTime::parse_from_str(part.trim(), "%H:%M");
+        match s.split_once('-').map(|(start, end)| (parse(start), parse(end))) {
+            Some((Ok(start), Ok(end))) => Ok(CheckWindow { start, end }),
+            _ => Err(format!("must look like \"02:00-05:00\", got \"{}\"", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for CheckWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, String> {
+        s.parse()
+    }
+}
+
+/// Reaction to lost file watcher events.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowStrategy {
+    /// Run a full scan to reconcile whatever changes were missed.
+    #[default]
+    Rescan,
+    /// Log a warning and carry on; missed changes stay unprotected until the
+    /// next full scan.
+    Drop,
+}
+
+/// Order in which a scan encodes the files it found unprotected.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanOrder {
+    /// Small files first, so as many files as possible are protected early.
+    SmallestFirst,
+    /// Large files first, as they hold the most data at risk.
+    LargestFirst,
+    /// In the order the directory walk found them.
+    #[default]
+    Discovery,
+}
+
+/// How thoroughly the startup scan handles new and changed files.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StartupScanMode {
+    /// Only record their size and mtime as tracked, and encode them in the
+    /// background afterwards, so the database is populated quickly.
+    Quick,
+    /// Encode them during the scan.
+    #[default]
+    Full,
+}
+
+/// I/O scheduling class for encodes and checks (Linux only), so they yield
+/// the disk to foreground workloads.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IoPriority {
+    /// Leave the priority unchanged.
+    #[default]
+    Normal,
+    /// Only use the disk when nothing else does.
+    Idle,
+    /// The lowest level of the default best-effort class.
+    BestEffort,
+}
+
+/// A `[[watched]]` entry of the config.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchedDirectory {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Shard counts for files below this directory, overriding the global
+    /// ones, e.g. a modest scheme for a directory of many tiny files.
+    #[serde(default)]
+    pub data_shards: Option<usize>,
+    #[serde(default)]
+    pub parity_shards: Option<usize>,
+}
+
+impl AppConfig {
+    /// The label configured for a watched directory, if any.
+    pub fn label_for(&self, dir: &Path) -> Option<&str> {
+        self.watched.iter().find(|watched| watched.path == dir).and_then(|watched| watched.label.as_deref())
+    }
+
+    /// The shard store as seen from each watched directory containing it.
+    pub fn shard_store_overlap(&self) -> Vec<PathBuf> {
+        self.watched_directories.iter().filter_map(|root| self.shard_store_below(root)).collect()
+    }
+
+    /// The shard store as seen from `root`, if it lies inside it. Both are
+    /// canonicalized for the comparison, so this only works once the shard
+    /// store exists.
+    pub fn shard_store_below(&self, root: &Path) -> Option<PathBuf> {
+        let store = fs::canonicalize(&self.shard_store_dir).ok()?;
+        let relative = store.strip_prefix(fs::canonicalize(root).ok()?).ok()?;
+        Some(root.join(relative))
+    }
+
+    /// The address the HTTP server listens on, from `bind_address` and `port`.
+    pub fn socket_addr(&self) -> Result<SocketAddr> {
+        let ip: IpAddr = self
+            .bind_address
+            .parse()
+            .with_context(|| format!("`bind_address` `{}` is not an IP address", self.bind_address))?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    /// Size of the largest file replicated to peers: those read whole, i.e.
+    /// up to `streaming_threshold_bytes`, or its default with streaming off.
+    pub fn max_replicated_bytes(&self) -> u64 {
+        match self.streaming_threshold_bytes {
+            0 => default_streaming_threshold_bytes(),
+            threshold => threshold,
+        }
+    }
+
+    /// Body size accepted by `POST /api/replicate`: the shards of the
+    /// largest replicated file under any configured shard counts, at up to
+    /// four bytes per shard byte as JSON, plus room for its record.
+    pub fn replica_body_limit(&self) -> usize {
+        let max = self.max_replicated_bytes();
+        let shard_bytes = std::iter::once((None, None))
+            .chain(self.watched.iter().map(|watched| (watched.data_shards, watched.parity_shards)))
+            .map(|(data, parity)| {
+                let (data, parity) = (data.unwrap_or(self.data_shards).max(1), parity.unwrap_or(self.parity_shards));
+                max.div_ceil(data as u64).saturating_mul((data + parity) as u64)
+            })
+            .max()
+            .unwrap_or_default();
+        usize::try_from(shard_bytes.saturating_mul(4).saturating_add(REPLICA_RECORD_ALLOWANCE)).unwrap_or(usize::MAX)
+    }
+
+    /// The data and parity shard counts for a file: those of the innermost
+    /// `[[watched]]` entry containing it, falling back to the global ones.
+    pub fn shards_for(&self, path: &Path) -> (usize, usize) {
+        let entry = self
+            .watched
+            .iter()
+            .filter(|watched| path.starts_with(&watched.path))
+            .max_by_key(|watched| watched.path.components().count());
+        (
+            entry.and_then(|watched| watched.data_shards).unwrap_or(self.data_shards),
+            entry.and_then(|watched| watched.parity_shards).unwrap_or(self.parity_shards),
+        )
+    }
+}
+
+/// Room in a replica's body for its record, e.g. the shard checksums.
+const REPLICA_RECORD_ALLOWANCE: u64 = 16 * 1024 * 1024;
+
+fn default_shard_store_dir() -> PathBuf {
+    PathBuf::from("rs_guard_shards")
+}
+
+fn default_metadata_backup_interval_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_check_interval_secs() -> u64 {
+    3600
+}
+
+fn default_metadata_backup_keep() -> usize {
+    7
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_bind_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port() -> u16 {
+    3000
+}
+
+fn default_inode_reserve() -> u64 {
+    1000
+}
+
+fn default_watcher_event_buffer() -> usize {
+    10_000
+}
+
+fn default_max_stream_clients() -> usize {
+    32
+}
+
+fn default_log_stream_batch_lines() -> usize {
+    100
+}
+
+fn default_shard_location_error_threshold() -> u64 {
+    10
+}
+
+fn default_streaming_threshold_bytes() -> u64 {
+    512 * 1024 * 1024
+}
+
+fn default_streaming_stripe_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_notification_coalesce_secs() -> u64 {
+    60
+}
+
+/// Three missed hourly checks.
+fn default_heartbeat_max_age_secs() -> u64 {
+    3 * 3600
+}
+
+fn default_listing_batch_size() -> usize {
+    500
+}
+
+fn default_shard_dir_fanout() -> usize {
+    256
+}
+
+fn default_shard_dir_depth() -> usize {
+    1
+}
+
+fn default_max_repair_attempts() -> u32 {
+    5
+}
+
+fn default_full_decode_memory_bytes() -> usize {
+    16 << 20
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            watched_directories: Vec::new(),
+            watched: Vec::new(),
+            data_shards: 4,
+            parity_shards: 2,
+            shard_store_dir: default_shard_store_dir(),
+            skip_non_utf8_paths: false,
+            metadata_backup_dir: None,
+            metadata_backup_interval_secs: default_metadata_backup_interval_secs(),
+            metadata_backup_keep: default_metadata_backup_keep(),
+            check_free_inodes: true,
+            inode_reserve: default_inode_reserve(),
+            shard_store_reserve_bytes: 0,
+            shard_headers: true,
+            shard_footers: false,
+            shard_cache_bytes: 0,
+            max_connections: 0,
+            max_stream_clients: default_max_stream_clients(),
+            exclude_patterns: Vec::new(),
+            allowlist_only: false,
+            protected_paths: Vec::new(),
+            protect_signatures: Vec::new(),
+            frontend_dir: None,
+            encode_timeout_secs: 0,
+            watcher_event_buffer: default_watcher_event_buffer(),
+            watcher_overflow: OverflowStrategy::default(),
+            merkle_roots: true,
+            default_read_source: ReadSource::default(),
+            replication_peers: Vec::new(),
+            notification_webhook: None,
+            notification_coalesce_secs: default_notification_coalesce_secs(),
+            verify_codec_on_startup: true,
+            deleted_retention_secs: 0,
+            initial_scan_order: ScanOrder::default(),
+            preserve_attrs: false,
+            network_fs_mode: false,
+            heartbeat_max_age_secs: default_heartbeat_max_age_secs(),
+            case_insensitive_paths: None,
+            listing_batch_size: default_listing_batch_size(),
+            io_priority: IoPriority::default(),
+            db_compact_interval_secs: 0,
+            check_interval_secs: default_check_interval_secs(),
+            skip_files_open_for_write: false,
+            startup_scan_mode: StartupScanMode::default(),
+            keep_versions: 0,
+            replication_verify_sample: 0,
+            min_reencode_interval_secs: 0,
+            report_signing_key: None,
+            full_decode_verify: false,
+            full_decode_memory_bytes: default_full_decode_memory_bytes(),
+            protect_self: false,
+            append_optimize: false,
+            log_stream_batch_ms: 0,
+            log_stream_batch_lines: default_log_stream_batch_lines(),
+            shard_location_error_threshold: default_shard_location_error_threshold(),
+            streaming_threshold_bytes: default_streaming_threshold_bytes(),
+            streaming_stripe_bytes: default_streaming_stripe_bytes(),
+            excluded_dirs: Vec::new(),
+            check_shard_lengths: true,
+            detect_drift: true,
+            trust_source_hash: false,
+            max_repair_attempts: default_max_repair_attempts(),
+            bind_address: default_bind_address(),
+            port: default_port(),
+            api_token: None,
+            allow_remote_shutdown: false,
+            parity_algorithm: ParityAlgorithm::default(),
+            hardlinks: HardlinkMode::default(),
+            max_verification_age_secs: 0,
+            sparse_files: true,
+            snapshot_reads: true,
+            check_window: None,
+            shard_dir_fanout: default_shard_dir_fanout(),
+            shard_dir_depth: default_shard_dir_depth(),
+            skip_overlapping_checks: true,
+            verify_shard_device: false,
+            preallocate_shards: false,
+        }
+    }
+}
+
+/// The shape a config value must have.
+enum Expect {
+    Bool,
+    String,
+    OptionalString,
+    StringList,
+    Integer { min: i64, max: i64 },
+    OneOf(&'static [&'static str]),
+    /// An array of tables whose keys must all be listed, with their types.
+    TableList { required: &'static [(&'static str, Expect)], optional: &'static [(&'static str, Expect)] },
+}
+
+/// Every accepted config key. New `AppConfig` fields must be listed here,
+/// otherwise they are reported as unknown.
+const SCHEMA: &[(&str, Expect)] = &[
+    ("watched_directories", Expect::StringList),
+    (
+        "watched",
+        Expect::TableList {
+            required: &[("path", Expect::String)],
+            optional: &[
+                ("label", Expect::String),
+                ("data_shards", Expect::Integer { min: 1, max: 65_535 }),
+                ("parity_shards", Expect::Integer { min: 1, max: 65_535 }),
+            ],
+        },
+    ),
+    ("data_shards", Expect::Integer { min: 1, max: 65_535 }),
+    ("parity_shards", Expect::Integer { min: 1, max: 65_535 }),
+    ("shard_store_dir", Expect::String),
+    ("skip_non_utf8_paths", Expect::Bool),
+    ("metadata_backup_dir", Expect::OptionalString),
+    ("metadata_backup_interval_secs", Expect::Integer { min: 1, max: i64::MAX }),
+    ("metadata_backup_keep", Expect::Integer { min: 1, max: 10_000 }),
+    ("check_free_inodes", Expect::Bool),
+    ("inode_reserve", Expect::Integer { min: 0, max: i64::MAX }),
+    ("shard_store_reserve_bytes", Expect::Integer { min: 0, max: i64::MAX }),
+    ("shard_headers", Expect::Bool),
+    ("shard_footers", Expect::Bool),
+    ("shard_cache_bytes", Expect::Integer { min: 0, max: i64::MAX }),
+    ("max_connections", Expect::Integer { min: 0, max: 1_000_000 }),
+    ("max_stream_clients", Expect::Integer { min: 0, max: 1_000_000 }),
+    ("exclude_patterns", Expect::StringList),
+    ("allowlist_only", Expect::Bool),
+    ("protected_paths", Expect::StringList),
+    ("protect_signatures", Expect::StringList),
+    ("frontend_dir", Expect::OptionalString),
+    ("encode_timeout_secs", Expect::Integer { min: 0, max: i64::MAX }),
+    ("watcher_event_buffer", Expect::Integer { min: 1, max: i64::MAX }),
+    ("watcher_overflow", Expect::OneOf(&["rescan", "drop"])),
+    ("merkle_roots", Expect::Bool),
+    ("default_read_source", Expect::OneOf(&["source", "shards"])),
+    ("replication_peers", Expect::StringList),
+    ("notification_webhook", Expect::OptionalString),
+    ("notification_coalesce_secs", Expect::Integer { min: 0, max: i64::MAX }),
+    ("verify_codec_on_startup", Expect::Bool),
+    ("deleted_retention_secs", Expect::Integer { min: 0, max: i64::MAX }),
+    ("initial_scan_order", Expect::OneOf(&["smallest_first", "largest_first", "discovery"])),
+    ("preserve_attrs", Expect::Bool),
+    ("network_fs_mode", Expect::Bool),
+    ("heartbeat_max_age_secs", Expect::Integer { min: 0, max: i64::MAX }),
+    ("case_insensitive_paths", Expect::Bool),
+    ("listing_batch_size", Expect::Integer { min: 1, max: u32::MAX as i64 }),
+    ("io_priority", Expect::OneOf(&["normal", "idle", "best_effort"])),
+    ("db_compact_interval_secs", Expect::Integer { min: 0, max: i64::MAX }),
+    ("check_interval_secs", Expect::Integer { min: 0, max: i64::MAX }),
+    ("skip_files_open_for_write", Expect::Bool),
+    ("startup_scan_mode", Expect::OneOf(&["quick", "full"])),
+    ("keep_versions", Expect::Integer { min: 0, max: 1000 }),
+    ("replication_verify_sample", Expect::Integer { min: 0, max: i64::MAX }),
+    ("min_reencode_interval_secs", Expect::Integer { min: 0, max: i64::MAX }),
+    ("report_signing_key", Expect::OptionalString),
+    ("full_decode_verify", Expect::Bool),
+    ("full_decode_memory_bytes", Expect::Integer { min: 1, max: i64::MAX }),
+    ("protect_self", Expect::Bool),
+    ("append_optimize", Expect::Bool),
+    ("log_stream_batch_ms", Expect::Integer { min: 0, max: i64::MAX }),
+    ("log_stream_batch_lines", Expect::Integer { min: 1, max: 1_000_000 }),
+    ("shard_location_error_threshold", Expect::Integer { min: 0, max: i64::MAX }),
+    ("streaming_threshold_bytes", Expect::Integer { min: 0, max: i64::MAX }),
+    ("streaming_stripe_bytes", Expect::Integer { min: 1, max: i64::MAX }),
+    ("check_shard_lengths", Expect::Bool),
+    ("detect_drift", Expect::Bool),
+    ("trust_source_hash", Expect::Bool),
+    ("max_repair_attempts", Expect::Integer { min: 0, max: u32::MAX as i64 }),
+    ("bind_address", Expect::String),
+    ("port", Expect::Integer { min: 1, max: u16::MAX as i64 }),
+    ("api_token", Expect::OptionalString),
+    ("allow_remote_shutdown", Expect::Bool),
+    ("parity_algorithm", Expect::OneOf(&["reed_solomon", "xor"])),
+    ("hardlinks", Expect::OneOf(&["shared", "separate"])),
+    ("max_verification_age_secs", Expect::Integer { min: 0, max: i64::MAX }),
+    ("sparse_files", Expect::Bool),
+    ("snapshot_reads", Expect::Bool),
+    ("check_window", Expect::OptionalString),
+    ("shard_dir_fanout", Expect::Integer { min: 16, max: 65_536 }),
+    ("shard_dir_depth", Expect::Integer { min: 0, max: 4 }),
+    ("skip_overlapping_checks", Expect::Bool),
+    ("verify_shard_device", Expect::Bool),
+    ("preallocate_shards", Expect::Bool),
+];
+
+/// Most shards per file, the limit of Reed-Solomon over GF(2^16). Past 256
+/// shards the encoder switches from GF(2^8) to that field.
+const MAX_TOTAL_SHARDS: usize = 65_536;
+
+const REQUIRED: &[&str] = &["data_shards", "parity_shards"];
+
+/// Every problem found in a config file, reported together.
+#[derive(Debug, thiserror::Error)]
+#[error("invalid configuration:\n  - {}", .errors.join("\n  - "))]
+pub struct ConfigError {
+    pub errors: Vec<String>,
 }
 
 pub fn load_config(path: &str) -> Result<AppConfig> {
     let config_str = fs::read_to_string(path)?;
-    let config: AppConfig = toml::from_str(&config_str)?;
+    parse_config(&config_str)
+}
+
+/// Parses and validates a TOML config.
+///
+/// Type and range problems are collected for all fields and returned as a
+/// single [`ConfigError`]; unknown keys only produce a warning.
+pub fn parse_config(config_str: &str) -> Result<AppConfig> {
+    let table: toml::Table = toml::from_str(config_str)
+        .map_err(|e| ConfigError { errors: vec![format!("not valid TOML: {}", e.message())] })?;
+
+    let mut errors = Vec::new();
+    for key in table.keys() {
+        if !SCHEMA.iter().any(|(name, _)| name == key) {
+            tracing::warn!("Unknown config field `{}` is ignored", key);
+        }
+    }
+    if !table.contains_key("watched_directories") && !table.contains_key("watched") {
+        errors.push("`watched_directories` (or `[[watched]]`) is required".to_string());
+    }
+    for name in REQUIRED {
+        if !table.contains_key(*name) {
+            errors.push(format!("`{}` is required", name));
+        }
+    }
+    for (name, expect) in SCHEMA {
+        if let Some(value) = table.get(*name) {
+            if let Err(message) = check_value(value, expect) {
+                errors.push(format!("`{}` {}", name, message));
+            }
+        }
+    }
+    if let (Some(data), Some(parity)) = (
+        table.get("data_shards").and_then(|v| v.as_integer()),
+        table.get("parity_shards").and_then(|v| v.as_integer()),
+    ) {
+        if data + parity > MAX_TOTAL_SHARDS as i64 {
+            errors.push(format!(
+                "`data_shards` + `parity_shards` must be at most 65536, got {} + {}",
+                data, parity
+            ));
+        }
+    }
+    if table.get("parity_algorithm").and_then(|v| v.as_str()) == Some("xor") {
+        if let Some(parity) = table.get("parity_shards").and_then(|v| v.as_integer()).filter(|p| *p != 1) {
+            errors.push(format!("`parity_algorithm = \"xor\"` needs `parity_shards` = 1, got {}", parity));
+        }
+    }
+    if let Some(fanout) = table.get("shard_dir_fanout").and_then(|v| v.as_integer()) {
+        if ![16, 256, 4096, 65_536].contains(&fanout) {
+            errors.push(format!("`shard_dir_fanout` must be 16, 256, 4096 or 65536, got {}", fanout));
+        }
+    }
+    let is_empty = |name: &str| table.get(name).and_then(|v| v.as_array()).is_none_or(|list| list.is_empty());
+    if table.get("allowlist_only").and_then(|v| v.as_bool()) == Some(true)
+        && is_empty("protected_paths")
+        && is_empty("protect_signatures")
+    {
+        errors.push("`allowlist_only` needs at least one entry in `protected_paths` or `protect_signatures`".to_string());
+    }
+    if let Some(signatures) = table.get("protect_signatures").and_then(|v| v.as_array()) {
+        for signature in signatures.iter().filter_map(|v| v.as_str()) {
+            if crate::filter::parse_signature(signature).is_none() {
+                errors.push(format!(
+                    "`protect_signatures` entry `{}` is neither a known file type nor 0x-prefixed hex",
+                    signature
+                ));
+            }
+        }
+    }
+    if let Some(key) = table.get("report_signing_key").and_then(|v| v.as_str()) {
+        if crate::signing::parse_key(key).is_none() {
+            errors.push("`report_signing_key` must be a hex-encoded 32-byte ed25519 secret key".to_string());
+        }
+    }
+    if let Some(address) = table.get("bind_address").and_then(|v| v.as_str()) {
+        if address.parse::<IpAddr>().is_err() {
+            errors.push(format!("`bind_address` must be an IP address such as 0.0.0.0, got `{}`", address));
+        }
+    }
+    if let Some(window) = table.get("check_window").and_then(|v| v.as_str()) {
+        if let Err(message) = window.parse::<CheckWindow>() {
+            errors.push(format!("`check_window` {}", message));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ConfigError { errors }.into());
+    }
+
+    let mut config: AppConfig = table.try_into()?;
+    for watched in config.watched.iter().filter(|w| w.data_shards.is_some() || w.parity_shards.is_some()) {
+        let (data, parity) = config.shards_for(&watched.path);
+        let dir = watched.path.display();
+        if data + parity > MAX_TOTAL_SHARDS {
+            errors.push(format!(
+                "`[[watched]]` {}: `data_shards` + `parity_shards` must be at most 65536, got {} + {}",
+                dir, data, parity
+            ));
+        }
+        if config.parity_algorithm == ParityAlgorithm::Xor && parity != 1 {
+            errors.push(format!("`[[watched]]` {}: `parity_algorithm = \"xor\"` needs `parity_shards` = 1, got {}", dir, parity));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ConfigError { errors }.into());
+    }
+    for watched in &config.watched {
+        if !config.watched_directories.contains(&watched.path) {
+            config.watched_directories.push(watched.path.clone());
+        }
+    }
     Ok(config)
-} 
\ No newline at end of file
+}
+
+fn check_value(value: &toml::Value, expect: &Expect) -> std::result::Result<(), String> {
+    let type_error = |expected: &str| Err(format!("must be {}, got {}", expected, value.type_str()));
+    match expect {
+        Expect::Bool if !value.is_bool() => type_error("a boolean"),
+        Expect::String | Expect::OptionalString if !value.is_str() => type_error("a string"),
+        Expect::StringList => match value.as_array() {
+            Some(items) if items.iter().all(|item| item.is_str()) => Ok(()),
+            _ => type_error("an array of strings"),
+        },
+        Expect::Integer { min, max } => match value.as_integer() {
+            Some(n) if n < *min || n > *max => Err(format!("must be between {} and {}, got {}", min, max, n)),
+            Some(_) => Ok(()),
+            None => type_error("an integer"),
+        },
+        Expect::OneOf(options) => match value.as_str() {
+            Some(s) if options.contains(&s) => Ok(()),
+            Some(s) => Err(format!("must be one of {}, got \"{}\"", options.join(", "), s)),
+            None => type_error("a string"),
+        },
+        Expect::TableList { required, optional } => {
+            let Some(tables) = value.as_array().filter(|items| items.iter().all(|item| item.is_table())) else {
+                return type_error("an array of tables");
+            };
+            for table in tables.iter().filter_map(|item| item.as_table()) {
+                if let Some((missing, _)) = required.iter().find(|(key, _)| !table.contains_key(*key)) {
+                    return Err(format!("entry is missing `{}`", missing));
+                }
+                for (key, value) in table {
+                    let Some((_, expect)) = required.iter().chain(optional.iter()).find(|(name, _)| name == key) else {
+                        return Err(format!("entry has unknown key `{}`", key));
+                    };
+                    check_value(value, expect).map_err(|message| format!("entry `{}` {}", key, message))?;
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
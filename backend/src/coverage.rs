@@ -0,0 +1,41 @@
+use anyhow::Result;
+use shared::CoverageReport;
+use std::collections::HashSet;
+use crate::{checker, filter, metadata, protector, scanner, AppContext};
+
+/// Walks the watched directories as a scan would and cross-references every
+/// eligible file against the metadata, then verifies the store with a full
+/// check. Unlike a scan, nothing is encoded, so gaps stay visible in the
+/// report instead of being filled.
+pub fn coverage_report(ctx: &AppContext) -> Result<CoverageReport> {
+    let mut report = CoverageReport::default();
+    // Canonical paths already seen, so hardlinks to one inode count once.
+    let mut seen = HashSet::new();
+    for root in ctx.watched.list() {
+        let mut files = Vec::new();
+        if let Err(e) = scanner::collect_files(&root, &mut files) {
+            tracing::error!("Failed to scan {}: {}", metadata::display_path(&root), e);
+            continue;
+        }
+        for path in files.into_iter().filter(|p| filter::should_protect(&ctx.config, &root, p)) {
+            let path = protector::canonical_path(ctx, &path).unwrap_or(path);
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            report.eligible_files += 1;
+            // A file that cannot even be inspected is not known to be protected.
+            let covered = protector::needs_protection(ctx, &path).map(|needed| !needed).unwrap_or_else(|e| {
+                tracing::warn!("Failed to inspect {}: {}", metadata::display_path(&path), e);
+                false
+            });
+            if covered {
+                report.protected_files += 1;
+            } else {
+                report.unprotected_paths.push(metadata::display_path(&path));
+            }
+        }
+    }
+    report.unprotected_paths.sort();
+    report.corrupted_paths = checker::run_check(ctx)?.corrupted_paths;
+    Ok(report)
+}
@@ -0,0 +1,51 @@
+use anyhow::Result;
+use std::path::Path;
+
+/// Free space of the filesystem holding a path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FsStats {
+    pub free_bytes: u64,
+    pub free_inodes: u64,
+    pub total_inodes: u64,
+}
+
+/// Queries the filesystem containing `path` via `statvfs`.
+#[cfg(unix)]
+pub fn stat(path: &Path) -> Result<Option<FsStats>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut vfs: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL terminated and `vfs` is a valid out pointer.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut vfs) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Some(FsStats {
+        free_bytes: vfs.f_bavail as u64 * vfs.f_frsize as u64,
+        free_inodes: vfs.f_favail as u64,
+        total_inodes: vfs.f_files as u64,
+    }))
+}
+
+/// Filesystem statistics are not available on this platform.
+#[cfg(not(unix))]
+pub fn stat(_path: &Path) -> Result<Option<FsStats>> {
+    Ok(None)
+}
+
+/// Returns false when writing `new_files` more files would leave fewer than
+/// `reserve` free inodes. Filesystems that do not report inodes (total of 0,
+/// e.g. btrfs) always pass.
+pub fn has_free_inodes(stats: &FsStats, new_files: u64, reserve: u64) -> bool {
+    if stats.total_inodes == 0 {
+        return true;
+    }
+    stats.free_inodes >= new_files.saturating_add(reserve)
+}
+
+/// Returns false when writing `needed` more bytes would leave less than
+/// `reserve` bytes free.
+pub fn has_free_space(stats: &FsStats, needed: u64, reserve: u64) -> bool {
+    stats.free_bytes >= needed.saturating_add(reserve)
+}
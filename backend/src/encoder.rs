@@ -1,16 +1,182 @@
-use reed_solomon_erasure::galois_8::ReedSolomon;
-use anyhow::Result;
+use reed_solomon_erasure::{galois_16, galois_8};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+/// How parity shards are computed from the data shards.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ParityAlgorithm {
+    #[default]
+    ReedSolomon,
+    /// RAID5-style XOR of the data shards. Much faster than Reed-Solomon,
+    /// but only supports a single parity shard.
+    Xor,
+}
+
+/// The Galois field Reed-Solomon parity is computed in. GF(2^8) allows at
+/// most 256 shards in total; GF(2^16) allows 65536, at the cost of speed
+/// and of shards padded to an even length.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum GaloisField {
+    #[default]
+    Gf8,
+    Gf16,
+}
+
+impl GaloisField {
+    /// The smallest field that fits `total_shards`.
+    pub fn for_shards(total_shards: usize) -> Self {
+        if total_shards > 256 {
+            GaloisField::Gf16
+        } else {
+            GaloisField::Gf8
+        }
+    }
+
+    /// Bytes per field element; shard lengths are a multiple of this.
+    pub fn symbol_len(self) -> usize {
+        match self {
+            GaloisField::Gf8 => 1,
+            GaloisField::Gf16 => 2,
+        }
+    }
+}
+
+/// The Reed-Solomon codec over either field.
+enum Codec {
+    Gf8(Box<galois_8::ReedSolomon>),
+    Gf16(Box<galois_16::ReedSolomon>),
+}
+
+impl Codec {
+    fn data_shard_count(&self) -> usize {
+        match self {
+            Codec::Gf8(rs) => rs.data_shard_count(),
+            Codec::Gf16(rs) => rs.data_shard_count(),
+        }
+    }
+
+    fn total_shard_count(&self) -> usize {
+        match self {
+            Codec::Gf8(rs) => rs.total_shard_count(),
+            Codec::Gf16(rs) => rs.total_shard_count(),
+        }
+    }
+
+    fn encode(&self, shards: &mut [Vec<u8>]) -> Result<()> {
+        match self {
+            Codec::Gf8(rs) => rs.encode(shards)?,
+            Codec::Gf16(rs) => {
+                let mut wide: Vec<Vec<[u8; 2]>> = shards.iter().map(|shard| to_symbols(shard)).collect();
+                rs.encode(&mut wide)?;
+                for (shard, wide) in shards.iter_mut().zip(wide) {
+                    *shard = wide.concat();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reconstruct(&self, shards: &mut [Option<Vec<u8>>]) -> Result<()> {
+        match self {
+            Codec::Gf8(rs) => rs.reconstruct(shards)?,
+            Codec::Gf16(rs) => {
+                let mut wide: Vec<Option<Vec<[u8; 2]>>> =
+                    shards.iter().map(|shard| shard.as_deref().map(to_symbols)).collect();
+                rs.reconstruct(&mut wide)?;
+                for (shard, wide) in shards.iter_mut().zip(wide) {
+                    *shard = wide.map(|wide| wide.concat());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn verify(&self, shards: &[&[u8]]) -> Result<bool> {
+        Ok(match self {
+            Codec::Gf8(rs) => rs.verify(shards)?,
+            Codec::Gf16(rs) => {
+                let wide: Vec<Vec<[u8; 2]>> = shards.iter().map(|shard| to_symbols(shard)).collect();
+                rs.verify(&wide)?
+            }
+        })
+    }
+}
+
+/// Splits a GF(2^16) shard into its 2-byte elements. Shards of that field
+/// always have an even length.
+fn to_symbols(shard: &[u8]) -> Vec<[u8; 2]> {
+    shard.chunks_exact(2).map(|pair| [pair[0], pair[1]]).collect()
+}
+
+/// Receives each stripe encoded by [`RSEncoder::encode_stream`] as soon as
+/// it is ready, before the next one is read.
+pub trait StripeWriter {
+    /// `data` is stripe `index` of the source and `shards` its data and
+    /// parity shards.
+    fn write_stripe(&mut self, index: usize, data: &[u8], shards: &[Vec<u8>]) -> Result<()>;
+}
+
+impl<F: FnMut(usize, &[u8], &[Vec<u8>]) -> Result<()>> StripeWriter for F {
+    fn write_stripe(&mut self, index: usize, data: &[u8], shards: &[Vec<u8>]) -> Result<()> {
+        self(index, data, shards)
+    }
+}
+
+/// What [`RSEncoder::encode_stream`] encoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamedFile {
+    pub len: u64,
+    /// Number of stripes; at least one, even for empty input.
+    pub stripes: usize,
+}
 
 /// A wrapper around the Reed-Solomon library.
 pub struct RSEncoder {
-    rs: ReedSolomon,
+    rs: Codec,
+    algorithm: ParityAlgorithm,
+    field: GaloisField,
 }
 
 impl RSEncoder {
     /// Creates a new encoder with the given shard configuration.
     pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self> {
-        let rs = ReedSolomon::new(data_shards, parity_shards)?;
-        Ok(Self { rs })
+        Self::with_algorithm(data_shards, parity_shards, ParityAlgorithm::ReedSolomon)
+    }
+
+    /// Creates an encoder that computes parity with `algorithm`, in the
+    /// smallest field that fits the shards.
+    pub fn with_algorithm(data_shards: usize, parity_shards: usize, algorithm: ParityAlgorithm) -> Result<Self> {
+        let field = GaloisField::for_shards(data_shards + parity_shards);
+        Self::with_field(data_shards, parity_shards, algorithm, field)
+    }
+
+    /// Creates an encoder over `field`, which for existing shards must be
+    /// the one they were encoded in.
+    pub fn with_field(data_shards: usize, parity_shards: usize, algorithm: ParityAlgorithm, field: GaloisField) -> Result<Self> {
+        if algorithm == ParityAlgorithm::Xor && parity_shards != 1 {
+            bail!("XOR parity needs exactly one parity shard, got {}", parity_shards);
+        }
+        let rs = match field {
+            GaloisField::Gf8 => Codec::Gf8(Box::new(galois_8::ReedSolomon::new(data_shards, parity_shards)?)),
+            GaloisField::Gf16 => Codec::Gf16(Box::new(galois_16::ReedSolomon::new(data_shards, parity_shards)?)),
+        };
+        Ok(Self { rs, algorithm, field })
+    }
+
+    /// The field parity is computed in.
+    pub fn field(&self) -> GaloisField {
+        self.field
+    }
+
+    /// With a single data shard every parity shard is a plain copy of the
+    /// data, i.e. the file is mirrored rather than erasure coded.
+    pub fn is_mirror(&self) -> bool {
+        self.rs.data_shard_count() == 1
     }
 
     /// Encodes data into shards.
@@ -22,10 +188,45 @@ impl RSEncoder {
         // 4. Returning paths or identifiers for the shards.
 
         let mut shards = self.make_shards(data)?;
+        if self.is_mirror() {
+            let copy = shards[0].clone();
+            shards[1..].iter_mut().for_each(|shard| shard.clone_from(&copy));
+            return Ok(shards);
+        }
+        if self.algorithm == ParityAlgorithm::Xor {
+            let (data, parity) = shards.split_at_mut(self.rs.data_shard_count());
+            for shard in data.iter() {
+                xor_into(&mut parity[0], shard);
+            }
+            return Ok(shards);
+        }
         self.rs.encode(&mut shards)?;
         Ok(shards)
     }
 
+    /// Encodes everything `reader` yields in stripes of `stripe_size` bytes,
+    /// each encoded on its own as by [`RSEncoder::encode`] and handed to
+    /// `writer` before the next is read. Only the last stripe may be
+    /// shorter, so memory use is bounded by one stripe and its shards
+    /// however long the input is.
+    pub fn encode_stream(&self, reader: &mut impl Read, stripe_size: usize, writer: &mut impl StripeWriter) -> Result<StreamedFile> {
+        let mut buf = vec![0; stripe_size.max(1)];
+        let mut streamed = StreamedFile::default();
+        loop {
+            let len = read_full(reader, &mut buf)?;
+            if len == 0 && streamed.stripes > 0 {
+                return Ok(streamed);
+            }
+            let shards = self.encode(&buf[..len])?;
+            writer.write_stripe(streamed.stripes, &buf[..len], &shards)?;
+            streamed.stripes += 1;
+            streamed.len += len as u64;
+            if len < buf.len() {
+                return Ok(streamed);
+            }
+        }
+    }
+
     /// Reconstructs data from shards, some of which may be missing.
     pub fn reconstruct(&self, received_shards: &mut [Option<Vec<u8>>]) -> Result<()> {
         // TODO: Real implementation needs to:
@@ -34,17 +235,69 @@ impl RSEncoder {
         // 3. Call the reconstruction.
         // 4. Write the reconstructed data back to the original file.
         
+        if self.is_mirror() {
+            // Any surviving copy restores all the others.
+            let copy = received_shards.iter().flatten().next().cloned()
+                .ok_or_else(|| anyhow!("no copy of the mirrored data survived"))?;
+            received_shards.iter_mut().filter(|s| s.is_none()).for_each(|s| *s = Some(copy.clone()));
+            return Ok(());
+        }
+        if self.algorithm == ParityAlgorithm::Xor {
+            let mut missing = received_shards.iter().enumerate().filter(|(_, s)| s.is_none()).map(|(i, _)| i);
+            let Some(lost) = missing.next() else {
+                return Ok(());
+            };
+            if missing.next().is_some() {
+                bail!("XOR parity can only rebuild a single lost shard");
+            }
+            // The XOR of all shards is zero, so the lost one is the XOR of the rest.
+            let mut rebuilt: Option<Vec<u8>> = None;
+            for shard in received_shards.iter().flatten() {
+                match &mut rebuilt {
+                    Some(acc) => xor_into(acc, shard),
+                    None => rebuilt = Some(shard.clone()),
+                }
+            }
+            received_shards[lost] = Some(rebuilt.ok_or_else(|| anyhow!("no shards survived"))?);
+            return Ok(());
+        }
         self.rs.reconstruct(received_shards)?;
         Ok(())
     }
 
+    /// Whether the parity shards are the ones the data shards encode to.
+    /// Works on whole shards or on the same range of each; in GF(2^16) the
+    /// range must start and end on an even offset.
+    pub fn verify(&self, shards: &[&[u8]]) -> Result<bool> {
+        let data_shards = self.rs.data_shard_count();
+        if self.is_mirror() {
+            return Ok(shards[1..].iter().all(|copy| *copy == shards[0]));
+        }
+        if self.algorithm == ParityAlgorithm::Xor {
+            let mut acc = shards[data_shards].to_vec();
+            for shard in &shards[..data_shards] {
+                xor_into(&mut acc, shard);
+            }
+            return Ok(acc.iter().all(|&b| b == 0));
+        }
+        self.rs.verify(shards)
+    }
+
+    /// Concatenates the data shards and strips the padding added by `encode`.
+    pub fn join(&self, shards: &[Vec<u8>], original_len: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = shards[..self.rs.data_shard_count()].concat();
+        data.truncate(original_len);
+        data
+    }
+
     /// Helper to create shard structure from data.
     fn make_shards(&self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
         let data_shards = self.rs.data_shard_count();
-        let parity_shards = self.rs.parity_shard_count();
         let total_shards = self.rs.total_shard_count();
         
-        let shard_size = (data.len() + data_shards - 1) / data_shards;
+        // Shards may not be empty, so even a zero-length file gets one byte of padding.
+        let symbol = self.field.symbol_len();
+        let shard_size = data.len().div_ceil(data_shards).max(1).next_multiple_of(symbol);
         let mut shards = vec![vec![0; shard_size]; total_shards];
         
         for (i, chunk) in data.chunks(shard_size).enumerate() {
@@ -52,4 +305,59 @@ impl RSEncoder {
         }
         Ok(shards)
     }
-} 
\ No newline at end of file
+}
+
+/// Reed-Solomon encodes the file at `path` in stripes of `stripe_size`
+/// bytes, handing each stripe's shards to `writer` as it goes. See
+/// [`RSEncoder::encode_stream`].
+pub fn encode_file_streaming(
+    path: &Path,
+    writer: &mut impl StripeWriter,
+    data_shards: usize,
+    parity_shards: usize,
+    stripe_size: usize,
+) -> Result<StreamedFile> {
+    let mut file = fs::File::open(path)?;
+    RSEncoder::new(data_shards, parity_shards)?.encode_stream(&mut file, stripe_size, writer)
+}
+
+/// Fills `buf` from `reader`, short only at the end of the input.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Input of the codec self-test, split into 4 data shards of 4 bytes.
+const SELF_TEST_DATA: &[u8; 16] = b"rs_guard selftst";
+
+/// Reed-Solomon parity (4 data + 2 parity shards) of [`SELF_TEST_DATA`],
+/// as produced by a known-good build.
+pub const SELF_TEST_PARITY: [[u8; 4]; 2] = [[0xc6, 0xfa, 0xcd, 0x84], [0x5a, 0x96, 0x7a, 0xdd]];
+
+/// Encodes a fixed vector and compares its parity with `expected`, to catch
+/// a miscompiled or mismatched codec before it writes parity that cannot
+/// rebuild anything. Startup passes [`SELF_TEST_PARITY`].
+pub fn verify_codec(expected: &[[u8; 4]; 2]) -> Result<()> {
+    let shards = RSEncoder::new(4, 2)?.encode(SELF_TEST_DATA)?;
+    for (index, (parity, expected)) in shards[4..].iter().zip(expected).enumerate() {
+        if parity != expected {
+            bail!(
+                "codec self-test failed: parity shard {} is {:02x?}, expected {:02x?}",
+                index, parity, expected
+            );
+        }
+    }
+    Ok(())
+}
+
+fn xor_into(acc: &mut [u8], shard: &[u8]) {
+    acc.iter_mut().zip(shard).for_each(|(a, b)| *a ^= b);
+}
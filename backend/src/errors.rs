@@ -0,0 +1,52 @@
+use shared::ErrorCounts;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::AppContext;
+
+/// Transient failures that rs_guard recovers from by itself, counted since
+/// startup or the last reset, so a rising error rate shows before it turns
+/// into a real problem. Shard read errors are counted by the shard store.
+#[derive(Default)]
+pub struct ErrorCounters {
+    source_rereads: AtomicU64,
+    replication_retries: AtomicU64,
+    notification_failures: AtomicU64,
+}
+
+impl ErrorCounters {
+    /// A source changed while being read for encoding and is read again.
+    pub fn source_reread(&self) {
+        self.source_rereads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A push to a replication peer failed and was rescheduled.
+    pub fn replication_retry(&self) {
+        self.replication_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A notification could not be delivered to the webhook.
+    pub fn notification_failure(&self) {
+        self.notification_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Current error counts, as served by `/api/errors`.
+pub fn snapshot(ctx: &AppContext) -> ErrorCounts {
+    let errors = &ctx.errors;
+    ErrorCounts {
+        shard_read_errors: ctx.store.read_errors(),
+        source_rereads: errors.source_rereads.load(Ordering::Relaxed),
+        replication_retries: errors.replication_retries.load(Ordering::Relaxed),
+        notification_failures: errors.notification_failures.load(Ordering::Relaxed),
+    }
+}
+
+/// Zeroes every counter, returning the counts up to the reset.
+pub fn reset(ctx: &AppContext) -> ErrorCounts {
+    let errors = &ctx.errors;
+    ErrorCounts {
+        shard_read_errors: ctx.store.reset_read_errors(),
+        source_rereads: errors.source_rereads.swap(0, Ordering::Relaxed),
+        replication_retries: errors.replication_retries.swap(0, Ordering::Relaxed),
+        notification_failures: errors.notification_failures.swap(0, Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,39 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use crate::metadata;
+
+/// Per-file mutual exclusion for operations that read or rewrite a file's
+/// shards, so that a check and a repair of the same file cannot interleave
+/// while work on different files proceeds in parallel.
+#[derive(Default)]
+pub struct FileLocks {
+    held: Mutex<HashSet<Vec<u8>>>,
+    released: Condvar,
+}
+
+/// Releases the lock on a file when dropped.
+pub struct FileLockGuard<'a> {
+    locks: &'a FileLocks,
+    key: Vec<u8>,
+}
+
+impl FileLocks {
+    /// Blocks until no one else holds the lock for `path`, then takes it.
+    pub fn lock(&self, path: &Path) -> FileLockGuard<'_> {
+        let key = metadata::path_key(path);
+        let mut held = self.held.lock().unwrap();
+        while held.contains(&key) {
+            held = self.released.wait(held).unwrap();
+        }
+        held.insert(key.clone());
+        FileLockGuard { locks: self, key }
+    }
+}
+
+impl Drop for FileLockGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.held.lock().unwrap().remove(&self.key);
+        self.locks.released.notify_all();
+    }
+}
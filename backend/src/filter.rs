@@ -0,0 +1,141 @@
+use globset::{GlobBuilder, GlobMatcher};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use crate::config::AppConfig;
+use crate::shard_store::SHARD_EXTENSION;
+
+/// Per-directory ignore file, similar in spirit to `.gitignore`.
+pub const IGNORE_FILE_NAME: &str = ".rsguardignore";
+
+/// Decides whether a file found under the watched directory `root` should be protected.
+pub fn should_protect(config: &AppConfig, root: &Path, path: &Path) -> bool {
+    if config.skip_non_utf8_paths && path.to_str().is_none() {
+        tracing::warn!("Skipping non-UTF8 path {:?}", path);
+        return false;
+    }
+    // Never protect shards, e.g. when the shard store sits inside a watched directory.
+    if path.extension().is_some_and(|ext| ext == SHARD_EXTENSION)
+        || config.excluded_dirs.iter().any(|dir| path.starts_with(dir))
+    {
+        return false;
+    }
+    if config.allowlist_only
+        && !config.protected_paths.iter().any(|allowed| path.starts_with(allowed))
+        && !matches_signature(config, path)
+    {
+        tracing::trace!("{:?} is not in protected_paths", path);
+        return false;
+    }
+    if is_ignored(config, root, path) {
+        tracing::trace!("Ignoring {:?}", path);
+        return false;
+    }
+    true
+}
+
+/// Magic numbers of the file types that can be named in `protect_signatures`.
+const NAMED_SIGNATURES: &[(&str, &[u8])] = &[
+    ("sqlite", b"SQLite format 3\0"),
+    ("pdf", b"%PDF-"),
+    ("png", b"\x89PNG\r\n\x1a\n"),
+    ("jpeg", b"\xff\xd8\xff"),
+    ("gif", b"GIF8"),
+    ("zip", b"PK\x03\x04"),
+    ("gzip", b"\x1f\x8b"),
+    ("7z", b"7z\xbc\xaf\x27\x1c"),
+];
+
+/// Resolves a `protect_signatures` entry to the leading bytes it matches:
+/// either a name from [`NAMED_SIGNATURES`] or hex digits after `0x`, e.g.
+/// `0x89504e47`.
+pub fn parse_signature(signature: &str) -> Option<Vec<u8>> {
+    if let Some(hex) = signature.strip_prefix("0x") {
+        return crate::signing::decode_hex(hex).filter(|magic| !magic.is_empty());
+    }
+    NAMED_SIGNATURES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(signature))
+        .map(|(_, magic)| magic.to_vec())
+}
+
+/// Returns true if the file starts with one of the `protect_signatures`,
+/// whatever its name or extension.
+pub fn matches_signature(config: &AppConfig, path: &Path) -> bool {
+    let signatures: Vec<Vec<u8>> = config.protect_signatures.iter().filter_map(|s| parse_signature(s)).collect();
+    let Some(longest) = signatures.iter().map(Vec::len).max() else {
+        return false;
+    };
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    let mut head = Vec::with_capacity(longest);
+    if file.take(longest as u64).read_to_end(&mut head).is_err() {
+        return false;
+    }
+    signatures.iter().any(|magic| head.starts_with(magic))
+}
+
+/// Returns true if the global `exclude_patterns` or any `.rsguardignore`
+/// between the watched `root` and the file excludes it.
+///
+/// Patterns without a `/` match any single path component (so `*.log`
+/// matches files and `cache` matches a whole directory); patterns with a
+/// `/` are matched against the path relative to the directory that
+/// defines them.
+pub fn is_ignored(config: &AppConfig, root: &Path, path: &Path) -> bool {
+    if matches_any(&config.exclude_patterns, root, path) {
+        return true;
+    }
+    let Some(parent) = path.parent() else {
+        return false;
+    };
+    // Every directory from the watched root down to the file's own directory.
+    let mut dirs: Vec<&Path> = parent.ancestors().take_while(|dir| dir.starts_with(root)).collect();
+    dirs.reverse();
+    for base in dirs {
+        let Ok(contents) = fs::read_to_string(base.join(IGNORE_FILE_NAME)) else {
+            continue;
+        };
+        let patterns: Vec<String> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        if matches_any(&patterns, base, path) {
+            return true;
+        }
+    }
+    false
+}
+
+fn matches_any(patterns: &[String], base: &Path, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(base) else {
+        return false;
+    };
+    patterns.iter().any(|pattern| match compile(pattern) {
+        Some((matcher, anchored)) if anchored => matcher.is_match(relative),
+        Some((matcher, _)) => relative.components().any(|c| matcher.is_match(c.as_os_str())),
+        None => false,
+    })
+}
+
+/// Compiles a pattern, returning the matcher and whether it is anchored to a path.
+fn compile(pattern: &str) -> Option<(GlobMatcher, bool)> {
+    let trimmed = pattern.trim_start_matches('/').trim_end_matches('/');
+    let anchored = trimmed.contains('/');
+    // A directory pattern such as `build/sub/` also excludes everything inside it.
+    let glob = if anchored && pattern.ends_with('/') {
+        format!("{}/**", trimmed)
+    } else {
+        trimmed.to_string()
+    };
+    match GlobBuilder::new(&glob).literal_separator(true).build() {
+        Ok(glob) => Some((glob.compile_matcher(), anchored)),
+        Err(e) => {
+            tracing::warn!("Ignoring invalid pattern `{}`: {}", pattern, e);
+            None
+        }
+    }
+}
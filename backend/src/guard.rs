@@ -0,0 +1,165 @@
+use anyhow::{anyhow, bail, Result};
+use shared::{CheckAndRepairResult, CheckTrigger};
+use std::fs;
+use std::path::Path;
+use crate::checker::{self, Finding, RunningCheck};
+use crate::config::AppConfig;
+use crate::metadata::{self, FileRecord};
+use crate::repair::{self, ShardRepairOutcome};
+use crate::{protector, AppContext};
+
+/// What [`Guard::repair`] fixed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileRepair {
+    /// Shards that were missing or damaged and were rebuilt, numbered as
+    /// for [`Guard::repair_shard`].
+    pub shards_repaired: Vec<usize>,
+    /// The source was missing or damaged and was rewritten from the shards.
+    pub source_restored: bool,
+}
+
+/// rs_guard's protection of individual files, for embedding in other tools
+/// without running the server. The API handlers go through the same calls.
+///
+/// All methods block; call them from a blocking thread in async code.
+#[derive(Clone)]
+pub struct Guard {
+    ctx: AppContext,
+}
+
+impl From<AppContext> for Guard {
+    fn from(ctx: AppContext) -> Self {
+        Self { ctx }
+    }
+}
+
+impl Guard {
+    /// Opens the shard store of `config` and the metadata DB at `db_path`.
+    /// The DB can only be open in one process at a time.
+    pub fn open(config: AppConfig, db_path: impl AsRef<Path>) -> Result<Self> {
+        let db = metadata::open_db(&db_path.as_ref().to_string_lossy())?;
+        Ok(Self { ctx: AppContext::new(config, db)? })
+    }
+
+    /// The shared state, for anything not covered by these methods.
+    pub fn context(&self) -> &AppContext {
+        &self.ctx
+    }
+
+    /// Encodes `path` into shards, replacing any earlier protection.
+    pub fn protect(&self, path: &Path) -> Result<FileRecord> {
+        let path = protector::canonical_path(&self.ctx, path)?;
+        protector::protect_file(&self.ctx, &path)
+    }
+
+    /// Checks a protected file's shards and source, returning what is wrong
+    /// with it, if anything.
+    pub fn verify(&self, path: &Path) -> Result<Option<Finding>> {
+        checker::check_file(&self.ctx, path)
+    }
+
+    /// Rebuilds every missing or damaged shard of a protected file, then
+    /// restores its source from the shards if that is missing or damaged.
+    /// A source that was edited since it was protected is left alone.
+    pub fn repair(&self, path: &Path) -> Result<FileRepair> {
+        let record = self.record(path)?;
+        if record.deleted_at.is_some() {
+            bail!("{} was deleted", metadata::display_path(path));
+        }
+        let mut repair = FileRepair::default();
+        for (number, stripe) in record.stripes().iter().enumerate() {
+            let shards = protector::load_shards(&self.ctx, stripe)?;
+            let first = number * stripe.total_shards();
+            for index in (0..shards.len()).filter(|&index| shards[index].is_none()).map(|index| first + index) {
+                match self.repair_shard(path, index)? {
+                    ShardRepairOutcome::Repaired(_) => repair.shards_repaired.push(index),
+                    ShardRepairOutcome::AlreadyHealthy => {}
+                    outcome => bail!("cannot repair shard {} of {}: {:?}", index, metadata::display_path(path), outcome),
+                }
+            }
+        }
+        let _lock = self.ctx.file_locks.lock(path);
+        let damaged = match fs::metadata(path) {
+            Err(_) => true,
+            Ok(meta) => {
+                meta.len() == record.size
+                    && protector::modified_secs(&meta) == record.mtime
+                    && protector::hash_file(path)? != record.checksum
+            }
+        };
+        if damaged {
+            if !protector::recover_file(&self.ctx, path, path)? {
+                bail!("{} does not match its checksum after being restored", metadata::display_path(path));
+            }
+            tracing::info!("Restored {} from its shards", metadata::display_path(path));
+            self.ctx.metrics.repaired();
+            self.ctx.publish_status();
+            repair.source_restored = true;
+        }
+        Ok(repair)
+    }
+
+    /// Runs a full check and repairs every corrupted file it finds with
+    /// [`repair::run_repair`], as one operation. Fails with
+    /// [`checker::CheckInProgress`] if another check is running, and counts
+    /// as one until done, so periodic checks with `skip_overlapping_checks`
+    /// hold off. Drifted files are only counted, as they need a re-protect,
+    /// not a repair.
+    pub fn check_and_repair(&self) -> Result<CheckAndRepairResult> {
+        let _running = RunningCheck::start_exclusive(&self.ctx)?;
+        let (run, files) = checker::run_check_detailed(&self.ctx, CheckTrigger::Manual)?;
+        let repairs = repair::run_repair(&self.ctx, &files);
+        let result = CheckAndRepairResult {
+            check_id: run.id,
+            checked: run.checked,
+            corrupted: run.corrupted,
+            repaired: repairs.repaired,
+            failed: repairs.failed_paths.len() as u64,
+            failed_paths: repairs.failed_paths,
+            drifted: run.drifted,
+        };
+        tracing::info!(
+            "Check and repair finished: {} checked, {} corrupted, {} repaired, {} failed",
+            result.checked, result.corrupted, result.repaired, result.failed
+        );
+        Ok(result)
+    }
+
+    /// Rebuilds one shard of a protected file from the others. Shards of
+    /// stripes appended under `append_optimize` are numbered on from those
+    /// of the first encode.
+    pub fn repair_shard(&self, path: &Path, index: usize) -> Result<ShardRepairOutcome> {
+        repair::repair_shard(&self.ctx, path, index)
+    }
+
+    /// Rebuilds a protected file from its shards and writes it to `out`,
+    /// returning whether `out` read back matches the stored checksum.
+    pub fn recover(&self, path: &Path, out: &Path) -> Result<bool> {
+        protector::recover_file(&self.ctx, path, out)
+    }
+
+    /// Like [`Guard::recover`], for `version` of the file: the current one
+    /// or one retained under `keep_versions`.
+    pub fn recover_version(&self, path: &Path, version: u32, out: &Path) -> Result<bool> {
+        protector::recover_version(&self.ctx, path, version, out)
+    }
+
+    /// Rebuilds the content of a protected file from its shards: the current
+    /// version, or an earlier one retained under `keep_versions`. `None` if
+    /// the file is not protected or the version no longer exists.
+    pub fn read(&self, path: &Path, version: Option<u32>) -> Result<Option<Vec<u8>>> {
+        let version = match version {
+            Some(version) => version,
+            None => protector::current_version(&self.ctx, path)?,
+        };
+        match protector::version_record(&self.ctx, path, version)? {
+            Some(record) => protector::reconstruct(&self.ctx, &record).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn record(&self, path: &Path) -> Result<FileRecord> {
+        metadata::get_file_metadata(&self.ctx.db, path)?
+            .ok_or_else(|| anyhow!("{} is not protected", metadata::display_path(path)))
+    }
+}
@@ -0,0 +1,36 @@
+use shared::HeartbeatStatus;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When the check loop last completed a cycle, so a stalled or dead loop
+/// can be noticed from outside through `/api/heartbeat`.
+pub struct Heartbeat {
+    /// Set to the start time until the first beat, so a loop that never
+    /// completes a cycle also goes stale.
+    last: Mutex<(Instant, Option<chrono::DateTime<chrono::Utc>>)>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self { last: Mutex::new((Instant::now(), None)) }
+    }
+}
+
+impl Heartbeat {
+    /// Records that a check cycle just completed.
+    pub fn beat(&self) {
+        *self.last.lock().unwrap() = (Instant::now(), Some(chrono::Utc::now()));
+    }
+
+    /// The heartbeat as reported by the API. It is stale once older than
+    /// `max_age_secs`; 0 never goes stale.
+    pub fn status(&self, max_age_secs: u64) -> HeartbeatStatus {
+        let (at, wall) = *self.last.lock().unwrap();
+        let age = at.elapsed();
+        HeartbeatStatus {
+            last_beat: wall.map(|time| time.to_rfc3339()),
+            age_ms: age.as_millis() as u64,
+            stale: max_age_secs > 0 && age > Duration::from_secs(max_age_secs),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::config::IoPriority;
+
+/// Lowers the I/O priority of the calling thread to `priority` until the
+/// returned guard is dropped, which restores the previous one. Encodes and
+/// checks run on shared blocking threads, so the change must not outlive
+/// them. Failures only log: a lower priority is a courtesy, not a must.
+pub fn lower(priority: IoPriority) -> IoPriorityGuard {
+    let previous = match priority {
+        IoPriority::Normal => None,
+        _ => current().and_then(|previous| {
+            set(raw(priority))
+                .map_err(|e| tracing::debug!("Cannot set I/O priority {:?}: {}", priority, e))
+                .ok()
+                .map(|()| previous)
+        }),
+    };
+    IoPriorityGuard { previous }
+}
+
+/// Restores the thread's I/O priority when dropped.
+pub struct IoPriorityGuard {
+    previous: Option<i32>,
+}
+
+impl Drop for IoPriorityGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous {
+            if let Err(e) = set(previous) {
+                tracing::debug!("Cannot restore I/O priority: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    pub const CLASS_SHIFT: i32 = 13;
+    pub const CLASS_BEST_EFFORT: i32 = 2;
+    pub const CLASS_IDLE: i32 = 3;
+    /// The lowest of the best-effort levels 0 to 7.
+    pub const BEST_EFFORT_LOWEST: i32 = 7;
+    const WHO_PROCESS: libc::c_int = 1;
+
+    // With `IOPRIO_WHO_PROCESS`, id 0 means the calling thread.
+    pub fn get() -> std::io::Result<i32> {
+        // SAFETY: ioprio_get takes two integers and has no memory effects.
+        let prio = unsafe { libc::syscall(libc::SYS_ioprio_get, WHO_PROCESS, 0) };
+        if prio < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(prio as i32)
+    }
+
+    pub fn set(prio: i32) -> std::io::Result<()> {
+        // SAFETY: as above, integers only.
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, WHO_PROCESS, 0, prio) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The `ioprio_set` value for `priority`.
+#[cfg(target_os = "linux")]
+fn raw(priority: IoPriority) -> i32 {
+    match priority {
+        IoPriority::Normal => 0,
+        IoPriority::Idle => sys::CLASS_IDLE << sys::CLASS_SHIFT,
+        IoPriority::BestEffort => (sys::CLASS_BEST_EFFORT << sys::CLASS_SHIFT) | sys::BEST_EFFORT_LOWEST,
+    }
+}
+
+/// The calling thread's I/O priority as a [`IoPriority`], e.g. to confirm
+/// that [`lower`] took effect. Anything that is neither idle nor the lowest
+/// best-effort level counts as normal.
+#[cfg(target_os = "linux")]
+pub fn current_priority() -> std::io::Result<IoPriority> {
+    let prio = sys::get()?;
+    Ok([IoPriority::Idle, IoPriority::BestEffort]
+        .into_iter()
+        .find(|priority| raw(*priority) == prio)
+        .unwrap_or(IoPriority::Normal))
+}
+
+#[cfg(target_os = "linux")]
+fn current() -> Option<i32> {
+    sys::get().map_err(|e| tracing::debug!("Cannot read I/O priority: {}", e)).ok()
+}
+
+#[cfg(target_os = "linux")]
+fn set(prio: i32) -> std::io::Result<()> {
+    sys::set(prio)
+}
+
+/// I/O priorities are only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+fn current() -> Option<i32> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn raw(_priority: IoPriority) -> i32 {
+    0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set(_prio: i32) -> std::io::Result<()> {
+    Ok(())
+}
@@ -1,14 +1,27 @@
-use std::net::SocketAddr;
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use axum::{
-    extract::State,
-    response::Json,
+    error_handling::HandleErrorLayer,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, Path, Query, State,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
     routing::{get, post},
     Router, http::StatusCode,
 };
-use shared::AppStatus;
-use tower_http::services::ServeDir;
+use serde::Deserialize;
+use shared::{
+    ApiError, AppStatus, ChangeSet, CheckAndRepairResult, CheckDiff, CheckRun, CheckTrigger, CoverageReport, DirectoryStatus, FileDetail, MetadataQueryPage, QuarantinedFile, QueriedRecord, RebuildResult, RecordStatus, RecoverResult, RefreshResult,
+    ReplicationVerifyReport, ReprotectResult, ShardLocationHealth, ShardRepairResult, ErrorCounts, ShardStoreStats, SignedReport, VersionInfo, WatchedDirResult, WorkerStats, METADATA_QUERY_VERSION, HeartbeatStatus,
+};
+use futures_util::Stream;
+use tokio::sync::broadcast;
+use tower::{limit::GlobalConcurrencyLimitLayer, load_shed::LoadShedLayer, BoxError, ServiceBuilder};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use anyhow::Result;
 // 在 release 构建中启用静态资源嵌入
@@ -20,22 +33,185 @@ use rust_embed::RustEmbed;
 #[folder = "../frontend/dist/"]
 struct Assets;
 
+pub mod assets;
+pub mod attrs;
+pub mod backlog;
+pub mod backup;
 pub mod checker;
+pub mod cli;
 pub mod config;
+pub mod coverage;
+pub mod disk;
 pub mod encoder;
+pub mod errors;
+pub mod file_locks;
+pub mod filter;
+pub mod guard;
+pub mod heartbeat;
+pub mod ioprio;
+pub mod merkle;
 pub mod metadata;
+pub mod metrics;
+pub mod notifications;
+pub mod par2;
+pub mod protector;
+pub mod recovery;
 pub mod repair;
+pub mod replication;
+pub mod report;
+pub mod salvage;
+pub mod scanner;
+pub mod self_protect;
+pub mod session;
+pub mod shard_store;
+pub mod signing;
+pub mod store_health;
+pub mod sparse;
+pub mod streams;
+pub mod throttle;
+pub mod version;
 pub mod watcher;
+pub mod workers;
+
+pub use guard::Guard;
 
 // Define an application state that can be shared across handlers.
 pub type AppState = Arc<Mutex<AppStatus>>;
 pub type DbState = Arc<metadata::MetadataDb>;
 
+/// Everything the background tasks and API handlers share. Cheap to clone.
+#[derive(Clone)]
+pub struct AppContext {
+    pub status: AppState,
+    pub db: DbState,
+    pub config: Arc<config::AppConfig>,
+    pub store: Arc<shard_store::ShardStore>,
+    pub replication: Arc<replication::ReplicationQueue>,
+    pub notifications: Arc<notifications::NotificationQueue>,
+    pub file_locks: Arc<file_locks::FileLocks>,
+    /// Signalled to stop the server gracefully, e.g. by `POST /api/shutdown`.
+    pub shutdown: Arc<tokio::sync::Notify>,
+    /// Integrity checks currently in progress.
+    pub checks_running: Arc<std::sync::atomic::AtomicUsize>,
+    /// Open `/api/events` streams.
+    pub streams: Arc<streams::StreamClients>,
+    /// Queued and running encodes and repairs.
+    pub workers: Arc<workers::Workers>,
+    /// Beats whenever a check cycle completes.
+    pub heartbeat: Arc<heartbeat::Heartbeat>,
+    /// Counters for the summary logged on shutdown.
+    pub session: Arc<session::Session>,
+    /// When the watcher last re-encoded each churning file.
+    pub reencode_throttle: Arc<throttle::ReencodeThrottle>,
+    /// Transient errors since startup, served by `/api/errors`.
+    pub errors: Arc<errors::ErrorCounters>,
+    /// The configured watched directories and those added since startup.
+    pub watched: Arc<watcher::WatchedDirs>,
+    /// Counters served only by `/api/metrics`.
+    pub metrics: Arc<metrics::Counters>,
+    /// Snapshots of the status sent to `/api/ws` clients when it changes.
+    pub status_updates: tokio::sync::broadcast::Sender<AppStatus>,
+}
+
+impl AppContext {
+    /// Builds the shared state for a configuration, opening its shard store.
+    pub fn new(mut app_config: config::AppConfig, db: metadata::MetadataDb) -> Result<Self> {
+        if app_config.case_insensitive_paths.is_none() {
+            let detected = app_config.watched_directories.first().is_some_and(|dir| protector::detect_case_insensitive(dir));
+            app_config.case_insensitive_paths = Some(detected);
+        }
+        let status = AppStatus {
+            watched_dirs: app_config.watched_directories.iter().map(|p| metadata::display_path(p)).collect(),
+            per_directory: app_config
+                .watched_directories
+                .iter()
+                .map(|dir| DirectoryStatus {
+                    path: metadata::display_path(dir),
+                    label: app_config.label_for(dir).map(str::to_string),
+                })
+                .collect(),
+            data_shards: app_config.data_shards,
+            parity_shards: app_config.parity_shards,
+            mirror_mode: app_config.data_shards == 1,
+            protected_files: metadata::count_files(&db)?,
+            ..Default::default()
+        };
+        let store = shard_store::ShardStore::new(&app_config.shard_store_dir)?
+            .with_headers(app_config.shard_headers)
+            .with_dir_layout(app_config.shard_dir_fanout, app_config.shard_dir_depth)
+            .with_preallocation(app_config.preallocate_shards)
+            .with_error_threshold(app_config.shard_location_error_threshold)
+            .with_cache_bytes(app_config.shard_cache_bytes);
+        app_config.excluded_dirs = app_config.shard_store_overlap();
+        for dir in &app_config.excluded_dirs {
+            tracing::warn!(
+                "The shard store {} is inside a watched directory; not protecting anything below it",
+                metadata::display_path(dir)
+            );
+        }
+        let watched = watcher::WatchedDirs::new(app_config.watched_directories.clone());
+        Ok(Self {
+            status: Arc::new(Mutex::new(status)),
+            db: Arc::new(db),
+            config: Arc::new(app_config),
+            store: Arc::new(store),
+            replication: Arc::default(),
+            notifications: Arc::default(),
+            file_locks: Arc::default(),
+            shutdown: Arc::default(),
+            checks_running: Arc::default(),
+            streams: Arc::default(),
+            workers: Arc::default(),
+            heartbeat: Arc::default(),
+            session: Arc::default(),
+            reencode_throttle: Arc::default(),
+            errors: Arc::default(),
+            watched: Arc::new(watched),
+            metrics: Arc::default(),
+            status_updates: tokio::sync::broadcast::channel(STATUS_UPDATES_CAPACITY).0,
+        })
+    }
+
+    /// Sends the current status to every `/api/ws` client. Call after a
+    /// check, scan, repair or encode has updated it.
+    pub fn publish_status(&self) {
+        if self.status_updates.receiver_count() == 0 {
+            return;
+        }
+        // Sending fails only if the last client went away in the meantime.
+        let _ = self.status_updates.send(self.status_snapshot());
+    }
+
+    /// The status as sent to `/api/ws` clients: in full, but with only the
+    /// last [`STATUS_UPDATE_LOG_LINES`] lines of the log.
+    fn status_snapshot(&self) -> AppStatus {
+        let mut snapshot = {
+            let mut status = self.status.lock().unwrap();
+            // The log is never trimmed, so it is left out of the clone.
+            let logs = std::mem::take(&mut status.logs);
+            let mut snapshot = status.clone();
+            snapshot.logs = logs[logs.len().saturating_sub(STATUS_UPDATE_LOG_LINES)..].to_vec();
+            status.logs = logs;
+            snapshot
+        };
+        refresh_store_status(self, &mut snapshot);
+        snapshot
+    }
+}
+
+/// Status snapshots kept for `/api/ws` clients that fall behind. A client
+/// that misses some only skips to a newer snapshot.
+const STATUS_UPDATES_CAPACITY: usize = 16;
+
+/// Log lines included in each status sent to `/api/ws` clients, which can
+/// follow the whole log on `/api/logs/stream`.
+const STATUS_UPDATE_LOG_LINES: usize = 100;
 
 pub async fn run() -> Result<()> {
     // Initialize logging
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        // With RUST_LOG=trace, closing encode/check/repair/scan spans log their timings.
+        .with(tracing_subscriber::fmt::layer().with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE))
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "backend=debug,tower_http=debug".into()),
@@ -43,98 +219,1152 @@ pub async fn run() -> Result<()> {
         .init();
 
     // Load configuration
-    let app_config = config::load_config("config/folders.toml")?;
+    let config_path = "config/folders.toml";
+    let app_config = config::load_config(config_path)?;
     tracing::info!("Configuration loaded: {:?}", app_config);
+    if app_config.data_shards == 1 {
+        tracing::info!(
+            "Running in mirror mode: each file is stored as {} full copies.",
+            1 + app_config.parity_shards
+        );
+    }
 
-    // Create shared application state
-    let app_state = Arc::new(Mutex::new(AppStatus {
-        watched_dirs: app_config.watched_directories.iter().map(|p| p.to_str().unwrap_or_default().to_string()).collect(),
-        data_shards: app_config.data_shards,
-        parity_shards: app_config.parity_shards,
-        ..Default::default()
-    }));
+    if app_config.verify_codec_on_startup {
+        encoder::verify_codec(&encoder::SELF_TEST_PARITY)?;
+        tracing::info!("Codec self-test passed.");
+    }
 
     // Open the metadata database
     // TODO: The DB path should be configurable.
-    let db = Arc::new(metadata::open_db("rs_guard_meta.db")?);
+    let db = metadata::open_db("rs_guard_meta.db")?;
+
+    // Create shared application state
+    let ctx = AppContext::new(app_config, db)?;
+    tracing::info!("Shard store layout: {}", ctx.store.dir_layout());
+
+    // Finish or undo whatever a crash interrupted before new work starts.
+    let recovery_ctx = ctx.clone();
+    let recovered = tokio::task::spawn_blocking(move || recovery::resume_interrupted(&recovery_ctx)).await??;
+    if !recovered.is_empty() {
+        tracing::info!("Recovered {} interrupted operations.", recovered.len());
+    }
 
     // Start file watcher
-    let watcher_paths = app_config.watched_directories.clone();
-    watcher::start_watching(app_state.clone(), watcher_paths)?;
+    let watcher_paths = ctx.watched.list();
+    watcher::start_watching(ctx.clone(), watcher_paths)?;
     tracing::info!("File watcher started.");
 
-    // TODO: Start a periodic background task for checking integrity.
-    let state_clone = app_state.clone();
-    let db_clone = db.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(3600)); // Check every hour
-        loop {
+    replication::start(ctx.clone());
+    notifications::start(ctx.clone());
+
+    // Protect anything that appeared or changed while we were not running.
+    let scan_ctx = ctx.clone();
+    match ctx.config.startup_scan_mode {
+        config::StartupScanMode::Full => {
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = scanner::scan_directories(&scan_ctx) {
+                    tracing::error!("Startup scan failed: {}", e);
+                }
+            });
+        }
+        config::StartupScanMode::Quick => {
+            tokio::task::spawn_blocking(move || {
+                match scanner::quick_scan(&scan_ctx) {
+                    Ok(tracked) => tracing::info!("Quick scan tracked {} files to encode.", tracked),
+                    Err(e) => tracing::error!("Startup scan failed: {}", e),
+                }
+                // Tracked files from an earlier run that did not finish are encoded too.
+                if let Err(e) = scanner::encode_tracked(&scan_ctx) {
+                    tracing::error!("Encoding tracked files failed: {}", e);
+                }
+            });
+        }
+    }
+
+    // Periodically verify every protected file.
+    match ctx.config.check_interval_secs {
+        0 => tracing::info!("Scheduled integrity checks are disabled (check_interval_secs = 0)."),
+        secs => {
+            checker::spawn_periodic_checks(ctx.clone(), Duration::from_secs(secs));
+        }
+    }
+
+    if let Some(backup_dir) = ctx.config.metadata_backup_dir.clone() {
+        let db_clone = ctx.db.clone();
+        let keep = ctx.config.metadata_backup_keep;
+        let period = Duration::from_secs(ctx.config.metadata_backup_interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match backup::write_backup(&db_clone, &backup_dir, keep) {
+                    Ok(path) => tracing::info!("Metadata backup written to {}", path.display()),
+                    Err(e) => tracing::error!("Metadata backup failed: {}", e),
+                }
+            }
+        });
+    }
+
+    if ctx.config.protect_self {
+        let period = Duration::from_secs(ctx.config.metadata_backup_interval_secs.max(1));
+        self_protect::spawn(ctx.clone(), config_path.into(), period)?;
+    }
+
+    if ctx.config.deleted_retention_secs > 0 {
+        let purge_ctx = ctx.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                interval.tick().await;
+                let ctx = purge_ctx.clone();
+                match tokio::task::spawn_blocking(move || protector::purge_deleted(&ctx, protector::now_secs())).await {
+                    Ok(Ok(purged)) if !purged.is_empty() => tracing::info!("Purged {} deleted files.", purged.len()),
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => tracing::error!("Purging deleted files failed: {}", e),
+                    Err(e) => tracing::error!("Purging deleted files panicked: {}", e),
+                }
+            }
+        });
+    }
+
+    if ctx.config.db_compact_interval_secs > 0 {
+        let compact_ctx = ctx.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(compact_ctx.config.db_compact_interval_secs));
+            // The first tick completes immediately; there is nothing to reclaim at startup.
             interval.tick().await;
-            tracing::info!("Kicking off periodic integrity check.");
-            if let Err(e) = checker::run_check(state_clone.clone(), db_clone.clone()).await {
-                tracing::error!("Periodic check failed: {}", e);
+            loop {
+                interval.tick().await;
+                let ctx = compact_ctx.clone();
+                match tokio::task::spawn_blocking(move || metadata::compact(&ctx.db)).await {
+                    Ok(Ok(compaction)) => {
+                        tracing::info!(
+                            "Compacted the metadata database from {} to {} bytes.",
+                            compaction.size_before, compaction.size_after
+                        );
+                        let mut status = compact_ctx.status.lock().unwrap();
+                        status.db_size_bytes = compaction.size_after;
+                        status.db_reclaimed_bytes += compaction.reclaimed();
+                    }
+                    Ok(Err(e)) => tracing::error!("Compacting the metadata database failed: {}", e),
+                    Err(e) => tracing::error!("Compacting the metadata database panicked: {}", e),
+                }
             }
-        }
-    });
-    
-    let app = app_router(app_state, db);
+        });
+    }
 
     // Start the server
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let addr = ctx.config.socket_addr()?;
     tracing::debug!("listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
-
+    serve(listener, ctx).await?;
     Ok(())
 }
 
-pub fn app_router(app_state: AppState, db: DbState) -> Router {
+/// Serves the application on `listener` until Ctrl-C or `ctx.shutdown` is
+/// signalled. In-flight requests are finished and the metadata DB is
+/// flushed before returning, and the session's summary is logged and
+/// returned.
+pub async fn serve(listener: tokio::net::TcpListener, ctx: AppContext) -> Result<session::SessionSummary> {
+    let shutdown = ctx.shutdown.clone();
+    let streams = ctx.streams.clone();
+    let db = ctx.db.clone();
+    let session = ctx.session.clone();
+    axum::serve(listener, app_router(ctx))
+        .with_graceful_shutdown(async move {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = shutdown.notified() => {}
+            }
+            tracing::info!("Shutting down.");
+            // Event streams never finish on their own.
+            streams.close();
+        })
+        .await?;
+    db.flush_async().await?;
+    let summary = session.summary();
+    tracing::info!(
+        files_protected = summary.files_protected,
+        checks_run = summary.checks_run,
+        corruptions_found = summary.corruptions_found,
+        shards_repaired = summary.shards_repaired,
+        uptime_secs = summary.uptime_secs,
+        "Session summary: {} files protected, {} checks run, {} corruptions found, {} shards repaired in {}s",
+        summary.files_protected, summary.checks_run, summary.corruptions_found, summary.shards_repaired, summary.uptime_secs
+    );
+    Ok(summary)
+}
+
+pub fn app_router(ctx: AppContext) -> Router {
+    let frontend_dir = ctx.config.frontend_dir.clone();
+
+    // Conditionally serve static files based on build profile
+    #[cfg(debug_assertions)]
+    let frontend = {
+        // In debug builds, serve from the filesystem for hot-reloading
+        assets::dir_frontend(&frontend_dir.unwrap_or_else(|| "../frontend/dist".into()))
+    };
+    #[cfg(not(debug_assertions))]
+    let frontend = match frontend_dir {
+        Some(dir) => assets::dir_frontend(&dir),
+        // In release builds, serve from the embedded assets for a single-binary deployment
+        None => assets::embedded_frontend::<Assets>(),
+    };
+
+    router_with_frontend(ctx, frontend)
+}
+
+/// Builds the application router with `frontend` serving everything
+/// outside `/api`.
+pub fn router_with_frontend(ctx: AppContext, frontend: Router) -> Router {
+    let max_connections = ctx.config.max_connections;
+    let replica_body_limit = ctx.config.replica_body_limit();
+
      // Define API routes
     let api_router = Router::new()
         .route("/status", get(get_status))
+        .route("/events", get(events_handler))
+        .route("/ws", get(ws_handler))
+        .route("/logs/stream", get(log_stream_handler))
         .route("/run-check", post(run_check_handler))
         .route("/run-repair", post(run_repair_handler))
-        .with_state((app_state, db));
+        .route("/check-and-repair", post(check_and_repair_handler))
+        .route("/checks", get(list_checks_handler))
+        .route("/checks/diff", get(diff_checks_handler))
+        .route("/checks/{id}", get(get_check_handler))
+        .route("/files", get(metadata_query_handler))
+        // `{*path}` must be the last segment, so `PUT /files/{path}/meta` is matched here too.
+        .route("/files/{*path}", get(file_detail_handler).put(file_meta_handler))
+        .route("/read/{*path}", get(read_handler))
+        .route("/download/{*path}", get(download_handler))
+        // Replicas carry whole files, so the default 2 MB body limit does not apply.
+        .route("/replicate", post(replicate_handler).layer(DefaultBodyLimit::max(replica_body_limit)))
+        .route("/replication/checksums", post(replica_checksums_handler))
+        .route("/replication/verify", post(replication_verify_handler))
+        .route("/reprotect-stale", post(reprotect_stale_handler))
+        .route("/refresh-metadata", post(refresh_metadata_handler))
+        .route("/changes", get(changes_handler))
+        .route("/repair-shard", post(repair_shard_handler))
+        .route("/recover", post(recover_handler))
+        .route("/rebuild", post(rebuild_handler))
+        .route("/watched-dirs", post(add_watched_dir_handler).delete(remove_watched_dir_handler))
+        .route("/quarantine", get(list_quarantine_handler))
+        .route("/quarantine/reset", post(reset_quarantine_handler))
+        .route("/shutdown", post(shutdown_handler))
+        .route("/shard-store/stats", get(shard_store_stats_handler))
+        .route("/shard-store/health", get(shard_store_health_handler))
+        .route("/metadata/query", get(metadata_query_handler))
+        .route("/workers", get(workers_handler))
+        .route("/heartbeat", get(heartbeat_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/errors", get(errors_handler))
+        .route("/errors/reset", post(reset_errors_handler))
+        .route("/coverage", post(coverage_handler))
+        .route("/report/signed", get(signed_report_handler))
+        .route("/version", get(version_handler))
+        // Unknown API paths must not fall through to the frontend's index.html.
+        .fallback(|| async { StatusCode::NOT_FOUND })
+        .with_state(ctx);
 
-    // Conditionally serve static files based on build profile
-    #[cfg(debug_assertions)]
-    {
-        // In debug builds, serve from the filesystem for hot-reloading
-        Router::new()
-            .nest("/api", api_router)
-            .fallback_service(ServeDir::new("../frontend/dist").append_index_html_on_directories(true))
+    let router = Router::new()
+        .nest("/api", api_router)
+        .merge(frontend);
+
+    if max_connections == 0 {
+        return router;
     }
-    #[cfg(not(debug_assertions))]
-    {
-        // In release builds, serve from the embedded assets for a single-binary deployment
-        Router::new()
-            .nest("/api", api_router)
-            .fallback_service(ServeDir::new("../frontend/dist").append_index_html_on_directories(true))
+    // Shed excess load with a 503 instead of queueing it, so a flood of
+    // requests cannot starve the background tasks.
+    router.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(|_: BoxError| async { StatusCode::SERVICE_UNAVAILABLE }))
+            .layer(LoadShedLayer::new())
+            .layer(GlobalConcurrencyLimitLayer::new(max_connections)),
+    )
+}
+
+/// How much of the status `/api/status` returns.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum StatusFields {
+    #[default]
+    Summary,
+    Full,
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+    #[serde(default)]
+    fields: StatusFields,
+}
+
+/// Returns the [`StatusSummary`] unless `?fields=full` asks for the whole
+/// [`AppStatus`], whose logs and lists make it costly to clone and send.
+async fn get_status(State(ctx): State<AppContext>, Query(query): Query<StatusQuery>) -> Response {
+    let mut status = ctx.status.lock().unwrap();
+    refresh_store_status(&ctx, &mut status);
+    match query.fields {
+        StatusFields::Summary => Json(status.summary()).into_response(),
+        StatusFields::Full => Json(status.clone()).into_response(),
     }
 }
 
-pub async fn get_status(State((app_state, _db)): State<(AppState, DbState)>) -> Json<AppStatus> {
-    let state = app_state.lock().unwrap().clone();
-    Json(state)
+/// `GET /api/events`: streams the service status as server-sent `status`
+/// events, one per second, until the client disconnects.
+async fn events_handler(
+    State(ctx): State<AppContext>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, (StatusCode, String)> {
+    let max = ctx.config.max_stream_clients;
+    let Some(slot) = ctx.streams.subscribe(max) else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, format!("too many event streams open (max_stream_clients = {})", max)));
+    };
+    let interval = tokio::time::interval(Duration::from_secs(1));
+    let events = futures_util::stream::unfold((ctx, interval, slot), |(ctx, mut interval, mut slot)| async move {
+        tokio::select! {
+            _ = slot.closed() => return None,
+            _ = interval.tick() => {}
+        }
+        let mut status = ctx.status.lock().unwrap().clone();
+        refresh_store_status(&ctx, &mut status);
+        Some((Event::default().event("status").json_data(status), (ctx, interval, slot)))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Fills in the parts of the status kept by the shard store.
+fn refresh_store_status(ctx: &AppContext, status: &mut AppStatus) {
+    status.degraded_shard_locations =
+        ctx.store.degraded_locations().iter().map(|location| metadata::display_path(location)).collect();
+}
+
+/// `GET /api/ws`: a WebSocket that sends the full status as JSON on connect
+/// and again whenever a check, scan, repair or encode changes it. Counts
+/// towards `max_stream_clients` like `/api/events`.
+async fn ws_handler(ws: WebSocketUpgrade, State(ctx): State<AppContext>) -> Result<Response, (StatusCode, String)> {
+    let max = ctx.config.max_stream_clients;
+    let Some(slot) = ctx.streams.subscribe(max) else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, format!("too many event streams open (max_stream_clients = {})", max)));
+    };
+    Ok(ws.on_upgrade(move |socket| push_status(ctx, socket, slot)))
+}
+
+async fn push_status(ctx: AppContext, mut socket: WebSocket, mut slot: streams::StreamSlot) {
+    // Subscribe before taking the snapshot, so no change falls in between.
+    let mut updates = ctx.status_updates.subscribe();
+    let mut next = Some(ctx.status_snapshot());
+    loop {
+        if let Some(status) = next.take() {
+            let Ok(text) = serde_json::to_string(&status) else {
+                return;
+            };
+            if socket.send(Message::Text(text.into())).await.is_err() {
+                return;
+            }
+        }
+        tokio::select! {
+            _ = slot.closed() => return,
+            update = updates.recv() => match update {
+                Ok(status) => next = Some(status),
+                // Only the latest status matters; wait for the next one.
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // Clients have nothing to say; pings are answered by axum.
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// How often `/api/logs/stream` looks for new lines when not batching.
+const LOG_STREAM_POLL: Duration = Duration::from_millis(100);
+
+/// `GET /api/logs/stream`: streams the lines added to the service log from
+/// now on, in order, as `log` events or, with `log_stream_batch_ms`, as
+/// `logs` events holding a JSON array of lines. Counts towards
+/// `max_stream_clients` like `/api/events`.
+async fn log_stream_handler(
+    State(ctx): State<AppContext>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, (StatusCode, String)> {
+    let max = ctx.config.max_stream_clients;
+    let Some(slot) = ctx.streams.subscribe(max) else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, format!("too many event streams open (max_stream_clients = {})", max)));
+    };
+    let period = match ctx.config.log_stream_batch_ms {
+        0 => LOG_STREAM_POLL,
+        ms => Duration::from_millis(ms),
+    };
+    let interval = tokio::time::interval(period);
+    let seen = ctx.status.lock().unwrap().logs.len();
+    let state = (ctx, interval, slot, seen, VecDeque::new());
+    let events = futures_util::stream::unfold(state, |(ctx, mut interval, mut slot, mut seen, mut pending)| async move {
+        while pending.is_empty() {
+            tokio::select! {
+                _ = slot.closed() => return None,
+                _ = interval.tick() => {}
+            }
+            let status = ctx.status.lock().unwrap();
+            let new = status.logs.get(seen..).unwrap_or_default();
+            seen = status.logs.len();
+            pending.extend(log_events(&ctx.config, new));
+        }
+        let event = pending.pop_front()?;
+        Some((event, (ctx, interval, slot, seen, pending)))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Frames new log lines as server-sent events, keeping their order.
+fn log_events(config: &config::AppConfig, lines: &[String]) -> Vec<Result<Event, axum::Error>> {
+    if config.log_stream_batch_ms == 0 {
+        return lines.iter().map(|line| Ok(Event::default().event("log").data(line))).collect();
+    }
+    lines
+        .chunks(config.log_stream_batch_lines.max(1))
+        .map(|batch| Event::default().event("logs").json_data(batch))
+        .collect()
 }
 
-async fn run_check_handler(State((app_state, db)): State<(AppState, DbState)>) -> StatusCode {
+async fn run_check_handler(State(ctx): State<AppContext>) -> StatusCode {
     tracing::info!("Manual integrity check triggered via API.");
     // Spawn a task to avoid blocking the API response
-    tokio::spawn(async move {
-        if let Err(e) = checker::run_check(app_state, db).await {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = checker::run_check(&ctx) {
             tracing::error!("Manual check failed: {}", e);
         }
     });
     StatusCode::ACCEPTED
 }
 
-async fn run_repair_handler(State((app_state, db)): State<(AppState, DbState)>) -> StatusCode {
+#[derive(Deserialize)]
+struct ChecksQuery {
+    /// Only list runs started this way.
+    trigger: Option<CheckTrigger>,
+}
+
+async fn list_checks_handler(
+    State(ctx): State<AppContext>,
+    Query(query): Query<ChecksQuery>,
+) -> Result<Json<Vec<CheckRun>>, (StatusCode, String)> {
+    let mut runs = metadata::list_check_runs(&ctx.db).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let Some(trigger) = query.trigger {
+        runs.retain(|run| run.trigger == trigger);
+    }
+    Ok(Json(runs))
+}
+
+async fn get_check_handler(
+    State(ctx): State<AppContext>,
+    Path(id): Path<u64>,
+) -> Result<Json<CheckRun>, (StatusCode, String)> {
+    match metadata::get_check_run(&ctx.db, id) {
+        Ok(Some(run)) => Ok(Json(run)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("no check run with id {}", id))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: u64,
+    to: u64,
+}
+
+async fn diff_checks_handler(
+    State(ctx): State<AppContext>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<CheckDiff>, (StatusCode, String)> {
+    let run = |id: u64| match metadata::get_check_run(&ctx.db, id) {
+        Ok(Some(run)) => Ok(run),
+        Ok(None) => Err((StatusCode::NOT_FOUND, format!("no check run with id {}", id))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    Ok(Json(checker::diff_runs(&run(query.from)?, &run(query.to)?)))
+}
+
+/// Turns a `{*path}` capture back into a filesystem path. The leading `/`
+/// of an absolute path is consumed by the route, so it is restored here.
+fn path_param(raw: &str) -> std::path::PathBuf {
+    if cfg!(unix) && !raw.starts_with('/') {
+        std::path::PathBuf::from(format!("/{}", raw))
+    } else {
+        std::path::PathBuf::from(raw)
+    }
+}
+
+async fn file_detail_handler(
+    State(ctx): State<AppContext>,
+    Path(raw): Path<String>,
+) -> Result<Json<FileDetail>, (StatusCode, String)> {
+    let path = path_param(&raw);
+    let record = match metadata::get_file_metadata(&ctx.db, &path) {
+        Ok(Some(record)) => record,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "file is not protected".to_string())),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    let mut detail = record.to_detail(&path);
+    if ctx.config.verify_shard_device {
+        let shards = record.data_shards + record.parity_shards;
+        detail.misplaced_shards = ctx
+            .store
+            .misplaced_shards(&protector::file_id_for(&path), shards)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    Ok(Json(detail))
+}
+
+#[derive(Deserialize)]
+struct FileMetaUpdate {
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    priority: u8,
+}
+
+/// `PUT /api/files/{path}/meta`: sets the tags and priority of a protected file.
+async fn file_meta_handler(
+    State(ctx): State<AppContext>,
+    Path(raw): Path<String>,
+    Json(update): Json<FileMetaUpdate>,
+) -> Result<Json<FileDetail>, (StatusCode, String)> {
+    let Some(raw) = raw.strip_suffix("/meta") else {
+        return Err((StatusCode::NOT_FOUND, "expected /api/files/{path}/meta".to_string()));
+    };
+    let path = path_param(raw);
+    tokio::task::spawn_blocking(move || {
+        let _lock = ctx.file_locks.lock(&path);
+        metadata::set_file_meta(&ctx.db, &path, update.tags, update.priority)
+            .map(|record| record.map(|record| record.to_detail(&path)))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map(Json)
+    .ok_or_else(|| (StatusCode::NOT_FOUND, "file is not protected".to_string()))
+}
+
+async fn shard_store_stats_handler(
+    State(ctx): State<AppContext>,
+) -> Result<Json<ShardStoreStats>, (StatusCode, String)> {
+    let layout = ctx.store.dir_layout();
+    let mut stats = tokio::task::spawn_blocking(move || metadata::shard_store_stats(&ctx.db))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    stats.dir_layout = layout;
+    Ok(Json(stats))
+}
+
+async fn shard_store_health_handler(
+    State(ctx): State<AppContext>,
+) -> Result<Json<Vec<ShardLocationHealth>>, (StatusCode, String)> {
+    tokio::task::spawn_blocking(move || store_health::probe(ctx.store.root()))
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Deserialize)]
+struct ReadQuery {
+    /// Defaults to `default_read_source` from the config.
+    from: Option<config::ReadSource>,
+}
+
+/// Returns a protected file's content, either from its source or rebuilt from its shards.
+async fn read_handler(
+    State(ctx): State<AppContext>,
+    Path(raw): Path<String>,
+    Query(query): Query<ReadQuery>,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let path = path_param(&raw);
+    let from = query.from.unwrap_or(ctx.config.default_read_source);
+    tokio::task::spawn_blocking(move || {
+        metadata::get_file_metadata(&ctx.db, &path)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .ok_or((StatusCode::NOT_FOUND, "file is not protected".to_string()))?;
+        match from {
+            config::ReadSource::Source => std::fs::read(&path).map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => (StatusCode::NOT_FOUND, "source file is missing".to_string()),
+                _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }),
+            config::ReadSource::Shards => Guard::from(ctx)
+                .read(&path, None)
+                .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?
+                .ok_or((StatusCode::NOT_FOUND, "file is not protected".to_string())),
+        }
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+    /// Defaults to the current version.
+    version: Option<u32>,
+}
+
+/// Rebuilds a version of a protected file from its shards. Earlier versions
+/// are only available while retained under `keep_versions`.
+async fn download_handler(
+    State(ctx): State<AppContext>,
+    Path(raw): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let path = path_param(&raw);
+    tokio::task::spawn_blocking(move || Guard::from(ctx).read(&path, query.version))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "that version no longer exists".to_string()))
+}
+
+/// Stores shards and a record pushed by a peer that lists us in
+/// `replication_peers`. The peer must send our `api_token`.
+async fn replicate_handler(
+    State(ctx): State<AppContext>,
+    headers: axum::http::HeaderMap,
+    Json(payload): Json<replication::ReplicaPayload>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_api_token(&ctx, &headers, "replication")?;
+    replication::validate(&payload).map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    tokio::task::spawn_blocking(move || replication::accept(&ctx, payload))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Checksums the shards we store for the paths a peer asks about, so it can
+/// compare them with its own. The peer must send our `api_token`.
+async fn replica_checksums_handler(
+    State(ctx): State<AppContext>,
+    headers: axum::http::HeaderMap,
+    Json(request): Json<replication::ChecksumRequest>,
+) -> Result<Json<replication::ShardChecksums>, (StatusCode, String)> {
+    require_api_token(&ctx, &headers, "replication")?;
+    tokio::task::spawn_blocking(move || replication::shard_checksums(&ctx, &request))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Deserialize)]
+struct VerifyQuery {
+    /// Queue mismatched files to be pushed to the peer again.
+    #[serde(default)]
+    resync: bool,
+}
+
+/// Compares our shards with those stored on the replication peers.
+async fn replication_verify_handler(
+    State(ctx): State<AppContext>,
+    Query(query): Query<VerifyQuery>,
+) -> Result<Json<ReplicationVerifyReport>, (StatusCode, String)> {
+    if ctx.config.replication_peers.is_empty() {
+        return Err((StatusCode::CONFLICT, "no replication_peers are configured".to_string()));
+    }
+    replication::verify(&ctx, query.resync)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Checks everything and repairs what it can in one pass, reporting both.
+async fn check_and_repair_handler(
+    State(ctx): State<AppContext>,
+) -> Result<Json<CheckAndRepairResult>, (StatusCode, String)> {
+    tracing::info!("Check and repair triggered via API.");
+    tokio::task::spawn_blocking(move || Guard::from(ctx).check_and_repair())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| match e.downcast_ref::<checker::CheckInProgress>() {
+            Some(busy) => (StatusCode::CONFLICT, busy.to_string()),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })
+}
+
+/// Like `/api/check-and-repair`, but in the background.
+async fn run_repair_handler(State(ctx): State<AppContext>) -> StatusCode {
     tracing::info!("Manual repair triggered via API.");
-    tokio::spawn(async move {
-        if let Err(e) = repair::run_repair(app_state, db).await {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = Guard::from(ctx).check_and_repair() {
             tracing::error!("Manual repair failed: {}", e);
         }
     });
     StatusCode::ACCEPTED
-} 
\ No newline at end of file
+}
+
+async fn reprotect_stale_handler(State(ctx): State<AppContext>) -> Result<Json<ReprotectResult>, StatusCode> {
+    tracing::info!("Reprotecting stale files via API.");
+    let result = tokio::task::spawn_blocking(move || protector::reprotect_stale(&ctx))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    match result {
+        Ok(result) => Ok(Json(result)),
+        Err(e) => {
+            tracing::error!("Reprotect failed: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RefreshQuery {
+    /// Only refresh files at or under this path.
+    path: Option<String>,
+}
+
+async fn refresh_metadata_handler(
+    State(ctx): State<AppContext>,
+    Query(query): Query<RefreshQuery>,
+) -> Result<Json<RefreshResult>, (StatusCode, String)> {
+    tracing::info!("Refreshing file metadata via API.");
+    let scope = query.path.as_deref().map(path_param);
+    tokio::task::spawn_blocking(move || protector::refresh_metadata(&ctx, scope.as_deref()))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    /// RFC 3339 timestamp, e.g. `2024-01-31T12:00:00Z`.
+    since: String,
+}
+
+async fn changes_handler(
+    State(ctx): State<AppContext>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Json<ChangeSet>, (StatusCode, String)> {
+    let since = chrono::DateTime::parse_from_rfc3339(&query.since)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid `since` timestamp: {}", e)))?;
+    let since_ms = since.timestamp_millis().max(0) as u64;
+    let changes = metadata::changes_since(&ctx.db, since_ms)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let classified = metadata::classify_changes(&changes);
+    let to_strings = |paths: Vec<std::path::PathBuf>| paths.iter().map(|p| metadata::display_path(p)).collect();
+    Ok(Json(ChangeSet {
+        added: to_strings(classified.added),
+        modified: to_strings(classified.modified),
+        removed: to_strings(classified.removed),
+    }))
+}
+
+async fn workers_handler(State(ctx): State<AppContext>) -> Json<WorkerStats> {
+    Json(ctx.workers.stats())
+}
+
+/// Answers 503 once the heartbeat is stale, so plain HTTP monitors can
+/// alert on the status code alone.
+async fn heartbeat_handler(State(ctx): State<AppContext>) -> (StatusCode, Json<HeartbeatStatus>) {
+    let heartbeat = ctx.heartbeat.status(ctx.config.heartbeat_max_age_secs);
+    let code = if heartbeat.stale { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+    (code, Json(heartbeat))
+}
+
+/// Runs a full check, so the request takes as long as one.
+async fn coverage_handler(State(ctx): State<AppContext>) -> Result<Json<CoverageReport>, (StatusCode, String)> {
+    tokio::task::spawn_blocking(move || coverage::coverage_report(&ctx))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Produces an integrity report signed with `report_signing_key`.
+async fn signed_report_handler(State(ctx): State<AppContext>) -> Result<Json<SignedReport>, (StatusCode, String)> {
+    if ctx.config.report_signing_key.is_none() {
+        return Err((StatusCode::CONFLICT, "no report_signing_key is configured".to_string()));
+    }
+    tokio::task::spawn_blocking(move || signing::signed_report(&ctx))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+async fn version_handler(State(ctx): State<AppContext>) -> Json<VersionInfo> {
+    Json(version::version_info(&ctx.config))
+}
+
+async fn errors_handler(State(ctx): State<AppContext>) -> Json<ErrorCounts> {
+    Json(errors::snapshot(&ctx))
+}
+
+/// Zeroes the error counters, returning the counts up to the reset.
+async fn reset_errors_handler(State(ctx): State<AppContext>) -> Json<ErrorCounts> {
+    Json(errors::reset(&ctx))
+}
+
+async fn metrics_handler(
+    State(ctx): State<AppContext>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let accept = headers.get(axum::http::header::ACCEPT).and_then(|value| value.to_str().ok());
+    let format = metrics::Format::negotiate(accept);
+    let body = tokio::task::spawn_blocking(move || metrics::render(&ctx, format))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(([(axum::http::header::CONTENT_TYPE, format.content_type())], body).into_response())
+}
+
+/// Page size of `/api/metadata/query` when the request does not give one.
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct MetadataQuery {
+    /// Only paths starting with this prefix.
+    prefix: Option<String>,
+    status: Option<RecordStatus>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// RFC 3339 bounds on when the file was last encoded, both inclusive.
+    protected_after: Option<String>,
+    protected_before: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// The supported way for external tools to read file metadata; the response
+/// carries [`METADATA_QUERY_VERSION`] so they can detect incompatible changes.
+/// Also serves the paginated listing at `/api/files`.
+async fn metadata_query_handler(
+    State(ctx): State<AppContext>,
+    Query(query): Query<MetadataQuery>,
+) -> Result<Json<MetadataQueryPage>, (StatusCode, String)> {
+    let timestamp = |name: &str, value: &Option<String>| -> Result<Option<u64>, (StatusCode, String)> {
+        value
+            .as_deref()
+            .map(|value| {
+                chrono::DateTime::parse_from_rfc3339(value)
+                    .map(|time| time.timestamp().max(0) as u64)
+                    .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid `{}` timestamp: {}", name, e)))
+            })
+            .transpose()
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    if !(1..=MAX_QUERY_LIMIT).contains(&limit) {
+        return Err((StatusCode::BAD_REQUEST, format!("`limit` must be between 1 and {}", MAX_QUERY_LIMIT)));
+    }
+    let filter = metadata::FileQuery {
+        path_prefix: query.prefix.as_deref().map(path_param),
+        status: query.status,
+        min_size: query.min_size,
+        max_size: query.max_size,
+        protected_after: timestamp("protected_after", &query.protected_after)?,
+        protected_before: timestamp("protected_before", &query.protected_before)?,
+    };
+    let offset = query.offset;
+    let filter = Arc::new(filter);
+    let batch_size = ctx.config.listing_batch_size;
+    let mut total = 0;
+    let mut records = Vec::new();
+    let mut after: Option<Vec<u8>> = None;
+    // One blocking task per batch, so a listing of a large database never
+    // monopolizes a worker and other requests run in between.
+    loop {
+        let (ctx, filter) = (ctx.clone(), filter.clone());
+        let (matches, next) = tokio::task::spawn_blocking(move || {
+            metadata::query_files_batch(&ctx.db, &filter, after.as_deref(), batch_size)
+        })
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        for found in matches {
+            if total >= offset as u64 && records.len() < limit {
+                records.push(found);
+            }
+            total += 1;
+        }
+        match next {
+            Some(key) => after = Some(key),
+            None => break,
+        }
+        tokio::task::yield_now().await;
+    }
+    let end = (offset + records.len()) as u64;
+    Ok(Json(MetadataQueryPage {
+        version: METADATA_QUERY_VERSION,
+        total,
+        offset: offset as u64,
+        records: records
+            .into_iter()
+            .map(|found| QueriedRecord { status: found.status, file: found.record.to_detail(&found.path) })
+            .collect(),
+        next_offset: (end < total).then_some(end),
+    }))
+}
+
+#[derive(Deserialize)]
+struct RecoverRequest {
+    path: String,
+    out: String,
+    /// An earlier version retained under `keep_versions`; the current one if unset.
+    version: Option<u32>,
+}
+
+/// `POST /api/recover`: rebuilds a protected file from its shards into
+/// `out`, then reads it back. A copy that does not match the stored
+/// checksum is reported with `500` and `verified: false`. As `out` can be
+/// any path the service may write, this requires the `api_token`.
+async fn recover_handler(
+    State(ctx): State<AppContext>,
+    headers: axum::http::HeaderMap,
+    Json(request): Json<RecoverRequest>,
+) -> Result<(StatusCode, Json<RecoverResult>), (StatusCode, String)> {
+    require_api_token(&ctx, &headers, "recovery via the API")?;
+    tracing::info!("Recovery of {} to {} requested via API.", request.path, request.out);
+    let path = std::path::PathBuf::from(&request.path);
+    let out = std::path::PathBuf::from(&request.out);
+    let version = request.version;
+    let verified = tokio::task::spawn_blocking(move || {
+        let guard = Guard::from(ctx);
+        if metadata::get_file_metadata(&guard.context().db, &path)?.is_none() {
+            return Ok(None);
+        }
+        match version {
+            Some(version) => guard.recover_version(&path, version, &out).map(Some),
+            None => guard.recover(&path, &out).map(Some),
+        }
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e: anyhow::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((StatusCode::NOT_FOUND, "file is not protected".to_string()))?;
+    let status = if verified { StatusCode::OK } else { StatusCode::INTERNAL_SERVER_ERROR };
+    Ok((status, Json(RecoverResult { path: request.path, out: request.out, verified })))
+}
+
+#[derive(Deserialize)]
+struct RebuildRequest {
+    path: String,
+}
+
+/// `POST /api/rebuild`: drops the shards and records of every file under a
+/// watched directory and encodes them again from scratch. Answers once done.
+async fn rebuild_handler(
+    State(ctx): State<AppContext>,
+    Json(request): Json<RebuildRequest>,
+) -> Result<Json<RebuildResult>, (StatusCode, String)> {
+    let dir = std::path::PathBuf::from(&request.path);
+    if ctx.watched.root_of(&dir).is_none() {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is not in a watched directory", request.path)));
+    }
+    if !dir.is_dir() {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is not a directory", request.path)));
+    }
+    tracing::info!("Rebuild of {} requested via API.", request.path);
+    let result = tokio::task::spawn_blocking(move || scanner::rebuild_directory(&ctx, &dir))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(result))
+}
+
+#[derive(Deserialize)]
+struct WatchedDirRequest {
+    path: String,
+}
+
+fn api_error(status: StatusCode, error: impl ToString) -> (StatusCode, Json<ApiError>) {
+    (status, Json(ApiError { error: error.to_string() }))
+}
+
+/// Starts watching another directory without a restart, then scans it in
+/// the background. The directory is not added to the config file, so it is
+/// only watched until the next restart.
+async fn add_watched_dir_handler(
+    State(ctx): State<AppContext>,
+    Json(request): Json<WatchedDirRequest>,
+) -> Result<(StatusCode, Json<WatchedDirResult>), (StatusCode, Json<ApiError>)> {
+    let dir = std::path::PathBuf::from(&request.path);
+    if !dir.is_dir() {
+        return Err(api_error(StatusCode::BAD_REQUEST, format!("{} is not an existing directory", request.path)));
+    }
+    match watcher::register(&ctx, &dir) {
+        Ok(()) => {}
+        Err(e) if e.is::<watcher::AlreadyWatched>() => return Err(api_error(StatusCode::CONFLICT, e)),
+        Err(e) if e.is::<watcher::ContainsShardStore>() => return Err(api_error(StatusCode::BAD_REQUEST, e)),
+        Err(e) => return Err(api_error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+    let watched_dirs: Vec<String> = ctx.watched.list().iter().map(|dir| metadata::display_path(dir)).collect();
+    {
+        let mut status = ctx.status.lock().unwrap();
+        status.watched_dirs = watched_dirs.clone();
+        status.per_directory.push(DirectoryStatus { path: request.path.clone(), label: None });
+        status.logs.push(format!("[Watcher] Added watched directory {}", request.path));
+    }
+    let scan_ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = scanner::scan_directory(&scan_ctx, &dir) {
+            tracing::error!("Initial scan of {} failed: {}", metadata::display_path(&dir), e);
+        }
+    });
+    Ok((StatusCode::CREATED, Json(WatchedDirResult { path: request.path, watched_dirs, purged: 0 })))
+}
+
+#[derive(Deserialize, Default)]
+struct RemoveWatchedDirRequest {
+    path: Option<String>,
+    /// Also drop the shards and records of the directory's files.
+    #[serde(default)]
+    purge: bool,
+}
+
+/// Stops watching a directory without a restart. Its files stay protected
+/// unless `purge` is set; either way they are no longer scanned or checked
+/// for having vanished. The path and flag may come as query parameters or
+/// in a JSON body, which takes precedence.
+async fn remove_watched_dir_handler(
+    State(ctx): State<AppContext>,
+    Query(query): Query<RemoveWatchedDirRequest>,
+    body: Option<Json<RemoveWatchedDirRequest>>,
+) -> Result<Json<WatchedDirResult>, (StatusCode, Json<ApiError>)> {
+    let request = match body {
+        Some(Json(body)) => RemoveWatchedDirRequest { path: body.path.or(query.path), purge: body.purge || query.purge },
+        None => query,
+    };
+    let Some(path) = request.path else {
+        return Err(api_error(StatusCode::BAD_REQUEST, "missing `path`"));
+    };
+    let dir = std::path::PathBuf::from(&path);
+    match watcher::unregister(&ctx, &dir) {
+        Ok(true) => {}
+        Ok(false) => return Err(api_error(StatusCode::NOT_FOUND, format!("{} is not a watched directory", path))),
+        Err(e) => return Err(api_error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+    let watched_dirs: Vec<String> = ctx.watched.list().iter().map(|dir| metadata::display_path(dir)).collect();
+    {
+        let mut status = ctx.status.lock().unwrap();
+        status.watched_dirs = watched_dirs.clone();
+        status.per_directory.retain(|entry| entry.path != path);
+        status.logs.push(format!("[Watcher] Removed watched directory {}", path));
+    }
+    let mut purged = 0;
+    if request.purge {
+        let purge_ctx = ctx.clone();
+        purged = tokio::task::spawn_blocking(move || scanner::purge_directory(&purge_ctx, &dir))
+            .await
+            .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?
+            .map_err(|e| api_error(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+        if let Ok(count) = metadata::count_files(&ctx.db) {
+            ctx.status.lock().unwrap().protected_files = count;
+        }
+        tracing::info!("Purged {} files of {}.", purged, path);
+    }
+    Ok(Json(WatchedDirResult { path, watched_dirs, purged }))
+}
+
+#[derive(Deserialize)]
+struct RepairShardRequest {
+    path: String,
+    shard_index: usize,
+}
+
+async fn repair_shard_handler(
+    State(ctx): State<AppContext>,
+    Json(request): Json<RepairShardRequest>,
+) -> Result<Json<ShardRepairResult>, (StatusCode, String)> {
+    tracing::info!("Shard repair of {} #{} requested via API.", request.path, request.shard_index);
+    let path = std::path::PathBuf::from(&request.path);
+    let index = request.shard_index;
+    let outcome = tokio::task::spawn_blocking(move || Guard::from(ctx).repair_shard(&path, index))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    match outcome {
+        repair::ShardRepairOutcome::Repaired(checksum) => Ok(Json(ShardRepairResult {
+            path: request.path,
+            shard_index: index,
+            checksum,
+        })),
+        repair::ShardRepairOutcome::AlreadyHealthy => {
+            Err((StatusCode::CONFLICT, "shard is healthy, nothing to repair".to_string()))
+        }
+        repair::ShardRepairOutcome::Unrecoverable(reason) => Err((StatusCode::UNPROCESSABLE_ENTITY, reason)),
+        repair::ShardRepairOutcome::NotProtected => Err((StatusCode::NOT_FOUND, "file is not protected".to_string())),
+        repair::ShardRepairOutcome::IndexOutOfRange => {
+            Err((StatusCode::BAD_REQUEST, "shard_index is out of range".to_string()))
+        }
+        repair::ShardRepairOutcome::Quarantined(reason) => {
+            Err((StatusCode::LOCKED, format!("file is quarantined: {}", reason)))
+        }
+    }
+}
+
+async fn list_quarantine_handler(
+    State(ctx): State<AppContext>,
+) -> Result<Json<Vec<QuarantinedFile>>, (StatusCode, String)> {
+    let quarantined = metadata::list_quarantined(&ctx.db)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(
+        quarantined
+            .into_iter()
+            .map(|(path, entry)| QuarantinedFile {
+                path: metadata::display_path(&path),
+                reason: entry.reason,
+                quarantined_at: entry.quarantined_at,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ResetQuarantineRequest {
+    path: String,
+}
+
+/// Releases a file from quarantine so it is repaired again.
+async fn reset_quarantine_handler(
+    State(ctx): State<AppContext>,
+    Json(request): Json<ResetQuarantineRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match metadata::reset_quarantine(&ctx.db, std::path::Path::new(&request.path)) {
+        Ok(true) => {
+            tracing::info!("Released {} from quarantine.", request.path);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, "file is not quarantined".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// `POST /api/shutdown`: stops the server gracefully. Requires
+/// `allow_remote_shutdown` and the configured `api_token` as a bearer token.
+async fn shutdown_handler(
+    State(ctx): State<AppContext>,
+    headers: axum::http::HeaderMap,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !ctx.config.allow_remote_shutdown {
+        return Err((StatusCode::FORBIDDEN, "remote shutdown is disabled".to_string()));
+    }
+    require_api_token(&ctx, &headers, "remote shutdown")?;
+    tracing::info!("Shutdown requested via API.");
+    ctx.shutdown.notify_one();
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Checks that the request carries the configured `api_token` as a bearer
+/// token. Without a configured token, `what` is refused outright.
+fn require_api_token(ctx: &AppContext, headers: &axum::http::HeaderMap, what: &str) -> Result<(), (StatusCode, String)> {
+    let Some(token) = &ctx.config.api_token else {
+        return Err((StatusCode::FORBIDDEN, format!("{} requires an api_token", what)));
+    };
+    let presented = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // blake3 hashes compare in constant time.
+    if presented.map(|p| blake3::hash(p.as_bytes())) != Some(blake3::hash(token.0.as_bytes())) {
+        return Err((StatusCode::UNAUTHORIZED, "missing or invalid bearer token".to_string()));
+    }
+    Ok(())
+}
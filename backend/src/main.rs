@@ -2,5 +2,20 @@ use anyhow::Result;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    backend::run().await
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("verify") => {
+            let code = tokio::task::spawn_blocking(move || backend::cli::verify(&args[1..])).await??;
+            std::process::exit(code);
+        }
+        Some("import-par2") => {
+            let code = tokio::task::spawn_blocking(move || backend::cli::import_par2(&args[1..])).await??;
+            std::process::exit(code);
+        }
+        Some("recover-from-shards") => {
+            let code = tokio::task::spawn_blocking(move || backend::cli::recover_from_shards(&args[1..])).await??;
+            std::process::exit(code);
+        }
+        _ => backend::run().await,
+    }
 }
@@ -0,0 +1,35 @@
+/// Computes the root of a binary Merkle tree whose leaves are the given
+/// hex-encoded shard checksums.
+///
+/// Leaves and inner nodes are hashed with distinct prefixes so a leaf can
+/// never be passed off as an inner node. An odd node at the end of a level
+/// is carried up unchanged.
+pub fn merkle_root(shard_checksums: &[String]) -> String {
+    let mut level: Vec<blake3::Hash> = shard_checksums
+        .iter()
+        .map(|checksum| hash_node(0, &[checksum.as_bytes()]))
+        .collect();
+    if level.is_empty() {
+        return hash_node(0, &[]).to_hex().to_string();
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => hash_node(1, &[left.as_bytes(), right.as_bytes()]),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0].to_hex().to_string()
+}
+
+fn hash_node(prefix: u8, parts: &[&[u8]]) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[prefix]);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
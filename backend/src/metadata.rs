@@ -1,22 +1,921 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use shared::{CheckRun, FaultTolerance, FileDetail, RecordStatus, ShardStoreStats};
+use crate::attrs::FileAttrs;
+use crate::encoder::{GaloisField, ParityAlgorithm};
+use crate::shard_store::ShardFooter;
+use crate::sparse::{self, Extent};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 
-// A placeholder for the metadata database.
 // Sled is a good choice for a simple, embedded key-value store.
 pub type MetadataDb = sled::Db;
 
+const FILES_TREE: &str = "files";
+const HISTORY_TREE: &str = "history";
+const SKIPPED_TREE: &str = "skipped";
+const CHECKS_TREE: &str = "checks";
+const REPAIR_ATTEMPTS_TREE: &str = "repair_attempts";
+const QUARANTINE_TREE: &str = "quarantine";
+const INODES_TREE: &str = "inodes";
+const CASE_FOLDED_TREE: &str = "case_folded";
+const TRACKED_TREE: &str = "tracked";
+const VERSIONS_TREE: &str = "versions";
+const PENDING_TREE: &str = "pending_ops";
+const HASHES_TREE: &str = "file_hashes";
+
+/// Name of the algorithm of the hashes rs_guard stores, as recorded in [`FileHash`].
+pub const BLAKE3: &str = "blake3";
+const EXPORT_VERSION: u32 = 1;
+
+/// Everything needed to check and rebuild a protected file from its shards.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileRecord {
+    /// Identifier used to name this file's shards in the shard store.
+    pub file_id: String,
+    /// Length of the original content in bytes.
+    pub size: u64,
+    /// Modification time of the source when it was encoded (seconds since the Unix epoch).
+    pub mtime: u64,
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    /// Length of every shard; the last data shard is zero padded.
+    pub shard_size: usize,
+    /// BLAKE3 digest of the original content, hex encoded.
+    pub checksum: String,
+    /// BLAKE3 digest of each shard, in shard order.
+    pub shard_checksums: Vec<String>,
+    /// When the file was last encoded (seconds since the Unix epoch).
+    pub protected_at: u64,
+    /// Set once the source has been deleted. The record and shards are kept
+    /// so the file can still be recovered.
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
+    /// Root of a Merkle tree over `shard_checksums`, when enabled at encode time.
+    #[serde(default)]
+    pub merkle_root: Option<String>,
+    /// Operator-assigned labels for grouping files.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Operator-assigned importance; higher priority files are checked first.
+    #[serde(default)]
+    pub priority: u8,
+    /// How the parity shards were computed; needed to rebuild lost shards.
+    #[serde(default)]
+    pub parity_algorithm: ParityAlgorithm,
+    /// Field of the Reed-Solomon parity; GF(2^8) for records written before
+    /// wider fields existed.
+    #[serde(default)]
+    pub field: GaloisField,
+    /// Time taken to read, encode and write the file (milliseconds).
+    #[serde(default)]
+    pub encode_duration_ms: u64,
+    /// Total bytes written to the shard store for this file.
+    #[serde(default)]
+    pub shard_bytes: u64,
+    /// When a check last found the file healthy (seconds since the Unix epoch).
+    #[serde(default)]
+    pub last_verified: Option<u64>,
+    /// Data extents of a sparse source. Only these were encoded; the rest
+    /// of the `size` bytes are holes.
+    #[serde(default)]
+    pub data_extents: Option<Vec<Extent>>,
+    /// Mode, owner and xattrs of the source, when `preserve_attrs` is enabled.
+    #[serde(default)]
+    pub attrs: Option<FileAttrs>,
+    /// Stripes appended since the first encode under `append_optimize`,
+    /// or every stripe after the first of a streamed encode. The fields
+    /// above describe the shards of the first `size` minus the appended
+    /// bytes, except `size` and `checksum`, which cover it all.
+    #[serde(default)]
+    pub appended: Vec<AppendedStripe>,
+    /// Checksum of the content before the first appended stripe.
+    #[serde(default)]
+    pub base_checksum: Option<String>,
+    /// Bytes per stripe when the file was encoded by streaming it in
+    /// stripes, which `appended` lists after the first. Stripes appended
+    /// later under `append_optimize` have their own lengths.
+    #[serde(default)]
+    pub stripe_size: Option<u64>,
+}
+
+/// Bytes appended to a protected file, encoded on their own so the shards
+/// of the content before them could be kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AppendedStripe {
+    /// Identifier used to name this stripe's shards.
+    pub file_id: String,
+    pub len: u64,
+    pub shard_size: usize,
+    /// BLAKE3 digest of the appended bytes, hex encoded.
+    pub checksum: String,
+    pub shard_checksums: Vec<String>,
+}
+
+/// Digest of a protected file's content, stored when it is encoded so
+/// checks can tell an intact source without reading its shards.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileHash {
+    /// Which algorithm computed `digest`, so digests of another algorithm
+    /// are never compared with it.
+    pub algorithm: String,
+    /// Hex encoded.
+    pub digest: String,
+}
+
+impl FileHash {
+    pub fn blake3(digest: impl Into<String>) -> Self {
+        Self { algorithm: BLAKE3.to_string(), digest: digest.into() }
+    }
+}
+
+/// A file that could not be encoded and is left for a later scan to retry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SkippedFile {
+    pub reason: String,
+    /// Seconds since the Unix epoch.
+    pub skipped_at: u64,
+}
+
+/// A file found by a quick startup scan and waiting to be encoded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrackedFile {
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+/// A file taken out of automatic repair until an operator resets it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuarantineEntry {
+    pub reason: String,
+    /// Seconds since the Unix epoch.
+    pub quarantined_at: u64,
+}
+
+/// Work on a file that has started but not finished. Left behind by a crash,
+/// it is resumed or rolled back on the next start.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PendingOperation {
+    /// Shards are being staged and committed for a new encode, in
+    /// `stripes` stripes of `shards` shards each when streamed.
+    Encode {
+        file_id: String,
+        shards: usize,
+        #[serde(default = "single_stripe")]
+        stripes: usize,
+    },
+    /// A single shard is being rewritten.
+    Repair { shard_index: usize },
+    /// A replica received from a peer is being staged and committed, in
+    /// `stripes` stripes of `shards` shards each.
+    Replica {
+        file_id: String,
+        shards: usize,
+        #[serde(default = "single_stripe")]
+        stripes: usize,
+    },
+}
+
+fn single_stripe() -> usize {
+    1
+}
+
+/// What happened to a file, as recorded in the change history.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+impl ChangeKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            ChangeKind::Added => 0,
+            ChangeKind::Modified => 1,
+            ChangeKind::Removed => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ChangeKind::Added),
+            1 => Some(ChangeKind::Modified),
+            2 => Some(ChangeKind::Removed),
+            _ => None,
+        }
+    }
+}
+
+/// One entry of the change history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Milliseconds since the Unix epoch.
+    pub at_ms: u64,
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+/// A portable dump of the metadata DB, written by [`export_json`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetadataExport {
+    pub version: u32,
+    pub exported_at: u64,
+    pub files: Vec<ExportedFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedFile {
+    pub path: String,
+    /// Exact path bytes, only present when `path` could not represent them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_path: Option<Vec<u8>>,
+    pub record: FileRecord,
+}
+
+impl FileRecord {
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    /// The self-description written at the end of shard `index` when
+    /// `shard_footers` is enabled.
+    pub fn shard_footer(&self, path: &Path, index: usize) -> ShardFooter {
+        ShardFooter {
+            file_id: self.file_id.clone(),
+            path: display_path(path),
+            index,
+            data_shards: self.data_shards,
+            parity_shards: self.parity_shards,
+            parity_algorithm: self.parity_algorithm,
+            field: self.field,
+            encoded_len: self.encoded_len(),
+            size: self.size,
+            data_extents: self.data_extents.clone(),
+            checksum: self.checksum.clone(),
+            shard_checksum: self.shard_checksums[index].clone(),
+            stripe: 0,
+            stripes: 1,
+        }
+    }
+
+    /// The independently encoded stripes of the file, each as a record of
+    /// its own: the first encode, then every appended stripe in order. A
+    /// record without appended stripes is its only stripe.
+    pub fn stripes(&self) -> Vec<FileRecord> {
+        if self.appended.is_empty() {
+            return vec![self.clone()];
+        }
+        let appended_len: u64 = self.appended.iter().map(|stripe| stripe.len).sum();
+        let base = FileRecord {
+            size: self.size - appended_len,
+            checksum: self.base_checksum.clone().unwrap_or_default(),
+            appended: Vec::new(),
+            base_checksum: None,
+            ..self.clone()
+        };
+        let mut stripes = vec![base];
+        for stripe in &self.appended {
+            stripes.push(FileRecord {
+                file_id: stripe.file_id.clone(),
+                size: stripe.len,
+                shard_size: stripe.shard_size,
+                checksum: stripe.checksum.clone(),
+                shard_checksums: stripe.shard_checksums.clone(),
+                merkle_root: None,
+                appended: Vec::new(),
+                base_checksum: None,
+                ..self.clone()
+            });
+        }
+        stripes
+    }
+
+    /// Number of bytes that were encoded: the data extents of a sparse file,
+    /// otherwise the whole content.
+    pub fn encoded_len(&self) -> usize {
+        match &self.data_extents {
+            Some(extents) => sparse::packed_len(extents),
+            None => self.size as usize,
+        }
+    }
+
+    /// Converts the record into its API representation.
+    pub fn to_detail(&self, path: &Path) -> FileDetail {
+        FileDetail {
+            path: display_path(path),
+            size: self.size,
+            mtime: self.mtime,
+            data_shards: self.data_shards,
+            parity_shards: self.parity_shards,
+            checksum: self.checksum.clone(),
+            shard_checksums: self.shard_checksums.clone(),
+            merkle_root: self.merkle_root.clone(),
+            tags: self.tags.clone(),
+            priority: self.priority,
+            encode_duration_ms: self.encode_duration_ms,
+            shard_bytes: self.shard_bytes,
+            protected_at: self.protected_at,
+            last_verified: self.last_verified,
+            deleted_at: self.deleted_at,
+            misplaced_shards: Vec::new(),
+            fault_tolerance: FaultTolerance::new(self.data_shards, self.parity_shards),
+        }
+    }
+}
+
 pub fn open_db(path: &str) -> Result<MetadataDb> {
     let db = sled::open(path)?;
     Ok(db)
 }
 
-pub fn store_file_metadata(/* db: &MetadataDb, ... */) -> Result<()> {
-    // TODO: Implement logic to store mapping from original file path/chunk
-    // to the set of shard paths that belong to it.
-    // Key: "file_path/chunk_index"
-    // Value: [shard_1_id, shard_2_id, ...]
+/// Encodes a path as a database key.
+///
+/// On Unix the raw bytes are used so that paths which are not valid UTF-8
+/// survive the round trip unchanged.
+pub fn path_key(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// Inverse of [`path_key`].
+pub fn path_from_key(key: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(key))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(key).into_owned())
+    }
+}
+
+/// Renders a path for the API and logs. Non-UTF8 bytes are replaced, so this
+/// must never be used as a lookup key.
+pub fn display_path(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+pub fn store_file_metadata(db: &MetadataDb, path: &Path, record: &FileRecord) -> Result<()> {
+    let tree = db.open_tree(FILES_TREE)?;
+    tree.insert(path_key(path), serde_json::to_vec(record)?)?;
+    Ok(())
+}
+
+pub fn get_file_metadata(db: &MetadataDb, path: &Path) -> Result<Option<FileRecord>> {
+    let tree = db.open_tree(FILES_TREE)?;
+    match tree.get(path_key(path))? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Replaces the tags and priority of a protected file. Returns the updated
+/// record, or `None` if the file is not protected.
+pub fn set_file_meta(db: &MetadataDb, path: &Path, tags: Vec<String>, priority: u8) -> Result<Option<FileRecord>> {
+    let Some(mut record) = get_file_metadata(db, path)? else {
+        return Ok(None);
+    };
+    record.tags = tags;
+    record.priority = priority;
+    store_file_metadata(db, path, &record)?;
+    Ok(Some(record))
+}
+
+pub fn remove_file_metadata(db: &MetadataDb, path: &Path) -> Result<Option<FileRecord>> {
+    let tree = db.open_tree(FILES_TREE)?;
+    db.open_tree(HASHES_TREE)?.remove(path_key(path))?;
+    match tree.remove(path_key(path))? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn put_file_hash(db: &MetadataDb, path: &Path, hash: &FileHash) -> Result<()> {
+    db.open_tree(HASHES_TREE)?.insert(path_key(path), serde_json::to_vec(hash)?)?;
+    Ok(())
+}
+
+/// The content hash stored when `path` was last encoded. `None` for files
+/// protected before hashes were stored.
+pub fn get_file_hash(db: &MetadataDb, path: &Path) -> Result<Option<FileHash>> {
+    match db.open_tree(HASHES_TREE)?.get(path_key(path))? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Remembers why a file was not encoded, replacing any earlier reason.
+pub fn record_skipped(db: &MetadataDb, path: &Path, reason: &str) -> Result<()> {
+    let tree = db.open_tree(SKIPPED_TREE)?;
+    let entry = SkippedFile { reason: reason.to_string(), skipped_at: now_ms() / 1000 };
+    tree.insert(path_key(path), serde_json::to_vec(&entry)?)?;
+    Ok(())
+}
+
+pub fn clear_skipped(db: &MetadataDb, path: &Path) -> Result<()> {
+    db.open_tree(SKIPPED_TREE)?.remove(path_key(path))?;
+    Ok(())
+}
+
+/// Returns every skipped file, ordered by path bytes.
+pub fn list_skipped(db: &MetadataDb) -> Result<Vec<(PathBuf, SkippedFile)>> {
+    let tree = db.open_tree(SKIPPED_TREE)?;
+    let mut skipped = Vec::new();
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        skipped.push((path_from_key(&key), serde_json::from_slice(&value)?));
+    }
+    Ok(skipped)
+}
+
+pub fn track_file(db: &MetadataDb, path: &Path, tracked: &TrackedFile) -> Result<()> {
+    db.open_tree(TRACKED_TREE)?.insert(path_key(path), serde_json::to_vec(tracked)?)?;
+    Ok(())
+}
+
+pub fn untrack_file(db: &MetadataDb, path: &Path) -> Result<()> {
+    db.open_tree(TRACKED_TREE)?.remove(path_key(path))?;
+    Ok(())
+}
+
+/// Files tracked by a quick scan that have not been encoded yet.
+pub fn list_tracked(db: &MetadataDb) -> Result<Vec<(PathBuf, TrackedFile)>> {
+    let tree = db.open_tree(TRACKED_TREE)?;
+    let mut tracked = Vec::new();
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        tracked.push((path_from_key(&key), serde_json::from_slice(&value)?));
+    }
+    Ok(tracked)
+}
+
+/// Key of a retained version: the path key, a NUL that cannot occur in a
+/// path, then the big-endian version so versions sort in order.
+fn version_key(path: &Path, version: u32) -> Vec<u8> {
+    let mut key = version_prefix(path);
+    key.extend_from_slice(&version.to_be_bytes());
+    key
+}
+
+fn version_prefix(path: &Path) -> Vec<u8> {
+    let mut key = path_key(path);
+    key.push(0);
+    key
+}
+
+/// Keeps the record of an earlier version of a file, whose shards were
+/// saved under `record.file_id`.
+pub fn store_version(db: &MetadataDb, path: &Path, version: u32, record: &FileRecord) -> Result<()> {
+    db.open_tree(VERSIONS_TREE)?.insert(version_key(path, version), serde_json::to_vec(record)?)?;
+    Ok(())
+}
+
+pub fn get_version(db: &MetadataDb, path: &Path, version: u32) -> Result<Option<FileRecord>> {
+    match db.open_tree(VERSIONS_TREE)?.get(version_key(path, version))? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn remove_version(db: &MetadataDb, path: &Path, version: u32) -> Result<()> {
+    db.open_tree(VERSIONS_TREE)?.remove(version_key(path, version))?;
+    Ok(())
+}
+
+/// Retained earlier versions of a file, oldest first.
+pub fn list_versions(db: &MetadataDb, path: &Path) -> Result<Vec<(u32, FileRecord)>> {
+    let prefix = version_prefix(path);
+    let mut versions = Vec::new();
+    for entry in db.open_tree(VERSIONS_TREE)?.scan_prefix(&prefix) {
+        let (key, value) = entry?;
+        let Ok(version) = <[u8; 4]>::try_from(&key[prefix.len()..]) else {
+            continue;
+        };
+        versions.push((u32::from_be_bytes(version), serde_json::from_slice(&value)?));
+    }
+    Ok(versions)
+}
+
+/// Number of repairs performed on a file since it was last reset.
+pub fn repair_attempts(db: &MetadataDb, path: &Path) -> Result<u32> {
+    let tree = db.open_tree(REPAIR_ATTEMPTS_TREE)?;
+    match tree.get(path_key(path))? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        None => Ok(0),
+    }
+}
+
+/// Counts one more repair of a file and returns the new total.
+pub fn record_repair_attempt(db: &MetadataDb, path: &Path) -> Result<u32> {
+    let attempts = repair_attempts(db, path)? + 1;
+    db.open_tree(REPAIR_ATTEMPTS_TREE)?.insert(path_key(path), serde_json::to_vec(&attempts)?)?;
+    Ok(attempts)
+}
+
+pub fn quarantine(db: &MetadataDb, path: &Path, reason: &str) -> Result<()> {
+    let tree = db.open_tree(QUARANTINE_TREE)?;
+    let entry = QuarantineEntry { reason: reason.to_string(), quarantined_at: now_ms() / 1000 };
+    tree.insert(path_key(path), serde_json::to_vec(&entry)?)?;
+    Ok(())
+}
+
+pub fn get_quarantined(db: &MetadataDb, path: &Path) -> Result<Option<QuarantineEntry>> {
+    let tree = db.open_tree(QUARANTINE_TREE)?;
+    match tree.get(path_key(path))? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns every quarantined file, ordered by path bytes.
+pub fn list_quarantined(db: &MetadataDb) -> Result<Vec<(PathBuf, QuarantineEntry)>> {
+    let tree = db.open_tree(QUARANTINE_TREE)?;
+    let mut quarantined = Vec::new();
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        quarantined.push((path_from_key(&key), serde_json::from_slice(&value)?));
+    }
+    Ok(quarantined)
+}
+
+/// Releases a file from quarantine and forgets its repair attempts.
+/// Returns whether the file was quarantined.
+pub fn reset_quarantine(db: &MetadataDb, path: &Path) -> Result<bool> {
+    db.open_tree(REPAIR_ATTEMPTS_TREE)?.remove(path_key(path))?;
+    Ok(db.open_tree(QUARANTINE_TREE)?.remove(path_key(path))?.is_some())
+}
+
+/// Marks an operation on `path` as in progress, replacing any earlier marker.
+pub fn begin_operation(db: &MetadataDb, path: &Path, op: &PendingOperation) -> Result<()> {
+    db.open_tree(PENDING_TREE)?.insert(path_key(path), serde_json::to_vec(op)?)?;
+    Ok(())
+}
+
+pub fn finish_operation(db: &MetadataDb, path: &Path) -> Result<()> {
+    db.open_tree(PENDING_TREE)?.remove(path_key(path))?;
+    Ok(())
+}
+
+/// Returns every operation that was started but not finished.
+pub fn list_operations(db: &MetadataDb) -> Result<Vec<(PathBuf, PendingOperation)>> {
+    let tree = db.open_tree(PENDING_TREE)?;
+    let mut ops = Vec::new();
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        ops.push((path_from_key(&key), serde_json::from_slice(&value)?));
+    }
+    Ok(ops)
+}
+
+/// Allocates an id for a new check run. Ids increase monotonically.
+pub fn next_check_id(db: &MetadataDb) -> Result<u64> {
+    Ok(db.generate_id()?)
+}
+
+pub fn store_check_run(db: &MetadataDb, run: &CheckRun) -> Result<()> {
+    let tree = db.open_tree(CHECKS_TREE)?;
+    tree.insert(run.id.to_be_bytes(), serde_json::to_vec(run)?)?;
+    Ok(())
+}
+
+pub fn get_check_run(db: &MetadataDb, id: u64) -> Result<Option<CheckRun>> {
+    let tree = db.open_tree(CHECKS_TREE)?;
+    match tree.get(id.to_be_bytes())? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the most recent check run, if any.
+pub fn last_check_run(db: &MetadataDb) -> Result<Option<CheckRun>> {
+    let tree = db.open_tree(CHECKS_TREE)?;
+    match tree.last()? {
+        Some((_, value)) => Ok(Some(serde_json::from_slice(&value)?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns every recorded check run, newest first.
+pub fn list_check_runs(db: &MetadataDb) -> Result<Vec<CheckRun>> {
+    let tree = db.open_tree(CHECKS_TREE)?;
+    let mut runs = Vec::new();
+    for entry in tree.iter().rev() {
+        let (_, value) = entry?;
+        runs.push(serde_json::from_slice(&value)?);
+    }
+    Ok(runs)
+}
+
+/// Marks a protected file as deleted and records the removal in the history.
+/// Returns false if the file is unknown or already marked.
+pub fn mark_deleted(db: &MetadataDb, path: &Path) -> Result<bool> {
+    let Some(mut record) = get_file_metadata(db, path)? else {
+        return Ok(false);
+    };
+    if record.deleted_at.is_some() {
+        return Ok(false);
+    }
+    record.deleted_at = Some(now_ms() / 1000);
+    store_file_metadata(db, path, &record)?;
+    record_change(db, path, ChangeKind::Removed)?;
+    Ok(true)
+}
+
+/// Appends an entry to the change history.
+///
+/// Keys are the timestamp followed by a unique id, so iteration order is
+/// chronological; values are the kind byte followed by the raw path.
+pub fn record_change(db: &MetadataDb, path: &Path, kind: ChangeKind) -> Result<()> {
+    let tree = db.open_tree(HISTORY_TREE)?;
+    let mut key = now_ms().to_be_bytes().to_vec();
+    key.extend_from_slice(&db.generate_id()?.to_be_bytes());
+    let mut value = vec![kind.to_byte()];
+    value.extend_from_slice(&path_key(path));
+    tree.insert(key, value)?;
+    Ok(())
+}
+
+/// Returns history entries recorded strictly after `since_ms`, oldest first.
+pub fn changes_since(db: &MetadataDb, since_ms: u64) -> Result<Vec<Change>> {
+    let tree = db.open_tree(HISTORY_TREE)?;
+    let mut changes = Vec::new();
+    let start = since_ms.saturating_add(1).to_be_bytes().to_vec();
+    for entry in tree.range(start..) {
+        let (key, value) = entry?;
+        let (Some(&kind), Some(at)) = (value.first(), key.get(..8)) else {
+            continue;
+        };
+        let Some(kind) = ChangeKind::from_byte(kind) else {
+            continue;
+        };
+        changes.push(Change {
+            at_ms: u64::from_be_bytes(at.try_into()?),
+            path: path_from_key(&value[1..]),
+            kind,
+        });
+    }
+    Ok(changes)
+}
+
+/// Files grouped by how they differ from their state before a point in time.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ClassifiedChanges {
+    pub added: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+/// Collapses a chronological slice of history into one classification per
+/// path by comparing whether the file existed before the first change and
+/// after the last one. Files created and removed within the window are omitted.
+pub fn classify_changes(changes: &[Change]) -> ClassifiedChanges {
+    let mut per_path: BTreeMap<&Path, (ChangeKind, ChangeKind)> = BTreeMap::new();
+    for change in changes {
+        per_path
+            .entry(&change.path)
+            .and_modify(|(_, last)| *last = change.kind)
+            .or_insert((change.kind, change.kind));
+    }
+    let mut classified = ClassifiedChanges::default();
+    for (path, (first, last)) in per_path {
+        let existed_before = first != ChangeKind::Added;
+        let exists_now = last != ChangeKind::Removed;
+        match (existed_before, exists_now) {
+            (false, true) => classified.added.push(path.to_path_buf()),
+            (true, true) => classified.modified.push(path.to_path_buf()),
+            (true, false) => classified.removed.push(path.to_path_buf()),
+            (false, false) => {}
+        }
+    }
+    classified
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Returns every protected file together with its record, ordered by path bytes.
+pub fn list_files(db: &MetadataDb) -> Result<Vec<(PathBuf, FileRecord)>> {
+    let tree = db.open_tree(FILES_TREE)?;
+    let mut files = Vec::new();
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        files.push((path_from_key(&key), serde_json::from_slice(&value)?));
+    }
+    Ok(files)
+}
+
+/// Filters for [`query_files_batch`]. Unset fields match every record.
+#[derive(Debug, Default, Clone)]
+pub struct FileQuery {
+    /// Matches paths whose bytes start with this prefix.
+    pub path_prefix: Option<PathBuf>,
+    pub status: Option<RecordStatus>,
+    /// Inclusive bounds on the file size in bytes.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Inclusive bounds on `protected_at` (seconds since the Unix epoch).
+    pub protected_after: Option<u64>,
+    pub protected_before: Option<u64>,
+}
+
+/// A record returned by [`query_files_batch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMatch {
+    pub path: PathBuf,
+    pub record: FileRecord,
+    pub status: RecordStatus,
+}
+
+/// Deleted takes precedence, as a deleted file is not repaired either way.
+pub fn record_status(db: &MetadataDb, path: &Path, record: &FileRecord) -> Result<RecordStatus> {
+    Ok(if record.deleted_at.is_some() {
+        RecordStatus::Deleted
+    } else if get_quarantined(db, path)?.is_some() {
+        RecordStatus::Quarantined
+    } else {
+        RecordStatus::Protected
+    })
+}
+
+/// Examines at most `batch_size` records after the key `after` (from the
+/// start if `None`) and returns those matching `query`, along with the key
+/// to resume from if records remain. Listings walk the tree this way so
+/// they can step aside between batches.
+pub fn query_files_batch(
+    db: &MetadataDb,
+    query: &FileQuery,
+    after: Option<&[u8]>,
+    batch_size: usize,
+) -> Result<(Vec<FileMatch>, Option<Vec<u8>>)> {
+    let tree = db.open_tree(FILES_TREE)?;
+    let prefix = query.path_prefix.as_deref().map(path_key).unwrap_or_default();
+    let entries = match after {
+        Some(after) => tree.range::<&[u8], _>((Bound::Excluded(after), Bound::Unbounded)),
+        None => tree.range::<&[u8], _>(prefix.as_slice()..),
+    };
+    let mut matches = Vec::new();
+    let mut examined = 0;
+    let mut after_batch = None;
+    for entry in entries {
+        let (key, value) = entry?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        examined += 1;
+        if examined > batch_size {
+            return Ok((matches, after_batch));
+        }
+        after_batch = Some(key.to_vec());
+        let record: FileRecord = serde_json::from_slice(&value)?;
+        let in_range = |value: u64, min: Option<u64>, max: Option<u64>| {
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        };
+        if !in_range(record.size, query.min_size, query.max_size)
+            || !in_range(record.protected_at, query.protected_after, query.protected_before)
+        {
+            continue;
+        }
+        let path = path_from_key(&key);
+        let status = record_status(db, &path, &record)?;
+        if query.status.is_some_and(|wanted| wanted != status) {
+            continue;
+        }
+        matches.push(FileMatch { path, record, status });
+    }
+    Ok((matches, None))
+}
+
+fn inode_key(dev: u64, ino: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&dev.to_be_bytes());
+    key[8..].copy_from_slice(&ino.to_be_bytes());
+    key
+}
+
+/// The path under which a hardlinked inode is protected, if one was chosen.
+pub fn inode_owner(db: &MetadataDb, dev: u64, ino: u64) -> Result<Option<PathBuf>> {
+    let tree = db.open_tree(INODES_TREE)?;
+    Ok(tree.get(inode_key(dev, ino))?.map(|key| path_from_key(&key)))
+}
+
+pub fn set_inode_owner(db: &MetadataDb, dev: u64, ino: u64, path: &Path) -> Result<()> {
+    db.open_tree(INODES_TREE)?.insert(inode_key(dev, ino), path_key(path))?;
+    Ok(())
+}
+
+/// Moves a record to another path, e.g. when a file was renamed.
+pub fn rename_file_metadata(db: &MetadataDb, from: &Path, to: &Path) -> Result<Option<FileRecord>> {
+    let hash = get_file_hash(db, from)?;
+    let Some(record) = remove_file_metadata(db, from)? else {
+        return Ok(None);
+    };
+    store_file_metadata(db, to, &record)?;
+    if let Some(hash) = hash {
+        put_file_hash(db, to, &hash)?;
+    }
+    Ok(Some(record))
+}
+
+fn case_folded_key(path: &Path) -> Vec<u8> {
+    display_path(path).to_lowercase().into_bytes()
+}
+
+/// The path last protected among those equal to `path` ignoring case.
+pub fn case_owner(db: &MetadataDb, path: &Path) -> Result<Option<PathBuf>> {
+    let tree = db.open_tree(CASE_FOLDED_TREE)?;
+    Ok(tree.get(case_folded_key(path))?.map(|key| path_from_key(&key)))
+}
+
+pub fn set_case_owner(db: &MetadataDb, path: &Path) -> Result<()> {
+    db.open_tree(CASE_FOLDED_TREE)?.insert(case_folded_key(path), path_key(path))?;
     Ok(())
 }
 
-pub fn get_file_metadata(/* ... */) {
-    // TODO: Implement lookup logic.
-} 
\ No newline at end of file
+pub fn count_files(db: &MetadataDb) -> Result<u64> {
+    Ok(db.open_tree(FILES_TREE)?.len() as u64)
+}
+
+/// On-disk sizes of the database around a [`compact`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl Compaction {
+    pub fn reclaimed(&self) -> u64 {
+        self.size_before.saturating_sub(self.size_after)
+    }
+}
+
+/// Flushes the database so that sled rewrites and frees the log segments
+/// that deletes and overwrites left mostly empty.
+pub fn compact(db: &MetadataDb) -> Result<Compaction> {
+    let size_before = db.size_on_disk()?;
+    db.flush()?;
+    Ok(Compaction { size_before, size_after: db.size_on_disk()? })
+}
+
+/// Totals over every record, for `/api/shard-store/stats`.
+pub fn shard_store_stats(db: &MetadataDb) -> Result<ShardStoreStats> {
+    let mut stats = ShardStoreStats::default();
+    for (_, record) in list_files(db)? {
+        stats.files += 1;
+        stats.shard_bytes += record.shard_bytes;
+        stats.encoded_bytes += record.size;
+        stats.encode_duration_ms += record.encode_duration_ms;
+    }
+    stats.average_encode_bytes_per_sec =
+        (stats.encode_duration_ms > 0).then(|| stats.encoded_bytes as f64 * 1000.0 / stats.encode_duration_ms as f64);
+    Ok(stats)
+}
+
+/// Serializes every file record into a JSON snapshot.
+pub fn export_json(db: &MetadataDb) -> Result<String> {
+    let files = list_files(db)?
+        .into_iter()
+        .map(|(path, record)| ExportedFile {
+            raw_path: path.to_str().is_none().then(|| path_key(&path)),
+            path: display_path(&path),
+            record,
+        })
+        .collect();
+    let export = MetadataExport {
+        version: EXPORT_VERSION,
+        exported_at: now_ms() / 1000,
+        files,
+    };
+    Ok(serde_json::to_string_pretty(&export)?)
+}
+
+/// Loads a snapshot produced by [`export_json`], overwriting records with the
+/// same path. Returns the number of records imported.
+pub fn import_json(db: &MetadataDb, json: &str) -> Result<usize> {
+    let export: MetadataExport = serde_json::from_str(json)?;
+    if export.version > EXPORT_VERSION {
+        anyhow::bail!("unsupported metadata export version {}", export.version);
+    }
+    for file in &export.files {
+        let path = match &file.raw_path {
+            Some(raw) => path_from_key(raw),
+            None => PathBuf::from(&file.path),
+        };
+        store_file_metadata(db, &path, &file.record)?;
+    }
+    Ok(export.files.len())
+}
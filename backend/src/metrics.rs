@@ -0,0 +1,144 @@
+use anyhow::Result;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::{errors, metadata, AppContext};
+
+/// Content type of the OpenMetrics text format served by `/api/metrics`.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Content type of the Prometheus text format served by `/api/metrics` to
+/// clients that do not ask for OpenMetrics.
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Which exposition format [`render`] writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    OpenMetrics,
+    /// The Prometheus text format 0.0.4, which has no exemplars or info
+    /// metrics; those are left out or written as gauges.
+    Prometheus,
+}
+
+impl Format {
+    /// OpenMetrics if the `Accept` header asks for it, as Prometheus does
+    /// when it can make use of exemplars.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if accept.contains("application/openmetrics-text") => Format::OpenMetrics,
+            _ => Format::Prometheus,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::OpenMetrics => CONTENT_TYPE,
+            Format::Prometheus => PROMETHEUS_CONTENT_TYPE,
+        }
+    }
+}
+
+/// Work done since startup that is only exposed as metrics.
+#[derive(Default)]
+pub struct Counters {
+    repairs: AtomicU64,
+    encode_bytes: AtomicU64,
+}
+
+impl Counters {
+    /// A shard was rebuilt or a source restored from its shards.
+    pub fn repaired(&self) {
+        self.repairs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `bytes` of file content were read and encoded into shards.
+    pub fn encoded(&self, bytes: u64) {
+        self.encode_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Renders the service's metrics in `format`.
+///
+/// The most recent corruption is exposed twice: as an exemplar on the
+/// detection counter, and as an info metric with a matching timestamp gauge
+/// for tools that ignore exemplars. Both carry the first newly corrupted
+/// path, so a Grafana panel can jump from the spike to the file and time.
+pub fn render(ctx: &AppContext, format: Format) -> Result<String> {
+    let runs = metadata::list_check_runs(&ctx.db)?;
+    let detected: usize = runs.iter().map(|run| run.newly_corrupted.len()).sum();
+    // Runs are listed newest first.
+    let last = runs.iter().find_map(|run| {
+        let path = run.newly_corrupted.first()?;
+        let at = chrono::DateTime::parse_from_rfc3339(&run.finished).ok()?;
+        Some((run.id, path, at.timestamp_millis() as f64 / 1000.0))
+    });
+    let last_check_duration = runs.first().and_then(|run| {
+        let started = chrono::DateTime::parse_from_rfc3339(&run.started).ok()?;
+        let finished = chrono::DateTime::parse_from_rfc3339(&run.finished).ok()?;
+        Some((finished - started).num_milliseconds() as f64 / 1000.0)
+    });
+    let total_files = ctx.status.lock().unwrap().total_files;
+
+    let mut out = String::new();
+    writeln!(out, "# TYPE rs_guard_total_files gauge")?;
+    writeln!(out, "# HELP rs_guard_total_files Files under the watched directories seen by the last scan.")?;
+    writeln!(out, "rs_guard_total_files {}", total_files)?;
+    writeln!(out, "# TYPE rs_guard_protected_files gauge")?;
+    writeln!(out, "rs_guard_protected_files {}", metadata::count_files(&ctx.db)?)?;
+    writeln!(out, "# TYPE rs_guard_corrupted_files gauge")?;
+    writeln!(out, "# HELP rs_guard_corrupted_files Files found corrupted by the latest check.")?;
+    writeln!(out, "rs_guard_corrupted_files {}", runs.first().map_or(0, |run| run.corrupted))?;
+    if let Some(duration) = last_check_duration {
+        writeln!(out, "# TYPE rs_guard_last_check_duration_seconds gauge")?;
+        writeln!(out, "rs_guard_last_check_duration_seconds {:.3}", duration)?;
+    }
+    counter_header(&mut out, format, "rs_guard_corruptions_detected", "Files that turned corrupted between checks.")?;
+    write!(out, "rs_guard_corruptions_detected_total {}", detected)?;
+    if let (Format::OpenMetrics, Some((_, path, at))) = (format, last) {
+        write!(out, " # {{path=\"{}\"}} 1 {:.3}", escape(path), at)?;
+    }
+    writeln!(out)?;
+    if let Some((check_id, path, at)) = last {
+        match format {
+            Format::OpenMetrics => writeln!(out, "# TYPE rs_guard_last_corruption info")?,
+            Format::Prometheus => writeln!(out, "# TYPE rs_guard_last_corruption_info gauge")?,
+        }
+        writeln!(out, "rs_guard_last_corruption_info{{path=\"{}\",check_id=\"{}\"}} 1", escape(path), check_id)?;
+        writeln!(out, "# TYPE rs_guard_last_corruption_timestamp_seconds gauge")?;
+        writeln!(out, "rs_guard_last_corruption_timestamp_seconds {:.3}", at)?;
+    }
+    counter_header(&mut out, format, "rs_guard_repairs", "Shards rebuilt and sources restored since startup.")?;
+    writeln!(out, "rs_guard_repairs_total {}", ctx.metrics.repairs.load(Ordering::Relaxed))?;
+    counter_header(&mut out, format, "rs_guard_encode_bytes", "Bytes of file content encoded since startup.")?;
+    writeln!(out, "rs_guard_encode_bytes_total {}", ctx.metrics.encode_bytes.load(Ordering::Relaxed))?;
+    let errors = errors::snapshot(ctx);
+    counter_header(&mut out, format, "rs_guard_errors", "Transient errors since startup or the last reset of /api/errors.")?;
+    for (kind, count) in [
+        ("shard_read", errors.shard_read_errors),
+        ("source_reread", errors.source_rereads),
+        ("replication_retry", errors.replication_retries),
+        ("notification", errors.notification_failures),
+    ] {
+        writeln!(out, "rs_guard_errors_total{{kind=\"{}\"}} {}", kind, count)?;
+    }
+    if format == Format::OpenMetrics {
+        writeln!(out, "# EOF")?;
+    }
+    Ok(out)
+}
+
+/// Writes the `TYPE` and `HELP` lines of the counter `name`, whose samples
+/// are `name_total`. OpenMetrics names the family without the suffix; the
+/// Prometheus format names it after the sample.
+fn counter_header(out: &mut String, format: Format, name: &str, help: &str) -> std::fmt::Result {
+    let family = match format {
+        Format::OpenMetrics => name.to_string(),
+        Format::Prometheus => format!("{}_total", name),
+    };
+    writeln!(out, "# TYPE {} counter", family)?;
+    writeln!(out, "# HELP {} {}", family, help)
+}
+
+/// Escapes a label value as the exposition format requires.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,125 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use crate::AppContext;
+
+/// An event posted as JSON to `notification_webhook`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    /// A check found files that became corrupted or healed since the previous one.
+    CorruptionChanged {
+        /// The latest check summarized.
+        check_id: u64,
+        newly_corrupted: Vec<String>,
+        newly_healed: Vec<String>,
+        /// How many notifications were coalesced into this one.
+        events: usize,
+    },
+}
+
+impl Notification {
+    /// Folds a later notification into this one. A path's latest transition
+    /// wins, so a file that corrupted and was then healed is only listed as
+    /// healed.
+    fn absorb(&mut self, later: Notification) {
+        match (self, later) {
+            (
+                Notification::CorruptionChanged { check_id, newly_corrupted, newly_healed, events },
+                Notification::CorruptionChanged {
+                    check_id: later_id,
+                    newly_corrupted: later_corrupted,
+                    newly_healed: later_healed,
+                    events: later_events,
+                },
+            ) => {
+                *check_id = later_id;
+                *events += later_events;
+                for path in later_corrupted {
+                    newly_healed.retain(|healed| *healed != path);
+                    if !newly_corrupted.contains(&path) {
+                        newly_corrupted.push(path);
+                    }
+                }
+                for path in later_healed {
+                    newly_corrupted.retain(|corrupted| *corrupted != path);
+                    if !newly_healed.contains(&path) {
+                        newly_healed.push(path);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Notifications waiting to be sent.
+#[derive(Default)]
+pub struct NotificationQueue {
+    pending: Mutex<VecDeque<Notification>>,
+    notify: Notify,
+}
+
+impl NotificationQueue {
+    /// Queues a notification if a `notification_webhook` is configured.
+    pub fn send(&self, ctx: &AppContext, notification: Notification) {
+        if ctx.config.notification_webhook.is_none() {
+            return;
+        }
+        self.pending.lock().unwrap().push_back(notification);
+        self.notify.notify_one();
+    }
+
+    async fn pop(&self) -> Notification {
+        loop {
+            if let Some(notification) = self.pending.lock().unwrap().pop_front() {
+                return notification;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn drain(&self) -> Vec<Notification> {
+        self.pending.lock().unwrap().drain(..).collect()
+    }
+}
+
+/// Spawns the task that posts queued notifications to `notification_webhook`.
+/// A failed post is logged and dropped; alerts are best effort.
+///
+/// With `notification_coalesce_secs` set, the first notification is held for
+/// that long and everything raised meanwhile is merged into it. As the next
+/// window only opens after the post, that also caps the rate to one
+/// notification per window.
+pub fn start(ctx: AppContext) {
+    let Some(url) = ctx.config.notification_webhook.clone() else {
+        return;
+    };
+    let window = Duration::from_secs(ctx.config.notification_coalesce_secs);
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        loop {
+            let mut notification = ctx.notifications.pop().await;
+            if !window.is_zero() {
+                tokio::time::sleep(window).await;
+                for later in ctx.notifications.drain() {
+                    notification.absorb(later);
+                }
+            }
+            if let Err(e) = post(&client, &url, &notification).await {
+                tracing::warn!("Failed to send notification to {}: {}", url, e);
+                ctx.errors.notification_failure();
+            }
+        }
+    });
+}
+
+async fn post(client: &reqwest::Client, url: &str, notification: &Notification) -> Result<()> {
+    let response = client.post(url).json(notification).send().await?;
+    if !response.status().is_success() {
+        bail!("webhook answered {}", response.status());
+    }
+    Ok(())
+}
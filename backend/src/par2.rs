@@ -0,0 +1,166 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use crate::{metadata, protector, AppContext};
+
+const PACKET_MAGIC: &[u8; 8] = b"PAR2\0PKT";
+const HEADER_LEN: usize = 64;
+const MAIN_PACKET: &[u8; 16] = b"PAR 2.0\0Main\0\0\0\0";
+const FILE_DESC_PACKET: &[u8; 16] = b"PAR 2.0\0FileDesc";
+
+/// Tag added to files imported from a PAR2 set, so they can be told apart.
+pub const IMPORT_TAG: &str = "par2";
+
+/// A source file as described by a PAR2 set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Par2File {
+    /// Path relative to the directory of the `.par2` file.
+    pub name: String,
+    pub length: u64,
+    /// MD5 of the whole file.
+    pub md5: [u8; 16],
+}
+
+/// The part of a PAR2 set rs_guard can use: which files it covers and what
+/// their intact content hashes to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Par2Set {
+    pub slice_size: u64,
+    /// Files in the recovery set, in the order of the main packet.
+    pub files: Vec<Par2File>,
+}
+
+/// Outcome of [`import`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Par2Import {
+    /// Files that matched their PAR2 checksum and are now protected.
+    pub imported: Vec<PathBuf>,
+    /// Files whose content no longer matches the PAR2 set. They are not
+    /// protected, as that would preserve the damage.
+    pub mismatched: Vec<PathBuf>,
+    pub missing: Vec<PathBuf>,
+}
+
+/// Reads the main and file description packets of a `.par2` file. Packets
+/// that fail their checksum are skipped, as PAR2 clients do; the file
+/// descriptions are repeated in every volume of a set, so any one will do.
+pub fn read_set(path: &Path) -> Result<Par2Set> {
+    let bytes = fs::read(path)?;
+    let mut main = None;
+    let mut descriptions: HashMap<[u8; 16], ([u8; 16], Par2File)> = HashMap::new();
+    let mut offset = 0;
+    while let Some(found) = find(&bytes[offset..], PACKET_MAGIC) {
+        let start = offset + found;
+        offset = start + PACKET_MAGIC.len();
+        let Some((set_id, kind, body)) = packet_at(&bytes, start) else {
+            continue;
+        };
+        offset = start + HEADER_LEN + body.len();
+        if kind == MAIN_PACKET {
+            main = parse_main(body).map(|main| (set_id, main)).or(main);
+        } else if kind == FILE_DESC_PACKET {
+            if let Some((file_id, file)) = parse_file_desc(body) {
+                descriptions.insert(file_id, (set_id, file));
+            }
+        }
+    }
+    let (set_id, (slice_size, file_ids)) = main.ok_or_else(|| anyhow!("no intact main packet found"))?;
+    let files = file_ids
+        .iter()
+        .map(|id| match descriptions.remove(id) {
+            Some((packet_set, file)) if packet_set == set_id => Ok(file),
+            _ => Err(anyhow!("no intact description of file {}", crate::signing::encode_hex(id))),
+        })
+        .collect::<Result<_>>()?;
+    Ok(Par2Set { slice_size, files })
+}
+
+/// Registers the files of the PAR2 set at `par2_path` as protected.
+///
+/// PAR2 recovery slices span the whole set in GF(2^16) and cannot be reused
+/// as rs_guard shards, so each file that still matches the MD5 recorded in
+/// the set is encoded with the configured shards instead. The PAR2 files are
+/// left untouched.
+pub fn import(ctx: &AppContext, par2_path: &Path) -> Result<Par2Import> {
+    let set = read_set(par2_path)?;
+    let base = par2_path.parent().unwrap_or(Path::new("."));
+    let mut outcome = Par2Import::default();
+    for file in &set.files {
+        let path = base.join(relative_path(&file.name)?);
+        if !path.is_file() {
+            outcome.missing.push(path);
+            continue;
+        }
+        if fs::metadata(&path)?.len() != file.length || md5_of(&path)? != file.md5 {
+            tracing::warn!("{} does not match its PAR2 set; not importing it", metadata::display_path(&path));
+            outcome.mismatched.push(path);
+            continue;
+        }
+        let record = protector::protect_file(ctx, &path)?;
+        if !record.tags.iter().any(|tag| tag == IMPORT_TAG) {
+            let mut tags = record.tags.clone();
+            tags.push(IMPORT_TAG.to_string());
+            metadata::set_file_meta(&ctx.db, &path, tags, record.priority)?;
+        }
+        outcome.imported.push(path);
+    }
+    Ok(outcome)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Returns the recovery set ID, type and body of an intact packet at `start`.
+fn packet_at(bytes: &[u8], start: usize) -> Option<([u8; 16], &[u8; 16], &[u8])> {
+    let header = bytes.get(start..start + HEADER_LEN)?;
+    let len = u64::from_le_bytes(header[8..16].try_into().ok()?);
+    let len = usize::try_from(len).ok().filter(|&len| len >= HEADER_LEN && len.is_multiple_of(4))?;
+    let packet = bytes.get(start..start.checked_add(len)?)?;
+    if md5::compute(&packet[32..]).0 != packet[16..32] {
+        return None;
+    }
+    Some((packet[32..48].try_into().ok()?, packet[48..64].try_into().ok()?, &packet[HEADER_LEN..]))
+}
+
+/// Slice size and the IDs of the files in the recovery set.
+fn parse_main(body: &[u8]) -> Option<(u64, Vec<[u8; 16]>)> {
+    let slice_size = u64::from_le_bytes(body.get(0..8)?.try_into().ok()?);
+    let count = u32::from_le_bytes(body.get(8..12)?.try_into().ok()?) as usize;
+    let ids = body.get(12..12 + count.checked_mul(16)?)?;
+    Some((slice_size, ids.chunks(16).map(|id| id.try_into().unwrap()).collect()))
+}
+
+fn parse_file_desc(body: &[u8]) -> Option<([u8; 16], Par2File)> {
+    let file_id = body.get(0..16)?.try_into().ok()?;
+    let md5 = body.get(16..32)?.try_into().ok()?;
+    let length = u64::from_le_bytes(body.get(48..56)?.try_into().ok()?);
+    let name = body.get(56..)?;
+    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+    Some((file_id, Par2File { name: String::from_utf8_lossy(name).into_owned(), length, md5 }))
+}
+
+/// Turns a name from a PAR2 set into a relative path, refusing names that
+/// would escape the set's directory.
+fn relative_path(name: &str) -> Result<PathBuf> {
+    let path: PathBuf = name.split(['/', '\\']).collect();
+    if path.components().any(|c| !matches!(c, Component::Normal(_))) {
+        bail!("refusing PAR2 file name `{}` outside the set's directory", name);
+    }
+    Ok(path)
+}
+
+fn md5_of(path: &Path) -> Result<[u8; 16]> {
+    let mut file = fs::File::open(path)?;
+    let mut context = md5::Context::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(context.compute().0);
+        }
+        context.consume(&buf[..read]);
+    }
+}
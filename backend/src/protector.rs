@@ -0,0 +1,992 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::field;
+use shared::{RefreshResult, ReprotectResult};
+use crate::config::{AppConfig, HardlinkMode};
+use crate::encoder::{GaloisField, ParityAlgorithm, RSEncoder, StripeWriter};
+use crate::shard_store::{ShardFooter, ShardKind};
+use crate::workers::Work;
+use crate::sparse::{self, Extent};
+use crate::{attrs, disk, ioprio, merkle};
+use crate::metadata::{self, AppendedStripe, ChangeKind, FileRecord, PendingOperation};
+use crate::AppContext;
+
+/// Encodes a file into data and parity shards and records it in the metadata DB.
+///
+/// New shards are staged and only replace the previous set once all of them
+/// are written. If `encode_timeout_secs` elapses first (checked between
+/// shard writes), the staged shards are discarded, the previous protection
+/// is left intact and the file is recorded as skipped for a later retry.
+/// Encodes that would dip into `shard_store_reserve_bytes` are refused up front.
+pub fn protect_file(ctx: &AppContext, path: &Path) -> Result<FileRecord> {
+    let config = &ctx.config;
+    let (data_shards, parity_shards) = config.shards_for(path);
+    let span = tracing::trace_span!(
+        "encode",
+        path = %metadata::display_path(path),
+        bytes = field::Empty,
+        data_shards,
+        parity_shards,
+        duration_ms = field::Empty,
+    );
+    let _entered = span.enter();
+    let queued = ctx.workers.queue(Work::Encode, 1);
+    let _lock = ctx.file_locks.lock(path);
+    let _active = queued.start();
+    let _io = ioprio::lower(config.io_priority);
+    let started = Instant::now();
+    let deadline = (config.encode_timeout_secs > 0)
+        .then(|| started + Duration::from_secs(config.encode_timeout_secs));
+    if config.skip_files_open_for_write && open_for_write(path) {
+        tracing::info!("Deferring {} until it is closed by its writer", metadata::display_path(path));
+        metadata::record_skipped(&ctx.db, path, &OpenForWrite.to_string())?;
+        return Err(OpenForWrite.into());
+    }
+    let size = fs::metadata(path)?.len();
+    if streams(ctx, path, size)? {
+        span.record("bytes", size);
+        let previous = metadata::get_file_metadata(&ctx.db, path)?;
+        let record = protect_streaming(ctx, path, previous, (data_shards, parity_shards), deadline)?;
+        span.record("duration_ms", record.encode_duration_ms);
+        return Ok(record);
+    }
+    let Source { data, extents: data_extents, meta } = read_source(ctx, path)?;
+    span.record("bytes", data.len());
+    let mtime = modified_secs(&meta);
+    let previous = metadata::get_file_metadata(&ctx.db, path)?;
+    if let Some(old) = &previous {
+        if appends_to(config, old, &data, data_extents.is_some(), (data_shards, parity_shards)) {
+            let record = protect_appended(ctx, path, old, &data, &meta, deadline)?;
+            span.record("duration_ms", record.encode_duration_ms);
+            return Ok(record);
+        }
+    }
+
+    let encoder = RSEncoder::with_algorithm(data_shards, parity_shards, config.parity_algorithm)?;
+    let shards = encoder.encode(&data)?;
+
+    let file_id = file_id_for(path);
+    let needed = (shards.len() * shards[0].len()) as u64;
+    if let Err(e) = check_free_space(ctx, needed) {
+        tracing::warn!("Not encoding {}: {}", metadata::display_path(path), e);
+        metadata::record_skipped(&ctx.db, path, &e.to_string())?;
+        return Err(e.into());
+    }
+
+    let shard_checksums: Vec<String> = shards.iter().map(|shard| checksum(shard)).collect();
+    let merkle_root = config.merkle_roots.then(|| merkle::merkle_root(&shard_checksums));
+    let mut record = FileRecord {
+        file_id: file_id.clone(),
+        size: if data_extents.is_some() { meta.len() } else { data.len() as u64 },
+        mtime,
+        data_shards,
+        parity_shards,
+        shard_size: shards[0].len(),
+        checksum: match &data_extents {
+            Some(extents) => sparse::hash_logical(&data, extents, meta.len()).to_hex().to_string(),
+            None => checksum(&data),
+        },
+        shard_checksums,
+        protected_at: now_secs(),
+        deleted_at: None,
+        merkle_root,
+        // Operator annotations survive re-encodes.
+        tags: previous.as_ref().map(|old| old.tags.clone()).unwrap_or_default(),
+        priority: previous.as_ref().map_or(0, |old| old.priority),
+        parity_algorithm: config.parity_algorithm,
+        field: encoder.field(),
+        encode_duration_ms: 0,
+        shard_bytes: needed,
+        last_verified: None,
+        data_extents,
+        attrs: if config.preserve_attrs { Some(attrs::read(path, &meta)?) } else { None },
+        appended: Vec::new(),
+        base_checksum: None,
+        stripe_size: None,
+    };
+
+    stage_encode(ctx, path, &record, (0, 1), &shards, deadline)?;
+    // A previous encode may have used a wider shard configuration.
+    if let Some(old) = &previous {
+        if config.keep_versions > 0 && old.checksum != record.checksum {
+            retain_version(ctx, path, old)?;
+        }
+        remove_all_shards(ctx, old)?;
+    }
+    ctx.store.commit_staged(&file_id, shards.len())?;
+    record.encode_duration_ms = started.elapsed().as_millis() as u64;
+    span.record("duration_ms", record.encode_duration_ms);
+
+    let change = match previous {
+        Some(old) if old.deleted_at.is_none() => ChangeKind::Modified,
+        _ => ChangeKind::Added,
+    };
+    finish_encode(ctx, path, &record, change, data.len() as u64)?;
+    Ok(record)
+}
+
+/// Whether `data`, the new content of a file encoded as `old`, can be
+/// protected by encoding only what was appended. That takes
+/// `append_optimize`, a file that only grew, with its old content intact
+/// and the same shard configuration, and none of the features that keep
+/// whole-file copies of shards elsewhere: shard footers, retained versions,
+/// replication and sparse encoding.
+fn appends_to(config: &AppConfig, old: &FileRecord, data: &[u8], sparse: bool, shards: (usize, usize)) -> bool {
+    config.append_optimize
+        && !config.shard_footers
+        && config.keep_versions == 0
+        && config.replication_peers.is_empty()
+        && !sparse
+        && old.data_extents.is_none()
+        && old.deleted_at.is_none()
+        && (old.data_shards, old.parity_shards) == shards
+        && old.parity_algorithm == config.parity_algorithm
+        && data.len() as u64 > old.size
+        && checksum(&data[..old.size as usize]) == old.checksum
+}
+
+/// Encodes what was appended to `path` since it was encoded as `old` into
+/// a stripe of its own, leaving the shards already stored untouched.
+fn protect_appended(
+    ctx: &AppContext,
+    path: &Path,
+    old: &FileRecord,
+    data: &[u8],
+    meta: &fs::Metadata,
+    deadline: Option<Instant>,
+) -> Result<FileRecord> {
+    let started = Instant::now();
+    let added = &data[old.size as usize..];
+    let encoder = RSEncoder::with_field(old.data_shards, old.parity_shards, old.parity_algorithm, old.field)?;
+    let shards = encoder.encode(added)?;
+    let needed = (shards.len() * shards[0].len()) as u64;
+    if let Err(e) = check_free_space(ctx, needed) {
+        tracing::warn!("Not encoding {}: {}", metadata::display_path(path), e);
+        metadata::record_skipped(&ctx.db, path, &e.to_string())?;
+        return Err(e.into());
+    }
+    let stripe = AppendedStripe {
+        file_id: stripe_id(&old.file_id, old.appended.len() + 1),
+        len: added.len() as u64,
+        shard_size: shards[0].len(),
+        checksum: checksum(added),
+        shard_checksums: shards.iter().map(|shard| checksum(shard)).collect(),
+    };
+    let mut record = FileRecord {
+        size: data.len() as u64,
+        mtime: modified_secs(meta),
+        checksum: checksum(data),
+        base_checksum: Some(old.base_checksum.clone().unwrap_or_else(|| old.checksum.clone())),
+        protected_at: now_secs(),
+        shard_bytes: old.shard_bytes + needed,
+        last_verified: None,
+        attrs: if ctx.config.preserve_attrs { Some(attrs::read(path, meta)?) } else { None },
+        ..old.clone()
+    };
+    record.appended.push(stripe);
+    let new_stripe = record.stripes().pop().expect("a stripe was just appended");
+    let position = (record.appended.len(), record.appended.len() + 1);
+    stage_encode(ctx, path, &new_stripe, position, &shards, deadline)?;
+    ctx.store.commit_staged(&new_stripe.file_id, shards.len())?;
+    record.encode_duration_ms = started.elapsed().as_millis() as u64;
+    finish_encode(ctx, path, &record, ChangeKind::Modified, added.len() as u64)?;
+    tracing::debug!(
+        "Encoded {} bytes appended to {} as stripe {}",
+        added.len(), metadata::display_path(path), record.appended.len()
+    );
+    Ok(record)
+}
+
+/// Whether `path`, of `size` bytes, is encoded by [`protect_streaming`].
+/// That takes a size above `streaming_threshold_bytes`, and no holes
+/// encoded under `sparse_files`, which describe the file as one stripe.
+/// Such files are too large to be replicated; see
+/// [`AppConfig::max_replicated_bytes`].
+fn streams(ctx: &AppContext, path: &Path, size: u64) -> Result<bool> {
+    let config = &ctx.config;
+    if config.streaming_threshold_bytes == 0 || size <= config.streaming_threshold_bytes {
+        return Ok(false);
+    }
+    Ok(!config.sparse_files || sparse::data_extents(&fs::File::open(path)?, size)?.is_none())
+}
+
+/// Encodes `path` in stripes of `streaming_stripe_bytes`, each read,
+/// encoded and staged before the next, so files larger than memory can be
+/// protected. The first stripe is described by the record's own fields and
+/// the others by `appended`, as for stripes added under `append_optimize`.
+fn protect_streaming(
+    ctx: &AppContext,
+    path: &Path,
+    previous: Option<FileRecord>,
+    (data_shards, parity_shards): (usize, usize),
+    deadline: Option<Instant>,
+) -> Result<FileRecord> {
+    let config = &ctx.config;
+    let started = Instant::now();
+    let mut file = fs::File::open(path)?;
+    let meta = file.metadata()?;
+    let total_shards = data_shards + parity_shards;
+    let needed = meta.len().div_ceil(data_shards as u64) * total_shards as u64;
+    if let Err(e) = check_free_space(ctx, needed) {
+        tracing::warn!("Not encoding {}: {}", metadata::display_path(path), e);
+        metadata::record_skipped(&ctx.db, path, &e.to_string())?;
+        return Err(e.into());
+    }
+
+    let file_id = file_id_for(path);
+    let encoder = RSEncoder::with_algorithm(data_shards, parity_shards, config.parity_algorithm)?;
+    let stripe_count = meta.len().div_ceil(config.streaming_stripe_bytes as u64).max(1) as usize;
+    let pending = PendingOperation::Encode { file_id: file_id.clone(), shards: total_shards, stripes: stripe_count };
+    metadata::begin_operation(&ctx.db, path, &pending)?;
+    let mut staged = StagedStripes {
+        ctx,
+        path,
+        file_id: &file_id,
+        data_shards,
+        parity_shards,
+        parity_algorithm: config.parity_algorithm,
+        field: encoder.field(),
+        stripe_count,
+        deadline,
+        hasher: blake3::Hasher::new(),
+        stripes: Vec::new(),
+    };
+    let streamed = encoder
+        .encode_stream(&mut Read::by_ref(&mut file).take(meta.len()), config.streaming_stripe_bytes, &mut staged)
+        .and_then(|_| match config.snapshot_reads && !same_version(&meta, &file.metadata()?) {
+            true => Err(anyhow!("{} changed while being read", metadata::display_path(path))),
+            false => Ok(()),
+        });
+    if let Err(e) = streamed {
+        for stripe in &staged.stripes {
+            ctx.store.discard_staged(&stripe.file_id, total_shards)?;
+        }
+        ctx.store.discard_staged(&stripe_id(&file_id, staged.stripes.len()), total_shards)?;
+        metadata::finish_operation(&ctx.db, path)?;
+        if e.is::<EncodeTimeout>() {
+            tracing::warn!("Encoding {} timed out; will retry later", metadata::display_path(path));
+            metadata::record_skipped(&ctx.db, path, &e.to_string())?;
+        }
+        return Err(e);
+    }
+
+    let mut stripes = std::mem::take(&mut staged.stripes);
+    let first = stripes.remove(0);
+    let mut record = FileRecord {
+        file_id: file_id.clone(),
+        size: meta.len(),
+        mtime: modified_secs(&meta),
+        data_shards,
+        parity_shards,
+        shard_size: first.shard_size,
+        checksum: staged.hasher.finalize().to_hex().to_string(),
+        merkle_root: config.merkle_roots.then(|| merkle::merkle_root(&first.shard_checksums)),
+        shard_checksums: first.shard_checksums,
+        protected_at: now_secs(),
+        deleted_at: None,
+        tags: previous.as_ref().map(|old| old.tags.clone()).unwrap_or_default(),
+        priority: previous.as_ref().map_or(0, |old| old.priority),
+        parity_algorithm: config.parity_algorithm,
+        field: encoder.field(),
+        encode_duration_ms: 0,
+        shard_bytes: needed,
+        last_verified: None,
+        data_extents: None,
+        attrs: if config.preserve_attrs { Some(attrs::read(path, &meta)?) } else { None },
+        base_checksum: (!stripes.is_empty()).then_some(first.checksum),
+        appended: stripes,
+        stripe_size: Some(config.streaming_stripe_bytes as u64),
+    };
+    if let Some(old) = &previous {
+        if config.keep_versions > 0 && old.checksum != record.checksum {
+            retain_version(ctx, path, old)?;
+        }
+        remove_all_shards(ctx, old)?;
+    }
+    for stripe in record.stripes() {
+        ctx.store.commit_staged(&stripe.file_id, total_shards)?;
+    }
+    record.encode_duration_ms = started.elapsed().as_millis() as u64;
+    let change = match previous {
+        Some(old) if old.deleted_at.is_none() => ChangeKind::Modified,
+        _ => ChangeKind::Added,
+    };
+    finish_encode(ctx, path, &record, change, record.size)?;
+    tracing::debug!("Encoded {} in {} stripes", metadata::display_path(path), record.appended.len() + 1);
+    Ok(record)
+}
+
+/// Identifier of the shards of stripe `index` of a file: the file's own
+/// for the first stripe, numbered on from there like appended stripes.
+pub fn stripe_id(file_id: &str, index: usize) -> String {
+    match index {
+        0 => file_id.to_string(),
+        index => format!("{}.a{}", file_id, index),
+    }
+}
+
+/// Stages the stripes of a streamed encode as they arrive.
+struct StagedStripes<'a> {
+    ctx: &'a AppContext,
+    path: &'a Path,
+    file_id: &'a str,
+    data_shards: usize,
+    parity_shards: usize,
+    parity_algorithm: ParityAlgorithm,
+    field: GaloisField,
+    /// Stripes the file is split into, for the shard footers.
+    stripe_count: usize,
+    deadline: Option<Instant>,
+    /// Hash of the content so far.
+    hasher: blake3::Hasher,
+    stripes: Vec<AppendedStripe>,
+}
+
+impl StripeWriter for StagedStripes<'_> {
+    fn write_stripe(&mut self, index: usize, data: &[u8], shards: &[Vec<u8>]) -> Result<()> {
+        let stripe = AppendedStripe {
+            file_id: stripe_id(self.file_id, index),
+            len: data.len() as u64,
+            shard_size: shards[0].len(),
+            checksum: checksum(data),
+            shard_checksums: shards.iter().map(|shard| checksum(shard)).collect(),
+        };
+        for (shard_index, shard) in shards.iter().enumerate() {
+            if self.deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(EncodeTimeout(self.ctx.config.encode_timeout_secs).into());
+            }
+            let kind = ShardKind::of(shard_index, self.data_shards);
+            let footer = self.ctx.config.shard_footers.then(|| self.footer(&stripe, index, shard_index));
+            self.ctx.store.stage_shard_with_footer(&stripe.file_id, shard_index, kind, shard, footer.as_ref())?;
+        }
+        self.hasher.update(data);
+        self.stripes.push(stripe);
+        Ok(())
+    }
+}
+
+impl StagedStripes<'_> {
+    /// The footer of shard `index` of `stripe`, stripe `number` of the file.
+    fn footer(&self, stripe: &AppendedStripe, number: usize, index: usize) -> ShardFooter {
+        ShardFooter {
+            file_id: stripe.file_id.clone(),
+            path: metadata::display_path(self.path),
+            index,
+            data_shards: self.data_shards,
+            parity_shards: self.parity_shards,
+            parity_algorithm: self.parity_algorithm,
+            field: self.field,
+            encoded_len: stripe.len as usize,
+            size: stripe.len,
+            data_extents: None,
+            checksum: stripe.checksum.clone(),
+            shard_checksum: stripe.shard_checksums[index].clone(),
+            stripe: number,
+            stripes: self.stripe_count,
+        }
+    }
+}
+
+/// Stages the shards of a new encode of `path`, described by `record`,
+/// recording the operation so a crash midway is cleaned up. On failure the
+/// staged shards are discarded; a timeout is recorded for a later retry.
+fn stage_encode(
+    ctx: &AppContext,
+    path: &Path,
+    record: &FileRecord,
+    stripe: (usize, usize),
+    shards: &[Vec<u8>],
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let pending = PendingOperation::Encode { file_id: record.file_id.clone(), shards: shards.len(), stripes: 1 };
+    metadata::begin_operation(&ctx.db, path, &pending)?;
+    if let Err(e) = stage_shards(ctx, path, record, stripe, shards, deadline) {
+        ctx.store.discard_staged(&record.file_id, shards.len())?;
+        metadata::finish_operation(&ctx.db, path)?;
+        if e.is::<EncodeTimeout>() {
+            tracing::warn!("Encoding {} timed out; will retry later", metadata::display_path(path));
+            metadata::record_skipped(&ctx.db, path, &e.to_string())?;
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Stores the record of a finished encode once its shards are in place.
+/// `encoded` is how many bytes of the file were read and encoded.
+fn finish_encode(ctx: &AppContext, path: &Path, record: &FileRecord, change: ChangeKind, encoded: u64) -> Result<()> {
+    metadata::store_file_metadata(&ctx.db, path, record)?;
+    // The checksum of every encode is the BLAKE3 hash of the whole content.
+    metadata::put_file_hash(&ctx.db, path, &metadata::FileHash::blake3(record.checksum.clone()))?;
+    metadata::finish_operation(&ctx.db, path)?;
+    metadata::record_change(&ctx.db, path, change)?;
+    metadata::clear_skipped(&ctx.db, path)?;
+    metadata::untrack_file(&ctx.db, path)?;
+    ctx.session.file_protected();
+    ctx.metrics.encoded(encoded);
+    ctx.replication.enqueue(&ctx.config.replication_peers, path);
+    tracing::debug!("Protected {} ({} bytes)", metadata::display_path(path), record.size);
+    Ok(())
+}
+
+/// An encode ran past `encode_timeout_secs` and was abandoned.
+#[derive(Debug, thiserror::Error)]
+#[error("encode timed out after {0}s")]
+pub struct EncodeTimeout(pub u64);
+
+/// Another process holds the file open for writing, so its content may be
+/// mid-write. Checked when `skip_files_open_for_write` is set.
+#[derive(Debug, thiserror::Error)]
+#[error("file is open for writing by another process")]
+pub struct OpenForWrite;
+
+/// Encoding would leave less than `shard_store_reserve_bytes` free.
+#[derive(Debug, thiserror::Error)]
+#[error("shard store has {free} bytes free, encoding needs {needed} plus a reserve of {reserve}")]
+pub struct InsufficientSpace {
+    pub free: u64,
+    pub needed: u64,
+    pub reserve: u64,
+}
+
+/// A source file's content as read for encoding, with the metadata it had
+/// while being read.
+struct Source {
+    data: Vec<u8>,
+    /// Data extents of a sparse file, whose holes are not in `data`.
+    extents: Option<Vec<Extent>>,
+    meta: fs::Metadata,
+}
+
+/// Times a source is read again because it changed while being read.
+const SNAPSHOT_ATTEMPTS: usize = 3;
+
+/// Reads a source for encoding. With `sparse_files`, a file with holes is
+/// read as its concatenated data extents, which are returned alongside.
+///
+/// The content comes from a single descriptor, up to the size it had when
+/// opened. With `snapshot_reads`, the file is read again if its size or
+/// mtime changed meanwhile, so the shards, size and mtime all describe one
+/// version of the file rather than a mix of two.
+fn read_source(ctx: &AppContext, path: &Path) -> Result<Source> {
+    let config = &ctx.config;
+    let attempts = if config.snapshot_reads { SNAPSHOT_ATTEMPTS } else { 1 };
+    for _ in 0..attempts {
+        let mut file = fs::File::open(path)?;
+        let meta = file.metadata()?;
+        let extents = if config.sparse_files { sparse::data_extents(&file, meta.len())? } else { None };
+        let data = match &extents {
+            Some(extents) => sparse::read_extents(&mut file, extents)?,
+            None => {
+                // Finding extents moved the offset.
+                file.rewind()?;
+                let mut data = Vec::with_capacity(meta.len() as usize);
+                (&mut file).take(meta.len()).read_to_end(&mut data)?;
+                data
+            }
+        };
+        if !config.snapshot_reads || same_version(&meta, &file.metadata()?) {
+            return Ok(Source { data, extents, meta });
+        }
+        tracing::debug!("{} changed while being read, reading it again", metadata::display_path(path));
+        ctx.errors.source_reread();
+    }
+    Err(anyhow!("{} kept changing while being read", metadata::display_path(path)))
+}
+
+/// Looks through `/proc` for a process holding `path` open with write
+/// access. Processes whose descriptors cannot be read (those of other
+/// users, without privileges) are not seen.
+#[cfg(target_os = "linux")]
+fn open_for_write(path: &Path) -> bool {
+    let Ok(target) = fs::canonicalize(path) else {
+        return false;
+    };
+    let Ok(procs) = fs::read_dir("/proc") else {
+        return false;
+    };
+    for proc_dir in procs.flatten() {
+        if !proc_dir.file_name().to_str().is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit())) {
+            continue;
+        }
+        let Ok(fds) = fs::read_dir(proc_dir.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            if fs::read_link(fd.path()).ok().as_deref() != Some(target.as_path()) {
+                continue;
+            }
+            let Ok(info) = fs::read_to_string(proc_dir.path().join("fdinfo").join(fd.file_name())) else {
+                continue;
+            };
+            let flags = info
+                .lines()
+                .find_map(|line| line.strip_prefix("flags:"))
+                .and_then(|flags| i32::from_str_radix(flags.trim(), 8).ok());
+            if flags.is_some_and(|flags| matches!(flags & libc::O_ACCMODE, libc::O_WRONLY | libc::O_RDWR)) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Only detected on Linux.
+#[cfg(not(target_os = "linux"))]
+fn open_for_write(_path: &Path) -> bool {
+    false
+}
+
+fn same_version(before: &fs::Metadata, after: &fs::Metadata) -> bool {
+    before.len() == after.len() && before.modified().ok() == after.modified().ok()
+}
+
+/// Refuses to write `needed` bytes of shards if that would eat into the
+/// configured free-space reserve. Passes when free space cannot be queried.
+fn check_free_space(ctx: &AppContext, needed: u64) -> std::result::Result<(), InsufficientSpace> {
+    let reserve = ctx.config.shard_store_reserve_bytes;
+    match disk::stat(ctx.store.root()) {
+        Ok(Some(stats)) if !disk::has_free_space(&stats, needed, reserve) => {
+            Err(InsufficientSpace { free: stats.free_bytes, needed, reserve })
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            tracing::warn!("Could not query free space of the shard store: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Writes every shard of `record`, stripe `stripe.0` of `stripe.1` of the
+/// file, to the staging area, with footers if `shard_footers` is enabled,
+/// giving up once `deadline` has passed.
+fn stage_shards(
+    ctx: &AppContext,
+    path: &Path,
+    record: &FileRecord,
+    (stripe, stripes): (usize, usize),
+    shards: &[Vec<u8>],
+    deadline: Option<Instant>,
+) -> Result<()> {
+    let timed_out = || deadline.is_some_and(|d| Instant::now() >= d);
+    for (index, shard) in shards.iter().enumerate() {
+        if timed_out() {
+            return Err(EncodeTimeout(ctx.config.encode_timeout_secs).into());
+        }
+        let footer = ctx.config.shard_footers.then(|| record.shard_footer(path, index).in_stripe(stripe, stripes));
+        let kind = ShardKind::of(index, record.data_shards);
+        ctx.store.stage_shard_with_footer(&record.file_id, index, kind, shard, footer.as_ref())?;
+    }
+    if timed_out() {
+        return Err(EncodeTimeout(ctx.config.encode_timeout_secs).into());
+    }
+    Ok(())
+}
+
+/// Returns true if the file has no record yet or has changed since it was encoded.
+pub fn needs_protection(ctx: &AppContext, path: &Path) -> Result<bool> {
+    let Some(record) = metadata::get_file_metadata(&ctx.db, path)? else {
+        return Ok(true);
+    };
+    let meta = fs::metadata(path)?;
+    if meta.len() != record.size || modified_secs(&meta) != record.mtime {
+        return Ok(true);
+    }
+    // Network filesystems may keep mtimes too coarse to reveal an edit.
+    Ok(ctx.config.network_fs_mode && hash_file(path)? != record.checksum)
+}
+
+/// Encodes the file only if [`needs_protection`] says it is new or changed.
+/// A hardlink is handled through the path its inode is protected under.
+pub fn protect_if_changed(ctx: &AppContext, path: &Path) -> Result<Option<FileRecord>> {
+    let path = &canonical_path(ctx, path)?;
+    if needs_protection(ctx, path)? {
+        protect_file(ctx, path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Re-encodes protected files whose readable source no longer matches the
+/// stored checksum, so the shards follow the live content instead of a
+/// repair rolling it back. Missing sources are left for repair to handle.
+pub fn reprotect_stale(ctx: &AppContext) -> Result<ReprotectResult> {
+    let mut result = ReprotectResult::default();
+    for (path, record) in metadata::list_files(&ctx.db)? {
+        if !path.is_file() {
+            continue;
+        }
+        result.examined += 1;
+        let stale = match hash_file(&path) {
+            Ok(hash) => hash != record.checksum,
+            Err(e) => {
+                tracing::warn!("Cannot read {}: {}", metadata::display_path(&path), e);
+                result.failed += 1;
+                continue;
+            }
+        };
+        if !stale {
+            continue;
+        }
+        match protect_file(ctx, &path) {
+            Ok(_) => result.reprotected += 1,
+            Err(e) => {
+                tracing::error!("Failed to reprotect {}: {}", metadata::display_path(&path), e);
+                result.failed += 1;
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Brings stored records back in line with their sources, e.g. after
+/// restoring an older metadata export. A file whose content still matches
+/// its checksum only has its size and mtime updated; one whose content
+/// changed is re-encoded. With `scope`, only files under it are examined.
+pub fn refresh_metadata(ctx: &AppContext, scope: Option<&Path>) -> Result<RefreshResult> {
+    let mut result = RefreshResult::default();
+    for (path, record) in metadata::list_files(&ctx.db)? {
+        if scope.is_some_and(|scope| !path.starts_with(scope)) || !path.is_file() {
+            continue;
+        }
+        result.examined += 1;
+        let (hash, meta) = match hash_file(&path).and_then(|hash| Ok((hash, fs::metadata(&path)?))) {
+            Ok(read) => read,
+            Err(e) => {
+                tracing::warn!("Cannot read {}: {}", metadata::display_path(&path), e);
+                result.failed += 1;
+                continue;
+            }
+        };
+        if hash != record.checksum {
+            match protect_file(ctx, &path) {
+                Ok(_) => result.reencoded += 1,
+                Err(e) => {
+                    tracing::error!("Failed to reprotect {}: {}", metadata::display_path(&path), e);
+                    result.failed += 1;
+                }
+            }
+            continue;
+        }
+        let (size, mtime) = (meta.len(), modified_secs(&meta));
+        if (record.size, record.mtime) == (size, mtime) {
+            continue;
+        }
+        let _lock = ctx.file_locks.lock(&path);
+        if let Some(mut record) = metadata::get_file_metadata(&ctx.db, &path)? {
+            record.size = size;
+            record.mtime = mtime;
+            metadata::store_file_metadata(&ctx.db, &path, &record)?;
+            result.refreshed += 1;
+        }
+    }
+    Ok(result)
+}
+
+/// Drops the records and shards of files deleted more than
+/// `deleted_retention_secs` before `now` (seconds since the Unix epoch),
+/// returning their paths. A retention of 0 keeps deleted files forever.
+pub fn purge_deleted(ctx: &AppContext, now: u64) -> Result<Vec<PathBuf>> {
+    let retention = ctx.config.deleted_retention_secs;
+    let mut purged = Vec::new();
+    if retention == 0 {
+        return Ok(purged);
+    }
+    for (path, record) in metadata::list_files(&ctx.db)? {
+        if record.deleted_at.is_none_or(|deleted| now.saturating_sub(deleted) <= retention) {
+            continue;
+        }
+        let _lock = ctx.file_locks.lock(&path);
+        // The file may have come back since the listing.
+        match metadata::get_file_metadata(&ctx.db, &path)? {
+            Some(current) if current.deleted_at.is_some() => {}
+            _ => continue,
+        }
+        remove_protection(ctx, &path, &record)?;
+        tracing::info!("Purged deleted file {} past its retention.", metadata::display_path(&path));
+        purged.push(path);
+    }
+    Ok(purged)
+}
+
+/// Rebuilds the original content of a protected file from its shards.
+///
+/// Shards that are missing or fail their checksum are treated as lost and
+/// reconstructed from the remaining ones.
+pub fn reconstruct(ctx: &AppContext, record: &FileRecord) -> Result<Vec<u8>> {
+    let data = reconstruct_encoded(ctx, record)?;
+    Ok(match &record.data_extents {
+        Some(extents) => sparse::expand(&data, extents, record.size),
+        None => data,
+    })
+}
+
+/// Rebuilds the bytes that were encoded, i.e. only the data extents of a
+/// sparse file, and verifies them against the stored checksum.
+fn reconstruct_encoded(ctx: &AppContext, record: &FileRecord) -> Result<Vec<u8>> {
+    if !record.appended.is_empty() {
+        let mut data = Vec::with_capacity(record.size as usize);
+        for stripe in record.stripes() {
+            data.extend(reconstruct_encoded(ctx, &stripe)?);
+        }
+        if checksum(&data) != record.checksum {
+            return Err(anyhow!("reconstructed content does not match the stored checksum"));
+        }
+        return Ok(data);
+    }
+    let encoder = RSEncoder::with_field(record.data_shards, record.parity_shards, record.parity_algorithm, record.field)?;
+    let mut shards = load_shards(ctx, record)?;
+    encoder.reconstruct(&mut shards)?;
+    let shards: Vec<Vec<u8>> = shards.into_iter().map(Option::unwrap_or_default).collect();
+    let data = encoder.join(&shards, record.encoded_len());
+    if encoded_checksum(record, &data) != record.checksum {
+        return Err(anyhow!("reconstructed content does not match the stored checksum"));
+    }
+    Ok(data)
+}
+
+/// Checksum of the logical content of `record` given the bytes that were encoded.
+pub fn encoded_checksum(record: &FileRecord, data: &[u8]) -> String {
+    match &record.data_extents {
+        Some(extents) => sparse::hash_logical(data, extents, record.size).to_hex().to_string(),
+        None => checksum(data),
+    }
+}
+
+/// Reconstructs a protected file and writes it to `out`. Sparse files are
+/// written back with their holes. Returns whether `out`, read back once
+/// written, matches the stored checksum; a mismatch is logged as an error.
+pub fn recover_file(ctx: &AppContext, path: &Path, out: &Path) -> Result<bool> {
+    let record = metadata::get_file_metadata(&ctx.db, path)?
+        .ok_or_else(|| anyhow!("{} is not protected", metadata::display_path(path)))?;
+    write_recovered(ctx, &record, out)
+}
+
+/// Like [`recover_file`], but reconstructs `version` of the file, which
+/// may be the current one or one retained under `keep_versions`.
+pub fn recover_version(ctx: &AppContext, path: &Path, version: u32, out: &Path) -> Result<bool> {
+    let record = version_record(ctx, path, version)?.ok_or_else(|| {
+        anyhow!("version {} of {} no longer exists", version, metadata::display_path(path))
+    })?;
+    write_recovered(ctx, &record, out)
+}
+
+fn write_recovered(ctx: &AppContext, record: &FileRecord, out: &Path) -> Result<bool> {
+    match &record.data_extents {
+        Some(extents) => sparse::write_sparse(out, &reconstruct_encoded(ctx, record)?, extents, record.size)?,
+        None if record.appended.is_empty() => fs::write(out, reconstruct_encoded(ctx, record)?)?,
+        // A stripe at a time, so a file encoded in stripes is never held
+        // whole. Written aside first, as `out` may be the damaged source.
+        None => {
+            let mut partial = out.as_os_str().to_owned();
+            partial.push(".rsguard-recover");
+            let partial = PathBuf::from(partial);
+            if let Err(e) = write_stripes(ctx, record, &partial).and_then(|_| Ok(fs::rename(&partial, out)?)) {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+        }
+    }
+    if let Some(attrs) = &record.attrs {
+        attrs::apply(out, attrs)?;
+    }
+    let verified = hash_file(out)? == record.checksum;
+    if !verified {
+        tracing::error!("{} does not match its stored checksum after being recovered", metadata::display_path(out));
+    }
+    Ok(verified)
+}
+
+fn write_stripes(ctx: &AppContext, record: &FileRecord, out: &Path) -> Result<()> {
+    let mut file = fs::File::create(out)?;
+    for stripe in record.stripes() {
+        file.write_all(&reconstruct_encoded(ctx, &stripe)?)?;
+    }
+    Ok(file.sync_all()?)
+}
+
+/// Number of the current version of a file. Versions count up from 1 with
+/// every change to the content while earlier ones are retained.
+pub fn current_version(ctx: &AppContext, path: &Path) -> Result<u32> {
+    Ok(metadata::list_versions(&ctx.db, path)?.last().map_or(1, |(version, _)| version + 1))
+}
+
+/// The record of `version` of a file, if it is the current version or one
+/// that is still retained.
+pub fn version_record(ctx: &AppContext, path: &Path, version: u32) -> Result<Option<FileRecord>> {
+    if let Some(record) = metadata::get_version(&ctx.db, path, version)? {
+        return Ok(Some(record));
+    }
+    if version == current_version(ctx, path)? {
+        return metadata::get_file_metadata(&ctx.db, path);
+    }
+    Ok(None)
+}
+
+/// Copies the shards of `old` aside as the newest retained version of
+/// `path`, then drops the oldest versions beyond `keep_versions`.
+fn retain_version(ctx: &AppContext, path: &Path, old: &FileRecord) -> Result<()> {
+    let versions = metadata::list_versions(&ctx.db, path)?;
+    let version = versions.last().map_or(1, |(version, _)| version + 1);
+    let mut kept = old.clone();
+    kept.file_id = format!("{}.v{}", old.file_id, version);
+    for stripe in &mut kept.appended {
+        stripe.file_id = format!("{}.v{}", stripe.file_id, version);
+    }
+    for (from, to) in old.stripes().iter().zip(kept.stripes()) {
+        for index in 0..from.total_shards() {
+            if let Some(shard) = ctx.store.read_shard(&from.file_id, index)? {
+                ctx.store.write_shard(&to.file_id, index, ShardKind::of(index, from.data_shards), &shard)?;
+            }
+        }
+    }
+    metadata::store_version(&ctx.db, path, version, &kept)?;
+    let excess = (versions.len() + 1).saturating_sub(ctx.config.keep_versions);
+    for (number, record) in versions.iter().take(excess) {
+        remove_all_shards(ctx, record)?;
+        metadata::remove_version(&ctx.db, path, *number)?;
+    }
+    Ok(())
+}
+
+/// Drops the record of `path`, its shards and every retained version.
+/// The caller holds the file's lock.
+pub fn remove_protection(ctx: &AppContext, path: &Path, record: &FileRecord) -> Result<()> {
+    remove_all_shards(ctx, record)?;
+    remove_versions(ctx, path)?;
+    metadata::remove_file_metadata(&ctx.db, path)?;
+    Ok(())
+}
+
+/// Drops every retained version of `path` along with its shards.
+fn remove_versions(ctx: &AppContext, path: &Path) -> Result<()> {
+    for (number, record) in metadata::list_versions(&ctx.db, path)? {
+        remove_all_shards(ctx, &record)?;
+        metadata::remove_version(&ctx.db, path, number)?;
+    }
+    Ok(())
+}
+
+/// Deletes the shards of every stripe of `record`.
+pub fn remove_all_shards(ctx: &AppContext, record: &FileRecord) -> Result<()> {
+    for stripe in record.stripes() {
+        ctx.store.remove_shards(&stripe.file_id, stripe.total_shards())?;
+    }
+    Ok(())
+}
+
+/// Loads every shard of a record, replacing missing or damaged ones with `None`.
+pub fn load_shards(ctx: &AppContext, record: &FileRecord) -> Result<Vec<Option<Vec<u8>>>> {
+    let mut shards = Vec::with_capacity(record.total_shards());
+    for index in 0..record.total_shards() {
+        let shard = ctx.store.read_shard(&record.file_id, index)?
+            .filter(|shard| checksum(shard) == record.shard_checksums[index]);
+        shards.push(shard);
+    }
+    Ok(shards)
+}
+
+/// Returns the path under which `path`'s content is protected. With
+/// `hardlinks = "shared"`, all links to one inode map to a single owner path
+/// (the first one seen that still links to it), so the content is encoded once.
+/// With `case_insensitive_paths`, a record kept under another spelling of
+/// the path is moved to this one first.
+pub fn canonical_path(ctx: &AppContext, path: &Path) -> Result<PathBuf> {
+    if ctx.config.case_insensitive_paths == Some(true) {
+        adopt_case_variant(ctx, path)?;
+    }
+    #[cfg(unix)]
+    if ctx.config.hardlinks == HardlinkMode::Shared {
+        use std::os::unix::fs::MetadataExt;
+
+        let meta = fs::metadata(path)?;
+        if meta.nlink() > 1 {
+            let owner = metadata::inode_owner(&ctx.db, meta.dev(), meta.ino())?;
+            match owner {
+                Some(owner) if owner == path => {}
+                // The owner may have been unlinked or replaced since it was chosen.
+                Some(owner) if fs::metadata(&owner).is_ok_and(|m| m.dev() == meta.dev() && m.ino() == meta.ino()) => {
+                    return Ok(owner);
+                }
+                _ => metadata::set_inode_owner(&ctx.db, meta.dev(), meta.ino(), path)?,
+            }
+        }
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Moves the record of a path differing from `path` only in case, e.g.
+/// after a case-only rename, so one file never has two records. Its shards
+/// stay where they are, since a record names its own `file_id`.
+fn adopt_case_variant(ctx: &AppContext, path: &Path) -> Result<()> {
+    if let Some(previous) = metadata::case_owner(&ctx.db, path)?.filter(|previous| previous != path) {
+        if metadata::get_file_metadata(&ctx.db, path)?.is_none() {
+            if let Some(mut record) = metadata::rename_file_metadata(&ctx.db, &previous, path)? {
+                tracing::info!(
+                    "{} was renamed to {}; moving its record",
+                    metadata::display_path(&previous),
+                    metadata::display_path(path)
+                );
+                // The old spelling may have been reported as removed by the rename.
+                if record.deleted_at.take().is_some() {
+                    metadata::store_file_metadata(&ctx.db, path, &record)?;
+                }
+            }
+        }
+    }
+    metadata::set_case_owner(&ctx.db, path)
+}
+
+/// Probes whether `dir` sits on a case-insensitive filesystem by creating a
+/// lowercase file and looking it up in uppercase. An unwritable directory
+/// counts as case-sensitive.
+pub fn detect_case_insensitive(dir: &Path) -> bool {
+    let probe = dir.join(format!(".rsguard-case-probe-{}", std::process::id()));
+    if fs::write(&probe, b"").is_err() {
+        return false;
+    }
+    let upper = dir.join(format!(".RSGUARD-CASE-PROBE-{}", std::process::id()));
+    let insensitive = upper.exists();
+    let _ = fs::remove_file(&probe);
+    insensitive
+}
+
+/// Shard file names are derived from the path so a re-encode replaces the old set.
+pub fn file_id_for(path: &Path) -> String {
+    blake3::hash(&metadata::path_key(path)).to_hex().to_string()
+}
+
+/// Whether `id` has the form of an id from [`file_id_for`], so shard paths
+/// can be built from it.
+pub fn is_file_id(id: &str) -> bool {
+    id.len() == 2 * blake3::OUT_LEN && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub fn checksum(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// BLAKE3 hash of the file at `path`, read in pieces; hex encoded like [`checksum`].
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+pub fn modified_secs(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,93 @@
+use anyhow::Result;
+use std::fmt;
+use std::path::PathBuf;
+use crate::metadata::{self, PendingOperation};
+use crate::repair::{self, ShardRepairOutcome};
+use crate::{protector, AppContext};
+
+/// How an interrupted operation was dealt with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    /// The operation was run again to completion.
+    Resumed,
+    /// Partial work was discarded, leaving the previous state in place.
+    RolledBack,
+    /// Resuming failed; the marker is kept so the next start tries again.
+    Failed(String),
+}
+
+/// An operation found unfinished at startup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveredOperation {
+    pub path: PathBuf,
+    pub operation: PendingOperation,
+    pub resolution: Resolution,
+}
+
+impl fmt::Display for RecoveredOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match &self.operation {
+            PendingOperation::Encode { .. } => "encode".to_string(),
+            PendingOperation::Repair { shard_index } => format!("repair of shard {}", shard_index),
+            PendingOperation::Replica { .. } => "replica".to_string(),
+        };
+        let resolution = match &self.resolution {
+            Resolution::Resumed => "resumed".to_string(),
+            Resolution::RolledBack => "rolled back".to_string(),
+            Resolution::Failed(e) => format!("failed: {}", e),
+        };
+        write!(f, "{} of {}: {}", operation, metadata::display_path(&self.path), resolution)
+    }
+}
+
+/// Finishes or undoes operations a crash left half done, and lists them as
+/// `recovered_operations` in the status.
+///
+/// Interrupted encodes are redone from the source when it still exists and
+/// otherwise rolled back. Repairs are redone. Replicas are rolled back; the
+/// peer sends them again.
+pub fn resume_interrupted(ctx: &AppContext) -> Result<Vec<RecoveredOperation>> {
+    let mut recovered = Vec::new();
+    for (path, operation) in metadata::list_operations(&ctx.db)? {
+        let resolution = match resolve(ctx, &path, &operation) {
+            Ok(resolution) => resolution,
+            Err(e) => Resolution::Failed(e.to_string()),
+        };
+        if !matches!(resolution, Resolution::Failed(_)) {
+            metadata::finish_operation(&ctx.db, &path)?;
+        }
+        let entry = RecoveredOperation { path, operation, resolution };
+        tracing::warn!("Interrupted {}", entry);
+        recovered.push(entry);
+    }
+    ctx.status.lock().unwrap().recovered_operations = recovered.iter().map(ToString::to_string).collect();
+    Ok(recovered)
+}
+
+fn resolve(ctx: &AppContext, path: &std::path::Path, operation: &PendingOperation) -> Result<Resolution> {
+    match operation {
+        PendingOperation::Encode { file_id, shards, stripes } => {
+            for stripe in 0..*stripes {
+                ctx.store.discard_staged(&protector::stripe_id(file_id, stripe), *shards)?;
+            }
+            if !path.is_file() {
+                return Ok(Resolution::RolledBack);
+            }
+            protector::protect_file(ctx, path)?;
+            Ok(Resolution::Resumed)
+        }
+        PendingOperation::Repair { shard_index } => match repair::repair_shard(ctx, path, *shard_index)? {
+            ShardRepairOutcome::Repaired(_) | ShardRepairOutcome::AlreadyHealthy => Ok(Resolution::Resumed),
+            ShardRepairOutcome::NotProtected | ShardRepairOutcome::IndexOutOfRange => Ok(Resolution::RolledBack),
+            ShardRepairOutcome::Unrecoverable(reason) | ShardRepairOutcome::Quarantined(reason) => {
+                Ok(Resolution::Failed(reason))
+            }
+        },
+        PendingOperation::Replica { file_id, shards, stripes } => {
+            for stripe in 0..*stripes {
+                ctx.store.discard_staged(&protector::stripe_id(file_id, stripe), *shards)?;
+            }
+            Ok(Resolution::RolledBack)
+        }
+    }
+}
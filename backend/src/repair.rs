@@ -1,24 +1,155 @@
-use anyhow::Result;
-use std::sync::{Arc, Mutex};
-use crate::metadata::MetadataDb;
-use shared::AppStatus;
-
-/// Attempts to repair corrupted or missing files.
-pub async fn run_repair(app_status: Arc<Mutex<AppStatus>>, db: Arc<MetadataDb>) -> Result<()> {
-    // TODO:
-    // 1. Lock the app status to 'Repairing'.
-    // 2. Get the list of corrupted/missing items from the "needs_repair" queue (or re-run a check).
-    // 3. For each item, load the available shards.
-    // 4. Use the `RSEncoder::reconstruct` function to rebuild the missing data.
-    // 5. Write the reconstructed shards or the full file back to disk.
-    // 6. Verify the repair by re-running a check on the repaired item.
-    // 7. Update AppStatus with the results.
-    // 8. Set status back to 'Idle'.
-
-    println!("Starting repair process...");
-    // Simulate work
-    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-    println!("Repair process finished.");
-
-    Ok(())
-} 
\ No newline at end of file
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::time::Instant;
+use tracing::{field, Span};
+use crate::checker::FileCheck;
+use crate::encoder::RSEncoder;
+use crate::metadata::{self, PendingOperation};
+use crate::shard_store::ShardKind;
+use crate::workers::Work;
+use crate::{protector, AppContext, Guard};
+
+/// What [`run_repair`] made of the corrupted files of a check.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairRun {
+    /// Corrupted files that check out after their repair.
+    pub repaired: u64,
+    pub failed_paths: Vec<String>,
+}
+
+/// Outcome of rebuilding a single shard.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShardRepairOutcome {
+    /// The shard was rebuilt and rewritten; carries its new checksum.
+    Repaired(String),
+    /// The shard is present and matches its checksum, nothing was written.
+    AlreadyHealthy,
+    /// Too few healthy shards remain to rebuild it.
+    Unrecoverable(String),
+    NotProtected,
+    IndexOutOfRange,
+    /// The file is quarantined and is not repaired until it is reset; carries the reason.
+    Quarantined(String),
+}
+
+/// Rebuilds one shard of a file from the others and rewrites only that shard.
+///
+/// A file that needs more than `max_repair_attempts` repairs is quarantined
+/// as flapping (e.g. it sits on a bad sector) instead of being repaired again.
+pub fn repair_shard(ctx: &AppContext, path: &Path, index: usize) -> Result<ShardRepairOutcome> {
+    let span = tracing::trace_span!(
+        "repair",
+        path = %metadata::display_path(path),
+        shard = index,
+        bytes = field::Empty,
+        data_shards = field::Empty,
+        parity_shards = field::Empty,
+        duration_ms = field::Empty,
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+    let outcome = rebuild_shard(ctx, path, index, &span);
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    outcome
+}
+
+/// Body of [`repair_shard`], filling in `span` once the record is known.
+fn rebuild_shard(ctx: &AppContext, path: &Path, index: usize, span: &Span) -> Result<ShardRepairOutcome> {
+    let queued = ctx.workers.queue(Work::Repair, 1);
+    let _lock = ctx.file_locks.lock(path);
+    let _active = queued.start();
+    let Some(record) = metadata::get_file_metadata(&ctx.db, path)? else {
+        return Ok(ShardRepairOutcome::NotProtected);
+    };
+    span.record("bytes", record.size);
+    span.record("data_shards", record.data_shards);
+    span.record("parity_shards", record.parity_shards);
+    // Shards of appended stripes are numbered on from those of the first.
+    let total = record.total_shards();
+    let stripes = record.stripes();
+    let (stripe, stripe_count) = (index / total, stripes.len());
+    let Some(record) = stripes.into_iter().nth(stripe) else {
+        return Ok(ShardRepairOutcome::IndexOutOfRange);
+    };
+    let (shard, index) = (index, index % total);
+    if let Some(entry) = metadata::get_quarantined(&ctx.db, path)? {
+        return Ok(ShardRepairOutcome::Quarantined(entry.reason));
+    }
+    let mut shards = protector::load_shards(ctx, &record)?;
+    if shards[index].is_some() {
+        return Ok(ShardRepairOutcome::AlreadyHealthy);
+    }
+    let max_attempts = ctx.config.max_repair_attempts;
+    if max_attempts > 0 && metadata::repair_attempts(&ctx.db, path)? >= max_attempts {
+        let reason = format!("flapping: damaged again after {} repairs", max_attempts);
+        tracing::warn!("Quarantining {}: {}", metadata::display_path(path), reason);
+        metadata::quarantine(&ctx.db, path, &reason)?;
+        return Ok(ShardRepairOutcome::Quarantined(reason));
+    }
+
+    let encoder = RSEncoder::with_field(record.data_shards, record.parity_shards, record.parity_algorithm, record.field)?;
+    if let Err(e) = encoder.reconstruct(&mut shards) {
+        return Ok(ShardRepairOutcome::Unrecoverable(e.to_string()));
+    }
+    let shards: Vec<Vec<u8>> = shards.into_iter().map(Option::unwrap_or_default).collect();
+    if protector::encoded_checksum(&record, &encoder.join(&shards, record.encoded_len())) != record.checksum {
+        return Ok(ShardRepairOutcome::Unrecoverable(
+            "reconstructed content does not match the stored checksum".to_string(),
+        ));
+    }
+
+    let expected = &record.shard_checksums[index];
+    if protector::checksum(&shards[index]) != *expected {
+        return flag_bad_rebuild(ctx, path, format!("rebuilt shard {} does not match its stored checksum", shard));
+    }
+
+    // The rebuilt shard is staged and read back from disk, and only replaces
+    // the damaged one once it verifies, so a bad write cannot make things worse.
+    metadata::begin_operation(&ctx.db, path, &PendingOperation::Repair { shard_index: shard })?;
+    let footer = ctx.config.shard_footers.then(|| record.shard_footer(path, index).in_stripe(stripe, stripe_count));
+    let kind = ShardKind::of(index, record.data_shards);
+    ctx.store.stage_shard_with_footer(&record.file_id, index, kind, &shards[index], footer.as_ref())?;
+    let staged = ctx.store.read_staged(&record.file_id, index)?.unwrap_or_default();
+    if protector::checksum(&staged) != *expected {
+        ctx.store.discard_staged_shard(&record.file_id, index)?;
+        metadata::finish_operation(&ctx.db, path)?;
+        return flag_bad_rebuild(ctx, path, format!("rebuilt shard {} did not read back as written", shard));
+    }
+    ctx.store.commit_staged_shard(&record.file_id, index)?;
+    metadata::finish_operation(&ctx.db, path)?;
+    metadata::record_repair_attempt(&ctx.db, path)?;
+    ctx.session.shard_repaired();
+    ctx.metrics.repaired();
+    ctx.publish_status();
+    tracing::info!("Rebuilt shard {} of {}", shard, metadata::display_path(path));
+    Ok(ShardRepairOutcome::Repaired(expected.clone()))
+}
+
+/// Leaves the damaged shard in place and quarantines the file, so a rebuild
+/// that cannot be trusted is looked at by an operator instead of retried.
+fn flag_bad_rebuild(ctx: &AppContext, path: &Path, reason: String) -> Result<ShardRepairOutcome> {
+    tracing::error!("Not replacing a shard of {}: {}", metadata::display_path(path), reason);
+    metadata::quarantine(&ctx.db, path, &reason)?;
+    Ok(ShardRepairOutcome::Quarantined(reason))
+}
+
+/// Repairs every corrupted file among `files`, the findings of a check, and
+/// checks each again afterwards. Drifted files are left for a re-protect.
+pub fn run_repair(ctx: &AppContext, files: &[FileCheck]) -> RepairRun {
+    let guard = Guard::from(ctx.clone());
+    let mut run = RepairRun::default();
+    for file in files.iter().filter(|file| file.is_corrupted()) {
+        let repaired = guard.repair(&file.path).and_then(|_| match guard.verify(&file.path)? {
+            None => Ok(()),
+            Some(finding) => bail!("still damaged after repair: {}", finding.message()),
+        });
+        match repaired {
+            Ok(()) => run.repaired += 1,
+            Err(e) => {
+                tracing::warn!("Could not repair {}: {}", metadata::display_path(&file.path), e);
+                run.failed_paths.push(metadata::display_path(&file.path));
+            }
+        }
+    }
+    run
+}
@@ -0,0 +1,380 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use shared::{ReplicaMismatch, ReplicationVerifyReport};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use crate::metadata::{self, FileRecord, PendingOperation};
+use crate::shard_store::ShardKind;
+use crate::{merkle, protector, AppContext};
+
+/// Upper bound for the delay between attempts to reach an unavailable peer.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// A file's record and shards, as sent to a peer's `POST /api/replicate`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicaPayload {
+    /// Raw path bytes as produced by [`metadata::path_key`].
+    pub path: Vec<u8>,
+    pub record: FileRecord,
+    /// The shards of every stripe of the record, numbered on from one
+    /// stripe to the next as for repairs.
+    pub shards: Vec<Vec<u8>>,
+}
+
+/// Returned for a file larger than [`AppConfig::max_replicated_bytes`],
+/// which is not sent and not retried.
+///
+/// [`AppConfig::max_replicated_bytes`]: crate::config::AppConfig::max_replicated_bytes
+#[derive(Debug, thiserror::Error)]
+#[error("{size} bytes is more than the {max} bytes replicated")]
+pub struct TooLarge {
+    pub size: u64,
+    pub max: u64,
+}
+
+struct Job {
+    peer: String,
+    path: PathBuf,
+    attempts: u32,
+}
+
+/// Files waiting to be pushed to replication peers.
+#[derive(Default)]
+pub struct ReplicationQueue {
+    jobs: Mutex<VecDeque<Job>>,
+    notify: Notify,
+    /// Jobs that are queued or waiting for a retry.
+    outstanding: AtomicU64,
+}
+
+impl ReplicationQueue {
+    /// Queues `path` to be pushed to every peer.
+    pub fn enqueue(&self, peers: &[String], path: &Path) {
+        for peer in peers {
+            self.outstanding.fetch_add(1, Ordering::Relaxed);
+            self.push(Job { peer: peer.clone(), path: path.to_path_buf(), attempts: 0 });
+        }
+    }
+
+    /// Number of pushes that have not succeeded yet.
+    pub fn outstanding(&self) -> u64 {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    /// Queues `path` to be pushed to `peer` only.
+    pub fn enqueue_for(&self, peer: &str, path: &Path) {
+        self.enqueue(std::slice::from_ref(&peer.to_string()), path);
+    }
+
+    fn push(&self, job: Job) {
+        self.jobs.lock().unwrap().push_back(job);
+        self.notify.notify_one();
+    }
+
+    async fn pop(&self) -> Job {
+        loop {
+            if let Some(job) = self.jobs.lock().unwrap().pop_front() {
+                return job;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Spawns the task that pushes newly protected files to the configured
+/// `replication_peers`. Failed pushes are retried with a growing delay and
+/// reported in the status; they never affect local protection.
+pub fn start(ctx: AppContext) {
+    if ctx.config.replication_peers.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        loop {
+            let job = ctx.replication.pop().await;
+            match push(&ctx, &client, &job).await {
+                Ok(()) => {
+                    ctx.replication.outstanding.fetch_sub(1, Ordering::Relaxed);
+                    tracing::debug!("Replicated {} to {}", metadata::display_path(&job.path), job.peer);
+                }
+                Err(e) if e.is::<TooLarge>() => {
+                    ctx.replication.outstanding.fetch_sub(1, Ordering::Relaxed);
+                    let message = format!("{} -> {}: {}", metadata::display_path(&job.path), job.peer, e);
+                    tracing::warn!("Not replicating {}", message);
+                    let mut status = ctx.status.lock().unwrap();
+                    status.replication_failures += 1;
+                    status.last_replication_error = Some(message);
+                }
+                Err(e) => {
+                    let delay = retry_delay(job.attempts);
+                    let message = format!("{} -> {}: {}", metadata::display_path(&job.path), job.peer, e);
+                    tracing::warn!("Replication failed ({}), retrying in {:?}", message, delay);
+                    ctx.errors.replication_retry();
+                    {
+                        let mut status = ctx.status.lock().unwrap();
+                        status.replication_failures += 1;
+                        status.last_replication_error = Some(message);
+                    }
+                    let retry_ctx = ctx.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        retry_ctx.replication.push(Job { attempts: job.attempts + 1, ..job });
+                    });
+                }
+            }
+            ctx.status.lock().unwrap().replication_pending = ctx.replication.outstanding();
+        }
+    });
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    Duration::from_secs(1u64 << attempts.min(16)).min(MAX_RETRY_DELAY)
+}
+
+async fn push(ctx: &AppContext, client: &reqwest::Client, job: &Job) -> Result<()> {
+    let (payload_ctx, path) = (ctx.clone(), job.path.clone());
+    let Some(payload) = tokio::task::spawn_blocking(move || build_payload(&payload_ctx, &path)).await?? else {
+        // The file was forgotten since it was queued; nothing to send.
+        return Ok(());
+    };
+    let url = format!("{}/api/replicate", job.peer.trim_end_matches('/'));
+    let response = authorized(ctx, client.post(url)).json(&payload).send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        bail!("peer answered {}: {}", status, response.text().await.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Collects the current record and shards of a protected file.
+fn build_payload(ctx: &AppContext, path: &Path) -> Result<Option<ReplicaPayload>> {
+    let _lock = ctx.file_locks.lock(path);
+    let Some(record) = metadata::get_file_metadata(&ctx.db, path)? else {
+        return Ok(None);
+    };
+    let max = ctx.config.max_replicated_bytes();
+    if record.size > max {
+        return Err(TooLarge { size: record.size, max }.into());
+    }
+    let stripes = record.stripes();
+    let mut shards = Vec::with_capacity(stripes.len() * record.total_shards());
+    for stripe in &stripes {
+        for index in 0..stripe.total_shards() {
+            let shard = ctx.store.read_shard_uncached(&stripe.file_id, index)?
+                .ok_or_else(|| anyhow!("shard {} is missing", shards.len()))?;
+            shards.push(shard);
+        }
+    }
+    Ok(Some(ReplicaPayload { path: metadata::path_key(path), record, shards }))
+}
+
+/// Checks that a received payload is internally consistent.
+pub fn validate(payload: &ReplicaPayload) -> std::result::Result<(), String> {
+    let record = &payload.record;
+    // The ids name the shard files, so they must not lead out of the store.
+    if !protector::is_file_id(&record.file_id) {
+        return Err("file_id is not a valid file id".to_string());
+    }
+    for (number, stripe) in record.appended.iter().enumerate() {
+        if stripe.file_id != protector::stripe_id(&record.file_id, number + 1) {
+            return Err(format!("appended stripe {} has an unexpected file id", number + 1));
+        }
+    }
+    let appended = record.appended.iter().try_fold(0u64, |sum, stripe| sum.checked_add(stripe.len));
+    if !matches!(appended, Some(len) if len <= record.size) {
+        return Err("appended stripes are longer than the file".to_string());
+    }
+    let expected = all_shard_checksums(record);
+    let stripes = record.stripes();
+    if payload.shards.len() != expected.len()
+        || stripes.iter().any(|stripe| stripe.shard_checksums.len() != stripe.total_shards())
+    {
+        return Err(format!("expected {} shards, got {}", stripes.len() * record.total_shards(), payload.shards.len()));
+    }
+    for (index, (shard, expected)) in payload.shards.iter().zip(&expected).enumerate() {
+        if protector::checksum(shard) != *expected {
+            return Err(format!("shard {} does not match its checksum", index));
+        }
+    }
+    if record.merkle_root.as_ref().is_some_and(|root| *root != merkle::merkle_root(&record.shard_checksums)) {
+        return Err("shard checksums do not match the Merkle root".to_string());
+    }
+    Ok(())
+}
+
+/// Stores a validated payload from a peer, replacing any previous replica
+/// of the same path.
+pub fn accept(ctx: &AppContext, payload: ReplicaPayload) -> Result<()> {
+    let path = metadata::path_from_key(&payload.path);
+    let _lock = ctx.file_locks.lock(&path);
+    let record = payload.record;
+    let (stripes, total) = (record.stripes(), record.total_shards());
+    let pending = PendingOperation::Replica { file_id: record.file_id.clone(), shards: total, stripes: stripes.len() };
+    metadata::begin_operation(&ctx.db, &path, &pending)?;
+    let mut shards = payload.shards.iter();
+    for (number, stripe) in stripes.iter().enumerate() {
+        for (index, shard) in shards.by_ref().take(total).enumerate() {
+            let footer = ctx.config.shard_footers.then(|| stripe.shard_footer(&path, index).in_stripe(number, stripes.len()));
+            let kind = ShardKind::of(index, record.data_shards);
+            if let Err(e) = ctx.store.stage_shard_with_footer(&stripe.file_id, index, kind, shard, footer.as_ref()) {
+                for stripe in &stripes {
+                    ctx.store.discard_staged(&stripe.file_id, total)?;
+                }
+                metadata::finish_operation(&ctx.db, &path)?;
+                return Err(e);
+            }
+        }
+    }
+    if let Some(old) = metadata::get_file_metadata(&ctx.db, &path)? {
+        protector::remove_all_shards(ctx, &old)?;
+    }
+    for stripe in &stripes {
+        ctx.store.commit_staged(&stripe.file_id, total)?;
+    }
+    metadata::store_file_metadata(&ctx.db, &path, &record)?;
+    metadata::finish_operation(&ctx.db, &path)?;
+    tracing::info!("Stored replica of {}", metadata::display_path(&path));
+    Ok(())
+}
+
+/// Paths, as raw bytes from [`metadata::path_key`], whose stored shards a
+/// peer is asked to checksum through `POST /api/replication/checksums`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChecksumRequest {
+    pub paths: Vec<Vec<u8>>,
+}
+
+/// Checksums of the shards actually stored for each requested path, `None`
+/// for a path without a record and for missing shards.
+pub type ShardChecksums = Vec<Option<Vec<Option<String>>>>;
+
+/// Paths compared per request to a peer.
+const VERIFY_BATCH: usize = 256;
+
+/// Hashes the shards stored for each path, reading them from disk so the
+/// comparison sees damage a cached copy would hide.
+pub fn shard_checksums(ctx: &AppContext, request: &ChecksumRequest) -> Result<ShardChecksums> {
+    let mut checksums = Vec::with_capacity(request.paths.len());
+    for key in &request.paths {
+        let path = metadata::path_from_key(key);
+        let _lock = ctx.file_locks.lock(&path);
+        let Some(record) = metadata::get_file_metadata(&ctx.db, &path)? else {
+            checksums.push(None);
+            continue;
+        };
+        let mut shards = Vec::with_capacity(record.total_shards());
+        for stripe in record.stripes() {
+            for index in 0..stripe.total_shards() {
+                let shard = ctx.store.read_shard_uncached(&stripe.file_id, index)?;
+                shards.push(shard.map(|shard| protector::checksum(&shard)));
+            }
+        }
+        checksums.push(Some(shards));
+    }
+    Ok(checksums)
+}
+
+/// Compares the shard checksums of the local records with what every peer
+/// actually stores, for `replication_verify_sample` random files or all of
+/// them. With `resync`, mismatched files are queued to be pushed again.
+pub async fn verify(ctx: &AppContext, resync: bool) -> Result<ReplicationVerifyReport> {
+    let list_ctx = ctx.clone();
+    let mut files = tokio::task::spawn_blocking(move || metadata::list_files(&list_ctx.db)).await??;
+    files.retain(|(_, record)| record.deleted_at.is_none());
+    let sample = ctx.config.replication_verify_sample;
+    if sample > 0 && files.len() > sample {
+        // Order by a hash salted with the time, which shuffles the files
+        // differently on every run.
+        let salt = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default().to_le_bytes();
+        files.sort_by_cached_key(|(path, _)| {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(&salt);
+            hasher.update(&metadata::path_key(path));
+            *hasher.finalize().as_bytes()
+        });
+        files.truncate(sample);
+    }
+
+    let client = reqwest::Client::new();
+    let mut report = ReplicationVerifyReport { files_checked: files.len() as u64, ..Default::default() };
+    for peer in &ctx.config.replication_peers {
+        let url = format!("{}/api/replication/checksums", peer.trim_end_matches('/'));
+        for batch in files.chunks(VERIFY_BATCH) {
+            let request = ChecksumRequest { paths: batch.iter().map(|(path, _)| metadata::path_key(path)).collect() };
+            let remote = match fetch_checksums(ctx, &client, &url, &request).await {
+                Ok(remote) if remote.len() == batch.len() => remote,
+                Ok(_) => {
+                    report.unreachable_peers.push(format!("{}: answered for the wrong number of files", peer));
+                    break;
+                }
+                Err(e) => {
+                    report.unreachable_peers.push(format!("{}: {}", peer, e));
+                    break;
+                }
+            };
+            for ((path, record), remote) in batch.iter().zip(remote) {
+                let Some(mismatch) = compare(peer, path, record, remote) else {
+                    continue;
+                };
+                tracing::warn!(
+                    "Replica of {} on {} differs in shards {:?}",
+                    metadata::display_path(path), peer, mismatch.shards
+                );
+                if resync {
+                    ctx.replication.enqueue_for(peer, path);
+                    report.resynced += 1;
+                }
+                report.mismatches.push(mismatch);
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn fetch_checksums(
+    ctx: &AppContext,
+    client: &reqwest::Client,
+    url: &str,
+    request: &ChecksumRequest,
+) -> Result<ShardChecksums> {
+    let response = authorized(ctx, client.post(url)).json(request).send().await?;
+    if !response.status().is_success() {
+        bail!("answered {}", response.status());
+    }
+    Ok(response.json().await?)
+}
+
+/// Adds our `api_token`, which peers require on the replication endpoints.
+fn authorized(ctx: &AppContext, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match &ctx.config.api_token {
+        Some(token) => request.bearer_auth(&token.0),
+        None => request,
+    }
+}
+
+fn compare(peer: &str, path: &Path, record: &FileRecord, remote: Option<Vec<Option<String>>>) -> Option<ReplicaMismatch> {
+    let expected = all_shard_checksums(record);
+    let (missing, shards) = match remote {
+        None => (true, (0..expected.len()).collect()),
+        Some(remote) => {
+            let shards: Vec<usize> = (0..expected.len())
+                .filter(|&index| remote.get(index).cloned().flatten().as_ref() != Some(&expected[index]))
+                .collect();
+            if shards.is_empty() {
+                return None;
+            }
+            (false, shards)
+        }
+    };
+    Some(ReplicaMismatch { peer: peer.to_string(), path: metadata::display_path(path), missing, shards })
+}
+
+/// The shard checksums of every stripe of `record`, in the order shards are
+/// numbered in a [`ReplicaPayload`].
+fn all_shard_checksums(record: &FileRecord) -> Vec<String> {
+    record.stripes().into_iter().flat_map(|stripe| stripe.shard_checksums).collect()
+}
@@ -0,0 +1,121 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+use shared::CheckRun;
+use crate::checker::{FileCheck, Finding};
+use crate::metadata;
+
+/// Machine-readable formats for integrity reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    JUnit,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            "junit" => Ok(ReportFormat::JUnit),
+            other => bail!("unknown report format `{}`, expected `json` or `junit`", other),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    project: &'static str,
+    generated_at: &'a str,
+    summary: Summary,
+    results: Vec<JsonResult>,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    total: u64,
+    passed: u64,
+    failed: u64,
+    drifted: u64,
+}
+
+#[derive(Serialize)]
+struct JsonResult {
+    path: String,
+    /// False only for corrupted files; drifted files need a re-protect, not a repair.
+    success: bool,
+    status: &'static str,
+    error_message: Option<String>,
+}
+
+/// Renders the outcome of a check as a report in `format`.
+pub fn render(format: ReportFormat, run: &CheckRun, files: &[FileCheck]) -> Result<String> {
+    match format {
+        ReportFormat::Json => render_json(run, files),
+        ReportFormat::JUnit => Ok(render_junit(run, files)),
+    }
+}
+
+fn render_json(run: &CheckRun, files: &[FileCheck]) -> Result<String> {
+    let report = JsonReport {
+        project: "rs_guard",
+        generated_at: &run.finished,
+        summary: Summary {
+            total: run.checked,
+            passed: run.checked - run.corrupted - run.drifted,
+            failed: run.corrupted,
+            drifted: run.drifted,
+        },
+        results: files
+            .iter()
+            .map(|file| JsonResult {
+                path: metadata::display_path(&file.path),
+                success: !file.is_corrupted(),
+                status: match file.finding {
+                    None => "ok",
+                    Some(Finding::Corrupted(_)) => "corrupted",
+                    Some(Finding::Drifted(_)) => "drifted",
+                },
+                error_message: file.finding.as_ref().map(|finding| finding.message().to_string()),
+            })
+            .collect(),
+    };
+    Ok(serde_json::to_string_pretty(&report)?)
+}
+
+/// One `testcase` per protected file in a single `integrity` suite.
+fn render_junit(run: &CheckRun, files: &[FileCheck]) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"rs_guard\" tests=\"{}\" failures=\"{}\" errors=\"0\" timestamp=\"{}\">\n",
+        run.checked, run.corrupted, xml_escape(&run.finished)
+    );
+    xml.push_str(&format!(
+        "    <testsuite name=\"integrity\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\">\n",
+        run.checked, run.corrupted, run.drifted
+    ));
+    for file in files {
+        let name = xml_escape(&metadata::display_path(&file.path));
+        let child = match &file.finding {
+            None => {
+                xml.push_str(&format!("        <testcase name=\"{}\" classname=\"integrity\"/>\n", name));
+                continue;
+            }
+            Some(Finding::Corrupted(problem)) => format!("<failure message=\"{}\"/>", xml_escape(problem)),
+            // Drift is not a failure of the protected data.
+            Some(Finding::Drifted(problem)) => format!("<skipped message=\"{}\"/>", xml_escape(problem)),
+        };
+        xml.push_str(&format!(
+            "        <testcase name=\"{}\" classname=\"integrity\">\n            {}\n        </testcase>\n",
+            name, child
+        ));
+    }
+    xml.push_str("    </testsuite>\n</testsuites>\n");
+    xml
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,159 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use crate::encoder::RSEncoder;
+use crate::shard_store::{ShardFooter, ShardHeader};
+use crate::{protector, scanner, sparse};
+
+/// Outcome of [`recover_from_shards`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SalvageSummary {
+    /// Files rebuilt, as written under the output directory.
+    pub recovered: Vec<PathBuf>,
+    /// Files that could not be rebuilt: their original path and why.
+    pub failed: Vec<(String, String)>,
+    /// Shard files skipped for lacking a valid footer or failing its checksum.
+    pub unusable_shards: u64,
+}
+
+/// The shards found for one version of one stripe of a file.
+struct Group {
+    footer: ShardFooter,
+    shards: Vec<Option<Vec<u8>>>,
+}
+
+/// Rebuilds every file whose shards under `shard_dir` carry footers (see
+/// `shard_footers`), without the metadata DB. Each file is written below
+/// `output` at its original path, e.g. `/data/a.txt` to `output/data/a.txt`.
+///
+/// Shards that fail the checksum in their footer are treated as lost, so a
+/// file is recovered as long as enough of its shards survive.
+pub fn recover_from_shards(shard_dir: &Path, output: &Path) -> Result<SalvageSummary> {
+    let mut files = Vec::new();
+    scanner::collect_files(shard_dir, &mut files)?;
+    let mut summary = SalvageSummary::default();
+    // Keyed by the file's id, stripe and content checksum, so leftovers of
+    // an older version of a file are not mixed with the current shards.
+    let mut groups: BTreeMap<(String, usize, String), Group> = BTreeMap::new();
+    for file in files {
+        if file.file_name().is_some_and(|name| name.to_string_lossy().contains(".staged")) {
+            continue;
+        }
+        let bytes = fs::read(&file)?;
+        let payload = ShardHeader::decode(&bytes).map_or(&bytes[..], |(_, payload)| payload);
+        let Some((payload, footer)) = ShardFooter::decode(payload) else {
+            summary.unusable_shards += 1;
+            continue;
+        };
+        let total = footer.data_shards + footer.parity_shards;
+        if footer.index >= total || protector::checksum(payload) != footer.shard_checksum {
+            summary.unusable_shards += 1;
+            continue;
+        }
+        // Stripes after the first have ids of the form `<file id>.a<n>`.
+        let file_id = footer.file_id.split('.').next().unwrap_or_default().to_string();
+        let key = (file_id, footer.stripe, footer.checksum.clone());
+        let index = footer.index;
+        let group = groups.entry(key).or_insert_with(|| Group { footer, shards: vec![None; total] });
+        group.shards[index] = Some(payload.to_vec());
+    }
+
+    let mut files: BTreeMap<String, Vec<Group>> = BTreeMap::new();
+    for ((file_id, _, _), group) in groups {
+        files.entry(file_id).or_default().push(group);
+    }
+    for (_, groups) in files {
+        // A file that was never striped, as every older one, has only stripe 0.
+        let striped = groups.iter().any(|group| group.footer.stripe > 0 || group.footer.stripes > 1);
+        if !striped {
+            for group in groups {
+                let path = group.footer.path.clone();
+                match rebuild(group, output) {
+                    Ok(out) => summary.recovered.push(out),
+                    Err(e) => summary.failed.push((path, e.to_string())),
+                }
+            }
+            continue;
+        }
+        let path = groups[0].footer.path.clone();
+        match rebuild_striped(groups, output) {
+            Ok(out) => summary.recovered.push(out),
+            Err(e) => summary.failed.push((path, e.to_string())),
+        }
+    }
+    Ok(summary)
+}
+
+fn rebuild(group: Group, output: &Path) -> Result<PathBuf> {
+    let data = decode(group.shards, &group.footer)?;
+    let footer = &group.footer;
+    let out = output_path(output, &footer.path)?;
+    match &footer.data_extents {
+        Some(extents) => sparse::write_sparse(&out, &data, extents, footer.size)?,
+        None => fs::write(&out, data)?,
+    }
+    Ok(out)
+}
+
+/// Rebuilds a file encoded in stripes by joining them in order, each
+/// checked against its own footer. Every stripe up to the highest count in
+/// the footers must be recoverable; where leftovers of an older version
+/// give a stripe several candidates, the first that decodes is used.
+fn rebuild_striped(groups: Vec<Group>, output: &Path) -> Result<PathBuf> {
+    let count = groups.iter().map(|group| group.footer.stripes.max(group.footer.stripe + 1)).max().unwrap_or(1);
+    let out = output_path(output, &groups[0].footer.path)?;
+    let mut file = fs::File::create(&out)?;
+    for stripe in 0..count {
+        let mut last_error = anyhow!("no shards of stripe {} of {} were found", stripe, count);
+        let mut data = None;
+        for group in groups.iter().filter(|group| group.footer.stripe == stripe) {
+            match decode(group.shards.clone(), &group.footer) {
+                Ok(decoded) => {
+                    data = Some(decoded);
+                    break;
+                }
+                Err(e) => last_error = e.context(format!("stripe {} of {}", stripe, count)),
+            }
+        }
+        let Some(data) = data else {
+            drop(file);
+            fs::remove_file(&out)?;
+            return Err(last_error);
+        };
+        file.write_all(&data)?;
+    }
+    Ok(out)
+}
+
+/// Reconstructs the content described by `footer` from its shards and
+/// checks it against the checksum in the footer.
+fn decode(mut shards: Vec<Option<Vec<u8>>>, footer: &ShardFooter) -> Result<Vec<u8>> {
+    let encoder = RSEncoder::with_field(footer.data_shards, footer.parity_shards, footer.parity_algorithm, footer.field)?;
+    encoder.reconstruct(&mut shards)?;
+    let shards: Vec<Vec<u8>> = shards.into_iter().map(Option::unwrap_or_default).collect();
+    let data = encoder.join(&shards, footer.encoded_len);
+    let checksum = match &footer.data_extents {
+        Some(extents) => sparse::hash_logical(&data, extents, footer.size).to_hex().to_string(),
+        None => protector::checksum(&data),
+    };
+    if checksum != footer.checksum {
+        bail!("reconstructed content does not match the checksum in the footers");
+    }
+    Ok(data)
+}
+
+/// Where the file at `path` is written below `output`, creating its parent.
+fn output_path(output: &Path, path: &str) -> Result<PathBuf> {
+    // Only the normal components, so a footer cannot direct the write outside `output`.
+    let relative: PathBuf = Path::new(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    let out = output.join(relative);
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(out)
+}
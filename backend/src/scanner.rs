@@ -0,0 +1,370 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::field;
+use shared::{RebuildResult, ServiceStatus};
+use crate::backlog::Backlog;
+use crate::config::ScanOrder;
+use crate::workers::Work;
+use crate::{disk, filter, metadata, protector, AppContext};
+
+/// Outcome of a pass over the watched directories.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScanSummary {
+    /// Eligible files found on disk.
+    pub files_seen: u64,
+    /// Files that were (re-)encoded during this pass.
+    pub files_protected: u64,
+    /// Files that could not be encoded.
+    pub files_failed: u64,
+    /// Files left unencoded because a preflight check failed, or because
+    /// they were open for writing.
+    pub files_deferred: u64,
+}
+
+/// Walks every watched directory and protects files that are new or have
+/// changed since they were last encoded.
+pub fn scan_directories(ctx: &AppContext) -> Result<ScanSummary> {
+    scan(ctx, None)
+}
+
+/// Scans only `dir`, a directory just added to the watched ones, as
+/// [`scan_directories`] would. Its files are added to the status's total.
+pub fn scan_directory(ctx: &AppContext, dir: &Path) -> Result<ScanSummary> {
+    scan(ctx, Some(dir))
+}
+
+fn scan(ctx: &AppContext, only: Option<&Path>) -> Result<ScanSummary> {
+    let roots = match only {
+        Some(dir) => vec![dir.to_path_buf()],
+        None => ctx.watched.list(),
+    };
+    let span = tracing::trace_span!(
+        "scan",
+        roots = roots.len(),
+        files = field::Empty,
+        protected = field::Empty,
+        duration_ms = field::Empty,
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+    let summary = scan_watched(ctx, roots, only.is_none());
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    if let Ok(summary) = &summary {
+        span.record("files", summary.files_seen);
+        span.record("protected", summary.files_protected);
+    }
+    summary
+}
+
+/// Scans `roots`; `all` when they are every watched directory, so the
+/// status's file total can be replaced rather than added to.
+fn scan_watched(ctx: &AppContext, roots: Vec<PathBuf>, all: bool) -> Result<ScanSummary> {
+    ctx.status.lock().unwrap().status = ServiceStatus::Scanning;
+    ctx.publish_status();
+    let mut summary = ScanSummary::default();
+
+    let mut pending = Vec::new();
+    // Canonical paths already seen, so hardlinks to one inode count once.
+    let mut seen = HashSet::new();
+    for root in roots {
+        let mut files = Vec::new();
+        if let Err(e) = collect_files(&root, &mut files) {
+            tracing::error!("Failed to scan {}: {}", metadata::display_path(&root), e);
+            continue;
+        }
+        for path in files.into_iter().filter(|p| filter::should_protect(&ctx.config, &root, p)) {
+            let path = match protector::canonical_path(ctx, &path) {
+                Ok(path) => path,
+                Err(e) => {
+                    summary.files_failed += 1;
+                    tracing::error!("Failed to inspect {}: {}", metadata::display_path(&path), e);
+                    continue;
+                }
+            };
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            summary.files_seen += 1;
+            match protector::needs_protection(ctx, &path) {
+                Ok(true) => pending.push(path),
+                Ok(false) => {}
+                Err(e) => {
+                    summary.files_failed += 1;
+                    tracing::error!("Failed to inspect {}: {}", metadata::display_path(&path), e);
+                }
+            }
+        }
+    }
+
+    let low_inodes = !inode_preflight(ctx, pending.len() as u64);
+    if low_inodes {
+        summary.files_deferred = pending.len() as u64;
+        pending.clear();
+    }
+
+    order_pending(ctx.config.initial_scan_order, &mut pending);
+    let mut backlog = Backlog::new(pending.iter().map(|path| file_size(path)).sum());
+    let mut queued = ctx.workers.queue(Work::Encode, pending.len());
+    for path in pending {
+        queued.next();
+        match encode_step(ctx, &mut backlog, &path, || protector::protect_file(ctx, &path)) {
+            Ok(_) => summary.files_protected += 1,
+            Err(e) if e.is::<protector::OpenForWrite>() => summary.files_deferred += 1,
+            Err(e) => {
+                summary.files_failed += 1;
+                tracing::error!("Failed to protect {}: {}", metadata::display_path(&path), e);
+            }
+        }
+    }
+
+    mark_vanished_files(ctx)?;
+
+    let protected = metadata::count_files(&ctx.db)?;
+    let mut status = ctx.status.lock().unwrap();
+    status.total_files = match all {
+        true => summary.files_seen,
+        false => status.total_files + summary.files_seen,
+    };
+    status.protected_files = protected;
+    status.low_inodes = low_inodes;
+    status.scan_eta_secs = None;
+    status.status = ServiceStatus::Idle;
+    status.logs.push(format!(
+        "[Scanner] {} files seen, {} newly protected, {} failed, {} deferred",
+        summary.files_seen, summary.files_protected, summary.files_failed, summary.files_deferred
+    ));
+    drop(status);
+    ctx.publish_status();
+    Ok(summary)
+}
+
+/// The first half of a `startup_scan_mode = "quick"` scan: records the size
+/// and mtime of every new or changed file as tracked, without reading or
+/// encoding it, and returns how many were tracked. [`encode_tracked`] then
+/// protects them in the background.
+pub fn quick_scan(ctx: &AppContext) -> Result<u64> {
+    ctx.status.lock().unwrap().status = ServiceStatus::Scanning;
+    ctx.publish_status();
+    let mut seen = HashSet::new();
+    let mut tracked = 0;
+    for root in ctx.watched.list() {
+        let mut files = Vec::new();
+        if let Err(e) = collect_files(&root, &mut files) {
+            tracing::error!("Failed to scan {}: {}", metadata::display_path(&root), e);
+            continue;
+        }
+        for path in files.into_iter().filter(|p| filter::should_protect(&ctx.config, &root, p)) {
+            let path = protector::canonical_path(ctx, &path).unwrap_or(path);
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            match protector::needs_protection(ctx, &path).and_then(|needed| Ok((needed, fs::metadata(&path)?))) {
+                Ok((true, meta)) => {
+                    let entry = metadata::TrackedFile { size: meta.len(), mtime: protector::modified_secs(&meta) };
+                    metadata::track_file(&ctx.db, &path, &entry)?;
+                    tracked += 1;
+                }
+                Ok((false, _)) => {}
+                Err(e) => tracing::error!("Failed to inspect {}: {}", metadata::display_path(&path), e),
+            }
+        }
+    }
+    let mut status = ctx.status.lock().unwrap();
+    status.total_files = seen.len() as u64;
+    status.status = ServiceStatus::Idle;
+    status.logs.push(format!("[Scanner] {} files seen, {} tracked for encoding", seen.len(), tracked));
+    drop(status);
+    ctx.publish_status();
+    Ok(tracked)
+}
+
+/// Encodes the files a [`quick_scan`] tracked, in `initial_scan_order`.
+/// Files gone in the meantime are dropped from the list.
+pub fn encode_tracked(ctx: &AppContext) -> Result<ScanSummary> {
+    let mut summary = ScanSummary::default();
+    let mut pending = Vec::new();
+    for (path, _) in metadata::list_tracked(&ctx.db)? {
+        if path.is_file() {
+            pending.push(path);
+        } else {
+            metadata::untrack_file(&ctx.db, &path)?;
+        }
+    }
+    summary.files_seen = pending.len() as u64;
+    order_pending(ctx.config.initial_scan_order, &mut pending);
+    let mut backlog = Backlog::new(pending.iter().map(|path| file_size(path)).sum());
+    let mut queued = ctx.workers.queue(Work::Encode, pending.len());
+    for path in pending {
+        queued.next();
+        match encode_step(ctx, &mut backlog, &path, || protector::protect_if_changed(ctx, &path)) {
+            Ok(Some(_)) => summary.files_protected += 1,
+            // Encoded by the watcher already.
+            Ok(None) => metadata::untrack_file(&ctx.db, &path)?,
+            Err(e) if e.is::<protector::OpenForWrite>() => summary.files_deferred += 1,
+            Err(e) => {
+                summary.files_failed += 1;
+                tracing::error!("Failed to protect {}: {}", metadata::display_path(&path), e);
+            }
+        }
+    }
+    let protected = metadata::count_files(&ctx.db)?;
+    let mut status = ctx.status.lock().unwrap();
+    status.protected_files = protected;
+    status.scan_eta_secs = None;
+    drop(status);
+    ctx.publish_status();
+    Ok(summary)
+}
+
+/// Drops the protection of every file recorded under `dir`, then encodes
+/// every eligible file below it from scratch with the current config, e.g.
+/// after a disk was replaced. Progress goes to the status and its log.
+pub fn rebuild_directory(ctx: &AppContext, dir: &Path) -> Result<RebuildResult> {
+    ctx.status.lock().unwrap().status = ServiceStatus::Scanning;
+    ctx.publish_status();
+    let rebuilt = rebuild(ctx, dir);
+    let protected = metadata::count_files(&ctx.db)?;
+    let mut status = ctx.status.lock().unwrap();
+    status.status = ServiceStatus::Idle;
+    status.scan_eta_secs = None;
+    if let Ok(result) = &rebuilt {
+        status.protected_files = protected;
+        status.logs.push(format!(
+            "[Rebuild] {}: {} removed, {} protected, {} failed",
+            result.path, result.removed, result.protected, result.failed
+        ));
+    }
+    drop(status);
+    ctx.publish_status();
+    rebuilt
+}
+
+/// Drops the shards, retained versions and records of every protected file
+/// below `dir`, returning how many there were.
+pub fn purge_directory(ctx: &AppContext, dir: &Path) -> Result<u64> {
+    let mut purged = 0;
+    for (path, record) in metadata::list_files(&ctx.db)?.into_iter().filter(|(path, _)| path.starts_with(dir)) {
+        let _lock = ctx.file_locks.lock(&path);
+        protector::remove_protection(ctx, &path, &record)?;
+        purged += 1;
+    }
+    Ok(purged)
+}
+
+fn rebuild(ctx: &AppContext, dir: &Path) -> Result<RebuildResult> {
+    let mut result = RebuildResult { path: metadata::display_path(dir), ..Default::default() };
+    result.removed = purge_directory(ctx, dir)?;
+    ctx.status.lock().unwrap().logs.push(format!("[Rebuild] {}: dropped {} files' shards", result.path, result.removed));
+
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    let mut pending = Vec::new();
+    let mut seen = HashSet::new();
+    let root = ctx.watched.root_of(dir).unwrap_or_else(|| dir.to_path_buf());
+    for path in files.into_iter().filter(|p| filter::should_protect(&ctx.config, &root, p)) {
+        let path = protector::canonical_path(ctx, &path).unwrap_or(path);
+        if seen.insert(path.clone()) {
+            pending.push(path);
+        }
+    }
+    let total = pending.len();
+    let mut backlog = Backlog::new(pending.iter().map(|path| file_size(path)).sum());
+    let mut queued = ctx.workers.queue(Work::Encode, total);
+    for (done, path) in pending.into_iter().enumerate() {
+        queued.next();
+        match encode_step(ctx, &mut backlog, &path, || protector::protect_file(ctx, &path)) {
+            Ok(_) => result.protected += 1,
+            Err(e) => {
+                tracing::error!("Failed to protect {}: {}", metadata::display_path(&path), e);
+                result.failed += 1;
+                result.failed_paths.push(metadata::display_path(&path));
+            }
+        }
+        if (done + 1) % REBUILD_PROGRESS_EVERY == 0 {
+            ctx.status.lock().unwrap().logs.push(format!("[Rebuild] {}: {}/{} files encoded", result.path, done + 1, total));
+        }
+    }
+    Ok(result)
+}
+
+/// Files a rebuild encodes between progress lines in the log.
+const REBUILD_PROGRESS_EVERY: usize = 100;
+
+/// Runs the encode of one file of `backlog` and publishes the new ETA.
+fn encode_step<T>(ctx: &AppContext, backlog: &mut Backlog, path: &Path, encode: impl FnOnce() -> T) -> T {
+    let size = file_size(path);
+    let started = Instant::now();
+    let outcome = encode();
+    backlog.done(size, started.elapsed());
+    ctx.status.lock().unwrap().scan_eta_secs = backlog.eta_secs();
+    ctx.publish_status();
+    outcome
+}
+
+/// Size of a file to encode; one that cannot be stat'ed counts as empty,
+/// as its encode will report the error.
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|meta| meta.len()).unwrap_or(0)
+}
+
+/// Sorts the files to encode by size as `order` asks.
+fn order_pending(order: ScanOrder, pending: &mut [PathBuf]) {
+    let size = |path: &PathBuf| file_size(path);
+    match order {
+        ScanOrder::SmallestFirst => pending.sort_by_cached_key(size),
+        ScanOrder::LargestFirst => pending.sort_by_cached_key(|path| std::cmp::Reverse(size(path))),
+        ScanOrder::Discovery => {}
+    }
+}
+
+/// Checks that encoding `files` more files leaves the configured inode
+/// reserve free in the shard store. Returns true when encoding may proceed.
+fn inode_preflight(ctx: &AppContext, files: u64) -> bool {
+    if !ctx.config.check_free_inodes || files == 0 {
+        return true;
+    }
+    let new_inodes = files * (ctx.config.data_shards + ctx.config.parity_shards) as u64;
+    match disk::stat(ctx.store.root()) {
+        Ok(Some(stats)) if !disk::has_free_inodes(&stats, new_inodes, ctx.config.inode_reserve) => {
+            tracing::warn!(
+                "Shard store has {} free inodes; encoding {} files needs {} plus a reserve of {}. Skipping.",
+                stats.free_inodes, files, new_inodes, ctx.config.inode_reserve
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            tracing::warn!("Could not query free inodes of the shard store: {}", e);
+            true
+        }
+    }
+}
+
+/// Marks records under a watched root whose source no longer exists as deleted,
+/// catching removals that happened while the watcher was not running.
+fn mark_vanished_files(ctx: &AppContext) -> Result<()> {
+    for (path, record) in metadata::list_files(&ctx.db)? {
+        let watched = ctx.watched.root_of(&path).is_some();
+        if watched && record.deleted_at.is_none() && !path.exists() {
+            metadata::mark_deleted(&ctx.db, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively collects regular files below `dir`. Symlinks are not followed.
+pub fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
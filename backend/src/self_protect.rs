@@ -0,0 +1,74 @@
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::config::AppConfig;
+use crate::{metadata, protector, AppContext, Guard};
+
+/// Subdirectory of the shard store holding rs_guard's own files.
+pub const SELF_DIR: &str = "self";
+/// Name of the metadata export protected with `protect_self`.
+pub const EXPORT_NAME: &str = "metadata.json";
+
+/// Where the shards, DB and metadata export of `protect_self` live.
+pub fn self_dir(config: &AppConfig) -> PathBuf {
+    config.shard_store_dir.join(SELF_DIR)
+}
+
+/// Opens the guard that protects the config file and metadata export.
+///
+/// It keeps its own DB and shard directory apart from the main ones, so
+/// protecting the metadata never feeds back into it, and writes shard
+/// footers, so `recover-from-shards` on the shard directory brings both
+/// back even when every DB is lost.
+pub fn open(config: &AppConfig) -> Result<Guard> {
+    let dir = self_dir(config);
+    fs::create_dir_all(&dir)?;
+    let self_config = AppConfig {
+        watched_directories: Vec::new(),
+        shard_store_dir: dir.join("shards"),
+        data_shards: config.data_shards,
+        parity_shards: config.parity_shards,
+        parity_algorithm: config.parity_algorithm,
+        shard_footers: true,
+        ..Default::default()
+    };
+    Guard::open(self_config, dir.join("meta.db"))
+}
+
+/// Exports the metadata DB of `ctx` and protects the export and the config
+/// file at `config_path` with `guard`, re-encoding whichever changed.
+/// Returns the paths of both.
+pub fn protect(guard: &Guard, ctx: &AppContext, config_path: &Path) -> Result<Vec<PathBuf>> {
+    let export = self_dir(&ctx.config).join(EXPORT_NAME);
+    let tmp = export.with_extension("json.tmp");
+    fs::write(&tmp, metadata::export_json(&ctx.db)?)?;
+    fs::rename(&tmp, &export)?;
+    let mut protected = Vec::new();
+    for path in [config_path, export.as_path()] {
+        let path = fs::canonicalize(path)?;
+        if protector::protect_if_changed(guard.context(), &path)?.is_some() {
+            tracing::info!("Protected {}", metadata::display_path(&path));
+        }
+        protected.push(path);
+    }
+    Ok(protected)
+}
+
+/// Protects the config file and a metadata export now and then every
+/// `period`.
+pub fn spawn(ctx: AppContext, config_path: PathBuf, period: Duration) -> Result<tokio::task::JoinHandle<()>> {
+    let guard = open(&ctx.config)?;
+    Ok(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let (guard, ctx, config_path) = (guard.clone(), ctx.clone(), config_path.clone());
+            match tokio::task::spawn_blocking(move || protect(&guard, &ctx, &config_path)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::error!("Protecting rs_guard's own files failed: {}", e),
+                Err(e) => tracing::error!("Self-protection task panicked: {}", e),
+            }
+        }
+    }))
+}
@@ -0,0 +1,60 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Counters since startup, logged as a parting summary on shutdown.
+pub struct Session {
+    started: Instant,
+    files_protected: AtomicU64,
+    checks_run: AtomicU64,
+    corruptions_found: AtomicU64,
+    shards_repaired: AtomicU64,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            files_protected: AtomicU64::new(0),
+            checks_run: AtomicU64::new(0),
+            corruptions_found: AtomicU64::new(0),
+            shards_repaired: AtomicU64::new(0),
+        }
+    }
+}
+
+/// What [`Session`] counted, as logged on shutdown.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub files_protected: u64,
+    pub checks_run: u64,
+    /// Files that turned corrupted between checks.
+    pub corruptions_found: u64,
+    pub shards_repaired: u64,
+    pub uptime_secs: u64,
+}
+
+impl Session {
+    pub fn file_protected(&self) {
+        self.files_protected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn check_run(&self, newly_corrupted: usize) {
+        self.checks_run.fetch_add(1, Ordering::Relaxed);
+        self.corruptions_found.fetch_add(newly_corrupted as u64, Ordering::Relaxed);
+    }
+
+    pub fn shard_repaired(&self) {
+        self.shards_repaired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary {
+            files_protected: self.files_protected.load(Ordering::Relaxed),
+            checks_run: self.checks_run.load(Ordering::Relaxed),
+            corruptions_found: self.corruptions_found.load(Ordering::Relaxed),
+            shards_repaired: self.shards_repaired.load(Ordering::Relaxed),
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
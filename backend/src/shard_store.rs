@@ -0,0 +1,818 @@
+use anyhow::Result;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::encoder::{GaloisField, ParityAlgorithm};
+use crate::sparse::Extent;
+
+type ShardKey = (String, usize);
+
+/// Leading bytes of every shard file written with headers.
+const MAGIC: &[u8; 4] = b"RSGS";
+const FORMAT_VERSION: u8 = 1;
+/// Extension of shard files written with headers.
+pub const SHARD_EXTENSION: &str = "rsguard";
+/// Top-level directory of the store taking new shards whose usual location
+/// is degraded. See [`ShardStore::with_error_threshold`].
+pub const RELOCATED_DIR: &str = "relocated";
+
+/// Whether a shard holds original data or parity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardKind {
+    Data,
+    Parity,
+}
+
+impl ShardKind {
+    pub fn of(index: usize, data_shards: usize) -> Self {
+        if index < data_shards { ShardKind::Data } else { ShardKind::Parity }
+    }
+}
+
+/// Self-description stored at the start of a shard file, so shards can be
+/// recognised and attributed even without the metadata DB.
+///
+/// Layout: magic (4) | version (1) | kind (1) | index (u32 BE) |
+/// file id length (u16 BE) | file id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardHeader {
+    pub file_id: String,
+    pub index: usize,
+    pub kind: ShardKind,
+}
+
+impl ShardHeader {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(header_len(&self.file_id));
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(match self.kind {
+            ShardKind::Data => 0,
+            ShardKind::Parity => 1,
+        });
+        bytes.extend_from_slice(&(self.index as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.file_id.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.file_id.as_bytes());
+        bytes
+    }
+
+    /// Splits a shard file into its header and payload. Returns `None` if
+    /// the file does not start with a valid header.
+    pub fn decode(bytes: &[u8]) -> Option<(ShardHeader, &[u8])> {
+        if bytes.get(..4)? != MAGIC || *bytes.get(4)? != FORMAT_VERSION {
+            return None;
+        }
+        let kind = match bytes.get(5)? {
+            0 => ShardKind::Data,
+            1 => ShardKind::Parity,
+            _ => return None,
+        };
+        let index = u32::from_be_bytes(bytes.get(6..10)?.try_into().ok()?) as usize;
+        let id_len = u16::from_be_bytes(bytes.get(10..12)?.try_into().ok()?) as usize;
+        let file_id = std::str::from_utf8(bytes.get(12..12 + id_len)?).ok()?.to_string();
+        Some((ShardHeader { file_id, index, kind }, &bytes[12 + id_len..]))
+    }
+}
+
+fn header_len(file_id: &str) -> usize {
+    12 + file_id.len()
+}
+
+/// Trailing bytes of every shard file written with a footer.
+const FOOTER_MAGIC: &[u8; 4] = b"RSGF";
+
+/// Everything needed to rebuild a file from its shards alone, stored at the
+/// end of each shard file when `shard_footers` is enabled. Last-resort
+/// recovery uses it when the metadata DB is lost; see `salvage`.
+///
+/// Layout: payload | footer as JSON | JSON length (u32 LE) | magic (4).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardFooter {
+    pub file_id: String,
+    /// Path of the protected file, as displayed.
+    pub path: String,
+    pub index: usize,
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub parity_algorithm: ParityAlgorithm,
+    #[serde(default)]
+    pub field: GaloisField,
+    /// Length of the encoded content; the data extents only for sparse files.
+    pub encoded_len: usize,
+    /// Length of the original file.
+    pub size: u64,
+    pub data_extents: Option<Vec<Extent>>,
+    /// Checksum of the original file's content.
+    pub checksum: String,
+    /// Checksum of this shard's payload.
+    pub shard_checksum: String,
+    /// Number of the stripe the shard belongs to, 0 for the first. The
+    /// fields above then describe that stripe alone, and `file_id` is the
+    /// stripe's own.
+    #[serde(default)]
+    pub stripe: usize,
+    /// Stripes the file had when the shard was written.
+    #[serde(default = "single_stripe")]
+    pub stripes: usize,
+}
+
+fn single_stripe() -> usize {
+    1
+}
+
+impl ShardFooter {
+    /// Marks the footer as that of a shard of stripe `stripe` of `stripes`.
+    pub fn in_stripe(self, stripe: usize, stripes: usize) -> Self {
+        Self { stripe, stripes, ..self }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = serde_json::to_vec(self).expect("footer serializes");
+        bytes.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(FOOTER_MAGIC);
+        bytes
+    }
+
+    /// Splits shard contents into their payload and footer. Returns `None`
+    /// if they do not end with a valid footer.
+    pub fn decode(bytes: &[u8]) -> Option<(&[u8], ShardFooter)> {
+        let json_end = bytes.len().checked_sub(8)?;
+        if &bytes[json_end + 4..] != FOOTER_MAGIC {
+            return None;
+        }
+        let json_len = u32::from_le_bytes(bytes[json_end..json_end + 4].try_into().ok()?) as usize;
+        let payload_end = json_end.checked_sub(json_len)?;
+        let footer = serde_json::from_slice(&bytes[payload_end..json_end]).ok()?;
+        Some((&bytes[..payload_end], footer))
+    }
+}
+
+/// Strips a valid footer, if any, from shard contents.
+fn without_footer(bytes: &[u8]) -> &[u8] {
+    ShardFooter::decode(bytes).map_or(bytes, |(payload, _)| payload)
+}
+
+/// On-disk home of the encoded shards.
+///
+/// Shards are grouped into subdirectories named after leading hex characters
+/// of their file id so that no single directory grows unbounded. By default
+/// there is one level of 256 directories; see [`ShardStore::with_dir_layout`].
+/// Recently read shards are kept in a bounded in-memory cache.
+///
+/// With headers enabled (the default), shard files carry a [`ShardHeader`]
+/// and the `.rsguard` extension. Headerless shards written before headers
+/// were enabled are still read.
+pub struct ShardStore {
+    root: PathBuf,
+    headers: bool,
+    cache: Mutex<ShardCache>,
+    disk_reads: AtomicU64,
+    /// Shard reads that failed other than by the shard not existing.
+    read_errors: AtomicU64,
+    write_delay: Duration,
+    read_delay: Duration,
+    /// Hex characters of the file id used per directory level.
+    dir_width: usize,
+    dir_depth: usize,
+    /// Device shards must live on; `None` means the device of `root`.
+    expected_device: Option<u64>,
+    preallocate: bool,
+    /// Read and write errors per top-level directory of the store.
+    location_errors: Mutex<HashMap<PathBuf, u64>>,
+    error_threshold: u64,
+}
+
+/// Hex characters per level and number of levels of the default layout.
+const DEFAULT_DIR_LAYOUT: (usize, usize) = (2, 1);
+
+/// LRU cache of shard contents bounded by total bytes rather than entries.
+struct ShardCache {
+    entries: LruCache<ShardKey, Vec<u8>>,
+    capacity_bytes: usize,
+    used_bytes: usize,
+}
+
+impl ShardCache {
+    fn new(capacity_bytes: usize) -> Self {
+        Self { entries: LruCache::unbounded(), capacity_bytes, used_bytes: 0 }
+    }
+
+    fn get(&mut self, key: &ShardKey) -> Option<Vec<u8>> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: ShardKey, data: Vec<u8>) {
+        if data.len() > self.capacity_bytes {
+            return;
+        }
+        self.remove(&key);
+        self.used_bytes += data.len();
+        self.entries.put(key, data);
+        while self.used_bytes > self.capacity_bytes {
+            match self.entries.pop_lru() {
+                Some((_, evicted)) => self.used_bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &ShardKey) {
+        if let Some(old) = self.entries.pop(key) {
+            self.used_bytes -= old.len();
+        }
+    }
+}
+
+impl ShardStore {
+    /// Opens the store rooted at `root`, creating the directory if needed.
+    /// The shard cache is disabled; see [`ShardStore::with_cache_bytes`].
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            headers: true,
+            cache: Mutex::new(ShardCache::new(0)),
+            disk_reads: AtomicU64::new(0),
+            read_errors: AtomicU64::new(0),
+            write_delay: Duration::ZERO,
+            read_delay: Duration::ZERO,
+            dir_width: DEFAULT_DIR_LAYOUT.0,
+            dir_depth: DEFAULT_DIR_LAYOUT.1,
+            expected_device: None,
+            preallocate: false,
+            location_errors: Mutex::default(),
+            error_threshold: 0,
+        })
+    }
+
+    /// Keeps up to `bytes` of recently read shards in memory.
+    pub fn with_cache_bytes(self, bytes: usize) -> Self {
+        *self.cache.lock().unwrap() = ShardCache::new(bytes);
+        self
+    }
+
+    /// Chooses whether new shards are written with a header and extension.
+    pub fn with_headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Sleeps for `delay` before every shard read from disk, simulating slow storage.
+    pub fn with_read_delay(mut self, delay: Duration) -> Self {
+        self.read_delay = delay;
+        self
+    }
+
+    /// Reserves each shard file's full size with [`preallocate`] before
+    /// writing it, so it is laid out in one piece and a full disk is
+    /// noticed before any data is written.
+    pub fn with_preallocation(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// Marks a top-level directory of the store, such as a mount point for
+    /// one disk, as degraded once `threshold` reads or writes there failed.
+    /// New shards that belong in a degraded directory are placed under
+    /// [`RELOCATED_DIR`] instead; shards already there are still read. Only
+    /// layouts with subdirectories have locations to steer away from. 0
+    /// never marks a location.
+    pub fn with_error_threshold(mut self, threshold: u64) -> Self {
+        self.error_threshold = threshold;
+        self
+    }
+
+    /// Top-level directories of the store marked degraded, sorted.
+    pub fn degraded_locations(&self) -> Vec<PathBuf> {
+        let errors = self.location_errors.lock().unwrap();
+        let mut degraded: Vec<PathBuf> =
+            errors.keys().filter(|location| self.is_degraded_in(&errors, location)).cloned().collect();
+        degraded.sort();
+        degraded
+    }
+
+    fn is_degraded_in(&self, errors: &HashMap<PathBuf, u64>, location: &Path) -> bool {
+        self.error_threshold != 0 && errors.get(location).is_some_and(|&count| count >= self.error_threshold)
+    }
+
+    /// Expects shards on device `dev` instead of the one holding the store's root.
+    pub fn with_expected_device(mut self, dev: u64) -> Self {
+        self.expected_device = Some(dev);
+        self
+    }
+
+    /// Spreads shards over `depth` levels of `fanout` subdirectories each.
+    /// `fanout` must be a power of 16 (16, 256, 4096, ...). Shards stored
+    /// under the default layout are still found after changing it.
+    pub fn with_dir_layout(mut self, fanout: usize, depth: usize) -> Self {
+        self.dir_width = (fanout.trailing_zeros() / 4) as usize;
+        self.dir_depth = depth;
+        self
+    }
+
+    /// Describes the directory layout, e.g. `"2 levels of 16 directories"`.
+    pub fn dir_layout(&self) -> String {
+        match self.dir_depth {
+            0 => "flat".to_string(),
+            depth => {
+                let plural = if depth == 1 { "" } else { "s" };
+                format!("{} level{} of {} directories", depth, plural, 1usize << (4 * self.dir_width))
+            }
+        }
+    }
+
+    /// Sleeps for `delay` before every shard write, simulating slow storage.
+    pub fn with_write_delay(mut self, delay: Duration) -> Self {
+        self.write_delay = delay;
+        self
+    }
+
+    /// Number of shard reads that had to go to disk.
+    pub fn disk_reads(&self) -> u64 {
+        self.disk_reads.load(Ordering::Relaxed)
+    }
+
+    /// Number of shard reads that failed with an I/O error since the last
+    /// [`ShardStore::reset_read_errors`].
+    pub fn read_errors(&self) -> u64 {
+        self.read_errors.load(Ordering::Relaxed)
+    }
+
+    /// Zeroes the read error count, returning what it was.
+    pub fn reset_read_errors(&self) -> u64 {
+        self.read_errors.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where a shard is written.
+    pub fn shard_path(&self, file_id: &str, index: usize) -> PathBuf {
+        if self.headers {
+            self.shard_dir(file_id).join(headered_name(file_id, index))
+        } else {
+            self.shard_dir(file_id).join(legacy_name(file_id, index))
+        }
+    }
+
+    fn shard_dir(&self, file_id: &str) -> PathBuf {
+        let home = self.home_dir(file_id);
+        let location = self.location_of(&home);
+        if self.dir_depth > 0 && self.is_degraded_in(&self.location_errors.lock().unwrap(), &location) {
+            return self.relocated_dir(file_id);
+        }
+        home
+    }
+
+    /// Where the configured layout places a file's shards.
+    fn home_dir(&self, file_id: &str) -> PathBuf {
+        self.layout_dir(file_id, self.dir_width, self.dir_depth)
+    }
+
+    /// Where a file's shards go while their home location is degraded.
+    fn relocated_dir(&self, file_id: &str) -> PathBuf {
+        let home = self.home_dir(file_id);
+        let layout = home.strip_prefix(&self.root).unwrap_or(Path::new(""));
+        self.root.join(RELOCATED_DIR).join(layout)
+    }
+
+    /// The top-level directory of the store containing the shard directory
+    /// `dir`: the root itself for a flat layout.
+    fn location_of(&self, dir: &Path) -> PathBuf {
+        match dir.strip_prefix(&self.root).ok().and_then(|relative| relative.components().next()) {
+            Some(first) => self.root.join(first),
+            None => self.root.clone(),
+        }
+    }
+
+    fn record_error(&self, location: PathBuf) {
+        if self.error_threshold == 0 {
+            return;
+        }
+        let mut errors = self.location_errors.lock().unwrap();
+        let count = errors.entry(location.clone()).or_default();
+        *count += 1;
+        if *count == self.error_threshold {
+            tracing::error!(
+                "Shard location {} failed {} times and is marked degraded; new shards for it go to {}",
+                location.display(),
+                count,
+                self.root.join(RELOCATED_DIR).display()
+            );
+        }
+    }
+
+    fn layout_dir(&self, file_id: &str, width: usize, depth: usize) -> PathBuf {
+        let mut dir = self.root.clone();
+        for level in 0..depth {
+            let start = (level * width).min(file_id.len());
+            dir.push(&file_id[start..(start + width).min(file_id.len())]);
+        }
+        dir
+    }
+
+    /// Directories a shard may live in: its relocated directory if there
+    /// is one, as shards there are newer, then the configured layout, then
+    /// the default one for shards written before the layout was changed.
+    fn candidate_dirs(&self, file_id: &str) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        let relocated = self.relocated_dir(file_id);
+        if self.dir_depth > 0 && relocated.is_dir() {
+            dirs.push(relocated);
+        }
+        dirs.push(self.home_dir(file_id));
+        if (self.dir_width, self.dir_depth) != DEFAULT_DIR_LAYOUT {
+            dirs.push(self.layout_dir(file_id, DEFAULT_DIR_LAYOUT.0, DEFAULT_DIR_LAYOUT.1));
+        }
+        dirs
+    }
+
+    pub fn write_shard(&self, file_id: &str, index: usize, kind: ShardKind, data: &[u8]) -> Result<()> {
+        self.write_shard_with_footer(file_id, index, kind, data, None)
+    }
+
+    /// Like [`ShardStore::write_shard`], appending `footer` to the shard file.
+    pub fn write_shard_with_footer(
+        &self,
+        file_id: &str,
+        index: usize,
+        kind: ShardKind,
+        data: &[u8],
+        footer: Option<&ShardFooter>,
+    ) -> Result<()> {
+        let key = (file_id.to_string(), index);
+        self.cache.lock().unwrap().remove(&key);
+        self.write_file(&self.shard_path(file_id, index), &self.file_contents(file_id, index, kind, data, footer))
+    }
+
+    /// Writes a shard next to its final location without replacing the
+    /// current one. See [`ShardStore::commit_staged`].
+    pub fn stage_shard(&self, file_id: &str, index: usize, kind: ShardKind, data: &[u8]) -> Result<()> {
+        self.stage_shard_with_footer(file_id, index, kind, data, None)
+    }
+
+    /// Like [`ShardStore::stage_shard`], appending `footer` to the shard file.
+    pub fn stage_shard_with_footer(
+        &self,
+        file_id: &str,
+        index: usize,
+        kind: ShardKind,
+        data: &[u8],
+        footer: Option<&ShardFooter>,
+    ) -> Result<()> {
+        self.write_file(&self.staged_path(file_id, index), &self.file_contents(file_id, index, kind, data, footer))
+    }
+
+    fn file_contents(&self, file_id: &str, index: usize, kind: ShardKind, data: &[u8], footer: Option<&ShardFooter>) -> Vec<u8> {
+        let mut contents = if self.headers {
+            ShardHeader { file_id: file_id.to_string(), index, kind }.encode()
+        } else {
+            Vec::new()
+        };
+        contents.extend_from_slice(data);
+        if let Some(footer) = footer {
+            contents.extend_from_slice(&footer.encode());
+        }
+        contents
+    }
+
+    /// Moves the first `count` staged shards of a file into place.
+    pub fn commit_staged(&self, file_id: &str, count: usize) -> Result<()> {
+        for index in 0..count {
+            self.commit_staged_shard(file_id, index)?;
+        }
+        Ok(())
+    }
+
+    /// Moves one staged shard into place, atomically replacing the current one.
+    pub fn commit_staged_shard(&self, file_id: &str, index: usize) -> Result<()> {
+        self.cache.lock().unwrap().remove(&(file_id.to_string(), index));
+        fs::rename(self.staged_path(file_id, index), self.shard_path(file_id, index))?;
+        Ok(())
+    }
+
+    /// Reads back the payload of a staged shard, or `None` if none is staged.
+    pub fn read_staged(&self, file_id: &str, index: usize) -> Result<Option<Vec<u8>>> {
+        let Some(bytes) = read_if_exists(&self.staged_path(file_id, index))? else {
+            return Ok(None);
+        };
+        let payload = match ShardHeader::decode(&bytes) {
+            Some((_, payload)) if self.headers => payload,
+            _ => &bytes[..],
+        };
+        Ok(Some(without_footer(payload).to_vec()))
+    }
+
+    /// Deletes the first `count` staged shards of a file, leaving the
+    /// committed ones untouched. Missing shards are ignored.
+    pub fn discard_staged(&self, file_id: &str, count: usize) -> Result<()> {
+        for index in 0..count {
+            self.discard_staged_shard(file_id, index)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes one staged shard, if there is one.
+    pub fn discard_staged_shard(&self, file_id: &str, index: usize) -> Result<()> {
+        remove_if_exists(&self.staged_path(file_id, index))
+    }
+
+    fn staged_path(&self, file_id: &str, index: usize) -> PathBuf {
+        if self.headers {
+            // Keep the extension so staged shards are recognisable too.
+            self.shard_dir(file_id).join(format!("{}.{}.staged.{}", file_id, index, SHARD_EXTENSION))
+        } else {
+            self.shard_dir(file_id).join(format!("{}.{}.staged", file_id, index))
+        }
+    }
+
+    fn write_file(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let written = self.write_file_unchecked(path, data);
+        if written.is_err() {
+            self.record_error(self.location_of(path.parent().unwrap_or(&self.root)));
+        }
+        written
+    }
+
+    fn write_file_unchecked(&self, path: &Path, data: &[u8]) -> Result<()> {
+        if !self.write_delay.is_zero() {
+            std::thread::sleep(self.write_delay);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if self.preallocate {
+            let mut file = fs::File::create(path)?;
+            preallocate(&file, data.len() as u64)?;
+            file.write_all(data)?;
+        } else {
+            fs::write(path, data)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a shard's payload, returning `None` if it does not exist.
+    pub fn read_shard(&self, file_id: &str, index: usize) -> Result<Option<Vec<u8>>> {
+        let key = (file_id.to_string(), index);
+        if let Some(data) = self.cache.lock().unwrap().get(&key) {
+            return Ok(Some(data));
+        }
+        let data = self.read_shard_uncached(file_id, index)?;
+        if let Some(data) = &data {
+            self.cache.lock().unwrap().insert(key, data.clone());
+        }
+        Ok(data)
+    }
+
+    /// Reads a shard straight from disk, bypassing and not filling the cache,
+    /// so a cached copy cannot hide damage to the stored one.
+    ///
+    /// A file whose header is damaged or names another shard is returned
+    /// whole, so it fails its checksum like any other corruption.
+    pub fn read_shard_uncached(&self, file_id: &str, index: usize) -> Result<Option<Vec<u8>>> {
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        if !self.read_delay.is_zero() {
+            std::thread::sleep(self.read_delay);
+        }
+        self.count_errors(file_id, self.read_payload(file_id, index))
+    }
+
+    fn read_payload(&self, file_id: &str, index: usize) -> Result<Option<Vec<u8>>> {
+        for dir in self.candidate_dirs(file_id) {
+            if self.headers {
+                if let Some(bytes) = read_if_exists(&dir.join(headered_name(file_id, index)))? {
+                    return Ok(Some(match ShardHeader::decode(&bytes) {
+                        Some((header, payload)) if header.file_id == file_id && header.index == index => {
+                            without_footer(payload).to_vec()
+                        }
+                        _ => bytes,
+                    }));
+                }
+            }
+            if let Some(bytes) = read_if_exists(&dir.join(legacy_name(file_id, index)))? {
+                return Ok(Some(without_footer(&bytes).to_vec()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Opens a shard for reading its payload in pieces, bypassing the cache,
+    /// or returns `None` if it does not exist. Like
+    /// [`ShardStore::read_shard_uncached`], a file whose header is damaged
+    /// or names another shard is read whole.
+    pub fn open_shard(&self, file_id: &str, index: usize) -> Result<Option<ShardReader>> {
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        self.count_errors(file_id, self.open_payload(file_id, index))
+    }
+
+    fn open_payload(&self, file_id: &str, index: usize) -> Result<Option<ShardReader>> {
+        for dir in self.candidate_dirs(file_id) {
+            if self.headers {
+                let path = dir.join(headered_name(file_id, index));
+                if let Some(len) = payload_len_if_exists(&path)? {
+                    let mut file = fs::File::open(&path)?;
+                    let mut header = vec![0; header_len(file_id).min(len as usize)];
+                    file.read_exact(&mut header)?;
+                    let start = match ShardHeader::decode(&header) {
+                        Some((header, _)) if header.file_id == file_id && header.index == index => header_len(file_id) as u64,
+                        _ => 0,
+                    };
+                    return Ok(Some(ShardReader { file, start, len: len - start }));
+                }
+            }
+            let path = dir.join(legacy_name(file_id, index));
+            if let Some(len) = payload_len_if_exists(&path)? {
+                return Ok(Some(ShardReader { file: fs::File::open(&path)?, start: 0, len }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn count_errors<T>(&self, file_id: &str, read: Result<T>) -> Result<T> {
+        if read.is_err() {
+            self.read_errors.fetch_add(1, Ordering::Relaxed);
+            self.record_error(self.location_of(&self.home_dir(file_id)));
+        }
+        read
+    }
+
+    /// Returns a shard's payload length without reading it, or `None` if it does not exist.
+    pub fn shard_len(&self, file_id: &str, index: usize) -> Result<Option<u64>> {
+        for dir in self.candidate_dirs(file_id) {
+            if self.headers {
+                if let Some(len) = payload_len_if_exists(&dir.join(headered_name(file_id, index)))? {
+                    return Ok(Some(len.saturating_sub(header_len(file_id) as u64)));
+                }
+            }
+            if let Some(len) = payload_len_if_exists(&dir.join(legacy_name(file_id, index)))? {
+                return Ok(Some(len));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the device id (`st_dev`) of the filesystem holding a shard,
+    /// or `None` if the shard does not exist.
+    #[cfg(unix)]
+    pub fn shard_device(&self, file_id: &str, index: usize) -> Result<Option<u64>> {
+        use std::os::unix::fs::MetadataExt;
+        for dir in self.candidate_dirs(file_id) {
+            let mut names = vec![legacy_name(file_id, index)];
+            if self.headers {
+                names.insert(0, headered_name(file_id, index));
+            }
+            for name in names {
+                match fs::metadata(dir.join(name)) {
+                    Ok(meta) => return Ok(Some(meta.dev())),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Indices among the first `count` shards of a file that are stored on
+    /// a different device than expected, e.g. because a mount point was
+    /// missing and the shards landed on the filesystem underneath it.
+    /// Always empty on platforms without device ids.
+    pub fn misplaced_shards(&self, file_id: &str, count: usize) -> Result<Vec<usize>> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let expected = match self.expected_device {
+                Some(dev) => dev,
+                None => fs::metadata(&self.root)?.dev(),
+            };
+            let mut misplaced = Vec::new();
+            for index in 0..count {
+                if matches!(self.shard_device(file_id, index)?, Some(dev) if dev != expected) {
+                    misplaced.push(index);
+                }
+            }
+            Ok(misplaced)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (file_id, count);
+            Ok(Vec::new())
+        }
+    }
+
+    /// Deletes the first `count` shards of a file. Missing shards are ignored.
+    pub fn remove_shards(&self, file_id: &str, count: usize) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        for index in 0..count {
+            cache.remove(&(file_id.to_string(), index));
+            for dir in self.candidate_dirs(file_id) {
+                remove_if_exists(&dir.join(headered_name(file_id, index)))?;
+                remove_if_exists(&dir.join(legacy_name(file_id, index)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The payload of a shard file, read a range at a time. See
+/// [`ShardStore::open_shard`].
+pub struct ShardReader {
+    file: fs::File,
+    /// Offset of the payload in the file, i.e. the header length.
+    start: u64,
+    len: u64,
+}
+
+impl ShardReader {
+    pub fn payload_len(&self) -> u64 {
+        self.len
+    }
+
+    /// Reads the payload from `offset` into `buf`, returning how many bytes
+    /// were read; fewer than `buf.len()` only at the end of the payload.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(self.len.saturating_sub(offset) as usize);
+        self.file.seek(SeekFrom::Start(self.start + offset))?;
+        self.file.read_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+}
+
+/// Allocates `len` bytes of disk space for `file` with `fallocate`,
+/// extending it to `len` bytes. Filesystems without `fallocate` support
+/// are left to allocate as the file is written.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &fs::File, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    // SAFETY: fallocate only changes the allocation of a descriptor we own.
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file: &fs::File, _len: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+fn headered_name(file_id: &str, index: usize) -> String {
+    format!("{}.{}.{}", file_id, index, SHARD_EXTENSION)
+}
+
+/// Name of a shard written without a header.
+fn legacy_name(file_id: &str, index: usize) -> String {
+    format!("{}.{}", file_id, index)
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Length of a shard file without its footer, reading only the footer's
+/// trailer rather than the whole file.
+fn payload_len_if_exists(path: &Path) -> Result<Option<u64>> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    if len < 8 {
+        return Ok(Some(len));
+    }
+    let mut trailer = [0u8; 8];
+    file.seek(SeekFrom::End(-8))?;
+    file.read_exact(&mut trailer)?;
+    if &trailer[4..] != FOOTER_MAGIC {
+        return Ok(Some(len));
+    }
+    let json_len = u32::from_le_bytes(trailer[..4].try_into().unwrap()) as u64;
+    Ok(Some(len.saturating_sub(8 + json_len)))
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signer, SigningKey};
+use shared::{IntegrityReport, SignedReport};
+use crate::{coverage, AppContext};
+
+/// Parses a `report_signing_key`: the 32-byte ed25519 secret key, hex encoded.
+pub fn parse_key(hex: &str) -> Option<SigningKey> {
+    let bytes: [u8; 32] = decode_hex(hex.trim())?.try_into().ok()?;
+    Some(SigningKey::from_bytes(&bytes))
+}
+
+/// Produces a coverage report, which includes a full check, and signs it
+/// with `report_signing_key` so auditors can tell it was not altered.
+pub fn signed_report(ctx: &AppContext) -> Result<SignedReport> {
+    let key = ctx.config.report_signing_key.as_deref()
+        .ok_or_else(|| anyhow!("no report_signing_key is configured"))?;
+    let key = parse_key(key).ok_or_else(|| anyhow!("report_signing_key is not a hex-encoded ed25519 key"))?;
+    let report = IntegrityReport {
+        generated_at: chrono::Utc::now().to_rfc3339(),
+        coverage: coverage::coverage_report(ctx)?,
+    };
+    let report = serde_json::to_string(&report)?;
+    Ok(SignedReport {
+        signature: encode_hex(&key.sign(report.as_bytes()).to_bytes()),
+        public_key: encode_hex(key.verifying_key().as_bytes()),
+        report,
+    })
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
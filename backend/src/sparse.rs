@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// A byte range of a file that holds data. Everything outside a file's
+/// extents is a hole and reads as zeros.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Finds the data extents of a file of `size` bytes using `SEEK_DATA` and
+/// `SEEK_HOLE`. Returns `None` when the file has no holes or the platform
+/// cannot report them.
+#[cfg(target_os = "linux")]
+pub fn data_extents(file: &File, size: u64) -> io::Result<Option<Vec<Extent>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut pos = 0u64;
+    while pos < size {
+        // SAFETY: lseek only repositions the offset of a descriptor we own.
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            // ENXIO: no data after `pos`, the rest of the file is a hole.
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err);
+        }
+        // SAFETY: as above.
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (data, hole) = (data as u64, (hole as u64).min(size));
+        if hole > data {
+            extents.push(Extent { offset: data, len: hole - data });
+        }
+        pos = hole;
+    }
+    if extents == [Extent { offset: 0, len: size }] {
+        return Ok(None);
+    }
+    Ok(Some(extents))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_extents(_file: &File, _size: u64) -> io::Result<Option<Vec<Extent>>> {
+    Ok(None)
+}
+
+/// Reads only the data extents of `file`, concatenated.
+pub fn read_extents(file: &mut File, extents: &[Extent]) -> io::Result<Vec<u8>> {
+    let mut packed = vec![0; packed_len(extents)];
+    let mut at = 0;
+    for extent in extents {
+        file.seek(SeekFrom::Start(extent.offset))?;
+        file.read_exact(&mut packed[at..at + extent.len as usize])?;
+        at += extent.len as usize;
+    }
+    Ok(packed)
+}
+
+pub fn packed_len(extents: &[Extent]) -> usize {
+    extents.iter().map(|extent| extent.len as usize).sum()
+}
+
+/// Maps `pos` in the packed data extents to its offset in the file, along
+/// with how many bytes from there are contiguous in both.
+pub fn locate(extents: &[Extent], pos: u64) -> (u64, u64) {
+    let mut start = 0;
+    for extent in extents {
+        if pos < start + extent.len {
+            return (extent.offset + pos - start, start + extent.len - pos);
+        }
+        start += extent.len;
+    }
+    (pos, 0)
+}
+
+/// Hashes the full logical content described by `packed` and `extents`
+/// without materializing the holes.
+pub fn hash_logical(packed: &[u8], extents: &[Extent], size: u64) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    let (mut pos, mut at) = (0u64, 0usize);
+    for extent in extents {
+        hash_zeros(&mut hasher, extent.offset - pos);
+        hasher.update(&packed[at..at + extent.len as usize]);
+        pos = extent.offset + extent.len;
+        at += extent.len as usize;
+    }
+    hash_zeros(&mut hasher, size - pos);
+    hasher.finalize()
+}
+
+fn hash_zeros(hasher: &mut blake3::Hasher, mut len: u64) {
+    static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+    while len > 0 {
+        let n = len.min(ZEROS.len() as u64);
+        hasher.update(&ZEROS[..n as usize]);
+        len -= n;
+    }
+}
+
+/// Expands packed extents into the full logical content.
+pub fn expand(packed: &[u8], extents: &[Extent], size: u64) -> Vec<u8> {
+    let mut data = vec![0; size as usize];
+    let mut at = 0;
+    for extent in extents {
+        let start = extent.offset as usize;
+        data[start..start + extent.len as usize].copy_from_slice(&packed[at..at + extent.len as usize]);
+        at += extent.len as usize;
+    }
+    data
+}
+
+/// Writes packed extents to `out`, leaving the gaps between them as holes.
+pub fn write_sparse(out: &Path, packed: &[u8], extents: &[Extent], size: u64) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(out)?;
+    let mut at = 0;
+    for extent in extents {
+        file.seek(SeekFrom::Start(extent.offset))?;
+        file.write_all(&packed[at..at + extent.len as usize])?;
+        at += extent.len as usize;
+    }
+    file.set_len(size)?;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use shared::ShardLocationHealth;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use crate::{disk, metadata};
+
+/// Name of the file written and removed to prove a location is writable.
+const PROBE_FILE: &str = ".rsguard-probe";
+
+/// Directories of the shard store at `root` that may sit on a disk of their
+/// own: the root itself, then every top-level entry that is a symlink or a
+/// mount point.
+pub fn locations(root: &Path) -> Vec<PathBuf> {
+    let mut locations = vec![root.to_path_buf()];
+    let Ok(entries) = fs::read_dir(root) else {
+        return locations;
+    };
+    let root_dev = device(root);
+    let mut separate: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_symlink() => true,
+            Ok(kind) if kind.is_dir() => root_dev.is_some() && device(&entry.path()) != root_dev,
+            _ => false,
+        })
+        .map(|entry| entry.path())
+        .collect();
+    separate.sort();
+    locations.extend(separate);
+    locations
+}
+
+/// Probes every location of the shard store at `root`: whether it can be
+/// listed and written to, its free space, and how many of its shards can be
+/// opened and read. A location's shards are not counted again in the root.
+pub fn probe(root: &Path) -> Vec<ShardLocationHealth> {
+    let locations = locations(root);
+    locations
+        .iter()
+        .map(|location| {
+            let mut health = ShardLocationHealth {
+                path: metadata::display_path(location),
+                reachable: is_writable(location),
+                free_bytes: None,
+                shard_count: 0,
+                read_errors: 0,
+            };
+            if health.reachable {
+                health.free_bytes = disk::stat(location).ok().flatten().map(|stats| stats.free_bytes);
+                let skip: Vec<&PathBuf> = locations.iter().filter(|other| *other != location).collect();
+                walk(location, &skip, &mut health);
+            }
+            health
+        })
+        .collect()
+}
+
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(PROBE_FILE);
+    let written = fs::metadata(dir).is_ok_and(|meta| meta.is_dir()) && fs::write(&probe, b"probe").is_ok();
+    written && fs::remove_file(&probe).is_ok()
+}
+
+/// Counts the shards below `dir` and those that fail to open or read.
+fn walk(dir: &Path, skip: &[&PathBuf], health: &mut ShardLocationHealth) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        health.read_errors += 1;
+        return;
+    };
+    for entry in entries {
+        let Ok(entry) = entry else {
+            health.read_errors += 1;
+            continue;
+        };
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() && !skip.contains(&&path) => walk(&path, skip, health),
+            Ok(kind) if kind.is_file() => {
+                let name = entry.file_name();
+                if name == PROBE_FILE || name.to_string_lossy().contains(".staged") {
+                    continue;
+                }
+                health.shard_count += 1;
+                let mut byte = [0u8; 1];
+                if fs::File::open(&path).and_then(|mut file| file.read(&mut byte)).is_err() {
+                    health.read_errors += 1;
+                }
+            }
+            Ok(_) => {}
+            Err(_) => health.read_errors += 1,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|meta| meta.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
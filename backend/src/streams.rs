@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Tracks the clients of long-lived streaming responses such as
+/// `/api/events`, so their number can be capped and they can all be ended
+/// when the server shuts down.
+pub struct StreamClients {
+    active: Arc<AtomicUsize>,
+    closed: watch::Sender<bool>,
+}
+
+/// A subscriber's place among the stream clients, released when dropped.
+pub struct StreamSlot {
+    active: Arc<AtomicUsize>,
+    closed: watch::Receiver<bool>,
+}
+
+impl Default for StreamClients {
+    fn default() -> Self {
+        Self { active: Arc::default(), closed: watch::Sender::new(false) }
+    }
+}
+
+impl StreamClients {
+    /// Takes a slot for a new stream, or returns `None` if `max` streams are
+    /// already open. A `max` of 0 means unlimited.
+    pub fn subscribe(&self, max: usize) -> Option<StreamSlot> {
+        let previous = self.active.fetch_add(1, Ordering::SeqCst);
+        let slot = StreamSlot { active: self.active.clone(), closed: self.closed.subscribe() };
+        if max != 0 && previous >= max {
+            // Dropping the slot gives the count back.
+            return None;
+        }
+        Some(slot)
+    }
+
+    /// Number of streams currently open.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Ends every open stream, e.g. so a graceful shutdown does not wait on them.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+}
+
+impl StreamSlot {
+    /// Completes once [`StreamClients::close`] has been called.
+    pub async fn closed(&mut self) {
+        let _ = self.closed.wait_for(|closed| *closed).await;
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What to do with a change the watcher saw, as decided by [`ReencodeThrottle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// Encode the file now.
+    Now,
+    /// Encode the file once the delay has passed, picking up every change
+    /// made until then.
+    After(Duration),
+    /// An encode is already scheduled and will pick up this change.
+    Coalesced,
+}
+
+struct Entry {
+    last: Instant,
+    scheduled: bool,
+}
+
+/// When each file was last encoded by the watcher, so a file under constant
+/// change is re-encoded at most every `min_reencode_interval_secs`.
+#[derive(Default)]
+pub struct ReencodeThrottle {
+    files: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+impl ReencodeThrottle {
+    /// Decides when a change to `path` is encoded. [`Admission::Now`] counts
+    /// as an encode; an [`Admission::After`] must be followed by [`fire`].
+    ///
+    /// [`fire`]: ReencodeThrottle::fire
+    pub fn admit(&self, path: &Path, interval: Duration) -> Admission {
+        let now = Instant::now();
+        let mut files = self.files.lock().unwrap();
+        // Files that have been quiet for a full interval need no entry.
+        files.retain(|_, entry| entry.scheduled || now.duration_since(entry.last) < interval);
+        match files.get_mut(path) {
+            None => {
+                files.insert(path.to_path_buf(), Entry { last: now, scheduled: false });
+                Admission::Now
+            }
+            Some(entry) if entry.scheduled => Admission::Coalesced,
+            Some(entry) => {
+                entry.scheduled = true;
+                Admission::After(interval.saturating_sub(now.duration_since(entry.last)))
+            }
+        }
+    }
+
+    /// Records that the scheduled encode of `path` is starting.
+    pub fn fire(&self, path: &Path) {
+        let mut files = self.files.lock().unwrap();
+        files.insert(path.to_path_buf(), Entry { last: Instant::now(), scheduled: false });
+    }
+}
@@ -0,0 +1,55 @@
+use shared::VersionInfo;
+use std::collections::BTreeMap;
+use crate::config::{AppConfig, IoPriority};
+use crate::encoder::ParityAlgorithm;
+
+/// Capabilities compiled into this build. They depend on the build profile
+/// and target platform rather than on the config.
+pub fn compiled_features() -> Vec<String> {
+    let mut features = Vec::new();
+    if cfg!(not(debug_assertions)) {
+        features.push("embedded_frontend");
+    }
+    if cfg!(target_os = "linux") {
+        features.extend(["extended_attributes", "io_priority", "open_for_write_detection"]);
+    }
+    if cfg!(unix) {
+        features.push("file_ownership");
+    }
+    features.into_iter().map(str::to_string).collect()
+}
+
+/// Which optional behaviors `config` turns on, keyed by a stable name.
+pub fn runtime_config(config: &AppConfig) -> BTreeMap<String, bool> {
+    [
+        ("xor_parity", config.parity_algorithm == ParityAlgorithm::Xor),
+        ("mirror_mode", config.data_shards == 1),
+        ("replication", !config.replication_peers.is_empty()),
+        ("notifications", config.notification_webhook.is_some()),
+        ("api_token", config.api_token.is_some()),
+        ("shard_headers", config.shard_headers),
+        ("shard_footers", config.shard_footers),
+        ("merkle_roots", config.merkle_roots),
+        ("sparse_files", config.sparse_files),
+        ("snapshot_reads", config.snapshot_reads),
+        ("preserve_attrs", config.preserve_attrs),
+        ("network_fs_mode", config.network_fs_mode),
+        ("allowlist_only", config.allowlist_only),
+        ("check_window", config.check_window.is_some()),
+        ("lowered_io_priority", config.io_priority != IoPriority::Normal),
+        ("skip_files_open_for_write", config.skip_files_open_for_write),
+        ("signed_reports", config.report_signing_key.is_some()),
+    ]
+    .into_iter()
+    .map(|(name, active)| (name.to_string(), active))
+    .collect()
+}
+
+/// The body of `/api/version`.
+pub fn version_info(config: &AppConfig) -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: compiled_features(),
+        runtime_config: runtime_config(config),
+    }
+}
@@ -1,43 +1,229 @@
 use anyhow::Result;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher, Config};
-use std::path::Path;
+use notify::event::{AccessKind, AccessMode};
+use notify::{Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher, Config};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TrySendError};
 use std::sync::{Arc, Mutex};
-use shared::AppStatus;
 use std::time::Duration;
+use crate::config::OverflowStrategy;
+use crate::throttle::Admission;
+use crate::{filter, metadata, protector, scanner, AppContext};
+
+/// How often watched directories are polled with `network_fs_mode`.
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The directories being watched: those of the config, then any added at
+/// runtime with [`register`].
+pub struct WatchedDirs {
+    dirs: Mutex<Vec<PathBuf>>,
+    /// The running watcher, once [`start_watching`] was called.
+    watcher: Mutex<Option<Box<dyn Watcher + Send>>>,
+}
+
+impl WatchedDirs {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self { dirs: Mutex::new(dirs), watcher: Mutex::new(None) }
+    }
+
+    pub fn list(&self) -> Vec<PathBuf> {
+        self.dirs.lock().unwrap().clone()
+    }
+
+    /// The watched directory `path` is in, if any.
+    pub fn root_of(&self, path: &Path) -> Option<PathBuf> {
+        self.dirs.lock().unwrap().iter().find(|root| path.starts_with(root)).cloned()
+    }
+}
+
+/// Returned by [`register`] for a directory that is already watched, or
+/// lies inside one that is.
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is already watched")]
+pub struct AlreadyWatched(pub String);
+
+/// Returned by [`register`] for a directory containing the shard store.
+/// Only the directories configured at startup have it excluded from
+/// protection; any other would end up protecting its own shards.
+#[derive(Debug, thiserror::Error)]
+#[error("{0} contains the shard store")]
+pub struct ContainsShardStore(pub String);
+
+/// Starts watching `dir` in the running watcher, if there is one, and adds
+/// it to the watched directories for scans and checks. The directory is
+/// not scanned; see [`scanner::scan_directory`].
+pub fn register(ctx: &AppContext, dir: &Path) -> Result<()> {
+    if ctx.config.shard_store_below(dir).is_some() {
+        return Err(ContainsShardStore(metadata::display_path(dir)).into());
+    }
+    let mut dirs = ctx.watched.dirs.lock().unwrap();
+    if let Some(root) = dirs.iter().find(|root| dir.starts_with(root)) {
+        return Err(AlreadyWatched(metadata::display_path(root)).into());
+    }
+    if let Some(watcher) = ctx.watched.watcher.lock().unwrap().as_mut() {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+    }
+    dirs.push(dir.to_path_buf());
+    tracing::info!("[Watcher] Now watching {}", metadata::display_path(dir));
+    Ok(())
+}
+
+/// Stops watching `dir`, which must be one of the watched directories
+/// itself rather than a directory inside one. Returns whether it was.
+pub fn unregister(ctx: &AppContext, dir: &Path) -> Result<bool> {
+    let mut dirs = ctx.watched.dirs.lock().unwrap();
+    let Some(position) = dirs.iter().position(|root| root == dir) else {
+        return Ok(false);
+    };
+    if let Some(watcher) = ctx.watched.watcher.lock().unwrap().as_mut() {
+        // Fails if the directory is gone, which stops the watch anyway.
+        if let Err(e) = watcher.unwatch(dir) {
+            tracing::warn!("[Watcher] Could not unwatch {}: {}", metadata::display_path(dir), e);
+        }
+    }
+    dirs.remove(position);
+    tracing::info!("[Watcher] No longer watching {}", metadata::display_path(dir));
+    Ok(true)
+}
 
 /// Spawns a background task to watch for file changes in the specified directories.
-pub fn start_watching(app_status: Arc<Mutex<AppStatus>>, paths: Vec<impl AsRef<Path>>) -> Result<()> {
-    
-    let (tx, rx) = std::sync::mpsc::channel();
+pub fn start_watching(ctx: AppContext, paths: Vec<impl AsRef<Path>>) -> Result<()> {
+
+    let (tx, rx) = mpsc::sync_channel(ctx.config.watcher_event_buffer);
+    // Set when an event was dropped because the channel was full.
+    let overflowed = Arc::new(AtomicBool::new(false));
+    let overflowed_tx = overflowed.clone();
 
     // This watcher will run in its own thread, so we can't use async here directly.
     // Instead, it sends events back to our tokio runtime via a channel.
-    let mut watcher = RecommendedWatcher::new(tx, Config::default()
-        .with_poll_interval(Duration::from_secs(2)))?;
+    let handler = move |res| {
+        if let Err(TrySendError::Full(_)) = tx.try_send(res) {
+            overflowed_tx.store(true, Ordering::Relaxed);
+        }
+    };
+    // Network filesystems deliver change events unreliably, if at all, so
+    // they are polled, comparing contents as their mtimes may be too coarse.
+    let mut watcher: Box<dyn Watcher + Send> = if ctx.config.network_fs_mode {
+        Box::new(PollWatcher::new(handler, Config::default()
+            .with_poll_interval(NETWORK_POLL_INTERVAL)
+            .with_compare_contents(true))?)
+    } else {
+        Box::new(RecommendedWatcher::new(handler, Config::default()
+            .with_poll_interval(Duration::from_secs(2)))?)
+    };
 
     for path in paths {
         watcher.watch(path.as_ref(), RecursiveMode::Recursive)?;
     }
+    // The watcher stops delivering events once dropped, so the context keeps
+    // it, where `register` can add directories to it.
+    *ctx.watched.watcher.lock().unwrap() = Some(watcher);
 
-    // This thread will block on receiving events and forward them to our main async runtime.
-    tokio::spawn(async move {
-        // TODO: This is a simplified receiver. A real implementation should:
-        // 1. Handle different event types (Create, Remove, Modify).
-        // 2. Batch events to avoid redundant processing (e.g., for large file copies).
-        // 3. Trigger the encoding process for new/modified files.
-        // 4. Trigger metadata updates for removed files.
-        // 5. Log events to the AppStatus.
+    // This thread will block on receiving events, so it lives on the blocking pool.
+    tokio::task::spawn_blocking(move || {
+        // TODO: Batch events to avoid redundant processing (e.g., for large file copies).
         for res in rx {
+            if overflowed.swap(false, Ordering::Relaxed) {
+                tracing::warn!("[Watcher] Event buffer of {} overflowed", ctx.config.watcher_event_buffer);
+                handle_overflow(&ctx);
+            }
             match res {
                 Ok(event) => {
-                    println!("[Watcher] Event: {:?}", event);
-                    let mut status = app_status.lock().unwrap();
-                    status.logs.push(format!("[Watcher] Event: {:?}", event.kind));
+                    tracing::debug!("[Watcher] Event: {:?}", event);
+                    ctx.status.lock().unwrap().logs.push(format!("[Watcher] Event: {:?}", event.kind));
+                    handle_event(&ctx, &event);
                 }
-                Err(e) => eprintln!("[Watcher] Error: {:?}", e),
+                Err(e) => tracing::error!("[Watcher] Error: {:?}", e),
             }
         }
     });
 
     Ok(())
-} 
\ No newline at end of file
+}
+
+/// Re-encodes files that were created or modified and marks vanished ones as deleted.
+pub fn handle_event(ctx: &AppContext, event: &Event) {
+    // The OS event queue overflowed, so an unknown number of changes were lost.
+    if event.need_rescan() {
+        tracing::warn!("[Watcher] OS event queue overflowed");
+        handle_overflow(ctx);
+        return;
+    }
+    // A writer closing the file is when a file deferred by
+    // `skip_files_open_for_write` becomes ready to encode.
+    if !matches!(
+        event.kind,
+        EventKind::Create(_)
+            | EventKind::Modify(_)
+            | EventKind::Remove(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+    ) {
+        return;
+    }
+    for path in &event.paths {
+        // Renames report the old name as a modify event, so check existence rather than the kind.
+        if !path.exists() {
+            if let Err(e) = metadata::mark_deleted(&ctx.db, path) {
+                tracing::error!("[Watcher] Failed to record removal of {}: {}", metadata::display_path(path), e);
+            }
+            continue;
+        }
+        let Some(root) = ctx.watched.root_of(path) else {
+            continue;
+        };
+        if !path.is_file() || !filter::should_protect(&ctx.config, &root, path) {
+            continue;
+        }
+        let interval = Duration::from_secs(ctx.config.min_reencode_interval_secs);
+        if interval.is_zero() {
+            protect(ctx, path);
+            continue;
+        }
+        match ctx.reencode_throttle.admit(path, interval) {
+            Admission::Now => protect(ctx, path),
+            Admission::After(delay) => {
+                tracing::debug!("[Watcher] Re-encoding {} in {:?}", metadata::display_path(path), delay);
+                let (ctx, path) = (ctx.clone(), path.clone());
+                std::thread::spawn(move || {
+                    std::thread::sleep(delay);
+                    ctx.reencode_throttle.fire(&path);
+                    // The file may have gone away while waiting.
+                    if path.is_file() {
+                        protect(&ctx, &path);
+                    }
+                });
+            }
+            Admission::Coalesced => {}
+        }
+    }
+}
+
+fn protect(ctx: &AppContext, path: &Path) {
+    match protector::protect_if_changed(ctx, path) {
+        Ok(Some(_)) => {
+            if let Ok(count) = metadata::count_files(&ctx.db) {
+                ctx.status.lock().unwrap().protected_files = count;
+            }
+            ctx.publish_status();
+        }
+        Ok(None) => {}
+        // Retried on the close event.
+        Err(e) if e.is::<protector::OpenForWrite>() => {}
+        Err(e) => tracing::error!("[Watcher] Failed to protect {}: {}", metadata::display_path(path), e),
+    }
+}
+
+/// Applies the configured [`OverflowStrategy`] after watcher events were lost.
+fn handle_overflow(ctx: &AppContext) {
+    match ctx.config.watcher_overflow {
+        OverflowStrategy::Rescan => {
+            tracing::info!("[Watcher] Rescanning watched directories to catch missed changes");
+            if let Err(e) = scanner::scan_directories(ctx) {
+                tracing::error!("[Watcher] Reconciling scan failed: {}", e);
+            }
+        }
+        OverflowStrategy::Drop => {
+            tracing::warn!("[Watcher] Missed changes are left for the next full scan");
+        }
+    }
+}
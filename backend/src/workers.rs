@@ -0,0 +1,82 @@
+use shared::WorkerStats;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Kinds of work whose queues and workers are counted in [`Workers`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Work {
+    Encode,
+    Repair,
+}
+
+/// Live counts of queued and running encodes and repairs, for `/api/workers`.
+#[derive(Default)]
+pub struct Workers {
+    encode_queue: AtomicUsize,
+    repair_queue: AtomicUsize,
+    active_encodes: AtomicUsize,
+    active_repairs: AtomicUsize,
+}
+
+impl Workers {
+    /// Counts `count` items of `work` as queued until they are started or
+    /// the returned guard is dropped.
+    pub fn queue(&self, work: Work, count: usize) -> Queued<'_> {
+        let (queue, active) = match work {
+            Work::Encode => (&self.encode_queue, &self.active_encodes),
+            Work::Repair => (&self.repair_queue, &self.active_repairs),
+        };
+        queue.fetch_add(count, Ordering::SeqCst);
+        Queued { queue, active, remaining: count }
+    }
+
+    pub fn stats(&self) -> WorkerStats {
+        WorkerStats {
+            encode_queue_depth: self.encode_queue.load(Ordering::SeqCst),
+            repair_queue_depth: self.repair_queue.load(Ordering::SeqCst),
+            active_encode_workers: self.active_encodes.load(Ordering::SeqCst),
+            active_repair_workers: self.active_repairs.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Items waiting in a queue of [`Workers`]. Whatever is left when dropped
+/// leaves the queue.
+pub struct Queued<'a> {
+    queue: &'a AtomicUsize,
+    active: &'a AtomicUsize,
+    remaining: usize,
+}
+
+impl<'a> Queued<'a> {
+    /// Takes one item off the queue, e.g. as it is handed to code that
+    /// counts itself as a worker.
+    pub fn next(&mut self) {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.queue.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Takes one item off the queue and counts it as running until the
+    /// returned guard is dropped.
+    pub fn start(mut self) -> Active<'a> {
+        self.next();
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Active(self.active)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queue.fetch_sub(self.remaining, Ordering::SeqCst);
+    }
+}
+
+/// A running item of work, counted until dropped.
+pub struct Active<'a>(&'a AtomicUsize);
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -9,7 +9,7 @@ async fn status_endpoint_returns_ok_and_correct_payload() {
 
     // Act: Send a request to the status endpoint
     let response = client
-        .get(format!("http://{}/api/status", app_address))
+        .get(format!("http://{}/api/status?fields=full", app_address))
         .send()
         .await
         .expect("Failed to execute request.");
@@ -187,7 +187,7 @@ async fn test_api_status(world: &mut RsGuardWorld) -> Result<(), Box<dyn std::er
     
     // 测试 API 端点
     let client = reqwest::Client::new();
-    let url = format!("http://{}/api/status", addr);
+    let url = format!("http://{}/api/status?fields=full", addr);
     
     let response = client.get(&url).send().await?;
     let status = response.status();
@@ -0,0 +1,163 @@
+//! Tests for config parsing and validation.
+
+use backend::config::{self, ConfigError};
+
+#[test]
+fn type_and_range_errors_are_reported_together() {
+    let toml = r#"
+        watched_directories = ["./data"]
+        data_shards = "four"
+        parity_shards = 70000
+    "#;
+    let err = config::parse_config(toml).unwrap_err();
+    let err = err.downcast_ref::<ConfigError>().expect("validation error");
+
+    assert_eq!(err.errors.len(), 2, "{:?}", err.errors);
+    assert!(err.errors[0].contains("`data_shards` must be an integer, got string"));
+    assert!(err.errors[1].contains("`parity_shards` must be between 1 and 65535, got 70000"));
+}
+
+#[test]
+fn xor_parity_requires_a_single_parity_shard() {
+    let toml = r#"
+        watched_directories = ["./data"]
+        data_shards = 4
+        parity_shards = 2
+        parity_algorithm = "xor"
+    "#;
+    let err = config::parse_config(toml).unwrap_err();
+    let err = err.downcast_ref::<ConfigError>().expect("validation error");
+    assert!(err.errors[0].contains("needs `parity_shards` = 1, got 2"), "{:?}", err.errors);
+
+    let config = config::parse_config(&toml.replace("parity_shards = 2", "parity_shards = 1")).unwrap();
+    assert_eq!(config.parity_algorithm, backend::encoder::ParityAlgorithm::Xor);
+}
+
+#[test]
+fn check_window_must_be_a_time_range() {
+    let base = "watched_directories = [\"./data\"]\ndata_shards = 4\nparity_shards = 2\n";
+    let err = config::parse_config(&format!("{}check_window = \"nightly\"", base)).unwrap_err();
+    let err = err.downcast_ref::<ConfigError>().expect("validation error");
+    assert!(err.errors[0].contains("`check_window` must look like"), "{:?}", err.errors);
+
+    let config = config::parse_config(&format!("{}check_window = \"23:00-02:30\"", base)).unwrap();
+    let window = config.check_window.unwrap();
+    assert!(window.contains(chrono::NaiveTime::from_hms_opt(1, 0, 0).unwrap()));
+    assert!(!window.contains(chrono::NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+}
+
+#[test]
+fn bind_address_and_port_default_to_localhost_3000() {
+    let base = "watched_directories = [\"./data\"]\ndata_shards = 4\nparity_shards = 2\n";
+    let config = config::parse_config(base).unwrap();
+    assert_eq!(config.socket_addr().unwrap(), "127.0.0.1:3000".parse().unwrap());
+
+    let config = config::parse_config(&format!("{}bind_address = \"0.0.0.0\"\nport = 8080", base)).unwrap();
+    assert_eq!(config.socket_addr().unwrap(), "0.0.0.0:8080".parse().unwrap());
+
+    let err = config::parse_config(&format!("{}bind_address = \"my-nas\"\nport = 70000", base)).unwrap_err();
+    let err = err.downcast_ref::<ConfigError>().expect("validation error");
+    assert_eq!(err.errors.len(), 2, "{:?}", err.errors);
+    assert!(err.errors[0].contains("`port` must be between 1 and 65535, got 70000"), "{:?}", err.errors);
+    assert!(err.errors[1].contains("`bind_address` must be an IP address"), "{:?}", err.errors);
+}
+
+#[test]
+fn check_interval_defaults_to_hourly_and_can_be_disabled() {
+    let base = "watched_directories = [\"./data\"]\ndata_shards = 4\nparity_shards = 2\n";
+    assert_eq!(config::parse_config(base).unwrap().check_interval_secs, 3600);
+    assert_eq!(config::parse_config(&format!("{}check_interval_secs = 0", base)).unwrap().check_interval_secs, 0);
+    assert!(config::parse_config(&format!("{}check_interval_secs = -1", base)).is_err());
+}
+
+#[test]
+fn allowlist_only_needs_protected_paths() {
+    let base = "watched_directories = [\"./data\"]\ndata_shards = 4\nparity_shards = 2\nallowlist_only = true\n";
+    let err = config::parse_config(base).unwrap_err();
+    let err = err.downcast_ref::<ConfigError>().expect("validation error");
+    assert!(err.errors[0].contains("needs at least one entry in `protected_paths`"), "{:?}", err.errors);
+
+    let config = config::parse_config(&format!("{}protected_paths = [\"./data/ledger.db\"]", base)).unwrap();
+    assert_eq!(config.protected_paths, vec![std::path::PathBuf::from("./data/ledger.db")]);
+}
+
+#[test]
+fn unknown_fields_do_not_fail_parsing() {
+    let toml = r#"
+        watched_directories = ["./data"]
+        data_shards = 4
+        parity_shards = 2
+        no_such_option = true
+    "#;
+    let config = config::parse_config(toml).unwrap();
+    assert_eq!(config.data_shards, 4);
+}
+
+#[test]
+fn shipped_config_is_valid() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../config/folders.toml");
+    config::load_config(path).unwrap();
+}
+
+#[test]
+fn labelled_watched_tables_propagate_to_status() {
+    use backend::{metadata, AppContext};
+    use shared::DirectoryStatus;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let toml = format!(
+        r#"
+        watched_directories = ["./plain"]
+        data_shards = 4
+        parity_shards = 2
+        shard_store_dir = "{}"
+
+        [[watched]]
+        path = "./photos"
+        label = "Family photos"
+
+        [[watched]]
+        path = "./unlabelled"
+        "#,
+        tmp.path().join("shards").display()
+    );
+    let config = config::parse_config(&toml).unwrap();
+    assert_eq!(config.label_for(std::path::Path::new("./photos")), Some("Family photos"));
+
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    let ctx = AppContext::new(config, db).unwrap();
+    let status = ctx.status.lock().unwrap();
+    assert_eq!(status.watched_dirs, vec!["./plain", "./photos", "./unlabelled"]);
+    assert_eq!(
+        status.per_directory,
+        vec![
+            DirectoryStatus { path: "./plain".to_string(), label: None },
+            DirectoryStatus { path: "./photos".to_string(), label: Some("Family photos".to_string()) },
+            DirectoryStatus { path: "./unlabelled".to_string(), label: None },
+        ]
+    );
+}
+
+#[test]
+fn watched_entries_need_a_path() {
+    let toml = "data_shards = 4\nparity_shards = 2\n\n[[watched]]\nlabel = \"Nowhere\"\n";
+    let err = config::parse_config(toml).unwrap_err();
+    let err = err.downcast_ref::<ConfigError>().expect("validation error");
+    assert_eq!(err.errors, vec!["`watched` entry is missing `path`".to_string()]);
+}
+
+#[test]
+fn watched_shard_overrides_are_validated() {
+    let toml = "data_shards = 4\nparity_shards = 2\n\n[[watched]]\npath = \"./tiny\"\ndata_shards = 60000\nparity_shards = 6000\n";
+    let err = config::parse_config(toml).unwrap_err();
+    let err = err.downcast_ref::<ConfigError>().expect("validation error");
+    assert_eq!(
+        err.errors,
+        vec!["`[[watched]]` ./tiny: `data_shards` + `parity_shards` must be at most 65536, got 60000 + 6000".to_string()]
+    );
+
+    let toml = "data_shards = 4\nparity_shards = 2\n\n[[watched]]\npath = \"./tiny\"\ndata_shards = \"two\"\n";
+    let err = config::parse_config(toml).unwrap_err();
+    let err = err.downcast_ref::<ConfigError>().expect("validation error");
+    assert_eq!(err.errors, vec!["`watched` entry `data_shards` must be an integer, got string".to_string()]);
+}
@@ -0,0 +1,101 @@
+//! Round-trip tests for the Reed-Solomon encoder.
+
+use backend::encoder::{ParityAlgorithm, RSEncoder};
+
+#[test]
+fn single_data_shard_mirrors_and_reconstructs_from_a_parity_copy() {
+    let encoder = RSEncoder::new(1, 2).unwrap();
+    assert!(encoder.is_mirror());
+
+    let data = b"mirrored, not erasure coded".to_vec();
+    let shards = encoder.encode(&data).unwrap();
+    assert_eq!(shards.len(), 3);
+    assert!(shards.iter().all(|shard| shard == &data));
+
+    let mut received: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+    received[0] = None;
+    encoder.reconstruct(&mut received).unwrap();
+    let shards: Vec<Vec<u8>> = received.into_iter().map(Option::unwrap).collect();
+    assert_eq!(encoder.join(&shards, data.len()), data);
+}
+
+#[test]
+fn xor_parity_rebuilds_a_lost_data_shard() {
+    let encoder = RSEncoder::with_algorithm(4, 1, ParityAlgorithm::Xor).unwrap();
+    let data: Vec<u8> = (0..10_001u32).map(|i| (i * 7 % 256) as u8).collect();
+    let shards = encoder.encode(&data).unwrap();
+    assert_eq!(shards.len(), 5);
+
+    let mut received: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+    received[2] = None;
+    encoder.reconstruct(&mut received).unwrap();
+    let rebuilt: Vec<Vec<u8>> = received.into_iter().map(Option::unwrap).collect();
+    assert_eq!(rebuilt, shards);
+    assert_eq!(encoder.join(&rebuilt, data.len()), data);
+
+    assert!(RSEncoder::with_algorithm(4, 2, ParityAlgorithm::Xor).is_err());
+}
+
+#[test]
+fn codec_self_test_passes_only_with_the_expected_parity() {
+    use backend::encoder::{verify_codec, SELF_TEST_PARITY};
+
+    verify_codec(&SELF_TEST_PARITY).unwrap();
+    let mut wrong = SELF_TEST_PARITY;
+    wrong[1][2] ^= 0x01;
+    let err = verify_codec(&wrong).unwrap_err();
+    assert!(err.to_string().contains("parity shard 1"), "{}", err);
+}
+
+#[test]
+fn streamed_stripes_each_decode_on_their_own() {
+    use backend::encoder::encode_file_streaming;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("large.bin");
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 256) as u8).collect();
+    std::fs::write(&path, &data).unwrap();
+
+    let encoder = RSEncoder::new(4, 2).unwrap();
+    let mut rebuilt = Vec::new();
+    let mut writer = |index: usize, stripe: &[u8], shards: &[Vec<u8>]| -> anyhow::Result<()> {
+        assert_eq!(stripe, &data[index * 4096..(index * 4096 + stripe.len())]);
+        let mut received: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+        received[0] = None;
+        received[5] = None;
+        encoder.reconstruct(&mut received).unwrap();
+        let shards: Vec<Vec<u8>> = received.into_iter().map(Option::unwrap).collect();
+        rebuilt.extend(encoder.join(&shards, stripe.len()));
+        Ok(())
+    };
+    let streamed = encode_file_streaming(&path, &mut writer, 4, 2, 4096).unwrap();
+
+    assert_eq!((streamed.len, streamed.stripes), (10_000, 3));
+    assert_eq!(rebuilt, data);
+}
+
+#[test]
+fn more_than_256_shards_are_encoded_in_gf16() {
+    use backend::encoder::GaloisField;
+
+    assert_eq!(RSEncoder::new(255, 1).unwrap().field(), GaloisField::Gf8);
+    let encoder = RSEncoder::new(300, 4).unwrap();
+    assert_eq!(encoder.field(), GaloisField::Gf16);
+    assert!(RSEncoder::with_field(300, 4, ParityAlgorithm::ReedSolomon, GaloisField::Gf8).is_err());
+
+    let data: Vec<u8> = (0..5_001u32).map(|i| (i * 13 % 256) as u8).collect();
+    let shards = encoder.encode(&data).unwrap();
+    assert_eq!(shards.len(), 304);
+    assert!(shards.iter().all(|shard| shard.len() == 18));
+    let slices: Vec<&[u8]> = shards.iter().map(|shard| &shard[4..10]).collect();
+    assert!(encoder.verify(&slices).unwrap());
+
+    let mut received: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+    for lost in [0, 17, 299, 302] {
+        received[lost] = None;
+    }
+    encoder.reconstruct(&mut received).unwrap();
+    let rebuilt: Vec<Vec<u8>> = received.into_iter().map(Option::unwrap).collect();
+    assert_eq!(rebuilt, shards);
+    assert_eq!(encoder.join(&rebuilt, data.len()), data);
+}
@@ -0,0 +1,76 @@
+//! Full-decode verification, in a binary of its own so its allocation
+//! counter is not disturbed by other tests.
+
+use backend::checker::Finding;
+use backend::config::AppConfig;
+use backend::Guard;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tracks the bytes allocated at any one time and the most seen since the
+/// last [`reset_peak`].
+struct CountingAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let now = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Returns the bytes allocated now, from which the peak is counted again.
+fn reset_peak() -> usize {
+    let now = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(now, Ordering::Relaxed);
+    now
+}
+
+#[test]
+fn full_decode_verification_of_a_large_file_stays_within_its_memory_budget() {
+    const BUDGET: usize = 1 << 20;
+    let tmp = tempfile::tempdir().unwrap();
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    let config = AppConfig {
+        watched_directories: vec![source.clone()],
+        shard_store_dir: tmp.path().join("shards"),
+        full_decode_verify: true,
+        full_decode_memory_bytes: BUDGET,
+        ..Default::default()
+    };
+    let guard = Guard::open(config, tmp.path().join("db")).unwrap();
+    let file = source.join("disk.img");
+    let content: Vec<u8> = (0..48 << 20).map(|i: u32| (i % 251) as u8).collect();
+    fs::write(&file, &content).unwrap();
+    guard.protect(&file).unwrap();
+    drop(content);
+
+    let baseline = reset_peak();
+    assert_eq!(guard.verify(&file).unwrap(), None);
+    let used = PEAK.load(Ordering::Relaxed) - baseline;
+    assert!(used < 2 * BUDGET, "verification used {} bytes for a budget of {}", used, BUDGET);
+
+    // Damage in the middle of the source is found by the comparison.
+    let mut damaged = fs::read(&file).unwrap();
+    damaged[30 << 20] ^= 0xff;
+    let mtime = fs::metadata(&file).unwrap().modified().unwrap();
+    fs::write(&file, &damaged).unwrap();
+    fs::File::options().write(true).open(&file).unwrap().set_modified(mtime).unwrap();
+    assert!(matches!(guard.verify(&file).unwrap(), Some(Finding::Corrupted(_))));
+}
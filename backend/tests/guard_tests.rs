@@ -0,0 +1,85 @@
+//! Tests for the embeddable `Guard` API, used without starting a server.
+
+use backend::checker::Finding;
+use backend::config::AppConfig;
+use backend::Guard;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+/// Opens a guard whose shard store and DB live in `tmp`, next to a `source`
+/// directory holding one protected file.
+fn protected_file(tmp: &TempDir, content: &[u8]) -> (Guard, PathBuf) {
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    let config = AppConfig {
+        watched_directories: vec![source.clone()],
+        shard_store_dir: tmp.path().join("shards"),
+        ..Default::default()
+    };
+    let guard = Guard::open(config, tmp.path().join("db")).unwrap();
+    let file = source.join("backup.tar");
+    fs::write(&file, content).unwrap();
+    guard.protect(&file).unwrap();
+    (guard, file)
+}
+
+fn content() -> Vec<u8> {
+    (0..12_000u32).map(|i| (i % 239) as u8).collect()
+}
+
+#[test]
+fn protected_file_verifies_and_recovers() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (guard, file) = protected_file(&tmp, &content());
+
+    assert_eq!(guard.verify(&file).unwrap(), None);
+    let out = tmp.path().join("recovered.tar");
+    assert!(guard.recover(&file, &out).unwrap());
+    assert_eq!(fs::read(&out).unwrap(), content());
+    assert_eq!(guard.read(&file, None).unwrap(), Some(content()));
+}
+
+#[test]
+fn damaged_shard_is_found_and_repaired() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (guard, file) = protected_file(&tmp, &content());
+    let record = backend::metadata::get_file_metadata(&guard.context().db, &file).unwrap().unwrap();
+    fs::write(guard.context().store.shard_path(&record.file_id, 1), b"bit rot").unwrap();
+
+    assert!(matches!(guard.verify(&file).unwrap(), Some(Finding::Corrupted(_))));
+    let repair = guard.repair(&file).unwrap();
+    assert_eq!(repair.shards_repaired, vec![1]);
+    assert!(!repair.source_restored);
+    assert_eq!(guard.verify(&file).unwrap(), None);
+}
+
+#[test]
+fn damaged_source_is_restored_from_its_shards() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (guard, file) = protected_file(&tmp, &content());
+    // Bit rot changes the content but not the size or modification time.
+    let modified = fs::metadata(&file).unwrap().modified().unwrap();
+    let mut rotten = content();
+    rotten[500] ^= 0x10;
+    fs::write(&file, &rotten).unwrap();
+    fs::File::options().write(true).open(&file).unwrap().set_modified(modified).unwrap();
+
+    assert!(matches!(guard.verify(&file).unwrap(), Some(Finding::Corrupted(_))));
+    let repair = guard.repair(&file).unwrap();
+    assert!(repair.shards_repaired.is_empty());
+    assert!(repair.source_restored);
+    assert_eq!(fs::read(&file).unwrap(), content());
+}
+
+#[test]
+fn unprotected_files_are_refused() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (guard, file) = protected_file(&tmp, &content());
+    let other = file.with_file_name("other.tar");
+    fs::write(&other, b"never protected").unwrap();
+
+    assert!(guard.verify(&other).is_err());
+    assert!(guard.repair(&other).is_err());
+    assert_eq!(guard.read(&other, None).unwrap(), None);
+}
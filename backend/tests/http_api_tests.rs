@@ -0,0 +1,1762 @@
+//! End-to-end tests of the HTTP API against a router bound to a random port.
+
+use backend::config::AppConfig;
+use backend::{app_router, assets, checker, metadata, protector, replication, router_with_frontend, scanner, AppContext};
+use rust_embed::RustEmbed;
+use shared::{
+    ApiError, AppStatus, ChangeSet, CheckRun, FileDetail, RebuildResult, RecoverResult, RefreshResult, ReprotectResult,
+    ServiceStatus, ShardRepairResult, WatchedDirResult,
+};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+/// Builds a context rooted in `tmp` with a `source` directory being watched.
+fn test_context(tmp: &TempDir, config: AppConfig) -> (AppContext, PathBuf) {
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    let config = AppConfig {
+        watched_directories: vec![source.clone()],
+        shard_store_dir: tmp.path().join("shards"),
+        ..config
+    };
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    (AppContext::new(config, db).unwrap(), source)
+}
+
+/// The `api_token` of nodes whose tests call endpoints that require it.
+fn test_token() -> backend::config::Secret {
+    backend::config::Secret("t0ken".to_string())
+}
+
+async fn spawn_app(ctx: AppContext) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app_router(ctx)).await.unwrap();
+    });
+    addr
+}
+
+#[tokio::test]
+async fn reprotect_stale_reencodes_files_edited_out_of_band() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let edited = source.join("edited.txt");
+    fs::write(&edited, b"original content").unwrap();
+    fs::write(source.join("untouched.txt"), b"same as before").unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+
+    // Simulate an edit the watcher missed.
+    fs::write(&edited, b"content changed behind our back").unwrap();
+
+    let addr = spawn_app(ctx.clone()).await;
+    let result: ReprotectResult = reqwest::Client::new()
+        .post(format!("http://{}/api/reprotect-stale", addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(result, ReprotectResult { examined: 2, reprotected: 1, failed: 0 });
+
+    let restored = tmp.path().join("restored.txt");
+    protector::recover_file(&ctx, &edited, &restored).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), b"content changed behind our back");
+}
+
+#[tokio::test]
+async fn refresh_metadata_corrects_the_size_and_mtime_of_unchanged_files() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let restored = source.join("restored.txt");
+    let other = source.join("other.txt");
+    fs::write(&restored, b"unchanged content").unwrap();
+    fs::write(&other, b"also unchanged").unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+    let correct = metadata::get_file_metadata(&ctx.db, &restored).unwrap().unwrap();
+
+    // Simulate records restored from an older export.
+    for path in [&restored, &other] {
+        let mut record = metadata::get_file_metadata(&ctx.db, path).unwrap().unwrap();
+        record.size += 100;
+        record.mtime = 1;
+        metadata::store_file_metadata(&ctx.db, path, &record).unwrap();
+    }
+
+    let addr = spawn_app(ctx.clone()).await;
+    let result: RefreshResult = reqwest::Client::new()
+        .post(format!("http://{}/api/refresh-metadata", addr))
+        .query(&[("path", restored.to_str().unwrap())])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(result, RefreshResult { examined: 1, refreshed: 1, reencoded: 0, failed: 0 });
+
+    let record = metadata::get_file_metadata(&ctx.db, &restored).unwrap().unwrap();
+    assert_eq!((record.size, record.mtime), (correct.size, correct.mtime));
+    // Not re-encoded: the shards are the ones written by the scan.
+    assert_eq!(record.protected_at, correct.protected_at);
+    assert_eq!(record.shard_checksums, correct.shard_checksums);
+    assert_eq!(metadata::get_file_metadata(&ctx.db, &other).unwrap().unwrap().mtime, 1);
+}
+
+#[tokio::test]
+async fn requests_beyond_max_connections_are_rejected_with_503() {
+    use tokio::io::AsyncWriteExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig { max_connections: 2, ..Default::default() });
+    let addr = spawn_app(ctx).await;
+    let url = format!("http://{}/api/status", addr);
+
+    // Requests whose promised body never arrives stay in flight without
+    // tying up a runtime worker.
+    let mut stalled = Vec::new();
+    for _ in 0..2 {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"POST /api/repair-shard HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 64\r\n\r\n")
+            .await
+            .unwrap();
+        stalled.push(stream);
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+    let rejected = reqwest::get(&url).await.unwrap();
+    assert_eq!(rejected.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+    drop(stalled);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(reqwest::get(&url).await.unwrap().status().is_success());
+}
+
+#[tokio::test]
+async fn event_streams_beyond_max_stream_clients_are_rejected() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig { max_stream_clients: 2, ..Default::default() });
+    let addr = spawn_app(ctx.clone()).await;
+    let url = format!("http://{}/api/events", addr);
+
+    let mut open = Vec::new();
+    for _ in 0..2 {
+        let response = reqwest::get(&url).await.unwrap();
+        assert!(response.status().is_success());
+        open.push(response);
+    }
+    let rejected = reqwest::get(&url).await.unwrap();
+    assert_eq!(rejected.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    assert!(rejected.text().await.unwrap().contains("max_stream_clients"));
+
+    // The streams already open keep delivering status events.
+    for response in &mut open {
+        let chunk = response.chunk().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&chunk).starts_with("event: status"));
+    }
+
+    // Closing one frees its slot.
+    drop(open.pop());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(ctx.streams.active(), 1);
+    assert!(reqwest::get(&url).await.unwrap().status().is_success());
+}
+
+#[tokio::test]
+async fn batched_log_stream_sends_rapid_lines_as_one_ordered_event() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig { log_stream_batch_ms: 300, ..Default::default() });
+    ctx.status.lock().unwrap().logs.push("before connecting".to_string());
+    let addr = spawn_app(ctx.clone()).await;
+
+    let mut response = reqwest::get(format!("http://{}/api/logs/stream", addr)).await.unwrap();
+    assert!(response.status().is_success());
+    {
+        let mut status = ctx.status.lock().unwrap();
+        for line in ["first", "second", "third"] {
+            status.logs.push(line.to_string());
+        }
+    }
+
+    let mut body = String::new();
+    while !body.contains("\n\n") {
+        let chunk = response.chunk().await.unwrap().unwrap();
+        body.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    let event = body.split("\n\n").next().unwrap();
+    assert!(event.starts_with("event: logs\n"), "{}", event);
+    let data = event.lines().find_map(|line| line.strip_prefix("data: ")).unwrap();
+    let lines: Vec<String> = serde_json::from_str(data).unwrap();
+    assert_eq!(lines, ["first", "second", "third"]);
+}
+
+#[tokio::test]
+async fn changes_endpoint_reports_added_modified_and_removed_files() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    fs::write(source.join("edited.txt"), b"v1").unwrap();
+    fs::write(source.join("deleted.txt"), b"doomed").unwrap();
+    fs::write(source.join("stable.txt"), b"unchanged").unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+
+    // History has millisecond resolution, so keep the boundary clear of both scans.
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let since = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    fs::write(source.join("edited.txt"), b"version two").unwrap();
+    fs::write(source.join("new.txt"), b"fresh").unwrap();
+    fs::remove_file(source.join("deleted.txt")).unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+
+    let addr = spawn_app(ctx).await;
+    let changes: ChangeSet = reqwest::Client::new()
+        .get(format!("http://{}/api/changes", addr))
+        .query(&[("since", since)])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let display = |name: &str| source.join(name).to_string_lossy().into_owned();
+    assert_eq!(changes, ChangeSet {
+        added: vec![display("new.txt")],
+        modified: vec![display("edited.txt")],
+        removed: vec![display("deleted.txt")],
+    });
+}
+
+#[tokio::test]
+async fn recover_reads_the_written_file_back_and_reports_it_verified() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig { api_token: Some(test_token()), ..Default::default() });
+    let files = protect_all(&ctx, &source, &[("data.bin", 10_000)]);
+    let out = tmp.path().join("restored.bin");
+    let addr = spawn_app(ctx).await;
+    let request = serde_json::json!({ "path": files[0].to_string_lossy(), "out": out.to_string_lossy() });
+
+    // `out` may be anywhere the service can write, so the token is required.
+    let url = format!("http://{}/api/recover", addr);
+    let anonymous = reqwest::Client::new().post(&url).json(&request).send().await.unwrap();
+    assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert!(!out.exists());
+
+    let response = reqwest::Client::new().post(&url).bearer_auth(&test_token().0).json(&request).send().await.unwrap();
+    assert!(response.status().is_success());
+    assert!(response.json::<RecoverResult>().await.unwrap().verified);
+    assert_eq!(fs::read(&out).unwrap(), fs::read(&files[0]).unwrap());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn recover_reports_a_copy_lost_on_write_as_unverified() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig { api_token: Some(test_token()), ..Default::default() });
+    let files = protect_all(&ctx, &source, &[("data.bin", 10_000)]);
+    // Writes through this path succeed but never reach a disk.
+    let out = tmp.path().join("restored.bin");
+    std::os::unix::fs::symlink("/dev/null", &out).unwrap();
+    let addr = spawn_app(ctx).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/api/recover", addr))
+        .bearer_auth(&test_token().0)
+        .json(&serde_json::json!({ "path": files[0].to_string_lossy(), "out": out.to_string_lossy() }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(!response.json::<RecoverResult>().await.unwrap().verified);
+}
+
+#[tokio::test]
+async fn rebuild_reencodes_a_directory_from_scratch_with_the_current_config() {
+    let tmp = tempfile::tempdir().unwrap();
+    let old_config = AppConfig { data_shards: 4, parity_shards: 2, ..Default::default() };
+    let (ctx, source) = test_context(&tmp, old_config);
+    let files = protect_all(&ctx, &source, &[("album/a.jpg", 5_000), ("album/b.jpg", 7_000), ("notes.txt", 100)]);
+    let old: Vec<_> = files.iter().map(|file| metadata::get_file_metadata(&ctx.db, file).unwrap().unwrap()).collect();
+    drop(ctx);
+
+    // The shard counts changed since the files were protected.
+    let (ctx, _) = test_context(&tmp, AppConfig { data_shards: 2, parity_shards: 1, ..Default::default() });
+    let addr = spawn_app(ctx.clone()).await;
+    let album = source.join("album");
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/api/rebuild", addr))
+        .json(&serde_json::json!({ "path": album.to_string_lossy() }))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let result: RebuildResult = response.json().await.unwrap();
+    assert_eq!((result.removed, result.protected, result.failed), (2, 2, 0));
+
+    for (file, old) in files[..2].iter().zip(&old) {
+        let record = metadata::get_file_metadata(&ctx.db, file).unwrap().unwrap();
+        assert_eq!((record.data_shards, record.parity_shards), (2, 1));
+        assert_ne!(record.shard_checksums, old.shard_checksums);
+        // Shards beyond the new set were removed, not left behind.
+        assert!(ctx.store.read_shard_uncached(&record.file_id, 3).unwrap().is_none());
+        let restored = tmp.path().join("restored");
+        assert!(protector::recover_file(&ctx, file, &restored).unwrap());
+        assert_eq!(fs::read(&restored).unwrap(), fs::read(file).unwrap());
+    }
+    // Files outside the directory keep their protection.
+    let untouched = metadata::get_file_metadata(&ctx.db, &files[2]).unwrap().unwrap();
+    assert_eq!(untouched.shard_checksums, old[2].shard_checksums);
+    assert!(ctx.status.lock().unwrap().logs.iter().any(|line| line.starts_with("[Rebuild]")));
+
+    let outside = reqwest::Client::new()
+        .post(format!("http://{}/api/rebuild", addr))
+        .json(&serde_json::json!({ "path": tmp.path().to_string_lossy() }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(outside.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn watched_dirs_can_be_added_at_runtime_and_are_scanned() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let extra = tmp.path().join("extra");
+    fs::create_dir_all(extra.join("nested")).unwrap();
+    let file = extra.join("nested/new.txt");
+    fs::write(&file, b"watched from now on").unwrap();
+    let addr = spawn_app(ctx.clone()).await;
+    let client = reqwest::Client::new();
+    let add = |path: PathBuf| {
+        client
+            .post(format!("http://{}/api/watched-dirs", addr))
+            .json(&serde_json::json!({ "path": path.to_string_lossy() }))
+            .send()
+    };
+
+    let response = add(extra.clone()).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+    let result: WatchedDirResult = response.json().await.unwrap();
+    let expected = vec![source.to_string_lossy().into_owned(), extra.to_string_lossy().into_owned()];
+    assert_eq!(result.watched_dirs, expected);
+    let status: AppStatus = client.get(format!("http://{}/api/status?fields=full", addr)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(status.watched_dirs, expected);
+
+    // The initial scan runs in the background.
+    for _ in 0..100 {
+        if metadata::get_file_metadata(&ctx.db, &file).unwrap().is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert!(metadata::get_file_metadata(&ctx.db, &file).unwrap().is_some());
+
+    // Directories already covered are refused, nested ones included.
+    for dir in [extra.clone(), extra.join("nested"), source] {
+        assert_eq!(add(dir).await.unwrap().status(), reqwest::StatusCode::CONFLICT);
+    }
+    let missing = add(tmp.path().join("missing")).await.unwrap();
+    assert_eq!(missing.status(), reqwest::StatusCode::BAD_REQUEST);
+    let error: ApiError = missing.json().await.unwrap();
+    assert!(error.error.contains("is not an existing directory"), "{}", error.error);
+    let store_parent = add(tmp.path().to_path_buf()).await.unwrap();
+    assert_eq!(store_parent.status(), reqwest::StatusCode::BAD_REQUEST);
+    let error: ApiError = store_parent.json().await.unwrap();
+    assert!(error.error.contains("contains the shard store"), "{}", error.error);
+}
+
+#[tokio::test]
+async fn removing_a_watched_dir_keeps_its_protection_unless_purged() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let files = protect_all(&ctx, &source, &[("kept.txt", 100)]);
+    let extra = tmp.path().join("extra");
+    fs::create_dir_all(&extra).unwrap();
+    backend::watcher::register(&ctx, &extra).unwrap();
+    let purged_file = extra.join("gone.txt");
+    fs::write(&purged_file, b"purged with its directory").unwrap();
+    let record = protector::protect_file(&ctx, &purged_file).unwrap();
+    let addr = spawn_app(ctx.clone()).await;
+    let client = reqwest::Client::new();
+
+    // The path as a query parameter; the files stay protected.
+    let response = client
+        .delete(format!("http://{}/api/watched-dirs", addr))
+        .query(&[("path", source.to_string_lossy())])
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let result: WatchedDirResult = response.json().await.unwrap();
+    assert_eq!((result.watched_dirs, result.purged), (vec![extra.to_string_lossy().into_owned()], 0));
+    assert!(metadata::get_file_metadata(&ctx.db, &files[0]).unwrap().is_some());
+    assert_eq!(ctx.status.lock().unwrap().watched_dirs, vec![extra.to_string_lossy().into_owned()]);
+
+    // The path in the body, purging what was protected below it.
+    let result: WatchedDirResult = client
+        .delete(format!("http://{}/api/watched-dirs", addr))
+        .json(&serde_json::json!({ "path": extra.to_string_lossy(), "purge": true }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!((result.watched_dirs.len(), result.purged), (0, 1));
+    assert!(metadata::get_file_metadata(&ctx.db, &purged_file).unwrap().is_none());
+    assert!(!ctx.store.shard_path(&record.file_id, 0).exists());
+    assert!(purged_file.exists());
+
+    let again = client
+        .delete(format!("http://{}/api/watched-dirs", addr))
+        .query(&[("path", extra.to_string_lossy())])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(again.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn repair_shard_rebuilds_only_the_corrupted_shard() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let file = source.join("data.bin");
+    fs::write(&file, vec![7u8; 4096]).unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+
+    let record = metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap();
+    let shard_paths: Vec<_> = (0..record.total_shards())
+        .map(|i| ctx.store.shard_path(&record.file_id, i))
+        .collect();
+    let originals: Vec<_> = shard_paths.iter().map(|p| fs::read(p).unwrap()).collect();
+    let mtimes: Vec<_> = shard_paths.iter().map(|p| fs::metadata(p).unwrap().modified().unwrap()).collect();
+    fs::write(&shard_paths[1], b"garbage").unwrap();
+    let corrupted_mtime = fs::metadata(&shard_paths[1]).unwrap().modified().unwrap();
+
+    let addr = spawn_app(ctx.clone()).await;
+    let client = reqwest::Client::new();
+    let repair = || {
+        client
+            .post(format!("http://{}/api/repair-shard", addr))
+            .json(&serde_json::json!({ "path": file.to_string_lossy(), "shard_index": 1 }))
+            .send()
+    };
+
+    let response = repair().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let result: ShardRepairResult = response.json().await.unwrap();
+    assert_eq!(result.checksum, record.shard_checksums[1]);
+
+    for (i, path) in shard_paths.iter().enumerate() {
+        assert_eq!(fs::read(path).unwrap(), originals[i], "shard {}", i);
+        if i != 1 {
+            assert_eq!(fs::metadata(path).unwrap().modified().unwrap(), mtimes[i], "shard {} was rewritten", i);
+        }
+    }
+    assert!(fs::metadata(&shard_paths[1]).unwrap().modified().unwrap() >= corrupted_mtime);
+
+    // The shard is healthy now, so a second request has nothing to do.
+    assert_eq!(repair().await.unwrap().status(), reqwest::StatusCode::CONFLICT);
+}
+
+#[derive(RustEmbed)]
+#[folder = "tests/fixtures/dist/"]
+struct TestAssets;
+
+#[tokio::test]
+async fn release_router_serves_the_embedded_index() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig::default());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let router = router_with_frontend(ctx, assets::embedded_frontend::<TestAssets>());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    let response = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/html");
+    let expected = TestAssets::get("index.html").unwrap().data;
+    assert_eq!(response.bytes().await.unwrap().as_ref(), expected.as_ref());
+}
+
+#[tokio::test]
+async fn client_routes_get_the_spa_but_unknown_api_paths_404() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig::default());
+    let dist = tmp.path().join("dist");
+    fs::create_dir_all(&dist).unwrap();
+    fs::write(dist.join("index.html"), "<html>spa</html>").unwrap();
+    fs::write(dist.join("app.js"), "console.log(1)").unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let router = router_with_frontend(ctx, assets::dir_frontend(&dist));
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    let get = |path: &str| reqwest::get(format!("http://{}{}", addr, path));
+    let client_route = get("/some/client/route").await.unwrap();
+    assert_eq!(client_route.status(), reqwest::StatusCode::OK);
+    assert_eq!(client_route.text().await.unwrap(), "<html>spa</html>");
+    assert_eq!(get("/app.js").await.unwrap().text().await.unwrap(), "console.log(1)");
+    assert_eq!(get("/api/unknown").await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    assert!(get("/api/status").await.unwrap().status().is_success());
+}
+
+#[tokio::test]
+async fn check_runs_are_persisted_and_listed() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let damaged = source.join("damaged.bin");
+    fs::write(&damaged, vec![1u8; 1024]).unwrap();
+    fs::write(source.join("healthy.bin"), vec![2u8; 1024]).unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+
+    let clean = checker::run_check(&ctx).unwrap();
+    let record = metadata::get_file_metadata(&ctx.db, &damaged).unwrap().unwrap();
+    fs::write(ctx.store.shard_path(&record.file_id, 0), b"bit rot").unwrap();
+    let dirty = checker::run_check(&ctx).unwrap();
+
+    let addr = spawn_app(ctx).await;
+    let client = reqwest::Client::new();
+    let runs: Vec<CheckRun> = client
+        .get(format!("http://{}/api/checks", addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(runs.len(), 2);
+    assert_ne!(runs[0].id, runs[1].id);
+    // Newest first.
+    assert_eq!(runs[0], dirty);
+    assert_eq!(runs[1], clean);
+    assert_eq!((clean.checked, clean.corrupted), (2, 0));
+    assert_eq!((dirty.checked, dirty.corrupted), (2, 1));
+    assert_eq!(dirty.corrupted_paths, vec![damaged.to_string_lossy().into_owned()]);
+
+    let detail: CheckRun = client
+        .get(format!("http://{}/api/checks/{}", addr, dirty.id))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail, dirty);
+    let missing = client.get(format!("http://{}/api/checks/999999", addr)).send().await.unwrap();
+    assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn file_detail_includes_the_merkle_root() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let file = source.join("data.bin");
+    fs::write(&file, b"some content").unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+    let record = metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap();
+
+    let addr = spawn_app(ctx).await;
+    let detail: FileDetail = reqwest::get(format!("http://{}/api/files{}", addr, file.display()))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail.path, file.to_string_lossy());
+    assert_eq!(detail.merkle_root, record.merkle_root);
+    assert!(detail.merkle_root.is_some());
+
+    let missing = reqwest::get(format!("http://{}/api/files{}", addr, source.join("nope").display())).await.unwrap();
+    assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn file_detail_describes_how_many_shards_can_be_lost() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig { data_shards: 4, parity_shards: 2, ..Default::default() });
+    let paths = protect_all(&ctx, &source, &[("four-plus-two.bin", 4096)]);
+
+    let addr = spawn_app(ctx).await;
+    let detail: FileDetail = reqwest::get(format!("http://{}/api/files{}", addr, paths[0].display()))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let tolerance = detail.fault_tolerance;
+    assert_eq!((tolerance.max_lost_shards, tolerance.total_shards), (2, 6));
+    assert!((tolerance.percent - 100.0 / 3.0).abs() < 1e-9);
+    assert_eq!(tolerance.description, "Survives losing up to 2 of its 6 pieces (33%)");
+}
+
+#[tokio::test]
+async fn file_detail_finds_percent_encoded_paths() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("notes #1 ?50%.txt", 100)]);
+
+    // Encoded as the frontend does, one segment at a time.
+    let encoded: String = paths[0]
+        .display()
+        .to_string()
+        .chars()
+        .map(|c| match c {
+            ' ' => "%20".to_string(),
+            '#' => "%23".to_string(),
+            '?' => "%3F".to_string(),
+            '%' => "%25".to_string(),
+            c => c.to_string(),
+        })
+        .collect();
+    let addr = spawn_app(ctx).await;
+    let detail: FileDetail = reqwest::get(format!("http://{}/api/files{}", addr, encoded))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail.path, paths[0].display().to_string());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn file_detail_flags_shards_on_an_unexpected_device() {
+    use backend::shard_store::ShardStore;
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Arc;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (mut ctx, source) = test_context(&tmp, AppConfig { verify_shard_device: true, ..Default::default() });
+    let file = source.join("data.bin");
+    fs::write(&file, b"some content").unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+    let file_id = protector::file_id_for(&file);
+    let store_dev = fs::metadata(tmp.path().join("shards")).unwrap().dev();
+    assert_eq!(ctx.store.shard_device(&file_id, 0).unwrap(), Some(store_dev));
+
+    let addr = spawn_app(ctx.clone()).await;
+    let url = format!("http://{}/api/files{}", addr, file.display());
+    let detail: FileDetail = reqwest::get(&url).await.unwrap().json().await.unwrap();
+    assert!(detail.misplaced_shards.is_empty());
+
+    // Pretend the store lives on another device, as if its disk had not been mounted.
+    ctx.store = Arc::new(ShardStore::new(tmp.path().join("shards")).unwrap().with_expected_device(store_dev + 1));
+    let addr = spawn_app(ctx).await;
+    let url = format!("http://{}/api/files{}", addr, file.display());
+    let detail: FileDetail = reqwest::get(&url).await.unwrap().json().await.unwrap();
+    assert_eq!(detail.misplaced_shards, (0..detail.data_shards + detail.parity_shards).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn file_meta_persists_and_appears_in_the_file_detail() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let file = source.join("important.db");
+    fs::write(&file, b"precious").unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+
+    let addr = spawn_app(ctx.clone()).await;
+    let client = reqwest::Client::new();
+    let response = client
+        .put(format!("http://{}/api/files{}/meta", addr, file.display()))
+        .json(&serde_json::json!({ "tags": ["finance", "daily"], "priority": 200 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    let detail: FileDetail = client.get(format!("http://{}/api/files{}", addr, file.display()))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail.tags, vec!["finance".to_string(), "daily".to_string()]);
+    assert_eq!(detail.priority, 200);
+
+    // Annotations are stored in the record and survive a re-encode.
+    fs::write(&file, b"precious, edited").unwrap();
+    protector::protect_file(&ctx, &file).unwrap();
+    let record = metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap();
+    assert_eq!(record.tags, vec!["finance".to_string(), "daily".to_string()]);
+    assert_eq!(record.priority, 200);
+
+    let missing = client
+        .put(format!("http://{}/api/files{}/meta", addr, source.join("nope").display()))
+        .json(&serde_json::json!({ "tags": [], "priority": 1 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn read_from_shards_bypasses_a_corrupted_source() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let file = source.join("data.bin");
+    let original: Vec<u8> = (0..5000u32).map(|i| (i % 199) as u8).collect();
+    fs::write(&file, &original).unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+    let mut corrupted = original.clone();
+    corrupted[100] ^= 0xff;
+    fs::write(&file, &corrupted).unwrap();
+
+    let addr = spawn_app(ctx).await;
+    let read = |from: &str| reqwest::get(format!("http://{}/api/read{}?from={}", addr, file.display(), from));
+    let from_shards = read("shards").await.unwrap();
+    assert_eq!(from_shards.status(), reqwest::StatusCode::OK);
+    assert_eq!(from_shards.bytes().await.unwrap().as_ref(), original.as_slice());
+    assert_eq!(read("source").await.unwrap().bytes().await.unwrap().as_ref(), corrupted.as_slice());
+}
+
+#[tokio::test]
+async fn files_protected_on_one_node_are_recoverable_on_its_replica() {
+    let tmp_b = tempfile::tempdir().unwrap();
+    let (node_b, _) = test_context(&tmp_b, AppConfig { api_token: Some(test_token()), ..Default::default() });
+    let addr_b = spawn_app(node_b.clone()).await;
+
+    let tmp_a = tempfile::tempdir().unwrap();
+    let (node_a, source) = test_context(&tmp_a, AppConfig {
+        replication_peers: vec![format!("http://{}", addr_b)],
+        api_token: Some(test_token()),
+        ..Default::default()
+    });
+    replication::start(node_a.clone());
+    let file = source.join("report.bin");
+    let content: Vec<u8> = (0..20_000u32).map(|i| (i % 241) as u8).collect();
+    fs::write(&file, &content).unwrap();
+    let scan_ctx = node_a.clone();
+    tokio::task::spawn_blocking(move || scanner::scan_directories(&scan_ctx)).await.unwrap().unwrap();
+
+    for _ in 0..100 {
+        if node_a.replication.outstanding() == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(node_a.replication.outstanding(), 0);
+
+    let restored = tmp_b.path().join("restored.bin");
+    protector::recover_file(&node_b, &file, &restored).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), content);
+    assert_eq!(
+        metadata::get_file_metadata(&node_b.db, &file).unwrap(),
+        metadata::get_file_metadata(&node_a.db, &file).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn replicas_need_the_api_token_and_valid_file_ids() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig { api_token: Some(test_token()), ..Default::default() });
+    let paths = protect_all(&ctx, &source, &[("report.bin", 4096)]);
+    let record = metadata::get_file_metadata(&ctx.db, &paths[0]).unwrap().unwrap();
+    let shards = (0..record.total_shards())
+        .map(|index| ctx.store.read_shard_uncached(&record.file_id, index).unwrap().unwrap())
+        .collect();
+    let mut payload = replication::ReplicaPayload { path: metadata::path_key(&source.join("elsewhere.bin")), record, shards };
+    let addr = spawn_app(ctx.clone()).await;
+
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api/replicate", addr);
+    let anonymous = client.post(&url).json(&payload).send().await.unwrap();
+    assert_eq!(anonymous.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let checksums = client
+        .post(format!("http://{}/api/replication/checksums", addr))
+        .json(&replication::ChecksumRequest { paths: vec![metadata::path_key(&paths[0])] })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(checksums.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    // An id that is not a hash would name shard files outside the store.
+    payload.record.file_id = "../../escaped".to_string();
+    let traversal = client.post(&url).bearer_auth(&test_token().0).json(&payload).send().await.unwrap();
+    assert_eq!(traversal.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(!tmp.path().join("escaped").exists());
+    assert!(metadata::get_file_metadata(&ctx.db, &source.join("elsewhere.bin")).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn files_too_large_to_replicate_are_reported_without_retrying() {
+    let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig {
+        replication_peers: vec![format!("http://{}", unused)],
+        streaming_threshold_bytes: 20_000,
+        ..Default::default()
+    });
+    replication::start(ctx.clone());
+    protect_all(&ctx, &source, &[("huge.bin", 50_000)]);
+    for _ in 0..100 {
+        if ctx.replication.outstanding() == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(ctx.replication.outstanding(), 0);
+    assert_eq!(backend::errors::snapshot(&ctx).replication_retries, 0);
+    let status = ctx.status.lock().unwrap().clone();
+    assert_eq!(status.replication_failures, 1);
+    assert!(status.last_replication_error.unwrap().contains("50000 bytes"));
+}
+
+#[tokio::test]
+async fn authenticated_shutdown_stops_the_server_cleanly() {
+    use backend::config::Secret;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig {
+        api_token: Some(Secret("s3cret".to_string())),
+        allow_remote_shutdown: true,
+        ..Default::default()
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(backend::serve(listener, ctx));
+
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api/shutdown", addr);
+    let denied = client.post(&url).bearer_auth("wrong").send().await.unwrap();
+    assert_eq!(denied.status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert!(!server.is_finished());
+
+    let accepted = client.post(&url).bearer_auth("s3cret").send().await.unwrap();
+    assert_eq!(accepted.status(), reqwest::StatusCode::ACCEPTED);
+    let result = tokio::time::timeout(std::time::Duration::from_secs(10), server).await.expect("server did not stop");
+    result.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_reports_a_summary_of_the_session() {
+    use backend::repair;
+    use backend::session::SessionSummary;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = tokio::spawn(backend::serve(listener, ctx.clone()));
+
+    let work_ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || {
+        let ctx = work_ctx;
+        let paths = protect_all(&ctx, &source, &[("a.bin", 1024), ("b.bin", 1024)]);
+        checker::run_check(&ctx).unwrap();
+        let record = metadata::get_file_metadata(&ctx.db, &paths[0]).unwrap().unwrap();
+        fs::write(ctx.store.shard_path(&record.file_id, 1), b"bit rot").unwrap();
+        checker::run_check(&ctx).unwrap();
+        repair::repair_shard(&ctx, &paths[0], 1).unwrap();
+    })
+    .await
+    .unwrap();
+
+    ctx.shutdown.notify_one();
+    let summary = tokio::time::timeout(std::time::Duration::from_secs(10), server)
+        .await
+        .expect("server did not stop")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        summary,
+        SessionSummary { files_protected: 2, checks_run: 2, corruptions_found: 1, shards_repaired: 1, uptime_secs: summary.uptime_secs }
+    );
+}
+
+#[tokio::test]
+async fn shutdown_is_refused_unless_enabled() {
+    use backend::config::Secret;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig { api_token: Some(Secret("s3cret".to_string())), ..Default::default() });
+    let addr = spawn_app(ctx).await;
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/api/shutdown", addr))
+        .bearer_auth("s3cret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn encode_throughput_is_recorded_and_aggregated() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let file = source.join("large.bin");
+    let content: Vec<u8> = (0..16 * 1024 * 1024u32).map(|i| (i % 253) as u8).collect();
+    fs::write(&file, &content).unwrap();
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    assert!(record.encode_duration_ms > 0);
+    assert_eq!(record.shard_bytes, (record.total_shards() * record.shard_size) as u64);
+
+    let addr = spawn_app(ctx).await;
+    let detail: FileDetail = reqwest::get(format!("http://{}/api/files{}", addr, file.display()))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail.encode_duration_ms, record.encode_duration_ms);
+    let stats: shared::ShardStoreStats = reqwest::get(format!("http://{}/api/shard-store/stats", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!((stats.files, stats.encoded_bytes), (1, content.len() as u64));
+    assert!(stats.average_encode_bytes_per_sec.unwrap() > 0.0);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn shard_store_health_reports_each_location_and_flags_an_unreachable_one() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let file = source.join("a.txt");
+    fs::write(&file, b"spread over disks").unwrap();
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    let store = ctx.store.root().to_path_buf();
+    let second = tmp.path().join("second-disk");
+    fs::create_dir_all(&second).unwrap();
+    fs::write(second.join("f.0.rsguard"), b"shard").unwrap();
+    std::os::unix::fs::symlink(&second, store.join("disk-b")).unwrap();
+    std::os::unix::fs::symlink(tmp.path().join("unplugged"), store.join("disk-c")).unwrap();
+
+    let addr = spawn_app(ctx).await;
+    let health: Vec<shared::ShardLocationHealth> =
+        reqwest::get(format!("http://{}/api/shard-store/health", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(health.len(), 3);
+    let (root, disk_b, disk_c) = (&health[0], &health[1], &health[2]);
+    assert!(root.reachable && root.free_bytes.is_some());
+    assert_eq!((root.shard_count, root.read_errors), (record.total_shards() as u64, 0));
+    assert!(disk_b.path.ends_with("disk-b") && disk_b.reachable);
+    assert_eq!((disk_b.shard_count, disk_b.read_errors), (1, 0));
+    assert!(disk_c.path.ends_with("disk-c") && !disk_c.reachable);
+    assert_eq!((disk_c.free_bytes, disk_c.shard_count), (None, 0));
+}
+
+#[tokio::test]
+async fn corruption_burst_within_the_window_sends_one_coalesced_notification() {
+    use axum::{routing::post, Json, Router};
+    use backend::notifications;
+    use std::sync::{Arc, Mutex};
+
+    let received: Arc<Mutex<Vec<serde_json::Value>>> = Arc::default();
+    let sink = received.clone();
+    let webhook = Router::new().route("/hook", post(move |Json(body): Json<serde_json::Value>| async move {
+        sink.lock().unwrap().push(body);
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, webhook).await.unwrap() });
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig {
+        notification_webhook: Some(format!("http://{}/hook", hook_addr)),
+        notification_coalesce_secs: 2,
+        ..Default::default()
+    });
+    for i in 0..10 {
+        fs::write(source.join(format!("{}.bin", i)), vec![i as u8; 2048]).unwrap();
+    }
+    notifications::start(ctx.clone());
+    // Each check finds one more file corrupted, raising one notification each.
+    let check_ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || {
+        scanner::scan_directories(&check_ctx).unwrap();
+        for i in 0..10 {
+            let path = check_ctx.config.watched_directories[0].join(format!("{}.bin", i));
+            let record = metadata::get_file_metadata(&check_ctx.db, &path).unwrap().unwrap();
+            fs::write(check_ctx.store.shard_path(&record.file_id, 0), b"bit rot").unwrap();
+            assert_eq!(checker::run_check(&check_ctx).unwrap().newly_corrupted.len(), 1);
+        }
+    })
+    .await
+    .unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 1, "{:?}", received);
+    assert_eq!(received[0]["event"], "corruption_changed");
+    assert_eq!(received[0]["events"], 10);
+    assert_eq!(received[0]["newly_corrupted"].as_array().unwrap().len(), 10);
+}
+
+/// Protects `names` under `source` with `len` bytes each and returns their paths.
+fn protect_all(ctx: &AppContext, source: &std::path::Path, files: &[(&str, usize)]) -> Vec<PathBuf> {
+    files
+        .iter()
+        .map(|(name, len)| {
+            let path = source.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, vec![7u8; *len]).unwrap();
+            protector::protect_file(ctx, &path).unwrap();
+            path
+        })
+        .collect()
+}
+
+async fn query_metadata(addr: SocketAddr, params: &[(&str, &str)]) -> shared::MetadataQueryPage {
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/metadata/query", addr))
+        .query(params)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+fn queried_paths(page: &shared::MetadataQueryPage) -> Vec<String> {
+    page.records.iter().map(|record| record.file.path.clone()).collect()
+}
+
+#[tokio::test]
+async fn metadata_query_filters_by_path_prefix_and_size() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("docs/a.txt", 100), ("docs/b.txt", 5000), ("photos/c.jpg", 2000)]);
+    let addr = spawn_app(ctx).await;
+    let display = |i: usize| paths[i].to_string_lossy().into_owned();
+
+    let docs = source.join("docs").to_string_lossy().into_owned();
+    let page = query_metadata(addr, &[("prefix", docs.as_str())]).await;
+    assert_eq!(page.version, shared::METADATA_QUERY_VERSION);
+    assert_eq!(queried_paths(&page), vec![display(0), display(1)]);
+
+    let page = query_metadata(addr, &[("min_size", "1000"), ("max_size", "2000")]).await;
+    assert_eq!(queried_paths(&page), vec![display(2)]);
+    let page = query_metadata(addr, &[("prefix", docs.as_str()), ("min_size", "1000")]).await;
+    assert_eq!(queried_paths(&page), vec![display(1)]);
+}
+
+#[tokio::test]
+async fn metadata_query_filters_by_status() {
+    use shared::RecordStatus;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("live.bin", 100), ("gone.bin", 100), ("flaky.bin", 100)]);
+    metadata::mark_deleted(&ctx.db, &paths[1]).unwrap();
+    metadata::quarantine(&ctx.db, &paths[2], "flapping").unwrap();
+    let addr = spawn_app(ctx).await;
+
+    for (status, expected, index) in [
+        ("protected", RecordStatus::Protected, 0),
+        ("deleted", RecordStatus::Deleted, 1),
+        ("quarantined", RecordStatus::Quarantined, 2),
+    ] {
+        let page = query_metadata(addr, &[("status", status)]).await;
+        assert_eq!(page.total, 1, "{}", status);
+        assert_eq!(page.records[0].status, expected);
+        assert_eq!(page.records[0].file.path, paths[index].to_string_lossy());
+    }
+    let invalid = reqwest::get(format!("http://{}/api/metadata/query?status=lost", addr)).await.unwrap();
+    assert_eq!(invalid.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn metadata_query_filters_by_protection_date() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("2023.bin", 100), ("2024.bin", 100), ("2025.bin", 100)]);
+    for (path, protected_at) in paths.iter().zip([1_672_531_200, 1_704_067_200, 1_735_689_600]) {
+        let mut record = metadata::get_file_metadata(&ctx.db, path).unwrap().unwrap();
+        record.protected_at = protected_at;
+        metadata::store_file_metadata(&ctx.db, path, &record).unwrap();
+    }
+    let addr = spawn_app(ctx).await;
+
+    let page = query_metadata(addr, &[
+        ("protected_after", "2023-06-01T00:00:00Z"),
+        ("protected_before", "2024-01-01T00:00:00Z"),
+    ])
+    .await;
+    assert_eq!(queried_paths(&page), vec![paths[1].to_string_lossy().into_owned()]);
+    let page = query_metadata(addr, &[("protected_after", "2024-06-01T00:00:00Z")]).await;
+    assert_eq!(queried_paths(&page), vec![paths[2].to_string_lossy().into_owned()]);
+
+    let invalid = reqwest::get(format!("http://{}/api/metadata/query?protected_after=yesterday", addr)).await.unwrap();
+    assert_eq!(invalid.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn metadata_query_pages_through_all_matches() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let names: Vec<String> = (0..5).map(|i| format!("{}.bin", i)).collect();
+    let files: Vec<(&str, usize)> = names.iter().map(|name| (name.as_str(), 100)).collect();
+    let paths = protect_all(&ctx, &source, &files);
+    let addr = spawn_app(ctx).await;
+
+    let mut seen = Vec::new();
+    let mut offset = Some(0);
+    while let Some(next) = offset {
+        let next = next.to_string();
+        let page = query_metadata(addr, &[("limit", "2"), ("offset", next.as_str())]).await;
+        assert_eq!(page.total, 5);
+        assert!(page.records.len() <= 2);
+        seen.extend(queried_paths(&page));
+        offset = page.next_offset;
+    }
+    let expected: Vec<String> = paths.iter().map(|path| path.to_string_lossy().into_owned()).collect();
+    assert_eq!(seen, expected);
+
+    let too_big = reqwest::get(format!("http://{}/api/metadata/query?limit=100000", addr)).await.unwrap();
+    assert_eq!(too_big.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn check_diff_classifies_each_path_between_two_runs() {
+    use backend::repair;
+    use shared::CheckDiff;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("healed.bin", 1024), ("still.bin", 1024), ("new.bin", 1024)]);
+    let corrupt = |path: &PathBuf| {
+        let record = metadata::get_file_metadata(&ctx.db, path).unwrap().unwrap();
+        fs::write(ctx.store.shard_path(&record.file_id, 0), b"bit rot").unwrap();
+    };
+    corrupt(&paths[0]);
+    corrupt(&paths[1]);
+    let first = checker::run_check(&ctx).unwrap();
+    repair::repair_shard(&ctx, &paths[0], 0).unwrap();
+    corrupt(&paths[2]);
+    let second = checker::run_check(&ctx).unwrap();
+
+    let addr = spawn_app(ctx).await;
+    let diff = |from: u64, to: u64| reqwest::get(format!("http://{}/api/checks/diff?from={}&to={}", addr, from, to));
+    let response = diff(first.id, second.id).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let display = |i: usize| vec![paths[i].to_string_lossy().into_owned()];
+    assert_eq!(response.json::<CheckDiff>().await.unwrap(), CheckDiff {
+        newly_corrupted: display(2),
+        newly_healed: display(0),
+        still_corrupted: display(1),
+    });
+    assert_eq!(diff(first.id, 999).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn worker_gauges_count_repairs_waiting_for_a_locked_file() {
+    use backend::repair;
+    use shared::WorkerStats;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let file = protect_all(&ctx, &source, &[("busy.bin", 4096)]).remove(0);
+    let addr = spawn_app(ctx.clone()).await;
+    let stats = || async { reqwest::get(format!("http://{}/api/workers", addr)).await.unwrap().json::<WorkerStats>().await.unwrap() };
+
+    // Repairs of a locked file queue up behind the lock.
+    let guard = ctx.file_locks.lock(&file);
+    let repairs: Vec<_> = (0..3)
+        .map(|index| {
+            let (ctx, file) = (ctx.clone(), file.clone());
+            std::thread::spawn(move || repair::repair_shard(&ctx, &file, index).unwrap())
+        })
+        .collect();
+    let mut queued = WorkerStats::default();
+    for _ in 0..100 {
+        queued = stats().await;
+        if queued.repair_queue_depth == 3 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(queued, WorkerStats { repair_queue_depth: 3, ..Default::default() });
+
+    drop(guard);
+    for repair in repairs {
+        repair.join().unwrap();
+    }
+    assert_eq!(stats().await, WorkerStats::default());
+}
+
+#[tokio::test]
+async fn heartbeat_advances_after_a_check_and_ages_with_time() {
+    use shared::HeartbeatStatus;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig { heartbeat_max_age_secs: 1, ..Default::default() });
+    protect_all(&ctx, &source, &[("a.bin", 1024)]);
+    let addr = spawn_app(ctx.clone()).await;
+    let heartbeat = || async {
+        let response = reqwest::get(format!("http://{}/api/heartbeat", addr)).await.unwrap();
+        (response.status(), response.json::<HeartbeatStatus>().await.unwrap())
+    };
+
+    let (_, before) = heartbeat().await;
+    assert_eq!(before.last_beat, None);
+    let check_ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || checker::run_check(&check_ctx)).await.unwrap().unwrap();
+    let (code, fresh) = heartbeat().await;
+    assert_eq!(code, reqwest::StatusCode::OK);
+    assert!(fresh.last_beat.is_some());
+    assert!(fresh.age_ms < 1000, "{:?}", fresh);
+    assert!(!fresh.stale);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1200)).await;
+    let (code, aged) = heartbeat().await;
+    assert!(aged.age_ms >= 1200, "{:?}", aged);
+    assert_eq!(aged.last_beat, fresh.last_beat);
+    assert!(aged.stale);
+    assert_eq!(code, reqwest::StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn status_updates_are_published_when_a_check_finishes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    protect_all(&ctx, &source, &[("a.bin", 1024)]);
+    ctx.status.lock().unwrap().logs.extend((0..150).map(|line| format!("line {}", line)));
+    let mut updates = ctx.status_updates.subscribe();
+
+    let check_ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || checker::run_check(&check_ctx)).await.unwrap().unwrap();
+    assert_eq!(updates.try_recv().unwrap().status, ServiceStatus::Checking);
+    let status = updates.try_recv().unwrap();
+    assert_eq!(status.status, ServiceStatus::Idle);
+    assert_eq!(status.last_check_result, "1 files checked, 0 corrupted, 0 drifted");
+    assert_eq!(status.last_check_time, ctx.status.lock().unwrap().last_check_time);
+    // Only the end of the log is sent; the status keeps all of it.
+    assert_eq!(status.logs.len(), 100);
+    assert_eq!(status.logs.last().unwrap(), "[Checker] 1 files checked, 0 corrupted, 0 drifted");
+    assert!(ctx.status.lock().unwrap().logs.len() > 150);
+}
+
+#[tokio::test]
+async fn websocket_clients_get_the_status_on_connect_and_on_every_change() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Reads one unmasked text frame, as the server sends them, as a status.
+    async fn read_status(stream: &mut tokio::net::TcpStream) -> AppStatus {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], 0x81, "expected a final text frame");
+        let len = match head[1] & 0x7f {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    protect_all(&ctx, &source, &[("a.bin", 1024)]);
+    ctx.status.lock().unwrap().logs.push("[Scanner] done".to_string());
+    let addr = spawn_app(ctx.clone()).await;
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            b"GET /api/ws HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    // The response head, up to the blank line; frames follow it.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    assert!(head.to_ascii_lowercase().contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="), "{}", head);
+
+    let initial = read_status(&mut stream).await;
+    assert_eq!(initial.status, ServiceStatus::Idle);
+    assert_eq!(initial.logs.last().unwrap(), "[Scanner] done");
+
+    let check_ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || checker::run_check(&check_ctx)).await.unwrap().unwrap();
+    assert_eq!(read_status(&mut stream).await.status, ServiceStatus::Checking);
+    let checked = read_status(&mut stream).await;
+    assert_eq!(checked.status, ServiceStatus::Idle);
+    assert_eq!(checked.last_check_result, "1 files checked, 0 corrupted, 0 drifted");
+}
+
+#[tokio::test]
+async fn status_summary_omits_the_lists_that_the_full_variant_includes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig::default());
+    ctx.status.lock().unwrap().logs.push("[Scanner] done".to_string());
+    let addr = spawn_app(ctx).await;
+    let status = |query: &'static str| async move {
+        let response = reqwest::get(format!("http://{}/api/status{}", addr, query)).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        response.json::<serde_json::Value>().await.unwrap()
+    };
+
+    for summary in [status("").await, status("?fields=summary").await] {
+        assert_eq!(summary["data_shards"], 4);
+        assert!(summary.get("protected_files").is_some());
+        for list in ["logs", "watched_dirs", "recovered_operations"] {
+            assert!(summary.get(list).is_none(), "summary has {}", list);
+        }
+    }
+    let full = status("?fields=full").await;
+    assert_eq!(full["logs"], serde_json::json!(["[Scanner] done"]));
+    assert_eq!(full["watched_dirs"].as_array().unwrap().len(), 1);
+    let invalid = reqwest::get(format!("http://{}/api/status?fields=everything", addr)).await.unwrap();
+    assert_eq!(invalid.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn metrics_carry_the_time_and_path_of_the_last_corruption() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("fine.bin", 1024), ("rotten.bin", 1024)]);
+    let addr = spawn_app(ctx.clone()).await;
+    let metrics = || async {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/api/metrics", addr))
+            .header("accept", "application/openmetrics-text; version=1.0.0")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("application/openmetrics-text"));
+        response.text().await.unwrap()
+    };
+
+    let clean = metrics().await;
+    assert!(clean.contains("rs_guard_corruptions_detected_total 0\n"), "{}", clean);
+    assert!(!clean.contains("rs_guard_last_corruption"), "{}", clean);
+    assert!(clean.ends_with("# EOF\n"));
+
+    let record = metadata::get_file_metadata(&ctx.db, &paths[1]).unwrap().unwrap();
+    fs::write(ctx.store.shard_path(&record.file_id, 0), b"bit rot").unwrap();
+    let check_ctx = ctx.clone();
+    let run = tokio::task::spawn_blocking(move || checker::run_check(&check_ctx)).await.unwrap().unwrap();
+    let at = chrono::DateTime::parse_from_rfc3339(&run.finished).unwrap().timestamp_millis() as f64 / 1000.0;
+
+    let dirty = metrics().await;
+    let path = paths[1].to_string_lossy();
+    assert!(dirty.contains("rs_guard_corrupted_files 1\n"), "{}", dirty);
+    assert!(
+        dirty.contains(&format!("rs_guard_corruptions_detected_total 1 # {{path=\"{}\"}} 1 {:.3}\n", path, at)),
+        "{}",
+        dirty
+    );
+    assert!(
+        dirty.contains(&format!("rs_guard_last_corruption_info{{path=\"{}\",check_id=\"{}\"}} 1\n", path, run.id)),
+        "{}",
+        dirty
+    );
+    assert!(dirty.contains(&format!("rs_guard_last_corruption_timestamp_seconds {:.3}\n", at)), "{}", dirty);
+}
+
+#[tokio::test]
+async fn prometheus_metrics_count_checks_repairs_and_encoded_bytes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("a.bin", 1000), ("b.bin", 2000)]);
+    scanner::scan_directories(&ctx).unwrap();
+    let record = metadata::get_file_metadata(&ctx.db, &paths[0]).unwrap().unwrap();
+    fs::remove_file(ctx.store.shard_path(&record.file_id, 1)).unwrap();
+    let check_ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || checker::run_check(&check_ctx)).await.unwrap().unwrap();
+    backend::repair::repair_shard(&ctx, &paths[0], 1).unwrap();
+    let addr = spawn_app(ctx.clone()).await;
+
+    let response = reqwest::get(format!("http://{}/api/metrics", addr)).await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/plain; version=0.0.4; charset=utf-8");
+    let metrics = response.text().await.unwrap();
+    for line in [
+        "rs_guard_total_files 2\n",
+        "rs_guard_protected_files 2\n",
+        "rs_guard_corrupted_files 1\n",
+        "# TYPE rs_guard_repairs_total counter\n",
+        "rs_guard_repairs_total 1\n",
+        "rs_guard_encode_bytes_total 3000\n",
+        "# TYPE rs_guard_last_corruption_info gauge\n",
+    ] {
+        assert!(metrics.contains(line), "{} missing from\n{}", line, metrics);
+    }
+    assert!(metrics.contains("rs_guard_last_check_duration_seconds "), "{}", metrics);
+    // Exemplars and the EOF marker are OpenMetrics only.
+    assert!(!metrics.contains(" # {"), "{}", metrics);
+    assert!(!metrics.contains("# EOF"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn failed_replication_pushes_are_counted_until_the_error_counters_are_reset() {
+    use shared::ErrorCounts;
+
+    // Nothing listens on the peer's port, so every push fails and is retried.
+    let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig {
+        replication_peers: vec![format!("http://{}", unused)],
+        ..Default::default()
+    });
+    replication::start(ctx.clone());
+    let addr = spawn_app(ctx.clone()).await;
+    let errors = || async {
+        reqwest::get(format!("http://{}/api/errors", addr)).await.unwrap().json::<ErrorCounts>().await.unwrap()
+    };
+    assert_eq!(errors().await, ErrorCounts::default());
+
+    protect_all(&ctx, &source, &[("offsite.bin", 2048)]);
+    for _ in 0..100 {
+        if errors().await.replication_retries > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(errors().await.replication_retries, 1);
+    let metrics = reqwest::get(format!("http://{}/api/metrics", addr)).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("rs_guard_errors_total{kind=\"replication_retry\"} 1\n"), "{}", metrics);
+
+    let client = reqwest::Client::new();
+    let before: ErrorCounts = client
+        .post(format!("http://{}/api/errors/reset", addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(before.replication_retries, 1);
+    assert_eq!(errors().await, ErrorCounts::default());
+}
+
+#[tokio::test]
+async fn listing_a_large_database_does_not_block_status_requests() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig { listing_batch_size: 50, ..Default::default() });
+    let paths = protect_all(&ctx, &source, &[("seed.bin", 256)]);
+    let record = metadata::get_file_metadata(&ctx.db, &paths[0]).unwrap().unwrap();
+    for i in 0..5000 {
+        metadata::store_file_metadata(&ctx.db, &source.join(format!("many/{:05}.bin", i)), &record).unwrap();
+    }
+    let addr = spawn_app(ctx).await;
+
+    let client = reqwest::Client::new();
+    let listing = client.get(format!("http://{}/api/files?offset=4990&limit=100", addr)).send();
+    let status = async {
+        let started = std::time::Instant::now();
+        let response = client.get(format!("http://{}/api/status?fields=full", addr)).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        started.elapsed()
+    };
+    let (listing, status_latency) = tokio::join!(listing, status);
+
+    let page: shared::MetadataQueryPage = listing.unwrap().json().await.unwrap();
+    assert_eq!(page.total, 5001);
+    assert_eq!(page.records.len(), 11);
+    assert_eq!(page.next_offset, None);
+    assert!(status_latency < std::time::Duration::from_millis(500), "{:?}", status_latency);
+}
+
+#[tokio::test]
+async fn coverage_lists_eligible_files_that_are_not_protected() {
+    use shared::CoverageReport;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig { exclude_patterns: vec!["*.tmp".to_string()], ..Default::default() });
+    let paths = protect_all(&ctx, &source, &[("kept.bin", 1024), ("rotten.bin", 1024)]);
+    fs::write(source.join("forgotten.bin"), b"never encoded").unwrap();
+    fs::write(source.join("scratch.tmp"), b"excluded, so not a gap").unwrap();
+    let record = metadata::get_file_metadata(&ctx.db, &paths[1]).unwrap().unwrap();
+    fs::write(ctx.store.shard_path(&record.file_id, 0), b"bit rot").unwrap();
+    let addr = spawn_app(ctx.clone()).await;
+
+    let response = reqwest::Client::new().post(format!("http://{}/api/coverage", addr)).send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let report: CoverageReport = response.json().await.unwrap();
+
+    assert_eq!(report.eligible_files, 3);
+    assert_eq!(report.protected_files, 2);
+    assert_eq!(report.unprotected_paths, vec![source.join("forgotten.bin").to_string_lossy().into_owned()]);
+    assert_eq!(report.corrupted_paths, vec![paths[1].to_string_lossy().into_owned()]);
+    assert!(metadata::get_file_metadata(&ctx.db, &source.join("forgotten.bin")).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn version_lists_compiled_features_and_active_options() {
+    use shared::VersionInfo;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, _) = test_context(&tmp, AppConfig { merkle_roots: true, ..Default::default() });
+    let addr = spawn_app(ctx).await;
+    let info: VersionInfo = reqwest::get(format!("http://{}/api/version", addr)).await.unwrap().json().await.unwrap();
+
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.features.contains(&"embedded_frontend".to_string()), cfg!(not(debug_assertions)));
+    assert_eq!(info.features.contains(&"io_priority".to_string()), cfg!(target_os = "linux"));
+    assert!(info.runtime_config["merkle_roots"]);
+    assert!(!info.runtime_config["replication"]);
+}
+
+#[tokio::test]
+async fn earlier_versions_are_downloadable_while_retained() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig { keep_versions: 2, ..Default::default() });
+    let file = source.join("notes.txt");
+    fs::write(&file, b"first draft").unwrap();
+    protector::protect_file(&ctx, &file).unwrap();
+    fs::write(&file, b"second draft, edited").unwrap();
+    protector::protect_file(&ctx, &file).unwrap();
+
+    let restored = tmp.path().join("restored.txt");
+    protector::recover_version(&ctx, &file, 1, &restored).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), b"first draft");
+
+    let addr = spawn_app(ctx).await;
+    let download = |version: u32| reqwest::get(format!("http://{}/api/download{}?version={}", addr, file.display(), version));
+    let first = download(1).await.unwrap();
+    assert_eq!(first.status(), reqwest::StatusCode::OK);
+    assert_eq!(first.bytes().await.unwrap().as_ref(), b"first draft");
+    assert_eq!(download(2).await.unwrap().bytes().await.unwrap().as_ref(), b"second draft, edited");
+    assert_eq!(download(3).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn replication_verify_reports_a_shard_that_differs_on_the_peer() {
+    use shared::ReplicationVerifyReport;
+
+    let tmp_b = tempfile::tempdir().unwrap();
+    let (node_b, _) = test_context(&tmp_b, AppConfig { api_token: Some(test_token()), ..Default::default() });
+    let addr_b = spawn_app(node_b.clone()).await;
+
+    let tmp_a = tempfile::tempdir().unwrap();
+    let (node_a, source) = test_context(&tmp_a, AppConfig {
+        replication_peers: vec![format!("http://{}", addr_b)],
+        api_token: Some(test_token()),
+        ..Default::default()
+    });
+    replication::start(node_a.clone());
+    for name in ["intact.bin", "drifted.bin"] {
+        fs::write(source.join(name), vec![7u8; 4096]).unwrap();
+    }
+    let scan_ctx = node_a.clone();
+    tokio::task::spawn_blocking(move || scanner::scan_directories(&scan_ctx)).await.unwrap().unwrap();
+    for _ in 0..100 {
+        if node_a.replication.outstanding() == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(node_a.replication.outstanding(), 0);
+
+    let drifted = source.join("drifted.bin");
+    let record = metadata::get_file_metadata(&node_b.db, &drifted).unwrap().unwrap();
+    fs::write(node_b.store.shard_path(&record.file_id, 2), b"bit rot").unwrap();
+    let addr_a = spawn_app(node_a).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/api/replication/verify", addr_a))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let report: ReplicationVerifyReport = response.json().await.unwrap();
+
+    assert_eq!(report.files_checked, 2);
+    assert!(report.unreachable_peers.is_empty());
+    assert_eq!(report.mismatches.len(), 1);
+    assert_eq!(report.mismatches[0].path, drifted.to_string_lossy());
+    assert_eq!(report.mismatches[0].shards, vec![2]);
+    assert!(!report.mismatches[0].missing);
+}
+
+#[tokio::test]
+async fn striped_records_are_replicated_and_salvaged_whole() {
+    use backend::salvage;
+    use std::sync::Arc;
+
+    let tmp_b = tempfile::tempdir().unwrap();
+    let (node_b, _) = test_context(&tmp_b, AppConfig {
+        shard_footers: true,
+        api_token: Some(test_token()),
+        ..Default::default()
+    });
+    let addr_b = spawn_app(node_b.clone()).await;
+
+    // Encoded in stripes before the peer was configured.
+    let tmp_a = tempfile::tempdir().unwrap();
+    let (node_a, source) = test_context(&tmp_a, AppConfig {
+        api_token: Some(test_token()),
+        streaming_threshold_bytes: 20_000,
+        streaming_stripe_bytes: 16_384,
+        ..Default::default()
+    });
+    let file = source.join("video.bin");
+    let content: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 253) as u8).collect();
+    fs::write(&file, &content).unwrap();
+    let record = protector::protect_file(&node_a, &file).unwrap();
+    assert_eq!(record.appended.len(), 3);
+
+    let peer = format!("http://{}", addr_b);
+    // Files above the streaming threshold are too large to replicate, so
+    // it is raised along with adding the peer.
+    let config = AppConfig {
+        replication_peers: vec![peer.clone()],
+        streaming_threshold_bytes: 100_000,
+        ..(*node_a.config).clone()
+    };
+    let node_a = AppContext { config: Arc::new(config), ..node_a };
+    replication::start(node_a.clone());
+    node_a.replication.enqueue_for(&peer, &file);
+    for _ in 0..100 {
+        if node_a.replication.outstanding() == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(node_a.replication.outstanding(), 0);
+    let report = replication::verify(&node_a, false).await.unwrap();
+    assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+
+    let restored = tmp_b.path().join("restored.bin");
+    assert!(protector::recover_file(&node_b, &file, &restored).unwrap());
+    assert_eq!(fs::read(&restored).unwrap(), content);
+
+    // Without the DB, the footers join the stripes back in order, even
+    // with a shard of a later stripe lost.
+    fs::remove_file(node_b.store.shard_path(&record.appended[1].file_id, 0)).unwrap();
+    let salvaged = salvage::recover_from_shards(&tmp_b.path().join("shards"), &tmp_b.path().join("salvaged")).unwrap();
+    assert_eq!(salvaged.recovered.len(), 1, "{:?}", salvaged.failed);
+    assert_eq!(fs::read(&salvaged.recovered[0]).unwrap(), content);
+}
+
+#[tokio::test]
+async fn signed_report_verifies_against_the_public_key_only_unaltered() {
+    use ed25519_dalek::{Signature, SigningKey, Verifier};
+    use shared::{IntegrityReport, SignedReport};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let secret = [42u8; 32];
+    let (ctx, source) = test_context(&tmp, AppConfig {
+        report_signing_key: Some(backend::signing::encode_hex(&secret)),
+        ..Default::default()
+    });
+    protect_all(&ctx, &source, &[("ledger.bin", 3000)]);
+    let addr = spawn_app(ctx).await;
+
+    let response = reqwest::get(format!("http://{}/api/report/signed", addr)).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let signed: SignedReport = response.json().await.unwrap();
+    let report: IntegrityReport = serde_json::from_str(&signed.report).unwrap();
+    assert_eq!(report.coverage.protected_files, 1);
+
+    let public_key = SigningKey::from_bytes(&secret).verifying_key();
+    assert_eq!(signed.public_key, backend::signing::encode_hex(public_key.as_bytes()));
+    let signature = backend::signing::decode_hex(&signed.signature).unwrap();
+    let signature = Signature::from_slice(&signature).unwrap();
+    assert!(public_key.verify(signed.report.as_bytes(), &signature).is_ok());
+
+    let altered = signed.report.replace("\"protected_files\":1", "\"protected_files\":2");
+    assert_ne!(altered, signed.report);
+    assert!(public_key.verify(altered.as_bytes(), &signature).is_err());
+}
+
+#[tokio::test]
+async fn check_runs_record_what_triggered_them() {
+    use shared::CheckTrigger;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    protect_all(&ctx, &source, &[("audit.bin", 2048)]);
+    let addr = spawn_app(ctx.clone()).await;
+    let list = |query: &'static str| reqwest::get(format!("http://{}/api/checks{}", addr, query));
+
+    // The periodic task's first check runs as the service starts.
+    let periodic = checker::spawn_periodic_checks(ctx.clone(), std::time::Duration::from_secs(3600));
+    let mut runs: Vec<CheckRun> = Vec::new();
+    for _ in 0..100 {
+        runs = list("").await.unwrap().json().await.unwrap();
+        if !runs.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    periodic.abort();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].trigger, CheckTrigger::Startup);
+
+    let scheduled_ctx = ctx.clone();
+    tokio::task::spawn_blocking(move || checker::run_scheduled_check(&scheduled_ctx)).await.unwrap().unwrap();
+    let response = reqwest::Client::new().post(format!("http://{}/api/run-check", addr)).send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    for _ in 0..100 {
+        runs = list("").await.unwrap().json().await.unwrap();
+        if runs.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    let triggers: Vec<CheckTrigger> = runs.iter().map(|run| run.trigger).collect();
+    assert_eq!(triggers, vec![CheckTrigger::Manual, CheckTrigger::Scheduled, CheckTrigger::Startup]);
+    let manual: Vec<CheckRun> = list("?trigger=manual").await.unwrap().json().await.unwrap();
+    assert_eq!(manual.len(), 1);
+    assert_eq!(manual[0].id, runs[0].id);
+}
+
+#[tokio::test]
+async fn check_and_repair_fixes_a_recoverable_corruption_in_one_call() {
+    use shared::CheckAndRepairResult;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("healthy.bin", 3000), ("damaged.bin", 5000)]);
+    let record = metadata::get_file_metadata(&ctx.db, &paths[1]).unwrap().unwrap();
+    fs::write(ctx.store.shard_path(&record.file_id, 0), b"bit rot").unwrap();
+    let addr = spawn_app(ctx.clone()).await;
+    let check_and_repair = || reqwest::Client::new().post(format!("http://{}/api/check-and-repair", addr)).send();
+
+    let response = check_and_repair().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let result: CheckAndRepairResult = response.json().await.unwrap();
+    assert_eq!(
+        (result.checked, result.corrupted, result.repaired, result.failed, result.drifted),
+        (2, 1, 1, 0, 0)
+    );
+    assert_eq!(protector::reconstruct(&ctx, &record).unwrap(), fs::read(&paths[1]).unwrap());
+
+    let again: CheckAndRepairResult = check_and_repair().await.unwrap().json().await.unwrap();
+    assert_eq!((again.corrupted, again.repaired), (0, 0));
+
+    // Refused while another check holds the store.
+    ctx.checks_running.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(check_and_repair().await.unwrap().status(), reqwest::StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn run_repair_rebuilds_a_damaged_shard_in_the_background() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (ctx, source) = test_context(&tmp, AppConfig::default());
+    let paths = protect_all(&ctx, &source, &[("damaged.bin", 5000)]);
+    let record = metadata::get_file_metadata(&ctx.db, &paths[0]).unwrap().unwrap();
+    fs::write(ctx.store.shard_path(&record.file_id, 0), b"bit rot").unwrap();
+    let addr = spawn_app(ctx.clone()).await;
+
+    let response = reqwest::Client::new().post(format!("http://{}/api/run-repair", addr)).send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    for _ in 0..100 {
+        let shard = ctx.store.read_shard_uncached(&record.file_id, 0).unwrap();
+        if shard.is_some_and(|shard| protector::checksum(&shard) == record.shard_checksums[0]) {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("shard 0 was not rebuilt");
+}
@@ -0,0 +1,71 @@
+//! Tests for the metadata DB, its exports and backups.
+
+use backend::metadata::{self, FileRecord};
+use backend::backup;
+use std::path::Path;
+
+fn sample_record(file_id: &str) -> FileRecord {
+    FileRecord {
+        file_id: file_id.to_string(),
+        size: 11,
+        mtime: 1_700_000_000,
+        data_shards: 4,
+        parity_shards: 2,
+        shard_size: 3,
+        checksum: "abc".to_string(),
+        shard_checksums: vec!["s".to_string(); 6],
+        protected_at: 1_700_000_100,
+        deleted_at: None,
+        merkle_root: None,
+        tags: Vec::new(),
+        priority: 0,
+        parity_algorithm: Default::default(),
+        field: Default::default(),
+        encode_duration_ms: 0,
+        shard_bytes: 0,
+        last_verified: None,
+        data_extents: None,
+        attrs: None,
+        appended: Vec::new(),
+        base_checksum: None,
+        stripe_size: None,
+    }
+}
+
+#[test]
+fn metadata_backup_is_written_and_importable() {
+    let tmp = tempfile::tempdir().unwrap();
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    metadata::store_file_metadata(&db, Path::new("/data/a.txt"), &sample_record("a")).unwrap();
+    metadata::store_file_metadata(&db, Path::new("/data/b.txt"), &sample_record("b")).unwrap();
+
+    let backup_dir = tmp.path().join("backups");
+    let written = backup::write_backup(&db, &backup_dir, 3).unwrap();
+    assert!(written.exists());
+    assert_eq!(backup::list_backups(&backup_dir).unwrap(), vec![written.clone()]);
+
+    let restored = metadata::open_db(tmp.path().join("restored").to_str().unwrap()).unwrap();
+    let json = std::fs::read_to_string(&written).unwrap();
+    assert_eq!(metadata::import_json(&restored, &json).unwrap(), 2);
+    assert_eq!(metadata::list_files(&restored).unwrap(), metadata::list_files(&db).unwrap());
+}
+
+#[test]
+fn compaction_runs_after_many_records_are_deleted() {
+    let tmp = tempfile::tempdir().unwrap();
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    for i in 0..2000 {
+        let path = format!("/data/{:04}.txt", i);
+        metadata::store_file_metadata(&db, Path::new(&path), &sample_record(&path)).unwrap();
+    }
+    db.flush().unwrap();
+    for i in 0..2000 {
+        metadata::remove_file_metadata(&db, Path::new(&format!("/data/{:04}.txt", i))).unwrap();
+    }
+
+    let compaction = metadata::compact(&db).unwrap();
+    assert!(compaction.size_before > 0);
+    assert_eq!(compaction.size_after, db.size_on_disk().unwrap());
+    assert_eq!(compaction.reclaimed(), compaction.size_before.saturating_sub(compaction.size_after));
+    assert_eq!(metadata::count_files(&db).unwrap(), 0);
+}
@@ -0,0 +1,1369 @@
+//! Tests for the encode/recover pipeline against real files on disk.
+
+use backend::config::AppConfig;
+use backend::{metadata, protector, scanner, AppContext};
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+/// Creates a context whose source dir, shard store and DB all live in `tmp`.
+fn test_context(tmp: &TempDir, config: AppConfig) -> AppContext {
+    let config = AppConfig {
+        shard_store_dir: tmp.path().join("shards"),
+        ..config
+    };
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    AppContext::new(config, db).unwrap()
+}
+
+fn source_dir(tmp: &TempDir) -> PathBuf {
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    source
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_path_is_protected_and_recovered_byte_for_byte() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join(OsStr::from_bytes(b"caf\xe9.bin"));
+    let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&file, &content).unwrap();
+
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source],
+        skip_non_utf8_paths: false,
+        ..Default::default()
+    });
+    scanner::scan_directories(&ctx).unwrap();
+
+    let files = metadata::list_files(&ctx.db).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].0, file);
+
+    fs::remove_file(&file).unwrap();
+    protector::recover_file(&ctx, &file, &file).unwrap();
+    assert_eq!(fs::read(&file).unwrap(), content);
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_path_is_skipped_when_configured() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    fs::write(source.join(OsStr::from_bytes(b"caf\xe9.bin")), b"skipped").unwrap();
+    fs::write(source.join("plain.txt"), b"protected").unwrap();
+
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source.clone()],
+        skip_non_utf8_paths: true,
+        ..Default::default()
+    });
+    scanner::scan_directories(&ctx).unwrap();
+
+    let files = metadata::list_files(&ctx.db).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].0, source.join("plain.txt"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn bulk_encode_is_skipped_when_inodes_run_low() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    fs::write(source.join("a.txt"), b"alpha").unwrap();
+    fs::write(source.join("b.txt"), b"beta").unwrap();
+
+    let stats = backend::disk::stat(tmp.path()).unwrap().unwrap();
+    if stats.total_inodes == 0 {
+        // Filesystems without a fixed inode table (btrfs, some overlays) cannot run low.
+        return;
+    }
+    // Reserve every inode that is currently free so any encode would dip into it.
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source],
+        inode_reserve: stats.free_inodes,
+        ..Default::default()
+    });
+    let summary = scanner::scan_directories(&ctx).unwrap();
+
+    assert_eq!(summary.files_protected, 0);
+    assert_eq!(summary.files_deferred, 2);
+    assert!(ctx.status.lock().unwrap().low_inodes);
+    assert_eq!(metadata::count_files(&ctx.db).unwrap(), 0);
+}
+
+#[test]
+fn rsguardignore_patterns_are_merged_with_exclude_patterns() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    fs::create_dir_all(source.join("nested")).unwrap();
+    fs::write(source.join(".rsguardignore"), "# logs are noise\n*.log\n").unwrap();
+    fs::write(source.join("app.log"), b"skip").unwrap();
+    fs::write(source.join("nested/deep.log"), b"skip").unwrap();
+    fs::write(source.join("scratch.tmp"), b"skip").unwrap();
+    fs::write(source.join("notes.txt"), b"keep").unwrap();
+    fs::write(source.join("nested/report.txt"), b"keep").unwrap();
+
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source.clone()],
+        exclude_patterns: vec!["*.tmp".to_string()],
+        ..Default::default()
+    });
+    scanner::scan_directories(&ctx).unwrap();
+
+    let mut protected: Vec<PathBuf> = metadata::list_files(&ctx.db).unwrap().into_iter().map(|(p, _)| p).collect();
+    protected.sort();
+    assert_eq!(protected, vec![
+        source.join(".rsguardignore"),
+        source.join("nested/report.txt"),
+        source.join("notes.txt"),
+    ]);
+}
+
+#[test]
+fn allowlist_only_protects_just_the_listed_files() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    for name in ["ledger.db", "notes.txt", "photo.jpg"] {
+        fs::write(source.join(name), name.as_bytes()).unwrap();
+    }
+
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source.clone()],
+        allowlist_only: true,
+        protected_paths: vec![source.join("ledger.db")],
+        ..Default::default()
+    });
+    scanner::scan_directories(&ctx).unwrap();
+
+    let protected: Vec<PathBuf> = metadata::list_files(&ctx.db).unwrap().into_iter().map(|(p, _)| p).collect();
+    assert_eq!(protected, vec![source.join("ledger.db")]);
+}
+
+#[test]
+fn files_matching_a_protect_signature_are_protected_without_an_extension() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let mut sqlite = b"SQLite format 3\0".to_vec();
+    sqlite.extend_from_slice(&[0u8; 84]);
+    fs::write(source.join("data"), &sqlite).unwrap();
+    fs::write(source.join("notes"), b"plain text, no signature").unwrap();
+
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source.clone()],
+        allowlist_only: true,
+        protect_signatures: vec!["sqlite".to_string()],
+        ..Default::default()
+    });
+    scanner::scan_directories(&ctx).unwrap();
+
+    let protected: Vec<PathBuf> = metadata::list_files(&ctx.db).unwrap().into_iter().map(|(p, _)| p).collect();
+    assert_eq!(protected, vec![source.join("data")]);
+}
+
+#[test]
+fn case_only_rename_keeps_a_single_record_with_case_insensitive_paths() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    fs::write(source.join("File.txt"), b"same file, new spelling").unwrap();
+
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source.clone()],
+        case_insensitive_paths: Some(true),
+        ..Default::default()
+    });
+    protector::protect_if_changed(&ctx, &source.join("File.txt")).unwrap();
+    let file_id = metadata::get_file_metadata(&ctx.db, &source.join("File.txt")).unwrap().unwrap().file_id;
+
+    fs::rename(source.join("File.txt"), source.join("file.txt")).unwrap();
+    metadata::mark_deleted(&ctx.db, &source.join("File.txt")).unwrap();
+    let reencoded = protector::protect_if_changed(&ctx, &source.join("file.txt")).unwrap();
+
+    assert!(reencoded.is_none(), "unchanged content should not be re-encoded");
+    let records = metadata::list_files(&ctx.db).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].0, source.join("file.txt"));
+    assert_eq!(records[0].1.file_id, file_id);
+    assert_eq!(records[0].1.deleted_at, None);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn encode_threads_run_at_the_configured_io_priority() {
+    use backend::config::IoPriority;
+    use backend::ioprio;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    fs::write(source.join("a.bin"), vec![3u8; 4096]).unwrap();
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source.clone()],
+        io_priority: IoPriority::Idle,
+        ..Default::default()
+    });
+
+    std::thread::spawn(move || {
+        let before = ioprio::current_priority().unwrap();
+        assert_eq!(before, IoPriority::Normal);
+        {
+            let _io = ioprio::lower(ctx.config.io_priority);
+            assert_eq!(ioprio::current_priority().unwrap(), IoPriority::Idle);
+        }
+        assert_eq!(ioprio::current_priority().unwrap(), before);
+
+        // An encode lowers its thread's priority only while it runs.
+        protector::protect_file(&ctx, &source.join("a.bin")).unwrap();
+        assert_eq!(ioprio::current_priority().unwrap(), before);
+    })
+    .join()
+    .unwrap();
+}
+
+/// Builds a PAR2 packet, as a PAR2 client would write it.
+fn par2_packet(set_id: &[u8; 16], kind: &[u8; 16], body: &[u8]) -> Vec<u8> {
+    let mut hashed = set_id.to_vec();
+    hashed.extend_from_slice(kind);
+    hashed.extend_from_slice(body);
+    let mut packet = b"PAR2\0PKT".to_vec();
+    packet.extend_from_slice(&(64 + body.len() as u64).to_le_bytes());
+    packet.extend_from_slice(&md5::compute(&hashed).0);
+    packet.extend_from_slice(&hashed);
+    packet
+}
+
+#[test]
+fn files_of_a_par2_set_are_imported_and_checked() {
+    use backend::{checker, par2};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let files: [(&str, Vec<u8>); 3] = [
+        ("disc1.iso", (0..9000u32).map(|i| (i % 253) as u8).collect()),
+        ("notes/readme.txt", b"burned 2019-05-04".to_vec()),
+        ("rotten.bin", vec![5u8; 3000]),
+    ];
+    let set_id = [9u8; 16];
+    let mut main = 4096u64.to_le_bytes().to_vec();
+    main.extend_from_slice(&(files.len() as u32).to_le_bytes());
+    let mut set = Vec::new();
+    for (index, (name, content)) in files.iter().enumerate() {
+        let file_id = [index as u8 + 1; 16];
+        main.extend_from_slice(&file_id);
+        let mut desc = file_id.to_vec();
+        desc.extend_from_slice(&md5::compute(content).0);
+        desc.extend_from_slice(&md5::compute(&content[..content.len().min(16 * 1024)]).0);
+        desc.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        desc.extend_from_slice(name.as_bytes());
+        desc.resize(desc.len().next_multiple_of(4), 0);
+        set.extend(par2_packet(&set_id, b"PAR 2.0\0FileDesc", &desc));
+        let path = source.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    set.extend(par2_packet(&set_id, b"PAR 2.0\0Main\0\0\0\0", &main));
+    let par2_path = source.join("archive.par2");
+    fs::write(&par2_path, &set).unwrap();
+    // Damaged after the PAR2 set was made, so it must not be imported as good.
+    fs::write(source.join("rotten.bin"), vec![6u8; 3000]).unwrap();
+
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source.clone()], ..Default::default() });
+    let outcome = par2::import(&ctx, &par2_path).unwrap();
+
+    assert_eq!(outcome.imported, vec![source.join("disc1.iso"), source.join("notes/readme.txt")]);
+    assert_eq!(outcome.mismatched, vec![source.join("rotten.bin")]);
+    assert!(outcome.missing.is_empty());
+    assert_eq!(metadata::count_files(&ctx.db).unwrap(), 2);
+    let record = metadata::get_file_metadata(&ctx.db, &source.join("disc1.iso")).unwrap().unwrap();
+    assert_eq!(record.tags, vec![par2::IMPORT_TAG.to_string()]);
+
+    fs::write(ctx.store.shard_path(&record.file_id, 0), b"bit rot").unwrap();
+    let run = checker::run_check(&ctx).unwrap();
+    assert_eq!(run.corrupted_paths, vec![source.join("disc1.iso").to_string_lossy().into_owned()]);
+}
+
+#[test]
+fn slow_encode_is_aborted_and_rolled_back_at_the_timeout() {
+    use backend::shard_store::ShardStore;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, b"first version").unwrap();
+    let mut ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source],
+        encode_timeout_secs: 1,
+        ..Default::default()
+    });
+    protector::protect_file(&ctx, &file).unwrap();
+
+    // Six shards at 400ms each would take 2.4s without the timeout.
+    ctx.store = Arc::new(ShardStore::new(tmp.path().join("shards")).unwrap().with_write_delay(Duration::from_millis(400)));
+    fs::write(&file, b"second version").unwrap();
+    let started = Instant::now();
+    let err = protector::protect_file(&ctx, &file).unwrap_err();
+    assert!(err.is::<protector::EncodeTimeout>(), "{}", err);
+    assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+
+    // Nothing staged is left behind and the previous protection still works.
+    let mut leftovers = Vec::new();
+    scanner::collect_files(&tmp.path().join("shards"), &mut leftovers).unwrap();
+    assert_eq!(leftovers.len(), 6, "{:?}", leftovers);
+    let restored = tmp.path().join("restored.bin");
+    protector::recover_file(&ctx, &file, &restored).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), b"first version");
+
+    let skipped = metadata::list_skipped(&ctx.db).unwrap();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].0, file);
+    assert!(skipped[0].1.reason.contains("timed out"), "{}", skipped[0].1.reason);
+}
+
+#[test]
+fn merkle_root_covers_every_shard_and_exposes_consistent_tampering() {
+    use backend::shard_store::ShardKind;
+    use backend::{checker, merkle};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, vec![5u8; 3000]).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+    let mut record = protector::protect_file(&ctx, &file).unwrap();
+    let root = record.merkle_root.clone().unwrap();
+    assert_eq!(root, merkle::merkle_root(&record.shard_checksums));
+
+    for index in 0..record.total_shards() {
+        let mut shard = ctx.store.read_shard(&record.file_id, index).unwrap().unwrap();
+        shard[0] ^= 0xff;
+        let mut checksums = record.shard_checksums.clone();
+        checksums[index] = protector::checksum(&shard);
+        assert_ne!(merkle::merkle_root(&checksums), root, "shard {}", index);
+    }
+
+    // Tamper with a shard and rewrite its checksum to match.
+    let forged = b"forged shard".to_vec();
+    ctx.store.write_shard(&record.file_id, 2, ShardKind::Parity, &forged).unwrap();
+    record.shard_checksums[2] = protector::checksum(&forged);
+    metadata::store_file_metadata(&ctx.db, &file, &record).unwrap();
+    let run = checker::run_check(&ctx).unwrap();
+    assert_eq!(run.corrupted_paths, vec![file.to_string_lossy().into_owned()]);
+}
+
+#[cfg(unix)]
+#[test]
+fn encoding_is_refused_when_it_would_dip_into_the_free_space_reserve() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, vec![9u8; 64 * 1024]).unwrap();
+    let shards = tmp.path().join("shards");
+    fs::create_dir_all(&shards).unwrap();
+
+    // Leave room for a few KiB above the reserve: plenty of free space, but
+    // less than the ~96 KiB of shards this file needs.
+    let free = backend::disk::stat(&shards).unwrap().unwrap().free_bytes;
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source],
+        shard_store_reserve_bytes: free - 4096,
+        ..Default::default()
+    });
+    let err = protector::protect_file(&ctx, &file).unwrap_err();
+    assert!(err.is::<protector::InsufficientSpace>(), "{}", err);
+    assert!(metadata::get_file_metadata(&ctx.db, &file).unwrap().is_none());
+    assert_eq!(metadata::list_skipped(&ctx.db).unwrap().len(), 1);
+}
+
+#[test]
+fn truncated_shard_is_flagged_by_length_before_any_hashing() {
+    use backend::checker;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, vec![3u8; 4096]).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+    let record = protector::protect_file(&ctx, &file).unwrap();
+
+    let shard = ctx.store.shard_path(&record.file_id, 3);
+    let mut bytes = fs::read(&shard).unwrap();
+    bytes.pop();
+    fs::write(&shard, &bytes).unwrap();
+
+    let reads_before = ctx.store.disk_reads();
+    let run = checker::run_check(&ctx).unwrap();
+    assert_eq!(run.corrupted_paths, vec![file.to_string_lossy().into_owned()]);
+    assert_eq!(ctx.store.disk_reads(), reads_before, "no shard should have been read");
+}
+
+#[test]
+fn check_and_repair_of_the_same_file_are_serialized() {
+    use backend::{checker, repair};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, vec![8u8; 8192]).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    fs::write(ctx.store.shard_path(&record.file_id, 1), vec![0u8; record.shard_size]).unwrap();
+
+    // While the file is locked, neither operation may touch it.
+    let guard = ctx.file_locks.lock(&file);
+    let (done_tx, done_rx) = mpsc::channel();
+    let (repair_ctx, repair_path, repair_done) = (ctx.clone(), file.clone(), done_tx.clone());
+    let repairing = std::thread::spawn(move || {
+        let outcome = repair::repair_shard(&repair_ctx, &repair_path, 1).unwrap();
+        repair_done.send("repair").unwrap();
+        outcome
+    });
+    let check_ctx = ctx.clone();
+    let checking = std::thread::spawn(move || {
+        let run = checker::run_check(&check_ctx).unwrap();
+        done_tx.send("check").unwrap();
+        run
+    });
+    assert!(done_rx.recv_timeout(Duration::from_millis(300)).is_err());
+    drop(guard);
+
+    let outcome = repairing.join().unwrap();
+    let run = checking.join().unwrap();
+    assert_eq!(outcome, repair::ShardRepairOutcome::Repaired(record.shard_checksums[1].clone()));
+    // The check saw the file either entirely before or entirely after the repair.
+    assert_eq!(run.checked, 1);
+    assert!(run.corrupted <= 1);
+    assert_eq!(checker::run_check(&ctx).unwrap().corrupted, 0);
+    let restored = tmp.path().join("restored.bin");
+    protector::recover_file(&ctx, &file, &restored).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), vec![8u8; 8192]);
+}
+
+#[test]
+fn shards_carry_a_header_and_are_never_protected_themselves() {
+    use backend::shard_store::{ShardHeader, ShardKind};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, vec![4u8; 2048]).unwrap();
+    // A shard store inside the watched directory must not feed back into itself.
+    let config = AppConfig {
+        watched_directories: vec![source.clone()],
+        shard_store_dir: source.join("shards"),
+        ..Default::default()
+    };
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    let ctx = AppContext::new(config, db).unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+    scanner::scan_directories(&ctx).unwrap();
+
+    let protected: Vec<PathBuf> = metadata::list_files(&ctx.db).unwrap().into_iter().map(|(p, _)| p).collect();
+    assert_eq!(protected, vec![file.clone()]);
+
+    let record = metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap();
+    for index in 0..record.total_shards() {
+        let path = ctx.store.shard_path(&record.file_id, index);
+        assert_eq!(path.extension().unwrap(), "rsguard");
+        let bytes = fs::read(&path).unwrap();
+        assert!(bytes.starts_with(b"RSGS"));
+        let (header, payload) = ShardHeader::decode(&bytes).unwrap();
+        assert_eq!(header.file_id, record.file_id);
+        assert_eq!(header.index, index);
+        assert_eq!(header.kind, ShardKind::of(index, record.data_shards));
+        assert_eq!(payload.len(), record.shard_size);
+    }
+}
+
+#[test]
+fn edited_source_is_reported_as_drifted_not_corrupted() {
+    use backend::checker;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("notes.txt");
+    fs::write(&file, b"first draft").unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+    protector::protect_file(&ctx, &file).unwrap();
+
+    // An edit the watcher never saw: the record still describes the old content.
+    fs::write(&file, b"second draft, a little longer").unwrap();
+    let run = checker::run_check(&ctx).unwrap();
+    assert_eq!(run.corrupted, 0);
+    assert_eq!(run.drifted_paths, vec![file.to_string_lossy().into_owned()]);
+
+    protector::protect_file(&ctx, &file).unwrap();
+    let run = checker::run_check(&ctx).unwrap();
+    assert_eq!((run.corrupted, run.drifted), (0, 0));
+}
+
+#[test]
+fn file_is_quarantined_after_too_many_repairs() {
+    use backend::repair::{self, ShardRepairOutcome};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("flaky.bin");
+    fs::write(&file, vec![3u8; 8192]).unwrap();
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source],
+        max_repair_attempts: 2,
+        ..Default::default()
+    });
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    let corrupt = || fs::write(ctx.store.shard_path(&record.file_id, 1), vec![0u8; record.shard_size]).unwrap();
+
+    for _ in 0..2 {
+        corrupt();
+        let outcome = repair::repair_shard(&ctx, &file, 1).unwrap();
+        assert!(matches!(outcome, ShardRepairOutcome::Repaired(_)), "{:?}", outcome);
+    }
+    corrupt();
+    let outcome = repair::repair_shard(&ctx, &file, 1).unwrap();
+    assert!(matches!(&outcome, ShardRepairOutcome::Quarantined(reason) if reason.contains("flapping")), "{:?}", outcome);
+    let quarantined = metadata::list_quarantined(&ctx.db).unwrap();
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].0, file);
+    // It stays quarantined until an operator resets it.
+    assert!(matches!(repair::repair_shard(&ctx, &file, 1).unwrap(), ShardRepairOutcome::Quarantined(_)));
+
+    assert!(metadata::reset_quarantine(&ctx.db, &file).unwrap());
+    assert!(matches!(repair::repair_shard(&ctx, &file, 1).unwrap(), ShardRepairOutcome::Repaired(_)));
+}
+
+#[cfg(unix)]
+#[test]
+fn hardlinks_to_one_inode_are_protected_once() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let first = source.join("a.bin");
+    let second = source.join("b.bin");
+    fs::write(&first, vec![1u8; 4096]).unwrap();
+    fs::hard_link(&first, &second).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+
+    let summary = scanner::scan_directories(&ctx).unwrap();
+    assert_eq!((summary.files_seen, summary.files_protected), (1, 1));
+    assert_eq!(metadata::count_files(&ctx.db).unwrap(), 1);
+    let owner = protector::canonical_path(&ctx, &second).unwrap();
+    assert_eq!(protector::canonical_path(&ctx, &first).unwrap(), owner);
+
+    // Modify through one link; the watcher may report either path.
+    fs::write(&second, vec![2u8; 5000]).unwrap();
+    let reencoded = [&second, &first]
+        .iter()
+        .filter(|path| protector::protect_if_changed(&ctx, path).unwrap().is_some())
+        .count();
+    assert_eq!(reencoded, 1);
+    assert_eq!(metadata::count_files(&ctx.db).unwrap(), 1);
+    let record = metadata::get_file_metadata(&ctx.db, &owner).unwrap().unwrap();
+    assert_eq!(record.checksum, protector::checksum(&vec![2u8; 5000]));
+    assert_eq!(scanner::scan_directories(&ctx).unwrap().files_protected, 0);
+}
+
+#[test]
+fn files_not_verified_recently_are_counted_as_stale() {
+    use backend::checker;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("archive.bin");
+    fs::write(&file, vec![4u8; 2048]).unwrap();
+    fs::write(source.join("fresh.bin"), vec![5u8; 2048]).unwrap();
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source],
+        max_verification_age_secs: 3600,
+        ..Default::default()
+    });
+    scanner::scan_directories(&ctx).unwrap();
+    assert_eq!(checker::update_stale_files(&ctx).unwrap(), 0);
+
+    let mut record = metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap();
+    record.protected_at -= 7200;
+    record.last_verified = Some(record.protected_at);
+    metadata::store_file_metadata(&ctx.db, &file, &record).unwrap();
+    assert_eq!(checker::update_stale_files(&ctx).unwrap(), 1);
+    assert_eq!(ctx.status.lock().unwrap().stale_files, 1);
+
+    checker::run_check(&ctx).unwrap();
+    assert_eq!(ctx.status.lock().unwrap().stale_files, 0);
+    assert!(metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap().last_verified > record.last_verified);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sparse_file_is_encoded_by_extent_and_recovered_with_holes() {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("disk.img");
+    let size = 64 * 1024 * 1024u64;
+    {
+        let mut f = fs::File::create(&file).unwrap();
+        f.set_len(size).unwrap();
+        f.write_all(&[7u8; 4096]).unwrap();
+        f.seek(SeekFrom::Start(32 * 1024 * 1024)).unwrap();
+        f.write_all(&[9u8; 4096]).unwrap();
+    }
+    let expected = fs::read(&file).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    let extents = record.data_extents.clone().expect("file system reports holes");
+    assert!(record.encoded_len() < 1024 * 1024, "{:?}", extents);
+    assert_eq!(record.size, size);
+    assert!(record.shard_bytes < 1024 * 1024);
+
+    fs::remove_file(&file).unwrap();
+    let restored = tmp.path().join("restored.img");
+    protector::recover_file(&ctx, &file, &restored).unwrap();
+    assert_eq!(fs::metadata(&restored).unwrap().len(), size);
+    assert!(fs::metadata(&restored).unwrap().blocks() * 512 < 1024 * 1024);
+    assert!(fs::read(&restored).unwrap() == expected);
+}
+
+#[test]
+fn periodic_check_only_runs_inside_the_check_window() {
+    use backend::checker;
+    use backend::config::CheckWindow;
+    use chrono::{Duration, Local};
+
+    let now = Local::now().time();
+    let window = |from: i64, to: i64| Some(CheckWindow {
+        start: now + Duration::hours(from),
+        end: now + Duration::hours(to),
+    });
+    for (check_window, runs) in [(window(1, 2), false), (window(-1, 1), true)] {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_dir(&tmp);
+        fs::write(source.join("a.txt"), b"content").unwrap();
+        let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], check_window, ..Default::default() });
+        scanner::scan_directories(&ctx).unwrap();
+
+        let run = checker::run_scheduled_check(&ctx).unwrap();
+        assert_eq!(run.is_some(), runs);
+        assert_eq!(metadata::list_check_runs(&ctx.db).unwrap().len(), runs as usize);
+        // Manual checks ignore the window.
+        assert_eq!(checker::run_check(&ctx).unwrap().checked, 1);
+    }
+}
+
+#[test]
+fn interrupted_encode_is_resumed_or_rolled_back_on_restart() {
+    use backend::metadata::PendingOperation;
+    use backend::recovery::{self, Resolution};
+    use backend::shard_store::ShardKind;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let edited = source.join("edited.bin");
+    let removed = source.join("removed.bin");
+    fs::write(&edited, vec![1u8; 4096]).unwrap();
+    fs::write(&removed, vec![2u8; 4096]).unwrap();
+    let config = AppConfig { watched_directories: vec![source], ..Default::default() };
+    let staged_leftover;
+    {
+        let ctx = test_context(&tmp, config.clone());
+        scanner::scan_directories(&ctx).unwrap();
+        // Crash while re-encoding both files: markers and a staged shard remain.
+        fs::write(&edited, vec![3u8; 5000]).unwrap();
+        for path in [&edited, &removed] {
+            let file_id = protector::file_id_for(path);
+            metadata::begin_operation(&ctx.db, path, &PendingOperation::Encode { file_id: file_id.clone(), shards: 6, stripes: 1 }).unwrap();
+            ctx.store.stage_shard(&file_id, 0, ShardKind::Data, b"partial").unwrap();
+        }
+        fs::remove_file(&removed).unwrap();
+        staged_leftover = fs::read_dir(ctx.store.shard_path(&protector::file_id_for(&removed), 0).parent().unwrap())
+            .unwrap()
+            .count();
+        ctx.db.flush().unwrap();
+    }
+
+    // sled's flusher thread can hold the lock on the old handle for a moment after it is dropped.
+    let db = (0..50)
+        .find_map(|_| {
+            metadata::open_db(tmp.path().join("db").to_str().unwrap())
+                .map_err(|_| std::thread::sleep(std::time::Duration::from_millis(20)))
+                .ok()
+        })
+        .expect("metadata db still locked");
+    let ctx = AppContext::new(AppConfig { shard_store_dir: tmp.path().join("shards"), ..config }, db).unwrap();
+    let recovered = recovery::resume_interrupted(&ctx).unwrap();
+    assert_eq!(recovered.len(), 2);
+    let resolution = |path: &PathBuf| recovered.iter().find(|op| &op.path == path).unwrap().resolution.clone();
+    assert_eq!(resolution(&edited), Resolution::Resumed);
+    assert_eq!(resolution(&removed), Resolution::RolledBack);
+    assert!(metadata::list_operations(&ctx.db).unwrap().is_empty());
+    assert_eq!(ctx.status.lock().unwrap().recovered_operations.len(), 2);
+
+    let record = metadata::get_file_metadata(&ctx.db, &edited).unwrap().unwrap();
+    assert_eq!(record.checksum, protector::checksum(&vec![3u8; 5000]));
+    let dir = ctx.store.shard_path(&protector::file_id_for(&removed), 0).parent().unwrap().to_path_buf();
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), staged_leftover - 1);
+    assert!(fs::read_dir(&dir).unwrap().all(|e| !e.unwrap().file_name().to_string_lossy().contains("staged")));
+}
+
+#[test]
+fn a_crashed_streamed_encode_discards_every_staged_stripe() {
+    use backend::metadata::PendingOperation;
+    use backend::recovery::{self, Resolution};
+    use backend::shard_store::ShardKind;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, AppConfig::default());
+    let file = source_dir(&tmp).join("video.bin");
+    // Crash midway through streaming a file that is gone by the restart.
+    let file_id = protector::file_id_for(&file);
+    let pending = PendingOperation::Encode { file_id: file_id.clone(), shards: 6, stripes: 3 };
+    metadata::begin_operation(&ctx.db, &file, &pending).unwrap();
+    for stripe in 0..3 {
+        for index in 0..6 {
+            let kind = ShardKind::of(index, 4);
+            ctx.store.stage_shard(&protector::stripe_id(&file_id, stripe), index, kind, b"partial").unwrap();
+        }
+    }
+
+    let recovered = recovery::resume_interrupted(&ctx).unwrap();
+    assert_eq!(recovered.len(), 1);
+    assert_eq!(recovered[0].resolution, Resolution::RolledBack);
+    let mut left = Vec::new();
+    scanner::collect_files(&tmp.path().join("shards"), &mut left).unwrap();
+    assert!(left.iter().all(|path| !path.to_string_lossy().contains("staged")), "{:?}", left);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn periodic_checks_do_not_overlap_a_slow_check() {
+    use backend::checker;
+    use backend::shard_store::ShardStore;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    fs::write(source.join("a.bin"), vec![6u8; 4096]).unwrap();
+    let mut ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+    scanner::scan_directories(&ctx).unwrap();
+    // Six shard reads at 100ms make each check take well over the 50ms interval.
+    ctx.store = Arc::new(ShardStore::new(tmp.path().join("shards")).unwrap().with_read_delay(Duration::from_millis(100)));
+
+    let task = checker::spawn_periodic_checks(ctx.clone(), Duration::from_millis(50));
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    task.abort();
+    while ctx.checks_running.load(Ordering::SeqCst) > 0 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let mut runs = metadata::list_check_runs(&ctx.db).unwrap();
+    assert!(!runs.is_empty());
+    let time = |s: &str| chrono::DateTime::parse_from_rfc3339(s).unwrap();
+    runs.sort_by_key(|run| time(&run.started));
+    for pair in runs.windows(2) {
+        assert!(time(&pair[1].started) >= time(&pair[0].finished), "{:?} overlaps {:?}", pair[1], pair[0]);
+    }
+}
+
+#[test]
+fn encode_runs_in_a_span_carrying_its_path_and_duration() {
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+
+    #[derive(Default)]
+    struct Fields(Vec<(String, String)>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    /// Collects the name and fields of every span as it closes.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<(String, Fields)>>>);
+
+    impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Capture {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            values.record(span.extensions_mut().get_mut::<Fields>().unwrap());
+        }
+
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let fields = span.extensions_mut().remove::<Fields>().unwrap();
+            self.0.lock().unwrap().push((span.name().to_string(), fields));
+        }
+    }
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, vec![4u8; 10_000]).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+
+    let capture = Capture::default();
+    let subscriber = tracing_subscriber::registry().with(capture.clone());
+    let record = tracing::subscriber::with_default(subscriber, || protector::protect_file(&ctx, &file)).unwrap();
+
+    let spans = capture.0.lock().unwrap();
+    let (_, fields) = spans.iter().find(|(name, _)| name == "encode").expect("no encode span");
+    let field = |name: &str| fields.0.iter().find(|(field, _)| field == name).map(|(_, value)| value.clone());
+    assert_eq!(field("path"), Some(file.display().to_string()));
+    assert_eq!(field("bytes"), Some("10000".to_string()));
+    assert_eq!(field("duration_ms"), Some(record.encode_duration_ms.to_string()));
+}
+
+#[test]
+fn encoding_during_appends_captures_a_single_version() {
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const CHUNK: usize = 4096;
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("growing.log");
+    let initial: Vec<u8> = (0..1024).flat_map(|i| vec![i as u8; CHUNK]).collect();
+    fs::write(&file, &initial).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+
+    let done = Arc::new(AtomicBool::new(false));
+    let appender = {
+        let (file, done) = (file.clone(), done.clone());
+        std::thread::spawn(move || {
+            let mut out = fs::OpenOptions::new().append(true).open(&file).unwrap();
+            for i in 0..200u32 {
+                out.write_all(&[(i % 251) as u8; CHUNK]).unwrap();
+                std::thread::sleep(std::time::Duration::from_micros(100));
+            }
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    let mut records = Vec::new();
+    loop {
+        let finished = done.load(Ordering::SeqCst);
+        match protector::protect_file(&ctx, &file) {
+            Ok(record) => records.push(record),
+            Err(e) => assert!(e.to_string().contains("kept changing"), "{}", e),
+        }
+        if finished {
+            break;
+        }
+    }
+    appender.join().unwrap();
+
+    let last = fs::read(&file).unwrap();
+    for record in &records {
+        // Earlier encodes' shards were replaced, but their checksums must match a whole prefix.
+        assert_eq!(record.size as usize % CHUNK, 0, "encoded part of an append");
+        assert!(record.size as usize >= initial.len() && record.size as usize <= last.len());
+        assert_eq!(record.checksum, protector::checksum(&last[..record.size as usize]));
+    }
+    let current = records.last().unwrap();
+    assert_eq!(protector::reconstruct(&ctx, current).unwrap(), last);
+}
+
+#[test]
+fn corruption_between_checks_is_reported_as_newly_corrupted_once() {
+    use backend::{checker, repair};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, vec![6u8; 8192]).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    let path = file.to_string_lossy().into_owned();
+
+    let first = checker::run_check(&ctx).unwrap();
+    assert!(first.newly_corrupted.is_empty());
+    fs::write(ctx.store.shard_path(&record.file_id, 1), vec![0u8; record.shard_size]).unwrap();
+    let second = checker::run_check(&ctx).unwrap();
+    let third = checker::run_check(&ctx).unwrap();
+    assert_eq!(second.newly_corrupted, vec![path.clone()]);
+    // Still corrupted, but no longer new.
+    assert_eq!(third.corrupted_paths, vec![path.clone()]);
+    assert!(third.newly_corrupted.is_empty());
+
+    repair::repair_shard(&ctx, &file, 1).unwrap();
+    let fourth = checker::run_check(&ctx).unwrap();
+    assert_eq!(fourth.newly_healed, vec![path]);
+    assert!(fourth.newly_corrupted.is_empty());
+}
+
+#[test]
+fn deleted_files_are_purged_once_past_their_retention() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("old.bin");
+    fs::write(&file, vec![4u8; 4096]).unwrap();
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source],
+        deleted_retention_secs: 3600,
+        ..Default::default()
+    });
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    fs::remove_file(&file).unwrap();
+    assert!(metadata::mark_deleted(&ctx.db, &file).unwrap());
+    let deleted_at = metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap().deleted_at.unwrap();
+    let shards: Vec<PathBuf> = (0..record.total_shards()).map(|i| ctx.store.shard_path(&record.file_id, i)).collect();
+
+    // Within the retention the file can still be recovered.
+    assert!(protector::purge_deleted(&ctx, deleted_at + 3600).unwrap().is_empty());
+    assert!(shards.iter().all(|shard| shard.exists()));
+
+    assert_eq!(protector::purge_deleted(&ctx, deleted_at + 3601).unwrap(), vec![file.clone()]);
+    assert!(metadata::get_file_metadata(&ctx.db, &file).unwrap().is_none());
+    assert!(shards.iter().all(|shard| !shard.exists()));
+}
+
+#[test]
+fn startup_scan_encodes_files_in_the_configured_size_order() {
+    use backend::config::ScanOrder;
+
+    for (order, expected) in [
+        (ScanOrder::SmallestFirst, ["c.bin", "a.bin", "d.bin", "b.bin"]),
+        (ScanOrder::LargestFirst, ["b.bin", "d.bin", "a.bin", "c.bin"]),
+    ] {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_dir(&tmp);
+        for (name, len) in [("a.bin", 2000), ("b.bin", 9000), ("c.bin", 10), ("d.bin", 5000)] {
+            fs::write(source.join(name), vec![1u8; len]).unwrap();
+        }
+        let ctx = test_context(&tmp, AppConfig {
+            watched_directories: vec![source.clone()],
+            initial_scan_order: order,
+            ..Default::default()
+        });
+        scanner::scan_directories(&ctx).unwrap();
+
+        // The change history records each encode in order.
+        let encoded: Vec<PathBuf> = metadata::changes_since(&ctx.db, 0).unwrap().into_iter().map(|c| c.path).collect();
+        let expected: Vec<PathBuf> = expected.iter().map(|name| source.join(name)).collect();
+        assert_eq!(encoded, expected, "{:?}", order);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn mode_and_xattrs_are_restored_on_recovery() {
+    use backend::attrs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("script.sh");
+    fs::write(&file, b"#!/bin/sh\necho protected\n").unwrap();
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o750)).unwrap();
+    attrs::set_xattr(&file, "user.origin", b"camera-1").unwrap();
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source],
+        preserve_attrs: true,
+        ..Default::default()
+    });
+    protector::protect_file(&ctx, &file).unwrap();
+    fs::remove_file(&file).unwrap();
+
+    protector::recover_file(&ctx, &file, &file).unwrap();
+    let meta = fs::metadata(&file).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o7777, 0o750);
+    let restored = attrs::read(&file, &meta).unwrap();
+    assert_eq!(restored.xattrs.get("user.origin").map(Vec::as_slice), Some(&b"camera-1"[..]));
+}
+
+#[test]
+fn same_size_edit_hidden_by_a_coarse_mtime_is_caught_in_network_mode() {
+    for network_fs_mode in [false, true] {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = source_dir(&tmp);
+        let file = source.join("share.txt");
+        fs::write(&file, b"version one").unwrap();
+        let ctx = test_context(&tmp, AppConfig {
+            watched_directories: vec![source],
+            network_fs_mode,
+            ..Default::default()
+        });
+        let record = protector::protect_file(&ctx, &file).unwrap();
+
+        // Same size, and the mtime reads as before, as on a share with coarse timestamps.
+        let mtime = fs::metadata(&file).unwrap().modified().unwrap();
+        fs::write(&file, b"version two").unwrap();
+        fs::File::options().write(true).open(&file).unwrap().set_modified(mtime).unwrap();
+        scanner::scan_directories(&ctx).unwrap();
+
+        let current = metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap();
+        if network_fs_mode {
+            assert_eq!(current.checksum, protector::checksum(b"version two"));
+        } else {
+            assert_eq!(current.checksum, record.checksum, "mtime heuristics should miss the edit");
+        }
+    }
+}
+
+#[test]
+fn rebuilt_shard_replaces_the_damaged_one_only_if_it_matches_its_checksum() {
+    use backend::repair::{self, ShardRepairOutcome};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let file = source.join("data.bin");
+    fs::write(&file, vec![9u8; 8192]).unwrap();
+    let ctx = test_context(&tmp, AppConfig { watched_directories: vec![source], ..Default::default() });
+    let mut record = protector::protect_file(&ctx, &file).unwrap();
+    let shard = ctx.store.shard_path(&record.file_id, 2);
+    let original = fs::read(&shard).unwrap();
+
+    fs::write(&shard, b"bit rot").unwrap();
+    let outcome = repair::repair_shard(&ctx, &file, 2).unwrap();
+    assert_eq!(outcome, ShardRepairOutcome::Repaired(record.shard_checksums[2].clone()));
+    assert_eq!(fs::read(&shard).unwrap(), original);
+
+    // With a stored checksum the rebuild cannot match, the damaged shard stays.
+    record.shard_checksums[2] = protector::checksum(b"something else");
+    metadata::store_file_metadata(&ctx.db, &file, &record).unwrap();
+    fs::write(&shard, b"bit rot").unwrap();
+    let outcome = repair::repair_shard(&ctx, &file, 2).unwrap();
+    assert!(matches!(&outcome, ShardRepairOutcome::Quarantined(reason) if reason.contains("stored checksum")), "{:?}", outcome);
+    assert_eq!(fs::read(&shard).unwrap(), b"bit rot");
+    assert_eq!(metadata::list_quarantined(&ctx.db).unwrap().len(), 1);
+}
+
+#[test]
+fn watched_directories_can_override_the_shard_scheme() {
+    use backend::config::WatchedDirectory;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    let (tiny, large) = (source.join("tiny"), source.join("large"));
+    for (dir, name) in [(&tiny, "icon.png"), (&large, "video.mkv")] {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(name), vec![9u8; 4096]).unwrap();
+    }
+    let ctx = test_context(&tmp, AppConfig {
+        data_shards: 8,
+        parity_shards: 4,
+        watched: vec![
+            WatchedDirectory { path: tiny.clone(), label: None, data_shards: Some(2), parity_shards: Some(1) },
+            WatchedDirectory { path: large.clone(), label: None, data_shards: None, parity_shards: None },
+        ],
+        watched_directories: vec![tiny.clone(), large.clone()],
+        ..Default::default()
+    });
+    scanner::scan_directories(&ctx).unwrap();
+
+    let scheme = |path: PathBuf| {
+        let record = metadata::get_file_metadata(&ctx.db, &path).unwrap().unwrap();
+        (record.data_shards, record.parity_shards, record.shard_checksums.len())
+    };
+    assert_eq!(scheme(tiny.join("icon.png")), (2, 1, 3));
+    assert_eq!(scheme(large.join("video.mkv")), (8, 4, 12));
+
+    // Files under the override still verify and rebuild with their own scheme.
+    let run = backend::checker::run_check(&ctx).unwrap();
+    assert_eq!((run.checked, run.corrupted), (2, 0));
+}
+
+#[test]
+fn quick_scan_tracks_files_at_once_and_encodes_them_afterwards() {
+    use backend::config::StartupScanMode;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    for name in ["a.bin", "b.bin", "c.bin"] {
+        fs::write(source.join(name), vec![5u8; 8192]).unwrap();
+    }
+    let ctx = test_context(&tmp, AppConfig {
+        watched_directories: vec![source.clone()],
+        startup_scan_mode: StartupScanMode::Quick,
+        ..Default::default()
+    });
+
+    assert_eq!(scanner::quick_scan(&ctx).unwrap(), 3);
+    let tracked: Vec<PathBuf> = metadata::list_tracked(&ctx.db).unwrap().into_iter().map(|(p, _)| p).collect();
+    assert_eq!(tracked, vec![source.join("a.bin"), source.join("b.bin"), source.join("c.bin")]);
+    assert_eq!(metadata::list_tracked(&ctx.db).unwrap()[0].1.size, 8192);
+    assert_eq!(metadata::count_files(&ctx.db).unwrap(), 0);
+
+    let summary = scanner::encode_tracked(&ctx).unwrap();
+    assert_eq!(summary.files_protected, 3);
+    assert_eq!(metadata::count_files(&ctx.db).unwrap(), 3);
+    assert!(metadata::list_tracked(&ctx.db).unwrap().is_empty());
+    // Nothing is tracked again once protected.
+    assert_eq!(scanner::quick_scan(&ctx).unwrap(), 0);
+}
+
+#[test]
+fn shard_store_inside_a_watched_directory_is_excluded_from_protection() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = source_dir(&tmp);
+    fs::write(source.join("doc.txt"), b"worth protecting").unwrap();
+    let config = AppConfig {
+        watched_directories: vec![source.clone()],
+        shard_store_dir: source.join(".rs_guard/shards"),
+        shard_headers: false,
+        shard_footers: true,
+        ..Default::default()
+    };
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    let ctx = AppContext::new(config, db).unwrap();
+    assert_eq!(ctx.config.excluded_dirs, vec![source.join(".rs_guard/shards")]);
+
+    scanner::scan_directories(&ctx).unwrap();
+    // A second pass finds the shards the first one wrote, and must leave them alone.
+    scanner::scan_directories(&ctx).unwrap();
+
+    let protected: Vec<PathBuf> = metadata::list_files(&ctx.db).unwrap().into_iter().map(|(p, _)| p).collect();
+    assert_eq!(protected, vec![source.join("doc.txt")]);
+
+    // A directory added later is refused instead, as only those configured
+    // at startup have the store excluded.
+    let error = backend::watcher::register(&ctx, tmp.path()).unwrap_err();
+    assert!(error.is::<backend::watcher::ContainsShardStore>(), "{}", error);
+    assert_eq!(ctx.watched.list(), vec![source]);
+}
+
+#[test]
+fn protect_self_makes_the_config_file_and_metadata_recoverable() {
+    use backend::{salvage, self_protect};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let config_file = tmp.path().join("folders.toml");
+    let config_text = b"watched_directories = [\"./source\"]\nprotect_self = true\n";
+    fs::write(&config_file, config_text).unwrap();
+    let ctx = test_context(&tmp, AppConfig { protect_self: true, ..Default::default() });
+    let file = source_dir(&tmp).join("report.txt");
+    fs::write(&file, b"quarterly numbers").unwrap();
+    protector::protect_file(&ctx, &file).unwrap();
+
+    let guard = self_protect::open(&ctx.config).unwrap();
+    let protected = self_protect::protect(&guard, &ctx, &config_file).unwrap();
+    let config_file = fs::canonicalize(&config_file).unwrap();
+    assert_eq!(protected[0], config_file);
+    assert!(metadata::get_file_metadata(&guard.context().db, &config_file).unwrap().is_some());
+    // Nothing of rs_guard's own ends up in the main DB.
+    assert_eq!(metadata::count_files(&ctx.db).unwrap(), 1);
+
+    fs::write(&config_file, b"garbled").unwrap();
+    let restored = tmp.path().join("restored.toml");
+    guard.recover(&config_file, &restored).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), config_text);
+
+    // The shard footers bring back both files without any DB.
+    let shard_dir = self_protect::self_dir(&ctx.config).join("shards");
+    let salvaged = salvage::recover_from_shards(&shard_dir, &tmp.path().join("salvaged")).unwrap();
+    assert_eq!(salvaged.recovered.len(), 2);
+    let export = salvaged.recovered.iter().find(|path| path.ends_with(self_protect::EXPORT_NAME)).unwrap();
+    assert!(fs::read_to_string(export).unwrap().contains("report.txt"));
+}
+
+#[test]
+fn appending_to_a_protected_file_encodes_only_the_new_stripe() {
+    use std::io::Write;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, AppConfig { append_optimize: true, ..Default::default() });
+    let file = source_dir(&tmp).join("app.log");
+    let mut content: Vec<u8> = (0..60_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&file, &content).unwrap();
+    let first = protector::protect_file(&ctx, &file).unwrap();
+    let base_shards: Vec<_> = (0..first.total_shards()).map(|index| ctx.store.shard_path(&first.file_id, index)).collect();
+    let written: Vec<_> = base_shards.iter().map(|shard| fs::metadata(shard).unwrap().modified().unwrap()).collect();
+
+    let tail: Vec<u8> = (0..9_000u32).map(|i| (i % 13) as u8).collect();
+    fs::OpenOptions::new().append(true).open(&file).unwrap().write_all(&tail).unwrap();
+    content.extend_from_slice(&tail);
+    let appended = protector::protect_file(&ctx, &file).unwrap();
+
+    // The first encode's shards are kept as they were; only the tail was encoded.
+    assert_eq!((appended.file_id.as_str(), appended.shard_size), (first.file_id.as_str(), first.shard_size));
+    assert_eq!(appended.appended.len(), 1);
+    assert_eq!(appended.appended[0].len, tail.len() as u64);
+    assert_eq!(appended.appended[0].shard_size, tail.len().div_ceil(appended.data_shards));
+    for (shard, modified) in base_shards.iter().zip(&written) {
+        assert_eq!(fs::metadata(shard).unwrap().modified().unwrap(), *modified);
+    }
+    assert_eq!(backend::checker::check_file(&ctx, &file).unwrap(), None);
+    let restored = tmp.path().join("restored.log");
+    protector::recover_file(&ctx, &file, &restored).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), content);
+
+    // A lost shard of the appended stripe is rebuilt like any other.
+    let tail_shard = appended.total_shards() + 1;
+    fs::remove_file(ctx.store.shard_path(&appended.appended[0].file_id, 1)).unwrap();
+    assert!(backend::checker::check_file(&ctx, &file).unwrap().is_some());
+    backend::repair::repair_shard(&ctx, &file, tail_shard).unwrap();
+    assert_eq!(backend::checker::check_file(&ctx, &file).unwrap(), None);
+
+    // Rewriting the old content is a full re-encode that drops the stripes.
+    content[10] ^= 0xff;
+    fs::write(&file, &content).unwrap();
+    let rewritten = protector::protect_file(&ctx, &file).unwrap();
+    assert!(rewritten.appended.is_empty());
+    assert!(!ctx.store.shard_path(&appended.appended[0].file_id, 0).exists());
+    protector::recover_file(&ctx, &file, &restored).unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), content);
+}
+
+#[test]
+fn files_above_the_streaming_threshold_are_encoded_in_stripes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, AppConfig {
+        streaming_threshold_bytes: 20_000,
+        streaming_stripe_bytes: 16_384,
+        ..Default::default()
+    });
+    let file = source_dir(&tmp).join("video.bin");
+    let content: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 253) as u8).collect();
+    fs::write(&file, &content).unwrap();
+
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    assert_eq!(record.stripe_size, Some(16_384));
+    assert_eq!(record.appended.len(), 3);
+    assert_eq!(record.shard_size, 16_384 / record.data_shards);
+    assert_eq!(record.appended[2].len, 50_000 - 3 * 16_384);
+    assert_eq!(record.checksum, protector::checksum(&content));
+    assert_eq!(backend::checker::check_file(&ctx, &file).unwrap(), None);
+
+    // A shard of a later stripe is rebuilt from that stripe alone.
+    fs::remove_file(ctx.store.shard_path(&record.appended[1].file_id, 0)).unwrap();
+    assert!(backend::checker::check_file(&ctx, &file).unwrap().is_some());
+    backend::repair::repair_shard(&ctx, &file, 2 * record.total_shards()).unwrap();
+    assert_eq!(backend::checker::check_file(&ctx, &file).unwrap(), None);
+
+    let restored = tmp.path().join("restored.bin");
+    assert!(protector::recover_file(&ctx, &file, &restored).unwrap());
+    assert_eq!(fs::read(&restored).unwrap(), content);
+
+    // Files at or below the threshold are still encoded whole.
+    let small = source_dir(&tmp).join("small.bin");
+    fs::write(&small, &content[..20_000]).unwrap();
+    let record = protector::protect_file(&ctx, &small).unwrap();
+    assert_eq!((record.stripe_size, record.appended.len()), (None, 0));
+}
+
+#[test]
+fn streamed_files_with_footers_are_salvaged_stripe_by_stripe() {
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, AppConfig {
+        shard_footers: true,
+        streaming_threshold_bytes: 20_000,
+        streaming_stripe_bytes: 16_384,
+        ..Default::default()
+    });
+    let file = source_dir(&tmp).join("video.bin");
+    let content: Vec<u8> = (0..50_000u32).map(|i| (i * 7 % 253) as u8).collect();
+    fs::write(&file, &content).unwrap();
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    assert_eq!(record.appended.len(), 3);
+
+    fs::remove_file(ctx.store.shard_path(&record.appended[2].file_id, 1)).unwrap();
+    let salvaged = backend::salvage::recover_from_shards(&tmp.path().join("shards"), &tmp.path().join("salvaged")).unwrap();
+    assert_eq!(salvaged.recovered.len(), 1, "{:?}", salvaged.failed);
+    assert_eq!(fs::read(&salvaged.recovered[0]).unwrap(), content);
+}
+
+#[test]
+fn wide_layouts_record_their_field_and_repair() {
+    use backend::encoder::GaloisField;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, AppConfig { data_shards: 300, parity_shards: 3, ..Default::default() });
+    let file = source_dir(&tmp).join("wide.bin");
+    let content: Vec<u8> = (0..40_000u32).map(|i| (i * 11 % 249) as u8).collect();
+    fs::write(&file, &content).unwrap();
+
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    assert_eq!(record.field, GaloisField::Gf16);
+    assert_eq!(metadata::get_file_metadata(&ctx.db, &file).unwrap().unwrap().field, GaloisField::Gf16);
+
+    fs::remove_file(ctx.store.shard_path(&record.file_id, 150)).unwrap();
+    backend::repair::repair_shard(&ctx, &file, 150).unwrap();
+    assert_eq!(backend::checker::check_file(&ctx, &file).unwrap(), None);
+    let restored = tmp.path().join("restored.bin");
+    assert!(protector::recover_file(&ctx, &file, &restored).unwrap());
+    assert_eq!(fs::read(&restored).unwrap(), content);
+}
+
+#[test]
+fn trusted_source_hash_skips_shard_hashing() {
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, AppConfig { trust_source_hash: true, ..Default::default() });
+    let file = source_dir(&tmp).join("notes.txt");
+    fs::write(&file, b"hashed once at encode time").unwrap();
+    let record = protector::protect_file(&ctx, &file).unwrap();
+    let hash = metadata::get_file_hash(&ctx.db, &file).unwrap().unwrap();
+    assert_eq!(hash, metadata::FileHash::blake3(record.checksum.clone()));
+
+    // Damage inside a shard is not looked for while the source matches...
+    let shard = ctx.store.shard_path(&record.file_id, 4);
+    let mut bytes = fs::read(&shard).unwrap();
+    bytes[0] ^= 0xff;
+    fs::write(&shard, &bytes).unwrap();
+    assert_eq!(backend::checker::check_file(&ctx, &file).unwrap(), None);
+
+    // ...but a hash of another algorithm is never compared with it.
+    metadata::put_file_hash(&ctx.db, &file, &metadata::FileHash { algorithm: "sha256".to_string(), digest: hash.digest })
+        .unwrap();
+    assert!(backend::checker::check_file(&ctx, &file).unwrap().is_some());
+
+    // A damaged source falls back to the full check, which reports it.
+    protector::protect_file(&ctx, &file).unwrap();
+    let meta = fs::metadata(&file).unwrap();
+    fs::write(&file, b"HASHED once at encode time").unwrap();
+    fs::File::options().write(true).open(&file).unwrap().set_modified(meta.modified().unwrap()).unwrap();
+    let finding = backend::checker::check_file(&ctx, &file).unwrap().unwrap();
+    assert!(finding.message().contains("no longer matches"), "{}", finding.message());
+
+    metadata::remove_file_metadata(&ctx.db, &file).unwrap();
+    assert_eq!(metadata::get_file_hash(&ctx.db, &file).unwrap(), None);
+}
+
+#[test]
+fn trusted_source_hash_still_requires_every_shard() {
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, AppConfig { trust_source_hash: true, check_shard_lengths: false, ..Default::default() });
+    let file = source_dir(&tmp).join("notes.txt");
+    fs::write(&file, b"hashed once at encode time").unwrap();
+    let record = protector::protect_file(&ctx, &file).unwrap();
+
+    fs::remove_file(ctx.store.shard_path(&record.file_id, 2)).unwrap();
+    let finding = backend::checker::check_file(&ctx, &file).unwrap().unwrap();
+    assert_eq!(finding.message(), "shard 2 is missing");
+}
+
+#[test]
+fn stored_hash_of_a_replaced_record_is_not_trusted() {
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, AppConfig { trust_source_hash: true, ..Default::default() });
+    let file = source_dir(&tmp).join("report.txt");
+    fs::write(&file, b"first draft").unwrap();
+    protector::protect_file(&ctx, &file).unwrap();
+    let export = metadata::export_json(&ctx.db).unwrap();
+    fs::write(&file, b"final draft").unwrap();
+    protector::protect_file(&ctx, &file).unwrap();
+
+    // The import puts back the record of the first draft, while the stored
+    // hash is still that of the final one, which the source matches.
+    metadata::import_json(&ctx.db, &export).unwrap();
+    assert!(backend::checker::check_file(&ctx, &file).unwrap().is_some());
+}
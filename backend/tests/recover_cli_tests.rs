@@ -0,0 +1,54 @@
+//! Tests for the `recover-from-shards` subcommand of the backend binary.
+
+use backend::config::AppConfig;
+use backend::{metadata, protector, AppContext};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn files_are_rebuilt_from_shard_footers_without_the_metadata_db() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = tmp.path().join("source");
+    fs::create_dir_all(source.join("nested")).unwrap();
+    let files = [
+        (source.join("a.bin"), (0..20_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>()),
+        (source.join("nested/b.txt"), b"footers carry everything needed".to_vec()),
+    ];
+    let shards = tmp.path().join("shards");
+    let db_path = tmp.path().join("db");
+    {
+        let config = AppConfig {
+            watched_directories: vec![source.clone()],
+            shard_store_dir: shards.clone(),
+            shard_footers: true,
+            ..Default::default()
+        };
+        let ctx = AppContext::new(config, metadata::open_db(db_path.to_str().unwrap()).unwrap()).unwrap();
+        for (path, content) in &files {
+            fs::write(path, content).unwrap();
+            protector::protect_file(&ctx, path).unwrap();
+        }
+        // Reads strip the footer again.
+        let record = metadata::get_file_metadata(&ctx.db, &files[0].0).unwrap().unwrap();
+        let shard = ctx.store.read_shard_uncached(&record.file_id, 1).unwrap().unwrap();
+        assert_eq!(protector::checksum(&shard), record.shard_checksums[1]);
+        // Losing a shard on top of the DB is still recoverable.
+        fs::remove_file(ctx.store.shard_path(&record.file_id, 0)).unwrap();
+    }
+    fs::remove_dir_all(&db_path).unwrap();
+
+    let out = tmp.path().join("recovered");
+    let output = Command::new(env!("CARGO_BIN_EXE_backend"))
+        .arg("recover-from-shards")
+        .arg(&shards)
+        .arg("--output")
+        .arg(&out)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    for (path, content) in &files {
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        assert_eq!(&fs::read(out.join(relative)).unwrap(), content, "{}", path.display());
+    }
+}
@@ -0,0 +1,45 @@
+use backend::backlog::Backlog;
+use backend::config::AppConfig;
+use backend::{metadata, scanner, AppContext};
+use std::fs;
+use std::time::Duration;
+
+#[test]
+fn backlog_eta_follows_the_observed_throughput_and_shrinks_as_files_are_done() {
+    let mut backlog = Backlog::new(100 << 20);
+    assert_eq!(backlog.eta_secs(), None);
+
+    // 10 MiB in 2s is 5 MiB/s, leaving 90 MiB for 18s.
+    backlog.done(10 << 20, Duration::from_secs(2));
+    assert_eq!(backlog.remaining_bytes(), 90 << 20);
+    assert_eq!(backlog.eta_secs(), Some(18));
+
+    backlog.done(40 << 20, Duration::from_secs(8));
+    assert_eq!(backlog.eta_secs(), Some(10));
+
+    backlog.done(50 << 20, Duration::from_secs(10));
+    assert_eq!(backlog.eta_secs(), Some(0));
+}
+
+#[test]
+fn scan_clears_its_eta_once_the_backlog_is_encoded() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    for i in 0..3 {
+        fs::write(source.join(format!("{}.bin", i)), vec![i as u8; 50_000]).unwrap();
+    }
+    let config = AppConfig {
+        watched_directories: vec![source],
+        shard_store_dir: tmp.path().join("shards"),
+        ..Default::default()
+    };
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    let ctx = AppContext::new(config, db).unwrap();
+    ctx.status.lock().unwrap().scan_eta_secs = Some(60);
+
+    assert_eq!(scanner::scan_directories(&ctx).unwrap().files_protected, 3);
+    let status = ctx.status.lock().unwrap();
+    assert_eq!(status.scan_eta_secs, None);
+    assert_eq!(status.summary().scan_eta_secs, None);
+}
@@ -0,0 +1,106 @@
+//! Tests for the on-disk shard store.
+
+use backend::shard_store::{ShardKind, ShardStore, RELOCATED_DIR};
+
+#[test]
+fn repeated_shard_reads_are_served_from_cache() {
+    let tmp = tempfile::tempdir().unwrap();
+    let store = ShardStore::new(tmp.path()).unwrap().with_cache_bytes(1024);
+    store.write_shard("abcdef", 0, ShardKind::Data, b"first").unwrap();
+
+    assert_eq!(store.read_shard("abcdef", 0).unwrap().unwrap(), b"first");
+    assert_eq!(store.read_shard("abcdef", 0).unwrap().unwrap(), b"first");
+    assert_eq!(store.disk_reads(), 1);
+
+    // Rewriting a shard must invalidate the cached copy.
+    store.write_shard("abcdef", 0, ShardKind::Data, b"second").unwrap();
+    assert_eq!(store.read_shard("abcdef", 0).unwrap().unwrap(), b"second");
+    assert_eq!(store.disk_reads(), 2);
+}
+
+#[test]
+fn shard_cache_is_bounded_by_bytes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let store = ShardStore::new(tmp.path()).unwrap().with_cache_bytes(8);
+    store.write_shard("abcdef", 0, ShardKind::Data, b"12345").unwrap();
+    store.write_shard("abcdef", 1, ShardKind::Data, b"67890").unwrap();
+
+    store.read_shard("abcdef", 0).unwrap();
+    // Caching the second shard evicts the first to stay within 8 bytes.
+    store.read_shard("abcdef", 1).unwrap();
+    store.read_shard("abcdef", 0).unwrap();
+    assert_eq!(store.disk_reads(), 3);
+}
+
+#[test]
+fn shards_are_spread_over_the_configured_directory_layout() {
+    let tmp = tempfile::tempdir().unwrap();
+    let store = ShardStore::new(tmp.path()).unwrap().with_dir_layout(16, 2);
+    assert_eq!(store.dir_layout(), "2 levels of 16 directories");
+    let ids: Vec<String> = (0..200u32).map(|i| blake3::hash(&i.to_le_bytes()).to_hex().to_string()).collect();
+    for id in &ids {
+        store.write_shard(id, 0, ShardKind::Data, id.as_bytes()).unwrap();
+    }
+
+    let top: Vec<_> = std::fs::read_dir(tmp.path()).unwrap().map(|e| e.unwrap().path()).collect();
+    assert!(top.len() > 1 && top.len() <= 16, "{} top-level directories", top.len());
+    for id in &ids {
+        let relative = store.shard_path(id, 0).strip_prefix(tmp.path()).unwrap().to_path_buf();
+        let dirs: Vec<_> = relative.parent().unwrap().components().collect();
+        assert_eq!(dirs.len(), 2, "{}", relative.display());
+        assert!(dirs.iter().all(|dir| dir.as_os_str().len() == 1));
+        assert_eq!(store.read_shard(id, 0).unwrap().unwrap(), id.as_bytes());
+    }
+
+    // Shards written under the default layout stay readable.
+    let old = ShardStore::new(tmp.path()).unwrap();
+    old.write_shard("abcdef", 1, ShardKind::Parity, b"old layout").unwrap();
+    assert_eq!(store.read_shard_uncached("abcdef", 1).unwrap().unwrap(), b"old layout");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn preallocated_shards_are_reserved_before_being_written() {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let len = 256 * 1024;
+    let file = std::fs::File::create(tmp.path().join("reserved")).unwrap();
+    backend::shard_store::preallocate(&file, len).unwrap();
+    let meta = file.metadata().unwrap();
+    assert_eq!(meta.len(), len);
+    assert!(meta.blocks() * 512 >= len, "{} blocks allocated", meta.blocks());
+
+    let store = ShardStore::new(tmp.path().join("shards")).unwrap().with_headers(false).with_preallocation(true);
+    let data = vec![7u8; len as usize];
+    store.write_shard("abcdef", 0, ShardKind::Data, &data).unwrap();
+    let meta = std::fs::metadata(store.shard_path("abcdef", 0)).unwrap();
+    assert_eq!(meta.len(), len);
+    assert!(meta.blocks() * 512 >= len);
+    assert_eq!(store.read_shard_uncached("abcdef", 0).unwrap().unwrap(), data);
+}
+
+#[test]
+fn locations_with_repeated_errors_are_degraded_and_get_no_new_shards() {
+    let tmp = tempfile::tempdir().unwrap();
+    let store = ShardStore::new(tmp.path()).unwrap().with_error_threshold(3);
+    store.write_shard("ab01", 0, ShardKind::Data, b"before").unwrap();
+    store.write_shard("cd01", 0, ShardKind::Data, b"elsewhere").unwrap();
+
+    // A directory in place of a shard fails every read, like a dying disk.
+    std::fs::create_dir_all(store.shard_path("ab02", 0)).unwrap();
+    for _ in 0..3 {
+        assert!(store.read_shard_uncached("ab02", 0).is_err());
+    }
+    assert_eq!(store.degraded_locations(), vec![tmp.path().join("ab")]);
+
+    store.write_shard("ab03", 0, ShardKind::Data, b"after").unwrap();
+    assert!(store.shard_path("ab03", 0).starts_with(tmp.path().join(RELOCATED_DIR)));
+    assert_eq!(store.read_shard_uncached("ab03", 0).unwrap().unwrap(), b"after");
+
+    // Shards already in the degraded location are still read, and other
+    // locations keep their shards.
+    assert_eq!(store.read_shard_uncached("ab01", 0).unwrap().unwrap(), b"before");
+    store.write_shard("cd02", 0, ShardKind::Data, b"healthy").unwrap();
+    assert!(store.shard_path("cd02", 0).starts_with(tmp.path().join("cd")));
+}
@@ -24,7 +24,7 @@ impl WebInterfaceTests {
         
         // 测试状态端点
         let response = client
-            .get(&format!("{}/api/status", context.server_url()))
+            .get(&format!("{}/api/status?fields=full", context.server_url()))
             .send()
             .await
             .expect("Failed to get status");
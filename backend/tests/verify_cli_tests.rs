@@ -0,0 +1,57 @@
+//! Tests for the `verify` subcommand of the backend binary.
+
+use backend::config::AppConfig;
+use backend::{metadata, protector, AppContext};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn verify_reports_corrupted_file_as_json_and_fails() {
+    let tmp = tempfile::tempdir().unwrap();
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    let healthy = source.join("healthy.bin");
+    let damaged = source.join("damaged.bin");
+    fs::write(&healthy, vec![1u8; 8192]).unwrap();
+    fs::write(&damaged, vec![2u8; 8192]).unwrap();
+
+    let shards = tmp.path().join("shards");
+    let db_path = tmp.path().join("db");
+    let config_path = tmp.path().join("folders.toml");
+    fs::write(&config_path, format!(
+        "watched_directories = [{:?}]\ndata_shards = 4\nparity_shards = 2\nshard_store_dir = {:?}\n",
+        source.to_str().unwrap(),
+        shards.to_str().unwrap(),
+    )).unwrap();
+
+    {
+        let config = AppConfig { watched_directories: vec![source], shard_store_dir: shards, ..Default::default() };
+        let ctx = AppContext::new(config, metadata::open_db(db_path.to_str().unwrap()).unwrap()).unwrap();
+        protector::protect_file(&ctx, &healthy).unwrap();
+        let record = protector::protect_file(&ctx, &damaged).unwrap();
+        fs::write(ctx.store.shard_path(&record.file_id, 1), vec![0u8; record.shard_size]).unwrap();
+        ctx.db.flush().unwrap();
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_backend"))
+        .args(["verify", "--format", "json", "--config"])
+        .arg(&config_path)
+        .arg("--db")
+        .arg(&db_path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["summary"]["total"], 2);
+    assert_eq!(report["summary"]["failed"], 1);
+    let result_for = |path: &std::path::Path| {
+        report["results"].as_array().unwrap().iter()
+            .find(|result| result["path"] == path.to_str().unwrap())
+            .cloned()
+            .unwrap()
+    };
+    assert_eq!(result_for(&damaged)["success"], false);
+    assert!(result_for(&damaged)["error_message"].as_str().unwrap().contains("shard 1"));
+    assert_eq!(result_for(&healthy)["success"], true);
+}
@@ -0,0 +1,126 @@
+//! Tests for how the file watcher reacts to events.
+
+use backend::config::{AppConfig, OverflowStrategy};
+use backend::{metadata, watcher, AppContext};
+use notify::event::{Event, EventKind, Flag};
+use std::fs;
+use tempfile::TempDir;
+
+fn test_context(tmp: &TempDir, overflow: OverflowStrategy) -> AppContext {
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    let config = AppConfig {
+        watched_directories: vec![source],
+        shard_store_dir: tmp.path().join("shards"),
+        watcher_overflow: overflow,
+        ..Default::default()
+    };
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    AppContext::new(config, db).unwrap()
+}
+
+fn overflow_event() -> Event {
+    Event::new(EventKind::Other).set_flag(Flag::Rescan)
+}
+
+#[test]
+fn queue_overflow_triggers_a_reconciling_scan() {
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, OverflowStrategy::Rescan);
+    // Changes whose events were lost in the overflow.
+    let missed = tmp.path().join("source/missed.txt");
+    fs::write(&missed, b"never saw an event").unwrap();
+
+    watcher::handle_event(&ctx, &overflow_event());
+
+    assert!(metadata::get_file_metadata(&ctx.db, &missed).unwrap().is_some());
+}
+
+#[test]
+fn queue_overflow_is_only_logged_with_the_drop_strategy() {
+    let tmp = tempfile::tempdir().unwrap();
+    let ctx = test_context(&tmp, OverflowStrategy::Drop);
+    fs::write(tmp.path().join("source/missed.txt"), b"never saw an event").unwrap();
+
+    watcher::handle_event(&ctx, &overflow_event());
+
+    assert_eq!(metadata::count_files(&ctx.db).unwrap(), 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn files_open_for_writing_are_deferred_until_closed() {
+    use notify::event::{AccessKind, AccessMode, CreateKind};
+    use std::io::Write;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    let config = AppConfig {
+        watched_directories: vec![source.clone()],
+        shard_store_dir: tmp.path().join("shards"),
+        skip_files_open_for_write: true,
+        ..Default::default()
+    };
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    let ctx = AppContext::new(config, db).unwrap();
+    let path = source.join("growing.log");
+
+    let mut writer = fs::File::create(&path).unwrap();
+    writer.write_all(b"first half").unwrap();
+    watcher::handle_event(&ctx, &Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone()));
+    assert!(metadata::get_file_metadata(&ctx.db, &path).unwrap().is_none());
+    let skipped = metadata::list_skipped(&ctx.db).unwrap();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].0, path);
+
+    writer.write_all(b", second half").unwrap();
+    drop(writer);
+    let closed = Event::new(EventKind::Access(AccessKind::Close(AccessMode::Write))).add_path(path.clone());
+    watcher::handle_event(&ctx, &closed);
+    let record = metadata::get_file_metadata(&ctx.db, &path).unwrap().expect("encoded once closed");
+    assert_eq!(record.size, b"first half, second half".len() as u64);
+    assert!(metadata::list_skipped(&ctx.db).unwrap().is_empty());
+}
+
+#[test]
+fn a_churning_file_is_reencoded_at_most_once_per_interval() {
+    use notify::event::{DataChange, ModifyKind};
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let source = tmp.path().join("source");
+    fs::create_dir_all(&source).unwrap();
+    let config = AppConfig {
+        watched_directories: vec![source.clone()],
+        shard_store_dir: tmp.path().join("shards"),
+        min_reencode_interval_secs: 2,
+        ..Default::default()
+    };
+    let db = metadata::open_db(tmp.path().join("db").to_str().unwrap()).unwrap();
+    let ctx = AppContext::new(config, db).unwrap();
+    let path = source.join("live.log");
+    let modified = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content))).add_path(path.clone());
+
+    // Append every 100ms for 5s: encodes at 0s, 2s and 4s, with the changes
+    // after the last one left to a final encode at 6s.
+    let mut log = fs::File::create(&path).unwrap();
+    let started = Instant::now();
+    let mut encodes_at_1s = None;
+    while started.elapsed() < Duration::from_secs(5) {
+        writeln!(log, "line at {:?}", started.elapsed()).unwrap();
+        watcher::handle_event(&ctx, &modified);
+        if encodes_at_1s.is_none() && started.elapsed() >= Duration::from_secs(1) {
+            encodes_at_1s = Some(ctx.session.summary().files_protected);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(encodes_at_1s, Some(1));
+    assert_eq!(ctx.session.summary().files_protected, 3);
+
+    std::thread::sleep(Duration::from_millis(1500));
+    assert_eq!(ctx.session.summary().files_protected, 4);
+    let record = metadata::get_file_metadata(&ctx.db, &path).unwrap().unwrap();
+    assert_eq!(record.size, fs::metadata(&path).unwrap().len());
+}
@@ -1,6 +1,9 @@
 use gloo_console::log;
 use reqwasm::http::Request;
-use shared::{AppStatus, ServiceStatus};
+use shared::{AppStatus, FileDetail, ServiceStatus};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{Event, HtmlInputElement, MessageEvent, WebSocket};
 use yew::prelude::*;
 
 const API_BASE: &str = "/api";
@@ -12,40 +15,86 @@ enum Msg {
     FetchError(String),
 }
 
+/// Formats a number of seconds as e.g. "2h 5m", "3m 10s" or "42s".
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Percent-encodes each segment of a file path for use in an API URL,
+/// keeping the slashes between them, so names with spaces, `#`, `?` or `%`
+/// reach the server intact.
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            segment
+                .bytes()
+                .map(|b| match b {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+                    _ => format!("%{:02X}", b),
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// URL of the `/api/ws` status socket on the host that served the page.
+fn status_socket_url() -> Option<String> {
+    let location = web_sys::window()?.location();
+    let scheme = if location.protocol().ok()? == "https:" { "wss" } else { "ws" };
+    Some(format!("{}://{}{}/ws", scheme, location.host().ok()?, API_BASE))
+}
+
 #[function_component(App)]
 fn app() -> Html {
     let status = use_state(AppStatus::default);
     let error_message = use_state(|| None::<String>);
+    let lookup_path = use_state(String::new);
+    let file_detail = use_state(|| None::<FileDetail>);
 
-    // Fetch status on component mount and then periodically
+    // Follow the status pushed over /api/ws: the full status on connect,
+    // then again whenever a check, scan, repair or encode changes it.
     {
         let status = status.clone();
         let error_message = error_message.clone();
         use_effect_with((), move |_| {
-            let status = status.clone();
-            let error_message = error_message.clone();
-            wasm_bindgen_futures::spawn_local(async move {
-                let fetched_status = Request::get(&format!("{}/status", API_BASE))
-                    .send()
-                    .await;
-
-                match fetched_status {
-                    Ok(response) => {
-                        if response.ok() {
-                             let parsed_status: Result<AppStatus, _> = response.json().await;
-                             match parsed_status {
-                                 Ok(s) => status.set(s),
-                                 Err(e) => error_message.set(Some(format!("JSON parsing error: {}", e))),
-                             }
-                        } else {
-                            let err_text = response.text().await.unwrap_or_default();
-                            error_message.set(Some(format!("API error [{}]: {}", response.status(), err_text)));
-                        }
+            let socket = status_socket_url().and_then(|url| WebSocket::new(&url).ok());
+            let on_message = {
+                let error_message = error_message.clone();
+                Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                    let Some(text) = event.data().as_string() else {
+                        return;
+                    };
+                    match serde_json::from_str::<AppStatus>(&text) {
+                        Ok(s) => status.set(s),
+                        Err(e) => error_message.set(Some(format!("JSON parsing error: {}", e))),
                     }
-                    Err(e) => error_message.set(Some(format!("Request error: {}", e))),
+                })
+            };
+            let on_close = {
+                let error_message = error_message.clone();
+                Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+                    error_message.set(Some("Lost the status connection; reload to reconnect".to_string()));
+                })
+            };
+            match &socket {
+                Some(socket) => {
+                    socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+                    socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
                 }
-            });
-            || ()
+                None => error_message.set(Some("Cannot open the status connection".to_string())),
+            }
+            move || {
+                if let Some(socket) = socket {
+                    socket.set_onclose(None);
+                    let _ = socket.close();
+                }
+                drop((on_message, on_close));
+            }
         });
     }
 
@@ -77,6 +126,42 @@ fn app() -> Html {
         })
     };
 
+    let on_lookup_input = {
+        let lookup_path = lookup_path.clone();
+        Callback::from(move |e: InputEvent| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            lookup_path.set(input.value());
+        })
+    };
+
+    let on_lookup = {
+        let lookup_path = lookup_path.clone();
+        let file_detail = file_detail.clone();
+        let error_message = error_message.clone();
+        Callback::from(move |_| {
+            let path = (*lookup_path).clone();
+            let file_detail = file_detail.clone();
+            let error_message = error_message.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                match Request::get(&format!("{}/files{}", API_BASE, encode_path(&path))).send().await {
+                    Ok(response) if response.ok() => match response.json::<FileDetail>().await {
+                        Ok(detail) => file_detail.set(Some(detail)),
+                        Err(e) => error_message.set(Some(format!("JSON parsing error: {}", e))),
+                    },
+                    Ok(response) if response.status() == 404 => {
+                        file_detail.set(None);
+                        error_message.set(Some(format!("{} is not protected", path)));
+                    }
+                    Ok(response) => {
+                        let err_text = response.text().await.unwrap_or_default();
+                        error_message.set(Some(format!("API error [{}]: {}", response.status(), err_text)));
+                    }
+                    Err(e) => error_message.set(Some(format!("Request error: {}", e))),
+                }
+            });
+        })
+    };
+
     let status_text = format!("{:?}", status.status);
     let status_color = match status.status {
         ServiceStatus::Idle => "bg-green-100 text-green-800",
@@ -98,6 +183,13 @@ fn app() -> Html {
                         <p>{err}</p>
                     </div>
                 }
+                if !status.degraded_shard_locations.is_empty() {
+                    <div class="bg-orange-100 border-l-4 border-orange-500 text-orange-700 p-4 mb-4" role="alert">
+                        <p class="font-bold">{"Degraded shard locations"}</p>
+                        <p>{"These locations keep failing and no longer receive new shards. Check their disks:"}</p>
+                        { for status.degraded_shard_locations.iter().map(|location| html!{ <p class="font-mono">{location}</p> }) }
+                    </div>
+                }
 
                 // --- Status & Actions ---
                 <div class="bg-white p-6 rounded-lg shadow-md mb-6">
@@ -108,6 +200,9 @@ fn app() -> Html {
                             <span class={classes!("px-3", "py-1", "text-sm", "font-semibold", "rounded-full", status_color)}>
                                 {status_text}
                             </span>
+                            if let Some(eta) = status.scan_eta_secs {
+                                <span class="text-gray-500 ml-2">{format!("about {} until all files are protected", format_duration(eta))}</span>
+                            }
                         </div>
                         <div class="flex space-x-2">
                             <button onclick={on_run_check} class="bg-blue-500 hover:bg-blue-600 text-white font-bold py-2 px-4 rounded transition-colors duration-200">
@@ -125,7 +220,11 @@ fn app() -> Html {
                     <div class="bg-white p-5 rounded-lg shadow-md">
                         <h3 class="font-semibold text-slate-600 mb-2">{"Shard Configuration"}</h3>
                         <p class="text-3xl font-bold text-slate-800">{format!("{}+{}", status.data_shards, status.parity_shards)}</p>
-                        <p class="text-gray-500">{"Data + Parity Shards"}</p>
+                        if status.mirror_mode {
+                            <p class="text-gray-500">{format!("Mirror mode: {} full copies", 1 + status.parity_shards)}</p>
+                        } else {
+                            <p class="text-gray-500">{"Data + Parity Shards"}</p>
+                        }
                     </div>
                      <div class="bg-white p-5 rounded-lg shadow-md">
                         <h3 class="font-semibold text-slate-600 mb-2">{"Protected Files"}</h3>
@@ -139,12 +238,43 @@ fn app() -> Html {
                     </div>
                 </div>
 
+                // --- File Lookup ---
+                <div class="bg-white p-5 rounded-lg shadow-md mb-6">
+                    <h3 class="font-semibold text-slate-600 mb-2">{"File Details"}</h3>
+                    <div class="flex space-x-2 mb-3">
+                        <input
+                            type="text"
+                            placeholder="/path/to/protected/file"
+                            class="flex-1 border rounded px-2 py-1 font-mono text-sm"
+                            value={(*lookup_path).clone()}
+                            oninput={on_lookup_input}
+                        />
+                        <button onclick={on_lookup} class="bg-slate-600 hover:bg-slate-700 text-white font-bold py-1 px-4 rounded transition-colors duration-200">
+                            {"Look Up"}
+                        </button>
+                    </div>
+                    if let Some(detail) = &*file_detail {
+                        <p><code class="bg-slate-100 rounded px-1">{detail.path.clone()}</code></p>
+                        <p class="text-xl font-bold text-slate-800">{detail.fault_tolerance.description.clone()}</p>
+                        <p class="text-gray-500">
+                            {format!("{} bytes in {}+{} shards", detail.size, detail.data_shards, detail.parity_shards)}
+                        </p>
+                    }
+                </div>
+
                 // --- Monitored Directories & Logs ---
                 <div class="grid grid-cols-1 lg:grid-cols-2 gap-6">
                     <div class="bg-white p-5 rounded-lg shadow-md">
                         <h3 class="font-semibold text-slate-600 mb-2">{"Monitored Directories"}</h3>
                         <ul class="list-disc list-inside text-gray-700 space-y-1">
-                            { for status.watched_dirs.iter().map(|dir| html!{ <li><code class="bg-slate-100 rounded px-1">{dir}</code></li> }) }
+                            { for status.per_directory.iter().map(|dir| html!{
+                                <li>
+                                    if let Some(label) = &dir.label {
+                                        <span class="font-semibold mr-2">{label.clone()}</span>
+                                    }
+                                    <code class="bg-slate-100 rounded px-1">{dir.path.clone()}</code>
+                                </li>
+                            }) }
                         </ul>
                     </div>
                      <div class="bg-white p-5 rounded-lg shadow-md">
@@ -16,11 +16,483 @@ pub enum ServiceStatus {
 pub struct AppStatus {
     pub status: ServiceStatus,
     pub watched_dirs: Vec<String>,
+    /// The watched directories with their configured labels.
+    #[serde(default)]
+    pub per_directory: Vec<DirectoryStatus>,
     pub last_check_time: Option<String>,
     pub last_check_result: String,
     pub total_files: u64,
     pub protected_files: u64,
     pub data_shards: usize,
     pub parity_shards: usize,
+    /// True when `data_shards` is 1, so parity shards are plain copies of the data.
+    pub mirror_mode: bool,
+    /// Set when the last bulk encode was skipped because the shard store is low on inodes.
+    pub low_inodes: bool,
+    /// Pushes to replication peers that have not succeeded yet.
+    pub replication_pending: u64,
+    /// Failed push attempts since startup; each one is retried.
+    pub replication_failures: u64,
+    pub last_replication_error: Option<String>,
+    /// Protected files not verified within `max_verification_age_secs`.
+    pub stale_files: u64,
+    /// Operations interrupted by a crash and how they were resolved at startup.
+    pub recovered_operations: Vec<String>,
+    /// On-disk size of the metadata database after its last compaction.
+    #[serde(default)]
+    pub db_size_bytes: u64,
+    /// Bytes the scheduled compactions reclaimed since startup.
+    #[serde(default)]
+    pub db_reclaimed_bytes: u64,
+    /// Estimated seconds until the files found by the running scan are
+    /// encoded, from the throughput seen so far. `None` when no encode
+    /// backlog is being worked through.
+    #[serde(default)]
+    pub scan_eta_secs: Option<u64>,
+    /// Top-level directories of the shard store that failed too often and
+    /// no longer take new shards. They need an operator's attention.
+    #[serde(default)]
+    pub degraded_shard_locations: Vec<String>,
     pub logs: Vec<String>,
 }
+
+/// A watched directory as listed in [`AppStatus::per_directory`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DirectoryStatus {
+    pub path: String,
+    /// Human-readable name from the `[[watched]]` config form.
+    pub label: Option<String>,
+}
+
+/// The core of [`AppStatus`] without its lists, as returned by
+/// `/api/status?fields=summary` for cheap polling.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct StatusSummary {
+    pub status: ServiceStatus,
+    pub last_check_time: Option<String>,
+    pub last_check_result: String,
+    pub total_files: u64,
+    pub protected_files: u64,
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub mirror_mode: bool,
+    pub low_inodes: bool,
+    pub replication_pending: u64,
+    pub replication_failures: u64,
+    pub stale_files: u64,
+    #[serde(default)]
+    pub scan_eta_secs: Option<u64>,
+    #[serde(default)]
+    pub degraded_shard_locations: Vec<String>,
+}
+
+impl AppStatus {
+    pub fn summary(&self) -> StatusSummary {
+        StatusSummary {
+            status: self.status.clone(),
+            last_check_time: self.last_check_time.clone(),
+            last_check_result: self.last_check_result.clone(),
+            total_files: self.total_files,
+            protected_files: self.protected_files,
+            data_shards: self.data_shards,
+            parity_shards: self.parity_shards,
+            mirror_mode: self.mirror_mode,
+            low_inodes: self.low_inodes,
+            replication_pending: self.replication_pending,
+            replication_failures: self.replication_failures,
+            stale_files: self.stale_files,
+            scan_eta_secs: self.scan_eta_secs,
+            degraded_shard_locations: self.degraded_shard_locations.clone(),
+        }
+    }
+}
+
+/// Result of re-encoding files whose source changed without rs_guard noticing.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReprotectResult {
+    /// Protected files whose source was compared against its stored checksum.
+    pub examined: u64,
+    /// Files re-encoded from their current source content.
+    pub reprotected: u64,
+    /// Files whose source could not be read or re-encoded.
+    pub failed: u64,
+}
+
+/// Result of `/api/refresh-metadata`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RefreshResult {
+    /// Protected files whose source was present and compared.
+    pub examined: u64,
+    /// Unchanged files whose stored size or mtime was corrected.
+    pub refreshed: u64,
+    /// Files re-encoded because their content changed.
+    pub reencoded: u64,
+    /// Files whose source could not be read or re-encoded.
+    pub failed: u64,
+}
+
+/// Files that changed since a point in time, as returned by `/api/changes`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChangeSet {
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// A shard rebuilt by `/api/repair-shard`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardRepairResult {
+    pub path: String,
+    pub shard_index: usize,
+    /// Checksum of the rewritten shard.
+    pub checksum: String,
+}
+
+/// A file rebuilt by `/api/recover`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecoverResult {
+    pub path: String,
+    pub out: String,
+    /// The written file was read back and matches the stored checksum.
+    pub verified: bool,
+}
+
+/// Totals over all protected files, as returned by `/api/shard-store/stats`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ShardStoreStats {
+    pub files: u64,
+    /// Bytes of shards written for all files.
+    pub shard_bytes: u64,
+    /// Source bytes that were encoded.
+    pub encoded_bytes: u64,
+    /// Time spent encoding all files, in milliseconds.
+    pub encode_duration_ms: u64,
+    /// `encoded_bytes` per second of encoding; unset until something took measurable time.
+    pub average_encode_bytes_per_sec: Option<f64>,
+    /// How shards are spread over subdirectories, e.g. "1 level of 256 directories".
+    pub dir_layout: String,
+}
+
+/// Transient errors since startup or the last reset, as returned by
+/// `/api/errors`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorCounts {
+    /// Shard files that exist but could not be read.
+    pub shard_read_errors: u64,
+    /// Sources read again because they changed while being encoded.
+    pub source_rereads: u64,
+    /// Failed pushes to replication peers, each retried later.
+    pub replication_retries: u64,
+    /// Notifications the webhook did not accept.
+    pub notification_failures: u64,
+}
+
+/// One location of the shard store, as returned by `/api/shard-store/health`:
+/// the store root, or a directory in it that is a symlink or mount point.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ShardLocationHealth {
+    pub path: String,
+    /// The location exists and a file could be written to it.
+    pub reachable: bool,
+    /// Unset when the location is unreachable or the platform cannot tell.
+    pub free_bytes: Option<u64>,
+    pub shard_count: u64,
+    /// Shards or directories that could not be opened or read.
+    pub read_errors: u64,
+}
+
+/// A file excluded from repair, as returned by `/api/quarantine`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuarantinedFile {
+    pub path: String,
+    pub reason: String,
+    /// Seconds since the Unix epoch.
+    pub quarantined_at: u64,
+}
+
+/// What started an integrity check.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckTrigger {
+    /// The periodic check task. Runs recorded before triggers were tracked
+    /// read as scheduled, as most of them were.
+    #[default]
+    Scheduled,
+    /// Requested through the API or the command line.
+    Manual,
+    /// The first periodic check, run as the service starts.
+    Startup,
+    /// Started in reaction to an event rather than a timer or a request,
+    /// e.g. by a tool embedding rs_guard.
+    AutoAfterEvent,
+}
+
+/// Findings of one integrity check, as returned by `/api/checks`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CheckRun {
+    pub id: u64,
+    #[serde(default)]
+    pub trigger: CheckTrigger,
+    /// RFC 3339 timestamps.
+    pub started: String,
+    pub finished: String,
+    /// Protected files that were examined.
+    pub checked: u64,
+    /// Files with a damaged or missing shard, or whose source no longer matches.
+    pub corrupted: u64,
+    pub corrupted_paths: Vec<String>,
+    /// Files whose source was edited without being re-protected.
+    #[serde(default)]
+    pub drifted: u64,
+    #[serde(default)]
+    pub drifted_paths: Vec<String>,
+    /// Files corrupted now that were not in the previous run.
+    #[serde(default)]
+    pub newly_corrupted: Vec<String>,
+    /// Files corrupted in the previous run that checked out this time.
+    #[serde(default)]
+    pub newly_healed: Vec<String>,
+}
+
+/// Combined outcome of `POST /api/check-and-repair`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CheckAndRepairResult {
+    /// The check run recorded for the check half.
+    pub check_id: u64,
+    pub checked: u64,
+    pub corrupted: u64,
+    /// Corrupted files that check out after their repair.
+    pub repaired: u64,
+    pub failed: u64,
+    pub failed_paths: Vec<String>,
+    /// Files edited without being re-protected; these are not repaired.
+    pub drifted: u64,
+}
+
+/// Result of `POST /api/rebuild`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RebuildResult {
+    pub path: String,
+    /// Files whose shards and records were dropped before re-encoding.
+    pub removed: u64,
+    /// Files encoded from scratch with the current config.
+    pub protected: u64,
+    pub failed: u64,
+    pub failed_paths: Vec<String>,
+}
+
+/// Result of `POST` and `DELETE /api/watched-dirs`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct WatchedDirResult {
+    pub path: String,
+    /// Every watched directory after the change.
+    pub watched_dirs: Vec<String>,
+    /// Files whose shards and records were dropped along with a removed
+    /// directory, when asked to purge them.
+    #[serde(default)]
+    pub purged: u64,
+}
+
+/// JSON body of an error from endpoints that return one rather than plain text.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ApiError {
+    pub error: String,
+}
+
+/// Whether every file that should be protected is, as returned by
+/// `POST /api/coverage`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CoverageReport {
+    /// Files under the watched directories that pass the filters.
+    pub eligible_files: u64,
+    /// Eligible files with an up-to-date record.
+    pub protected_files: u64,
+    /// Eligible files never encoded, or changed since their last encode.
+    pub unprotected_paths: Vec<String>,
+    /// Protected files the verification found damaged.
+    pub corrupted_paths: Vec<String>,
+}
+
+/// What `GET /api/report/signed` attests to: the coverage of the watched
+/// directories and the result of a full check, at a point in time.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct IntegrityReport {
+    /// RFC 3339 time the report was produced.
+    pub generated_at: String,
+    pub coverage: CoverageReport,
+}
+
+/// An [`IntegrityReport`] with a detached ed25519 signature.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignedReport {
+    /// The report as JSON, exactly the bytes that were signed.
+    pub report: String,
+    /// Hex-encoded signature over `report`.
+    pub signature: String,
+    /// Hex-encoded public key to verify `signature` with.
+    pub public_key: String,
+}
+
+/// A file whose replica on a peer differs from the local shards.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplicaMismatch {
+    pub peer: String,
+    pub path: String,
+    /// The peer holds no replica of the file at all.
+    pub missing: bool,
+    /// Shards the peer lacks or whose checksum differs from the local one.
+    pub shards: Vec<usize>,
+}
+
+/// Result of comparing the local shards with the replication peers', as
+/// returned by `POST /api/replication/verify`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReplicationVerifyReport {
+    /// Files compared with every reachable peer.
+    pub files_checked: u64,
+    pub mismatches: Vec<ReplicaMismatch>,
+    /// Peers that could not be asked, with the error.
+    pub unreachable_peers: Vec<String>,
+    /// Mismatched files queued to be pushed again.
+    pub resynced: u64,
+}
+
+/// The build and its active options, as returned by `/api/version` for
+/// support requests.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VersionInfo {
+    pub version: String,
+    /// Capabilities compiled into this build, e.g. `io_priority`.
+    pub features: Vec<String>,
+    /// Optional behaviors by name, and whether the config turns them on.
+    pub runtime_config: std::collections::BTreeMap<String, bool>,
+}
+
+/// Liveness of the check loop, as returned by `/api/heartbeat`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeartbeatStatus {
+    /// RFC 3339 time the last check cycle completed, if any did yet.
+    pub last_beat: Option<String>,
+    /// Milliseconds since the last beat, or since startup without one.
+    pub age_ms: u64,
+    /// Older than `heartbeat_max_age_secs`.
+    pub stale: bool,
+}
+
+/// Encode and repair queues and workers, as returned by `/api/workers`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct WorkerStats {
+    /// Files waiting to be encoded, e.g. the rest of a scan.
+    pub encode_queue_depth: usize,
+    /// Shard repairs waiting, e.g. for a lock held by a check.
+    pub repair_queue_depth: usize,
+    pub active_encode_workers: usize,
+    pub active_repair_workers: usize,
+}
+
+/// How the corrupted files differ between two check runs, as returned by
+/// `/api/checks/diff`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct CheckDiff {
+    /// Corrupted in the later run but not the earlier one.
+    pub newly_corrupted: Vec<String>,
+    /// Corrupted in the earlier run but not the later one.
+    pub newly_healed: Vec<String>,
+    /// Corrupted in both runs.
+    pub still_corrupted: Vec<String>,
+}
+
+/// Version of the `/api/metadata/query` response format. It only changes
+/// when a field is removed or changes meaning; new fields may be added.
+pub const METADATA_QUERY_VERSION: u32 = 1;
+
+/// Where a protected file stands, as filtered on by `/api/metadata/query`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordStatus {
+    /// The source exists and is checked and repaired as usual.
+    Protected,
+    /// The source was deleted; the record and shards are kept for recovery.
+    Deleted,
+    /// The source exists but was taken out of automatic repair.
+    Quarantined,
+}
+
+/// A file record together with its status.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueriedRecord {
+    pub status: RecordStatus,
+    #[serde(flatten)]
+    pub file: FileDetail,
+}
+
+/// One page of `/api/metadata/query` results, ordered by path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetadataQueryPage {
+    /// See [`METADATA_QUERY_VERSION`].
+    pub version: u32,
+    /// Records matching the filters, across all pages.
+    pub total: u64,
+    pub offset: u64,
+    pub records: Vec<QueriedRecord>,
+    /// Offset of the next page, if there is one.
+    pub next_offset: Option<u64>,
+}
+
+/// A protected file as returned by `/api/files/{path}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileDetail {
+    pub path: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub mtime: u64,
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub checksum: String,
+    pub shard_checksums: Vec<String>,
+    /// Root of a Merkle tree over `shard_checksums`, if one was stored.
+    pub merkle_root: Option<String>,
+    pub tags: Vec<String>,
+    pub priority: u8,
+    /// Time taken by the last encode, in milliseconds.
+    pub encode_duration_ms: u64,
+    /// Bytes written to the shard store by the last encode.
+    pub shard_bytes: u64,
+    /// Seconds since the Unix epoch.
+    pub protected_at: u64,
+    /// When a check last found the file healthy (seconds since the Unix epoch).
+    pub last_verified: Option<u64>,
+    pub deleted_at: Option<u64>,
+    /// Shards found on another device than the shard store, when
+    /// `verify_shard_device` is enabled.
+    #[serde(default)]
+    pub misplaced_shards: Vec<usize>,
+    #[serde(default)]
+    pub fault_tolerance: FaultTolerance,
+}
+
+/// How much of a file's shards can be lost while it stays recoverable.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FaultTolerance {
+    pub total_shards: usize,
+    /// Shards that may be lost, i.e. the number of parity shards.
+    pub max_lost_shards: usize,
+    /// `max_lost_shards` as a percentage of `total_shards`.
+    pub percent: f64,
+    /// The above in words, e.g. "Survives losing up to 2 of its 6 pieces (33%)".
+    pub description: String,
+}
+
+impl FaultTolerance {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Self {
+        let total_shards = data_shards + parity_shards;
+        let percent = if total_shards == 0 { 0.0 } else { 100.0 * parity_shards as f64 / total_shards as f64 };
+        let pieces = if data_shards == 1 { "copies" } else { "pieces" };
+        let description = match parity_shards {
+            0 => format!("Cannot survive losing any of its {} {}", total_shards, pieces),
+            1 => format!("Survives losing 1 of its {} {} ({:.0}%)", total_shards, pieces, percent),
+            lost => format!("Survives losing up to {} of its {} {} ({:.0}%)", lost, total_shards, pieces, percent),
+        };
+        Self { total_shards, max_lost_shards: parity_shards, percent, description }
+    }
+}
@@ -34,7 +34,7 @@ impl TestHelper {
     /// 获取服务状态
     async fn get_status(&self) -> Result<Value, reqwest::Error> {
         self.client
-            .get(&format!("{}/api/status", self.base_url))
+            .get(&format!("{}/api/status?fields=full", self.base_url))
             .send()
             .await?
             .json()